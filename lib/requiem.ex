@@ -350,7 +350,7 @@ defmodule Requiem do
       @spec close(non_neg_integer, atom) :: no_return
       def close(code, reason), do: send(self(), {:__close__, true, code, reason})
 
-      @spec stream_send(non_neg_integer, binary, boolean) :: no_return
+      @spec stream_send(non_neg_integer, binary | [binary], boolean) :: no_return
       def stream_send(stream_id, data, fin) do
         if Requiem.StreamId.is_writable?(stream_id) do
           send(self(), {:__stream_send__, stream_id, data, fin})
@@ -361,6 +361,63 @@ defmodule Requiem do
         end
       end
 
+      # sends the chunks, a header and its body say, unframed and in one piece,
+      # or none of them when the stream can't take them all
+      @spec stream_send_full(non_neg_integer, [binary], boolean) :: no_return
+      def stream_send_full(stream_id, data, fin) do
+        if Requiem.StreamId.is_writable?(stream_id) do
+          send(self(), {:__stream_send_full__, stream_id, data, fin})
+        else
+          Logger.error(
+            "<Requiem.Connection> You can't send data on this stream[stream_id: #{stream_id}]. This stream is not writable."
+          )
+        end
+      end
+
+      # with `dgram_pull_mode`, after handle_info(:dgram_readable, conn, state):
+      # dgram_peek/1 passes up to `max_len` bytes of the next datagram, as received, to
+      # handle_info({:dgram_peeked, prefix, total_len}, conn, state), dgram_recv/0 takes it
+      # into handle_dgram/3 and dgram_drop/0 drops it
+      @spec dgram_peek(non_neg_integer) :: no_return
+      def dgram_peek(max_len), do: send(self(), {:__dgram_peek__, max_len})
+
+      @spec dgram_recv() :: no_return
+      def dgram_recv(), do: send(self(), {:__dgram_recv__, true})
+
+      @spec dgram_drop() :: no_return
+      def dgram_drop(), do: send(self(), {:__dgram_recv__, false})
+
+      # validates the path of the client again, handle_info({:path_validated, address},
+      # conn, state) or handle_info({:path_failed, address}, conn, state) telling the result
+      @spec probe_path(non_neg_integer) :: no_return
+      def probe_path(timeout_ms \\ 3_000), do: send(self(), {:__probe_path__, timeout_ms})
+
+      # the priority of a stream (RFC 9218), `urgency` from 0, the most urgent, to 7
+      @spec stream_priority(non_neg_integer, 0..7, boolean) :: no_return
+      def stream_priority(stream_id, urgency, incremental \\ true),
+        do: send(self(), {:__stream_priority__, stream_id, urgency, incremental})
+
+      # with `stream_pull_mode`, reads up to `max_bytes` of a stream into handle_stream/4,
+      # after handle_info({:stream_readable, stream_id}, conn, state)
+      @spec stream_read(non_neg_integer, pos_integer) :: no_return
+      def stream_read(stream_id, max_bytes),
+        do: send(self(), {:__stream_read__, stream_id, max_bytes})
+
+      # the data of the stream which is still held back `timeout_ms` after it was sent
+      # is dropped, and the stream reset with `code`
+      @spec stream_deadline(non_neg_integer, non_neg_integer, non_neg_integer) :: no_return
+      def stream_deadline(stream_id, timeout_ms, code),
+        do: send(self(), {:__stream_deadline__, stream_id, timeout_ms, code})
+
+      @spec shutdown(non_neg_integer, atom | binary, non_neg_integer) ::
+              :ok | {:timeout, non_neg_integer}
+      def shutdown(code, reason, timeout \\ 5_000),
+        do: Requiem.Shutdown.run(__MODULE__, code, reason, timeout)
+
+      @spec handoff(non_neg_integer, pid) :: no_return
+      def handoff(code, to),
+        do: send(self(), {:__handoff__, code, to})
+
       @spec stream_open(boolean, term) :: no_return
       def stream_open(is_bidi, message),
         do: send(self(), {:__stream_open__, is_bidi, message})
@@ -369,6 +426,18 @@ defmodule Requiem do
       def dgram_send(data),
         do: send(self(), {:__dgram_send__, data})
 
+      # a burst of datagrams sent at once, those which don't fit in the send queue dropped
+      @spec dgram_send_batch([binary]) :: no_return
+      def dgram_send_batch(data),
+        do: send(self(), {:__dgram_send_batch__, data})
+
+      # an HTTP datagram (RFC 9297) of the request stream `flow_id`, which isn't the
+      # WebTransport session's. those received are passed to
+      # handle_info({:h3_dgram, flow_id, data}, conn, state)
+      @spec h3_dgram_send(non_neg_integer, binary) :: no_return
+      def h3_dgram_send(flow_id, data),
+        do: send(self(), {:__h3_dgram_send__, flow_id, data})
+
       @otp_app Keyword.fetch!(opts, :otp_app)
 
       @impl Requiem
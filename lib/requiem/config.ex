@@ -7,19 +7,64 @@ defmodule Requiem.Config do
           :server_name
           | :host
           | :port
+          | :datapath
+          | :datapath_recv_batch_size
+          | :datapath_send_batch_size
+          | :datapath_gso
+          | :datapath_gro
+          | :datapath_workers
+          | :datapath_idle_reap_timeout
+          | :datapath_socket
+          | :statsd_address
+          | :statsd_interval
+          | :statsd_prefix
+          | :statsd_tags
+          | :control_socket
           | :dispatcher_pool_size
           | :socket_pool_size
           | :socket_read_timeout
+          | :sync_drain
+          | :qlog_pid
+          | :qlog_dir
+          | :qlog_max_file_size
+          | :qlog_max_total_size
+          | :qlog_gzip
+          | :congestion_events
+          | :pmtu_events
+          | :event_timestamps
+          | :stream_close_events
+          | :packet_tap
+          | :path_samples
+          | :path_sample_interval
+          | :stream_recv_window
+          | :stream_recv_overflow
+          | :stream_pull_mode
+          | :dgram_pull_mode
+          | :dgram_budget
+          | :keepalive_interval
+          | :max_lifetime
+          | :max_inactivity
+          | :stream_send_deadline
+          | :send_order
+          | :stream_framing
+          | :impairment
+          | :owner_down_close_code
+          | :max_connections
+          | :trace_context
           | :socket_write_timeout
           | :token_secret
           | :connection_id_secret
           | :dgram_queue_size
+          | :stream_buffer_size
+          | :stream_buffer_min_size
+          | :stream_buffer_max_size
           | :cert_chain
           | :priv_key
           | :verify_locations_file
           | :verify_locations_directory
           | :grease
           | :verify_peer
+          | :peer_cert_pins
           | :enable_early_data
           | :application_protos
           | :max_idle_timeout
@@ -35,14 +80,58 @@ defmodule Requiem.Config do
           | :disable_active_migration
           | :cc_algorithm_name
           | :enable_hystart
+          | :max_field_section_size
+          | :max_concurrent_requests
+          | :max_header_count
 
   @default_values [
     server_name: "ReQUIem",
     host: "0.0.0.0",
     port: 443,
+    datapath: :beam,
+    datapath_recv_batch_size: 32,
+    datapath_send_batch_size: 32,
+    datapath_gso: true,
+    datapath_gro: true,
+    datapath_workers: 1,
+    datapath_idle_reap_timeout: 0,
+    datapath_socket: nil,
+    statsd_address: nil,
+    statsd_interval: 10_000,
+    statsd_prefix: "requiem",
+    statsd_tags: true,
+    control_socket: nil,
     dispatcher_pool_size: 10,
     socket_pool_size: 0,
     socket_read_timeout: 100,
+    sync_drain: false,
+    qlog_pid: nil,
+    qlog_dir: nil,
+    qlog_max_file_size: 10_000_000,
+    qlog_max_total_size: 1_000_000_000,
+    qlog_gzip: false,
+    congestion_events: false,
+    pmtu_events: false,
+    event_timestamps: false,
+    stream_close_events: false,
+    packet_tap: nil,
+    path_samples: 0,
+    path_sample_interval: 1_000,
+    stream_recv_window: nil,
+    stream_recv_overflow: :pause,
+    stream_pull_mode: false,
+    dgram_pull_mode: false,
+    dgram_budget: nil,
+    keepalive_interval: nil,
+    max_lifetime: nil,
+    max_inactivity: nil,
+    stream_send_deadline: nil,
+    send_order: :dgram_first,
+    stream_framing: :raw,
+    impairment: nil,
+    owner_down_close_code: 0x0,
+    max_connections: nil,
+    trace_context: nil,
     socket_write_timeout: 100,
     token_secret: :crypto.strong_rand_bytes(16),
     connection_id_secret: :crypto.strong_rand_bytes(32),
@@ -54,27 +143,78 @@ defmodule Requiem.Config do
     initial_max_streams_bidi: 1,
     initial_max_streams_uni: 2,
     dgram_queue_size: 1000,
+    stream_buffer_size: 10_240,
+    stream_buffer_min_size: 0,
+    stream_buffer_max_size: 0,
     max_idle_timeout: 60_000,
-    disable_active_migration: true
+    disable_active_migration: true,
+    max_field_section_size: 0,
+    max_concurrent_requests: 0,
+    max_header_count: 0
   ]
 
   @key_table %{
     server_name: true,
     port: true,
     host: true,
+    datapath: true,
+    datapath_recv_batch_size: true,
+    datapath_send_batch_size: true,
+    datapath_gso: true,
+    datapath_gro: true,
+    datapath_workers: true,
+    datapath_idle_reap_timeout: true,
+    datapath_socket: true,
+    statsd_address: true,
+    statsd_interval: true,
+    statsd_prefix: true,
+    statsd_tags: true,
+    control_socket: true,
     dispatcher_pool_size: true,
     socket_pool_size: true,
     socket_read_timeout: true,
+    sync_drain: true,
+    qlog_pid: true,
+    qlog_dir: true,
+    qlog_max_file_size: true,
+    qlog_max_total_size: true,
+    qlog_gzip: true,
+    congestion_events: true,
+    pmtu_events: true,
+    event_timestamps: true,
+    stream_close_events: true,
+    packet_tap: true,
+    path_samples: true,
+    path_sample_interval: true,
+    stream_recv_window: true,
+    stream_recv_overflow: true,
+    stream_pull_mode: true,
+    dgram_pull_mode: true,
+    dgram_budget: true,
+    keepalive_interval: true,
+    max_lifetime: true,
+    max_inactivity: true,
+    stream_send_deadline: true,
+    send_order: true,
+    stream_framing: true,
+    impairment: true,
+    owner_down_close_code: true,
+    max_connections: true,
+    trace_context: true,
     socket_write_timeout: true,
     token_secret: true,
     connection_id_secret: true,
     dgram_queue_size: true,
+    stream_buffer_size: true,
+    stream_buffer_min_size: true,
+    stream_buffer_max_size: true,
     cert_chain: true,
     priv_key: true,
     verify_locations_file: true,
     verify_locations_directory: true,
     grease: true,
     verify_peer: true,
+    peer_cert_pins: true,
     enable_early_data: true,
     application_protos: true,
     max_idle_timeout: true,
@@ -89,9 +229,17 @@ defmodule Requiem.Config do
     max_ack_delay: true,
     disable_active_migration: true,
     cc_algorithm_name: true,
-    enable_hystart: true
+    enable_hystart: true,
+    max_field_section_size: true,
+    max_concurrent_requests: true,
+    max_header_count: true
   }
 
+  # the values which don't go in the dumps
+  @secret_keys [:token_secret, :connection_id_secret]
+
+  @handlers_name Module.concat(__MODULE__, Handlers)
+
   @spec get!(module, config_key) :: term
   def get!(handler, key) do
     case handler |> config_name() |> FastGlobal.get(nil) do
@@ -120,6 +268,51 @@ defmodule Requiem.Config do
     config2 = Keyword.merge(@default_values, config1)
     check_key_existence(config2)
     store(handler, config2)
+    register(handler)
+  end
+
+  @spec handlers() :: [module]
+  def handlers() do
+    FastGlobal.get(@handlers_name, [])
+  end
+
+  @doc """
+  The config of the handler with the values JSON can hold: the secrets are
+  redacted, the binaries which aren't UTF-8 are hex encoded and the other terms
+  which aren't numbers, booleans, strings or lists of them are inspected.
+  """
+  @spec dump(module) :: Keyword.t()
+  def dump(handler) do
+    case handler |> config_name() |> FastGlobal.get(nil) do
+      nil -> []
+      conf -> Enum.map(conf, fn {k, v} -> {k, dump_value(k, v)} end)
+    end
+  end
+
+  defp dump_value(key, v) when key in @secret_keys and v != nil, do: "redacted"
+  defp dump_value(_key, v), do: dump_value(v)
+
+  defp dump_value(v) when is_number(v) or is_boolean(v) or is_nil(v), do: v
+  defp dump_value(v) when is_atom(v), do: inspect(v)
+
+  defp dump_value(v) when is_binary(v) do
+    if String.valid?(v), do: v, else: Base.encode16(v, case: :lower)
+  end
+
+  defp dump_value(v) when is_list(v) do
+    if Enum.all?(v, &(is_number(&1) or is_binary(&1))),
+      do: Enum.map(v, &dump_value/1),
+      else: inspect(v)
+  end
+
+  defp dump_value(v), do: inspect(v)
+
+  defp register(handler) do
+    handlers = handlers()
+
+    if handler not in handlers do
+      FastGlobal.put(@handlers_name, [handler | handlers])
+    end
   end
 
   @spec store(module, Keyword.t()) :: :ok
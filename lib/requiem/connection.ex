@@ -4,6 +4,7 @@ defmodule Requiem.Connection do
   use GenServer, restart: :temporary
 
   alias Requiem.Address
+  alias Requiem.Config
   alias Requiem.ConnectRequest
   alias Requiem.ExceptionGuard
   alias Requiem.ErrorCode
@@ -20,6 +21,7 @@ defmodule Requiem.Connection do
           trace_id: binary,
           conn_state: ConnectionState.t(),
           conn: any,
+          sender: integer | nil,
           timer: reference
         }
 
@@ -30,8 +32,12 @@ defmodule Requiem.Connection do
             trace_id: nil,
             conn_state: nil,
             conn: nil,
+            sender: nil,
             timer: nil
 
+  # CRYPTO_ERROR of the bad_certificate alert (RFC 9001 Section 4.8)
+  @bad_certificate 0x12A
+
   @spec process_packet(pid, Address.t(), binary) :: :ok
   def process_packet(pid, address, packet) do
     Tracer.trace(__MODULE__, "process_packet")
@@ -46,22 +52,14 @@ defmodule Requiem.Connection do
 
   @impl GenServer
   def init(opts) do
-    state = new(opts)
+    state = opts |> new() |> put_trace_context()
     Tracer.trace(__MODULE__, state.trace_id, "@init")
 
-    config_ptr = Keyword.fetch!(opts, :config_ptr)
-    sender_pid = Keyword.fetch!(opts, :sender_pid)
-
-    case NIF.Connection.accept(
-           config_ptr,
-           state.conn_state.dcid,
-           state.conn_state.odcid,
-           state.conn_state.address.raw,
-           sender_pid,
-           1024 * 10
-         ) do
+    case accept(state, opts) do
       {:ok, conn} ->
         Tracer.trace(__MODULE__, state.trace_id, "@acccept: completed")
+
+        watch_owner(state, conn)
         Process.flag(:trap_exit, true)
 
         case ConnectionRegistry.register(
@@ -71,11 +69,11 @@ defmodule Requiem.Connection do
           {:ok, _pid} ->
             Tracer.trace(__MODULE__, state.trace_id, "@init: registered")
 
-            {:ok, %{state | conn: conn}}
+            {:ok, %{state | conn: conn, sender: sync_sender(state, opts)}}
 
           {:error, {:already_registered, _pid}} ->
             Tracer.trace(__MODULE__, state.trace_id, "@init: failed registered")
-            NIF.Connection.destroy(conn)
+            destroy(%{state | conn: conn})
             {:stop, :normal}
         end
 
@@ -150,7 +148,7 @@ defmodule Requiem.Connection do
   def handle_cast({:__packet__, address, packet}, state) do
     Tracer.trace(__MODULE__, state.trace_id, "@packet")
 
-    case NIF.Connection.on_packet(state.conn, packet, address.raw) do
+    case on_packet(state, packet, address.raw) do
       {:ok, next_timeout} ->
         Tracer.trace(
           __MODULE__,
@@ -165,7 +163,7 @@ defmodule Requiem.Connection do
         close(false, :no_error, :shutdown)
         {:noreply, state}
 
-      {:error, :system_error} ->
+      {:error, _reason} ->
         close(false, :internal_error, :server_error)
         {:noreply, state}
     end
@@ -219,187 +217,215 @@ defmodule Requiem.Connection do
   end
 
   @impl GenServer
+  def handle_info({:__at__, emitted_at, received_at, message}, state) do
+    conn_state = %{state.conn_state | event_time: {emitted_at, received_at}}
+    handle_info(message, %{state | conn_state: conn_state})
+  end
+
   def handle_info(
         {:__connect__, authority, path, origin},
         %{handler_initialized: false} = state
       ) do
     Tracer.trace(__MODULE__, state.trace_id, "@connect")
     request = ConnectRequest.new(authority, path, origin)
-    conn = state.conn
+    state = put_peer_cert(state)
+
+    if peer_cert_pinned?(state) do
+      init_handler(request, state)
+    else
+      Tracer.trace(__MODULE__, state.trace_id, "@connect: peer certificate not pinned")
+      close(false, @bad_certificate, :bad_certificate)
+      {:noreply, state}
+    end
+  end
+
+  def handle_info(
+        {:__connect__, _authority, _path, _origin},
+        %{handler_initialized: true} = state
+      ) do
+    case NIF.Connection.reject_connect_request(state.conn, 419) do
+      :ok ->
+        {:noreply, state}
+
+      {:error, :already_closed} ->
+        close(false, :no_error, :shutdown)
+        {:noreply, state}
+
+      {:error, reason} ->
+        Logger.error(
+          "<Requiem.Connection:#{self()}> accept_connect_request failed: #{inspect(reason)}"
+        )
 
-    ExceptionGuard.guard(
-      fn ->
         close(false, :internal_error, :server_error)
         {:noreply, state}
-      end,
-      fn ->
-        case state.handler.init(state.conn_state, request) do
-          {:ok, %ConnectionState{} = conn_state, handler_state} ->
-            Tracer.trace(__MODULE__, state.trace_id, "@handler.init: completed")
+    end
+  end
 
-            case NIF.Connection.accept_connect_request(conn) do
-              {:ok, next_timeout} ->
-                state = reset_conn_timer(state, next_timeout)
+  def handle_info({:__request_rejected__, reason}, state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@request_rejected: #{inspect(reason)}")
+    # the native layer has already responded to the request with an error status
+    {:noreply, state}
+  end
 
-                {:noreply,
-                 %{
-                   state
-                   | conn: conn,
-                     conn_state: conn_state,
-                     handler_state: handler_state,
-                     handler_initialized: true
-                 }}
+  def handle_info({:__reset__, _reason}, state) do
+    # HTTP3 stream reset
+    Tracer.trace(__MODULE__, state.trace_id, "@reset")
+    # currently requiem doesn't support WebTransport control stream's "reset" event.
+    # just close connection.
+    close(false, :no_error, :shutdown)
+    {:noreply, state}
+  end
 
-              {:error, :already_closed} ->
-                close(false, :no_error, :shutdown)
-                {:noreply, state}
+  def handle_info(:__session_finished__, state) do
+    # HTTP3 stream finished
+    Tracer.trace(__MODULE__, state.trace_id, "@session_finished")
+    close(false, :no_error, :shutdown)
+    {:noreply, state}
+  end
 
-              {:error, :system_error} ->
-                Logger.error(
-                  "<Requiem.Connection:#{self()}> accept_connect_request failed by system error"
-                )
+  def handle_info({:__stream_finished__, _stream_id}, state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@stream_finished")
+    # TODO
+    {:noreply, state}
+  end
 
-                close(false, :internal_error, :server_error)
-                {:noreply, state}
-            end
+  def handle_info({:__stream_reset__, stream_id, code}, %{handler_initialized: true} = state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@stream_reset: #{stream_id}, code: #{code}")
+    handler_handle_info({:stream_reset, stream_id, code}, state)
+  end
 
-          {:ok, %ConnectionState{} = conn_state, handler_state, timeout}
-          when is_integer(timeout) ->
-            Tracer.trace(__MODULE__, state.trace_id, "@handler.init: completed with timeout")
+  def handle_info({:__stream_reset__, _stream_id, _code}, state) do
+    {:noreply, state}
+  end
 
-            case NIF.Connection.accept_connect_request(conn) do
-              {:ok, next_timeout} ->
-                state = reset_conn_timer(state, next_timeout)
+  def handle_info({:__stream_readable__, stream_id}, %{handler_initialized: true} = state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@stream_readable: #{stream_id}")
+    handler_handle_info({:stream_readable, stream_id}, state)
+  end
 
-                {:noreply,
-                 %{
-                   state
-                   | conn: conn,
-                     conn_state: conn_state,
-                     handler_state: handler_state,
-                     handler_initialized: true
-                 }, timeout}
+  def handle_info({:__stream_readable__, _stream_id}, state) do
+    {:noreply, state}
+  end
 
-              {:error, :already_closed} ->
-                close(false, :no_error, :shutdown)
-                {:noreply, state}
+  def handle_info(:__dgram_readable__, %{handler_initialized: true} = state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@dgram_readable")
+    handler_handle_info(:dgram_readable, state)
+  end
 
-              {:error, :system_error} ->
-                Logger.error(
-                  "<Requiem.Connection:#{self()}> accept_connect_request failed by system error"
-                )
+  def handle_info(:__dgram_readable__, state) do
+    {:noreply, state}
+  end
 
-                close(false, :internal_error, :server_error)
-                {:noreply, state}
-            end
+  def handle_info({:__dgram_peek__, max_len}, state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@dgram_peek")
 
-          {:ok, %ConnectionState{} = conn_state, handler_state, :hibernate} ->
-            Tracer.trace(__MODULE__, state.trace_id, "@handler.init: completed with :hibernate")
+    case NIF.Connection.dgram_peek(state.conn, max_len) do
+      {:ok, prefix, total_len} ->
+        handler_handle_info({:dgram_peeked, prefix, total_len}, state)
 
-            case NIF.Connection.accept_connect_request(conn) do
-              {:ok, next_timeout} ->
-                state = reset_conn_timer(state, next_timeout)
+      {:error, reason} ->
+        Logger.debug("<Requiem.Connection:#{self()}> failed to peek datagram: #{inspect(reason)}")
 
-                {:noreply,
-                 %{
-                   state
-                   | conn: conn,
-                     conn_state: conn_state,
-                     handler_state: handler_state,
-                     handler_initialized: true
-                 }, :hibernate}
+        {:noreply, state}
+    end
+  end
 
-              {:error, :already_closed} ->
-                close(false, :no_error, :shutdown)
-                {:noreply, state}
+  def handle_info({:__dgram_recv__, deliver}, state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@dgram_recv")
 
-              {:error, :system_error} ->
-                Logger.error(
-                  "<Requiem.Connection:#{self()}> accept_connect_request failed by system error"
-                )
+    case NIF.Connection.dgram_recv(state.conn, deliver) do
+      {:ok, next_timeout} ->
+        {:noreply, reset_conn_timer(state, next_timeout)}
 
-                close(false, :internal_error, :server_error)
-                {:noreply, state}
-            end
+      {:error, reason} ->
+        Logger.debug("<Requiem.Connection:#{self()}> failed to take datagram: #{inspect(reason)}")
 
-          {:stop, code, reason} when is_integer(code) and is_atom(reason) ->
-            Tracer.trace(__MODULE__, state.trace_id, "@handler.init: stop")
-            # TODO assert code >= 400 && code < 600
-            # TODO pass reason
-            case NIF.Connection.reject_connect_request(conn, code) do
-              {:ok, next_timeout} ->
-                state = reset_conn_timer(state, next_timeout)
-                {:noreply, state}
+        {:noreply, state}
+    end
+  end
 
-              {:error, :already_closed} ->
-                close(false, :no_error, :shutdown)
-                {:noreply, state}
+  def handle_info({:__stream_read__, stream_id, max_bytes}, state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@stream_read: #{stream_id}")
 
-              {:error, :system_error} ->
-                Logger.error(
-                  "<Requiem.Connection:#{self()}> accept_connect_request failed by system error"
-                )
+    case NIF.Connection.stream_read(state.conn, stream_id, max_bytes) do
+      {:ok, "", next_timeout} ->
+        {:noreply, reset_conn_timer(state, next_timeout)}
 
-                close(false, :internal_error, :server_error)
-                {:noreply, state}
-            end
+      {:ok, data, next_timeout} ->
+        handler_handle_stream(stream_id, data, reset_conn_timer(state, next_timeout))
 
-          other ->
-            Logger.error(
-              "<Requiem.Connection:#{self()}> handle_cast returned unknown pattern: #{inspect(other)}"
-            )
+      {:error, reason} ->
+        Logger.debug(
+          "<Requiem.Connection:#{self()}> failed to read stream #{stream_id}: #{inspect(reason)}"
+        )
 
-            close(false, :internal_error, :server_error)
-            {:noreply, state}
-        end
-      end
-    )
+        {:noreply, state}
+    end
   end
 
+  # the HTTP/3 events of the requests which aren't WebTransport's are passed to the
+  # handler as handle_info({:h3_headers, stream_id, headers, has_body}, conn, state),
+  # {:h3_data, stream_id, body}, {:h3_finished, stream_id}, {:h3_reset, stream_id, code},
+  # {:h3_goaway, id} and {:h3_priority_update, stream_id}
   def handle_info(
-        {:__connect__, _authority, _path, _origin},
+        {:__h3_headers__, stream_id, headers, has_body},
         %{handler_initialized: true} = state
       ) do
-    case NIF.Connection.reject_connect_request(state.conn, 419) do
-      :ok ->
-        {:noreply, state}
+    Tracer.trace(__MODULE__, state.trace_id, "@h3_headers: #{stream_id}")
+    handler_handle_info({:h3_headers, stream_id, headers, has_body}, state)
+  end
 
-      {:error, :already_closed} ->
-        close(false, :no_error, :shutdown)
-        {:noreply, state}
+  def handle_info({:__h3_data__, stream_id}, %{handler_initialized: true} = state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@h3_data: #{stream_id}")
+    max_bytes = Config.get!(state.handler, :stream_buffer_size)
 
-      {:error, :system_error} ->
-        Logger.error(
-          "<Requiem.Connection:#{self()}> accept_connect_request failed by system error"
+    case NIF.Connection.h3_recv_body(state.conn, stream_id, max_bytes) do
+      {:ok, "", next_timeout} ->
+        {:noreply, reset_conn_timer(state, next_timeout)}
+
+      {:ok, body, next_timeout} ->
+        # quiche tells of the data once, the rest is read with another call
+        if byte_size(body) >= max_bytes, do: send(self(), {:__h3_data__, stream_id})
+        handler_handle_info({:h3_data, stream_id, body}, reset_conn_timer(state, next_timeout))
+
+      {:error, reason} ->
+        Logger.debug(
+          "<Requiem.Connection:#{self()}> failed to read body #{stream_id}: #{inspect(reason)}"
         )
 
-        close(false, :internal_error, :server_error)
         {:noreply, state}
     end
   end
 
-  def handle_info(:__reset__, state) do
-    # HTTP3 stream reset
-    Tracer.trace(__MODULE__, state.trace_id, "@reset")
-    # currently requiem doesn't support WebTransport control stream's "reset" event.
-    # just close connection.
-    close(false, :no_error, :shutdown)
-    {:noreply, state}
+  def handle_info({:__h3_finished__, stream_id}, %{handler_initialized: true} = state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@h3_finished: #{stream_id}")
+    handler_handle_info({:h3_finished, stream_id}, state)
   end
 
-  def handle_info(:__session_finished__, state) do
-    # HTTP3 stream finished
-    Tracer.trace(__MODULE__, state.trace_id, "@session_finished")
-    close(false, :no_error, :shutdown)
-    {:noreply, state}
+  def handle_info({:__h3_reset__, stream_id, code}, %{handler_initialized: true} = state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@h3_reset: #{stream_id}, code: #{code}")
+    handler_handle_info({:h3_reset, stream_id, code}, state)
   end
 
-  def handle_info({:__stream_finished__, _stream_id}, state) do
-    Tracer.trace(__MODULE__, state.trace_id, "@stream_finished")
-    # TODO
-    {:noreply, state}
+  def handle_info({:__h3_goaway__, id}, %{handler_initialized: true} = state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@h3_goaway: #{id}")
+    handler_handle_info({:h3_goaway, id}, state)
+  end
+
+  def handle_info({:__h3_priority_update__, stream_id}, %{handler_initialized: true} = state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@h3_priority_update: #{stream_id}")
+    handler_handle_info({:h3_priority_update, stream_id}, state)
   end
 
+  def handle_info({:__h3_headers__, _stream_id, _headers, _has_body}, state),
+    do: {:noreply, state}
+
+  def handle_info({:__h3_data__, _stream_id}, state), do: {:noreply, state}
+  def handle_info({:__h3_finished__, _stream_id}, state), do: {:noreply, state}
+  def handle_info({:__h3_reset__, _stream_id, _code}, state), do: {:noreply, state}
+  def handle_info({:__h3_goaway__, _id}, state), do: {:noreply, state}
+  def handle_info({:__h3_priority_update__, _stream_id}, state), do: {:noreply, state}
+
   def handle_info(:__goaway__, state) do
     # HTTP3 stream goaway
     Tracer.trace(__MODULE__, state.trace_id, "@goaway")
@@ -421,61 +447,32 @@ defmodule Requiem.Connection do
         close(false, :no_error, :shutdown)
         {:noreply, state}
 
-      {:error, :system_error} ->
-        Tracer.trace(__MODULE__, state.trace_id, "@timeout: error")
+      {:error, reason} ->
+        Tracer.trace(__MODULE__, state.trace_id, "@timeout: error #{inspect(reason)}")
         close(false, :internal_error, :server_error)
         {:noreply, state}
     end
   end
 
-  def handle_info({:__stream_recv__, stream_id, data}, %{handler_initialized: true} = state) do
+  def handle_info(
+        {:__wt_stream_recv__, _session_id, stream_id, data},
+        %{handler_initialized: true} = state
+      ) do
     Tracer.trace(__MODULE__, state.trace_id, "@stream_recv: handler_initialized: true")
 
-    ExceptionGuard.guard(
-      fn ->
-        close(false, :internal_error, :server_error)
-        {:noreply, state}
-      end,
-      fn ->
-        case state.handler.handle_stream(
-               stream_id,
-               data,
-               state.conn_state,
-               state.handler_state
-             ) do
-          {:ok, %ConnectionState{} = conn_state, handler_state} ->
-            {:noreply, %{state | conn_state: conn_state, handler_state: handler_state}}
-
-          {:ok, %ConnectionState{} = conn_state, handler_state, timeout}
-          when is_integer(timeout) ->
-            {:noreply, %{state | conn_state: conn_state, handler_state: handler_state}, timeout}
-
-          {:ok, %ConnectionState{} = conn_state, handler_state, :hibernate} ->
-            {:noreply, %{state | conn_state: conn_state, handler_state: handler_state},
-             :hibernate}
-
-          {:stop, code, reason} when is_integer(code) and is_atom(reason) ->
-            close(true, code, reason)
-            {:noreply, state}
-
-          other ->
-            Logger.error(
-              "<Requiem.Connection:#{self()}> handle_stream returned unknown pattern: #{inspect(other)}"
-            )
-
-            close(false, :internal_error, :server_error)
-            {:noreply, state}
-        end
-      end
-    )
+    state = ack_stream(state, stream_id, data)
+    handler_handle_stream(stream_id, data, state)
   end
 
-  def handle_info({:__stream_recv__, _stream_id, _data}, state) do
+  def handle_info({:__wt_stream_recv__, _session_id, _stream_id, _data}, state) do
     # just ignore
     {:noreply, state}
   end
 
-  def handle_info({:__dgram_recv__, data}, %{handler_initialized: true} = state) do
+  def handle_info(
+        {:__wt_dgram_recv__, _session_id, data},
+        %{handler_initialized: true} = state
+      ) do
     ExceptionGuard.guard(
       fn ->
         close(false, :internal_error, :server_error)
@@ -514,7 +511,7 @@ defmodule Requiem.Connection do
     )
   end
 
-  def handle_info({:__dgram_recv__, _data}, state) do
+  def handle_info({:__wt_dgram_recv__, _session_id, _data}, state) do
     # just ignore
     {:noreply, state}
   end
@@ -523,32 +520,176 @@ defmodule Requiem.Connection do
     Tracer.trace(__MODULE__, state.trace_id, "@close")
 
     # TODO set proper error code
-    case NIF.Connection.close(state.conn, app, 0x1, to_string(reason)) do
-      {:ok, next_timeout} ->
-        Tracer.trace(
-          __MODULE__,
-          state.trace_id,
-          "@close: completed. next_timeout: #{next_timeout}"
-        )
+    close_connection(state, app, 0x1, to_string(reason))
+  end
 
-        state = reset_conn_timer(state, next_timeout)
-        {:noreply, state}
+  def handle_info({:__shutdown__, code, reason}, state) do
+    # the handler module is shutting down, see Requiem.Shutdown
+    Tracer.trace(__MODULE__, state.trace_id, "@shutdown: #{code}")
+    close_connection(state, true, code, reason)
+  end
 
-      {:error, :already_closed} ->
-        Tracer.trace(__MODULE__, state.trace_id, "@close: already closed, set delayed close")
-        send(self(), {:__delayed_close__, :normal})
-        {:noreply, state}
+  def handle_info({:__handoff__, code, to}, state) do
+    # the state goes to `to` before the client is asked to go away
+    Tracer.trace(__MODULE__, state.trace_id, "@handoff: #{code}")
 
-      {:error, :system_error} ->
-        Tracer.trace(__MODULE__, state.trace_id, "@close: error, set delayed close")
-        send(self(), {:__delayed_close__, {:shutdown, :system_error}})
-        {:noreply, state}
+    case NIF.Connection.handoff_state(state.conn) do
+      {:ok, handoff_state} ->
+        send(to, {:requiem_handoff, state.trace_id, handoff_state})
+
+      {:error, reason} ->
+        Logger.error(
+          "<Requiem.Connection:#{self()}> failed to export handoff state: #{inspect(reason)}"
+        )
     end
-  end
 
-  def handle_info({:__delayed_close__, reason}, state) do
-    Tracer.trace(__MODULE__, state.trace_id, "@delayed_closed")
-    {:stop, reason, state}
+    close_connection(state, true, code, "handoff")
+  end
+
+  def handle_info(:__handshake_started__, state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@handshake_started")
+    {:noreply, state}
+  end
+
+  def handle_info(:__handshake_done__, state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@handshake_done")
+    {:noreply, state}
+  end
+
+  def handle_info({:__path_migrated__, peer}, state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@path_migrated")
+    address = Address.from_rust_peer(peer)
+    state = %{state | conn_state: %{state.conn_state | address: address}}
+
+    if state.handler_initialized do
+      handler_handle_info({:path_migrated, address}, state)
+    else
+      {:noreply, state}
+    end
+  end
+
+  def handle_info({:__path_validated__, peer}, %{handler_initialized: true} = state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@path_validated")
+    handler_handle_info({:path_validated, Address.from_rust_peer(peer)}, state)
+  end
+
+  def handle_info({:__path_failed__, peer}, %{handler_initialized: true} = state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@path_failed")
+    handler_handle_info({:path_failed, Address.from_rust_peer(peer)}, state)
+  end
+
+  def handle_info({:__path_validated__, _peer}, state) do
+    {:noreply, state}
+  end
+
+  def handle_info({:__path_failed__, _peer}, state) do
+    {:noreply, state}
+  end
+
+  def handle_info({:__probe_path__, timeout_ms}, state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@probe_path")
+
+    case NIF.Connection.probe_path(state.conn, timeout_ms) do
+      {:ok, next_timeout} ->
+        {:noreply, reset_conn_timer(state, next_timeout)}
+
+      {:error, reason} ->
+        Logger.debug("<Requiem.Connection:#{self()}> failed to probe path: #{inspect(reason)}")
+        {:noreply, state}
+    end
+  end
+
+  def handle_info({:__congestion__, cc_state, cwnd}, %{handler_initialized: true} = state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@congestion: #{cc_state}, cwnd: #{cwnd}")
+    handler_handle_info({:congestion, cc_state, cwnd}, state)
+  end
+
+  def handle_info({:__congestion__, _cc_state, _cwnd}, state) do
+    {:noreply, state}
+  end
+
+  def handle_info({:__pmtu__, pmtu}, %{handler_initialized: true} = state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@pmtu: #{pmtu}")
+    handler_handle_info({:pmtu, pmtu}, state)
+  end
+
+  def handle_info({:__pmtu__, _pmtu}, state) do
+    {:noreply, state}
+  end
+
+  def handle_info({:__stream_closed__, stream_id}, %{handler_initialized: true} = state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@stream_closed: #{stream_id}")
+    handler_handle_info({:stream_closed, stream_id}, state)
+  end
+
+  def handle_info({:__stream_closed__, _stream_id}, state) do
+    {:noreply, state}
+  end
+
+  def handle_info({:__stream_expired__, stream_id, code}, %{handler_initialized: true} = state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@stream_expired: #{stream_id}, #{code}")
+    handler_handle_info({:stream_expired, stream_id, code}, state)
+  end
+
+  def handle_info({:__stream_expired__, _stream_id, _code}, state) do
+    {:noreply, state}
+  end
+
+  def handle_info({:__stream_priority__, stream_id, urgency, incremental}, state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@stream_priority: #{stream_id}, #{urgency}")
+
+    case NIF.Connection.set_stream_priority(state.conn, stream_id, urgency, incremental) do
+      :ok ->
+        :ok
+
+      {:error, reason} ->
+        Logger.debug(
+          "<Requiem.Connection:#{self()}> failed to set the priority of stream #{stream_id}: #{inspect(reason)}"
+        )
+    end
+
+    {:noreply, state}
+  end
+
+  def handle_info({:__stream_deadline__, stream_id, timeout_ms, code}, state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@stream_deadline: #{stream_id}")
+
+    case NIF.Connection.set_send_deadline(state.conn, stream_id, {timeout_ms, code}) do
+      :ok ->
+        :ok
+
+      {:error, reason} ->
+        Logger.debug(
+          "<Requiem.Connection:#{self()}> failed to set the send deadline of stream #{stream_id}: #{inspect(reason)}"
+        )
+    end
+
+    {:noreply, state}
+  end
+
+  def handle_info(:__closing__, state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@closing")
+    {:noreply, state}
+  end
+
+  def handle_info({:__connection_error__, reason}, state) do
+    # quiche failed to write a packet and has closed the connection
+    Logger.error("<Requiem.Connection:#{self()}> connection error: #{reason}")
+    send(self(), {:__delayed_close__, {:shutdown, reason}})
+    {:noreply, state}
+  end
+
+  def handle_info({:__closed__, close_info}, state) do
+    # quiche has closed the connection, or the native datapath has removed it
+    Tracer.trace(__MODULE__, state.trace_id, "@closed: close_info: #{inspect(close_info)}")
+    send(self(), {:__delayed_close__, :normal})
+    # kept for the terminate callback of the handler
+    {:noreply, %{state | conn_state: %{state.conn_state | close_info: close_info}}}
+  end
+
+  def handle_info({:__delayed_close__, reason}, state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@delayed_closed")
+    {:stop, reason, state}
   end
 
   def handle_info({:__stream_open__, is_bidi, message}, state) do
@@ -570,8 +711,8 @@ defmodule Requiem.Connection do
         close(false, :no_error, :shutdown)
         {:noreply, state}
 
-      {:error, :system_error} ->
-        Tracer.trace(__MODULE__, state.trace_id, "@stream_open: error")
+      {:error, reason} ->
+        Tracer.trace(__MODULE__, state.trace_id, "@stream_open: error #{inspect(reason)}")
         # close(false, 0, :server_error)
         {:noreply, state}
     end
@@ -580,51 +721,107 @@ defmodule Requiem.Connection do
   def handle_info({:__stream_send__, stream_id, data, fin}, state) do
     Tracer.trace(__MODULE__, state.trace_id, "@stream_send")
 
-    case NIF.Connection.stream_send(state.conn, stream_id, data, fin) do
+    # with a stream framing, each send is a message framed by the NIF
+    result =
+      if is_list(data) or Config.get!(state.handler, :stream_framing) != :raw do
+        NIF.Connection.stream_send_vectored(state.conn, stream_id, List.wrap(data), fin)
+      else
+        stream_send(state, stream_id, data, fin)
+      end
+
+    handle_stream_send_result(result, state)
+  end
+
+  def handle_info({:__stream_send_full__, stream_id, data, fin}, state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@stream_send_full")
+
+    state.conn
+    |> NIF.Connection.stream_send_full(stream_id, data, fin)
+    |> handle_stream_send_result(state)
+  end
+
+  def handle_info({:__dgram_send__, data}, state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@dgram_send")
+
+    case NIF.Connection.dgram_send(state.conn, data) do
       {:ok, next_timeout} ->
         Tracer.trace(
           __MODULE__,
           state.trace_id,
-          "@stream_send: completed. next_timeout: #{next_timeout}"
+          "@dgram_send: completed. next_timeout: #{next_timeout}"
         )
 
         state = reset_conn_timer(state, next_timeout)
         {:noreply, state}
 
       {:error, :already_closed} ->
-        Tracer.trace(__MODULE__, state.trace_id, "@stream_send: already closed")
+        Tracer.trace(__MODULE__, state.trace_id, "@dgram_send: already closed")
         close(false, :no_error, :shutdown)
         {:noreply, state}
 
-      {:error, :system_error} ->
-        Tracer.trace(__MODULE__, state.trace_id, "@stream_send: error")
+      {:error, reason} ->
+        Tracer.trace(__MODULE__, state.trace_id, "@dgram_send: error #{inspect(reason)}")
         # close(false, 0, :server_error)
         {:noreply, state}
     end
   end
 
-  def handle_info({:__dgram_send__, data}, state) do
-    Tracer.trace(__MODULE__, state.trace_id, "@dgram_send")
+  def handle_info({:__dgram_send_batch__, data}, state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@dgram_send_batch")
 
-    case NIF.Connection.dgram_send(state.conn, data) do
+    case NIF.Connection.dgram_send_batch(state.conn, data) do
+      {:ok, accepted, next_timeout} ->
+        Tracer.trace(
+          __MODULE__,
+          state.trace_id,
+          "@dgram_send_batch: #{accepted}/#{length(data)} sent. next_timeout: #{next_timeout}"
+        )
+
+        state = reset_conn_timer(state, next_timeout)
+        {:noreply, state}
+
+      {:error, :already_closed} ->
+        Tracer.trace(__MODULE__, state.trace_id, "@dgram_send_batch: already closed")
+        close(false, :no_error, :shutdown)
+        {:noreply, state}
+
+      {:error, reason} ->
+        Tracer.trace(__MODULE__, state.trace_id, "@dgram_send_batch: error #{inspect(reason)}")
+        {:noreply, state}
+    end
+  end
+
+  def handle_info({:__h3_dgram_recv__, flow_id, data}, %{handler_initialized: true} = state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@h3_dgram_recv: #{flow_id}")
+    handler_handle_info({:h3_dgram, flow_id, data}, state)
+  end
+
+  def handle_info({:__h3_dgram_recv__, _flow_id, _data}, state) do
+    # just ignore
+    {:noreply, state}
+  end
+
+  def handle_info({:__h3_dgram_send__, flow_id, data}, state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@h3_dgram_send")
+
+    case NIF.Connection.h3_dgram_send(state.conn, flow_id, data) do
       {:ok, next_timeout} ->
         Tracer.trace(
           __MODULE__,
           state.trace_id,
-          "@dgram_send: completed. next_timeout: #{next_timeout}"
+          "@h3_dgram_send: completed. next_timeout: #{next_timeout}"
         )
 
         state = reset_conn_timer(state, next_timeout)
         {:noreply, state}
 
       {:error, :already_closed} ->
-        Tracer.trace(__MODULE__, state.trace_id, "@dgram_send: already closed")
+        Tracer.trace(__MODULE__, state.trace_id, "@h3_dgram_send: already closed")
         close(false, :no_error, :shutdown)
         {:noreply, state}
 
-      {:error, :system_error} ->
-        Tracer.trace(__MODULE__, state.trace_id, "@dgram_send: error")
-        # close(false, 0, :server_error)
+      {:error, reason} ->
+        Tracer.trace(__MODULE__, state.trace_id, "@h3_dgram_send: error #{inspect(reason)}")
         {:noreply, state}
     end
   end
@@ -666,6 +863,46 @@ defmodule Requiem.Connection do
     {:noreply, state}
   end
 
+  defp handler_handle_stream(stream_id, data, state) do
+    ExceptionGuard.guard(
+      fn ->
+        close(false, :internal_error, :server_error)
+        {:noreply, state}
+      end,
+      fn ->
+        case state.handler.handle_stream(
+               stream_id,
+               data,
+               state.conn_state,
+               state.handler_state
+             ) do
+          {:ok, %ConnectionState{} = conn_state, handler_state} ->
+            {:noreply, %{state | conn_state: conn_state, handler_state: handler_state}}
+
+          {:ok, %ConnectionState{} = conn_state, handler_state, timeout}
+          when is_integer(timeout) ->
+            {:noreply, %{state | conn_state: conn_state, handler_state: handler_state}, timeout}
+
+          {:ok, %ConnectionState{} = conn_state, handler_state, :hibernate} ->
+            {:noreply, %{state | conn_state: conn_state, handler_state: handler_state},
+             :hibernate}
+
+          {:stop, code, reason} when is_integer(code) and is_atom(reason) ->
+            close(true, code, reason)
+            {:noreply, state}
+
+          other ->
+            Logger.error(
+              "<Requiem.Connection:#{self()}> handle_stream returned unknown pattern: #{inspect(other)}"
+            )
+
+            close(false, :internal_error, :server_error)
+            {:noreply, state}
+        end
+      end
+    )
+  end
+
   defp handler_handle_info(request, state) do
     case state.handler.handle_info(
            request,
@@ -701,9 +938,13 @@ defmodule Requiem.Connection do
     Tracer.trace(__MODULE__, state.trace_id, "@terminate #{inspect(reason)}")
 
     state = cancel_conn_timer(state)
-    NIF.Connection.destroy(state.conn)
+    destroy(state)
     state = %{state | conn: nil}
 
+    if state.sender != nil do
+      NIF.SocketSender.destroy(state.sender)
+    end
+
     ConnectionRegistry.unregister(
       state.handler,
       state.conn_state.dcid
@@ -725,12 +966,112 @@ defmodule Requiem.Connection do
     end
   end
 
+  # with `sync_drain`, the packets are returned by the NIF and written from this process
+  # with its own sender, instead of going through the mailbox of the sender process.
+  defp sync_sender(state, opts) do
+    with true <- Config.get!(state.handler, :sync_drain),
+         {:ok, socket_ptr} <- Keyword.fetch(opts, :socket_ptr),
+         {:ok, sender_ptr} <-
+           NIF.SocketSender.get(socket_ptr, Keyword.fetch!(opts, :sender_index)) do
+      sender_ptr
+    else
+      _ -> nil
+    end
+  end
+
+  defp on_packet(%{sender: nil} = state, packet, peer) do
+    NIF.Connection.on_packet(state.conn, packet, peer)
+  end
+
+  defp on_packet(state, packet, peer) do
+    with {:ok, next_timeout, packets} <-
+           NIF.Connection.on_packet_sync(state.conn, packet, peer) do
+      write_packets(state.sender, packets)
+      {:ok, next_timeout}
+    end
+  end
+
+  defp handle_stream_send_result(result, state) do
+    case result do
+      {:ok, next_timeout} ->
+        Tracer.trace(
+          __MODULE__,
+          state.trace_id,
+          "@stream_send: completed. next_timeout: #{next_timeout}"
+        )
+
+        state = reset_conn_timer(state, next_timeout)
+        {:noreply, state}
+
+      {:error, :already_closed} ->
+        Tracer.trace(__MODULE__, state.trace_id, "@stream_send: already closed")
+        close(false, :no_error, :shutdown)
+        {:noreply, state}
+
+      {:error, reason} ->
+        Tracer.trace(__MODULE__, state.trace_id, "@stream_send: error #{inspect(reason)}")
+        # close(false, 0, :server_error)
+        {:noreply, state}
+    end
+  end
+
+  defp stream_send(%{sender: nil} = state, stream_id, data, fin) do
+    NIF.Connection.stream_send(state.conn, stream_id, data, fin)
+  end
+
+  defp stream_send(state, stream_id, data, fin) do
+    case NIF.Connection.stream_send_sync(state.conn, stream_id, data, fin) do
+      {:ok, next_timeout, packets} ->
+        write_packets(state.sender, packets)
+        {:ok, next_timeout}
+
+      {:error, reason, packets} ->
+        write_packets(state.sender, packets)
+        {:error, reason}
+
+      error ->
+        error
+    end
+  end
+
+  defp write_packets(sender, packets) do
+    Enum.each(packets, fn {peer, packet} -> NIF.SocketSender.send(sender, peer, packet) end)
+  end
+
+  defp close_connection(state, app, code, reason) do
+    case NIF.Connection.close(state.conn, app, code, reason) do
+      {:ok, next_timeout} ->
+        Tracer.trace(
+          __MODULE__,
+          state.trace_id,
+          "@close: completed. next_timeout: #{next_timeout}"
+        )
+
+        state = reset_conn_timer(state, next_timeout)
+        {:noreply, state}
+
+      {:error, :already_closed} ->
+        Tracer.trace(__MODULE__, state.trace_id, "@close: already closed, set delayed close")
+        send(self(), {:__delayed_close__, :normal})
+        {:noreply, state}
+
+      {:error, reason} ->
+        Tracer.trace(__MODULE__, state.trace_id, "@close: error(#{reason}), set delayed close")
+        send(self(), {:__delayed_close__, {:shutdown, reason}})
+        {:noreply, state}
+    end
+  end
+
   defp reset_conn_timer(state, timeout) do
     state
     |> cancel_conn_timer()
     |> start_conn_timer(timeout)
   end
 
+  defp start_conn_timer(state, :infinity) do
+    state
+  end
+
   defp start_conn_timer(state, timeout) do
     timer = Process.send_after(self(), :__timeout__, timeout)
     %{state | timer: timer}
@@ -755,6 +1096,546 @@ defmodule Requiem.Connection do
     send(self(), {:__close__, app, err, reason})
   end
 
+  defp accept(state, opts) do
+    case Keyword.fetch(opts, :datapath) do
+      {:ok, datapath} ->
+        # the connection is already accepted by the native datapath,
+        # just attach this process as the owner of its events.
+        conn_id = Keyword.fetch!(opts, :conn_id)
+
+        conn = {:native, datapath, conn_id}
+
+        # before set_owner, so that the packets held until then are logged too
+        configure_conn(state, conn)
+
+        with :ok <- NIF.Datapath.set_owner(datapath, conn_id, self()),
+             do: {:ok, conn}
+
+      :error ->
+        case NIF.Connection.accept(
+               state.handler,
+               Keyword.fetch!(opts, :config_ptr),
+               state.conn_state.dcid,
+               state.conn_state.odcid,
+               state.conn_state.address.raw,
+               Keyword.fetch!(opts, :sender_pid),
+               Config.get!(state.handler, :stream_buffer_size)
+             ) do
+          {:ok, conn} ->
+            NIF.Connection.set_request_limits(
+              conn,
+              Config.get!(state.handler, :max_field_section_size),
+              Config.get!(state.handler, :max_concurrent_requests),
+              Config.get!(state.handler, :max_header_count)
+            )
+
+            NIF.Connection.set_stream_buffer_range(
+              conn,
+              Config.get!(state.handler, :stream_buffer_min_size),
+              Config.get!(state.handler, :stream_buffer_max_size)
+            )
+
+            configure_conn(state, conn)
+
+            {:ok, conn}
+
+          error ->
+            error
+        end
+    end
+  end
+
+  # the options of the handler which apply alike on the BEAM path and the native datapath
+  defp configure_conn(state, conn) do
+    set_trace_context(state, conn)
+    enable_qlog(state, conn)
+    enable_congestion_events(state, conn)
+    enable_pmtu_events(state, conn)
+    enable_event_timestamps(state, conn)
+    enable_stream_close_events(state, conn)
+    enable_packet_tap(state, conn)
+    enable_sampling(state, conn)
+    enable_recv_credit(state, conn)
+    enable_stream_framing(state, conn)
+    enable_stream_pull(state, conn)
+    enable_dgram_pull(state, conn)
+    set_dgram_budget(state, conn)
+    set_send_deadline(state, conn)
+    set_send_order(state, conn)
+    set_keepalive(state, conn)
+    set_lifetime_policy(state, conn)
+    enable_impairment(state, conn)
+  end
+
+  # a connection of the BEAM path is freed by a process of its own once this one is down,
+  # whether terminate/2 has run or it has been killed, and never while it still uses it
+  defp watch_owner(state, conn) when is_integer(conn) do
+    owner = self()
+    close_code = Config.get!(state.handler, :owner_down_close_code)
+
+    spawn(fn ->
+      ref = Process.monitor(owner)
+
+      receive do
+        {:DOWN, ^ref, :process, _pid, _reason} -> NIF.Connection.destroy(conn, close_code)
+      end
+    end)
+  end
+
+  defp watch_owner(_state, _conn), do: :ok
+
+  # the datapath forgets its connection, closing it unless it's closed already
+  defp destroy(%{conn: {:native, _datapath, _conn_id} = conn} = state) do
+    NIF.Connection.destroy(conn, Config.get!(state.handler, :owner_down_close_code))
+  end
+
+  defp destroy(_state), do: :ok
+
+  # the handler decides in init/2 whether it accepts the certificate of the client,
+  # returning {:stop, code, reason} to refuse it. that's after the handshake, which
+  # quiche 0.12 gives no hook into, so a refused client has completed it already.
+  defp put_peer_cert(state) do
+    if Config.get(state.handler, :verify_peer) do
+      case NIF.Connection.peer_cert(state.conn) do
+        {:ok, peer_cert} ->
+          %{state | conn_state: %{state.conn_state | peer_cert: peer_cert}}
+
+        {:error, _reason} ->
+          state
+      end
+    else
+      state
+    end
+  end
+
+  # with `peer_cert_pins`, the SHA-256 fingerprints of the certificates the clients may
+  # present, which lets self-signed certificates in the verify locations stand for
+  # the clients without a PKI
+  defp peer_cert_pinned?(state) do
+    case Config.get(state.handler, :peer_cert_pins) do
+      nil ->
+        true
+
+      pins ->
+        cert = state.conn_state.peer_cert
+        cert != nil && Enum.member?(pins, :crypto.hash(:sha256, cert))
+    end
+  end
+
+  defp init_handler(request, state) do
+    conn = state.conn
+
+    ExceptionGuard.guard(
+      fn ->
+        close(false, :internal_error, :server_error)
+        {:noreply, state}
+      end,
+      fn ->
+        case state.handler.init(state.conn_state, request) do
+          {:ok, %ConnectionState{} = conn_state, handler_state} ->
+            Tracer.trace(__MODULE__, state.trace_id, "@handler.init: completed")
+
+            case NIF.Connection.accept_connect_request(conn) do
+              {:ok, next_timeout} ->
+                state = reset_conn_timer(state, next_timeout)
+
+                {:noreply,
+                 %{
+                   state
+                   | conn: conn,
+                     conn_state: conn_state,
+                     handler_state: handler_state,
+                     handler_initialized: true
+                 }}
+
+              {:error, :already_closed} ->
+                close(false, :no_error, :shutdown)
+                {:noreply, state}
+
+              {:error, reason} ->
+                Logger.error(
+                  "<Requiem.Connection:#{self()}> accept_connect_request failed: #{inspect(reason)}"
+                )
+
+                close(false, :internal_error, :server_error)
+                {:noreply, state}
+            end
+
+          {:ok, %ConnectionState{} = conn_state, handler_state, timeout}
+          when is_integer(timeout) ->
+            Tracer.trace(__MODULE__, state.trace_id, "@handler.init: completed with timeout")
+
+            case NIF.Connection.accept_connect_request(conn) do
+              {:ok, next_timeout} ->
+                state = reset_conn_timer(state, next_timeout)
+
+                {:noreply,
+                 %{
+                   state
+                   | conn: conn,
+                     conn_state: conn_state,
+                     handler_state: handler_state,
+                     handler_initialized: true
+                 }, timeout}
+
+              {:error, :already_closed} ->
+                close(false, :no_error, :shutdown)
+                {:noreply, state}
+
+              {:error, reason} ->
+                Logger.error(
+                  "<Requiem.Connection:#{self()}> accept_connect_request failed: #{inspect(reason)}"
+                )
+
+                close(false, :internal_error, :server_error)
+                {:noreply, state}
+            end
+
+          {:ok, %ConnectionState{} = conn_state, handler_state, :hibernate} ->
+            Tracer.trace(__MODULE__, state.trace_id, "@handler.init: completed with :hibernate")
+
+            case NIF.Connection.accept_connect_request(conn) do
+              {:ok, next_timeout} ->
+                state = reset_conn_timer(state, next_timeout)
+
+                {:noreply,
+                 %{
+                   state
+                   | conn: conn,
+                     conn_state: conn_state,
+                     handler_state: handler_state,
+                     handler_initialized: true
+                 }, :hibernate}
+
+              {:error, :already_closed} ->
+                close(false, :no_error, :shutdown)
+                {:noreply, state}
+
+              {:error, reason} ->
+                Logger.error(
+                  "<Requiem.Connection:#{self()}> accept_connect_request failed: #{inspect(reason)}"
+                )
+
+                close(false, :internal_error, :server_error)
+                {:noreply, state}
+            end
+
+          {:stop, code, reason} when is_integer(code) and is_atom(reason) ->
+            Tracer.trace(__MODULE__, state.trace_id, "@handler.init: stop")
+            # TODO assert code >= 400 && code < 600
+            # TODO pass reason
+            case NIF.Connection.reject_connect_request(conn, code) do
+              {:ok, next_timeout} ->
+                state = reset_conn_timer(state, next_timeout)
+                {:noreply, state}
+
+              {:error, :already_closed} ->
+                close(false, :no_error, :shutdown)
+                {:noreply, state}
+
+              {:error, reason} ->
+                Logger.error(
+                  "<Requiem.Connection:#{self()}> accept_connect_request failed: #{inspect(reason)}"
+                )
+
+                close(false, :internal_error, :server_error)
+                {:noreply, state}
+            end
+
+          other ->
+            Logger.error(
+              "<Requiem.Connection:#{self()}> handle_cast returned unknown pattern: #{inspect(other)}"
+            )
+
+            close(false, :internal_error, :server_error)
+            {:noreply, state}
+        end
+      end
+    )
+  end
+
+  defp enable_congestion_events(state, conn) do
+    if Config.get!(state.handler, :congestion_events) do
+      NIF.Connection.enable_congestion_events(conn)
+    end
+  end
+
+  # `event_timestamps` sets `event_time` of the ConnectionState before the stream,
+  # datagram and lifecycle events are handled
+  defp enable_event_timestamps(state, conn) do
+    if Config.get!(state.handler, :event_timestamps) do
+      NIF.Connection.enable_event_timestamps(conn)
+    end
+  end
+
+  # `pmtu_events` passes {:pmtu, pmtu} to the handler when the path MTU changes
+  defp enable_pmtu_events(state, conn) do
+    if Config.get!(state.handler, :pmtu_events) do
+      NIF.Connection.enable_pmtu_events(conn)
+    end
+  end
+
+  # `stream_close_events` passes {:stream_closed, stream_id} to the handler once
+  # a stream is collected
+  defp enable_stream_close_events(state, conn) do
+    if Config.get!(state.handler, :stream_close_events) do
+      NIF.Connection.enable_stream_close_events(conn)
+    end
+  end
+
+  # `path_samples` is the number of samples kept, 0 disables the sampling
+  defp enable_sampling(state, conn) do
+    case Config.get!(state.handler, :path_samples) do
+      capacity when capacity > 0 ->
+        NIF.Connection.enable_sampling(
+          conn,
+          Config.get!(state.handler, :path_sample_interval),
+          capacity
+        )
+
+      _ ->
+        :ok
+    end
+  end
+
+  # `stream_framing` other than :raw passes the data of the streams to the handler
+  # as the messages it frames, cut and reassembled by the NIF, and frames each
+  # stream_send of the handler
+  defp enable_stream_framing(state, conn) do
+    case Config.get!(state.handler, :stream_framing) do
+      :raw -> :ok
+      framing -> NIF.Connection.set_stream_framing(conn, framing)
+    end
+  end
+
+  # `stream_send_deadline` is `{timeout_ms, code}`: the data of a stream held back by
+  # the flow control longer than that is dropped and the stream reset with the code,
+  # which the handler learns with {:stream_expired, stream_id, code}
+  defp set_send_deadline(state, conn) do
+    case Config.get!(state.handler, :stream_send_deadline) do
+      {timeout_ms, code} = deadline when is_integer(timeout_ms) and is_integer(code) ->
+        NIF.Connection.set_send_deadline(conn, nil, deadline)
+
+      _ ->
+        :ok
+    end
+  end
+
+  # `dgram_pull_mode` leaves the datagrams in the connection, the handler is told of them
+  # with :dgram_readable and takes them with dgram_recv/0 or dgram_drop/0
+  defp enable_dgram_pull(state, conn) do
+    if Config.get!(state.handler, :dgram_pull_mode) do
+      NIF.Connection.set_dgram_pull_mode(conn, true)
+    end
+  end
+
+  # `dgram_budget` is the number of datagrams passed to handle_dgram per packet received
+  # at most, the others wait for the next packet, nil passes all of them
+  defp set_dgram_budget(state, conn) do
+    case Config.get!(state.handler, :dgram_budget) do
+      budget when is_integer(budget) and budget > 0 ->
+        NIF.Connection.set_dgram_budget(conn, budget)
+
+      _ ->
+        :ok
+    end
+  end
+
+  # `keepalive_interval` is the milliseconds without sending anything after which
+  # the connection probes the peer, nil sends no probe
+  defp set_keepalive(state, conn) do
+    case Config.get!(state.handler, :keepalive_interval) do
+      interval when is_integer(interval) and interval > 0 ->
+        NIF.Connection.set_keepalive(conn, interval)
+
+      _ ->
+        :ok
+    end
+  end
+
+  # `max_lifetime` and `max_inactivity` are {milliseconds, app_error_code}: the connection
+  # is closed with the code once it has lived that long, or has gone that long without
+  # stream data nor datagrams, to have the clients authenticate again or move elsewhere
+  defp set_lifetime_policy(state, conn) do
+    max_lifetime = Config.get!(state.handler, :max_lifetime)
+    max_inactivity = Config.get!(state.handler, :max_inactivity)
+
+    if max_lifetime || max_inactivity do
+      NIF.Connection.set_lifetime_policy(conn, max_lifetime, max_inactivity)
+    else
+      :ok
+    end
+  end
+
+  # `send_order` is :dgram_first, the DATAGRAMs going out before the stream data sent
+  # at the same time, :stream_first, the DATAGRAMs getting what the stream data leaves,
+  # or {:dgram_share, percent}, the DATAGRAMs going first up to that percent of the window
+  defp set_send_order(state, conn) do
+    case Config.get!(state.handler, :send_order) do
+      :dgram_first -> :ok
+      order -> NIF.Connection.set_send_order(conn, order)
+    end
+  end
+
+  # `stream_pull_mode` leaves the data of the streams in the connection until the handler
+  # asks for it with stream_read/2, after {:stream_readable, stream_id}
+  defp enable_stream_pull(state, conn) do
+    if Config.get!(state.handler, :stream_pull_mode) do
+      NIF.Connection.set_stream_pull_mode(conn, true)
+    else
+      :ok
+    end
+  end
+
+  # `impairment` makes the connection drop, duplicate, reorder and delay its packets,
+  # deterministically for a given seed, so that tests can run over a bad network
+  defp enable_impairment(state, conn) do
+    case Config.get!(state.handler, :impairment) do
+      nil ->
+        :ok
+
+      opts ->
+        ppm = fn key -> round(Keyword.get(opts, key, 0.0) * 1_000_000) end
+
+        NIF.Connection.set_impairment(
+          conn,
+          {ppm.(:loss), ppm.(:duplicate), ppm.(:reorder), Keyword.get(opts, :delay, 0),
+           Keyword.get(opts, :seed, 1)}
+        )
+    end
+  end
+
+  # `stream_recv_window` is the number of bytes of a stream passed to the handler
+  # until the previous ones are handled, nil delivers them as they arrive.
+  # `stream_recv_overflow` is what happens to a stream whose peer sends more meanwhile:
+  # :pause leaves it to the flow control, {:reset, code} stops the stream.
+  defp enable_recv_credit(state, conn) do
+    case Config.get!(state.handler, :stream_recv_window) do
+      window when is_integer(window) and window > 0 ->
+        reset_code =
+          case Config.get!(state.handler, :stream_recv_overflow) do
+            {:reset, code} when is_integer(code) -> code
+            _ -> nil
+          end
+
+        NIF.Connection.set_stream_recv_window(conn, window, reset_code)
+
+      _ ->
+        :ok
+    end
+  end
+
+  # the credit of the data comes back as it's taken out of the mailbox
+  defp ack_stream(state, stream_id, data) do
+    if Config.get!(state.handler, :stream_recv_window) do
+      case NIF.Connection.stream_ack(state.conn, stream_id, byte_size(data)) do
+        {:ok, next_timeout} ->
+          reset_conn_timer(state, next_timeout)
+
+        {:error, reason} ->
+          Logger.debug(
+            "<Requiem.Connection:#{self()}> failed to ack stream data: #{inspect(reason)}"
+          )
+
+          state
+      end
+    else
+      state
+    end
+  end
+
+  # `packet_tap` is `{:pid, pid_or_name}` or `{:pcap, dir}`
+  defp enable_packet_tap(state, conn) do
+    target =
+      case Config.get!(state.handler, :packet_tap) do
+        {:pid, name} when is_atom(name) -> {:pid, Process.whereis(name)}
+        {:pcap, dir} -> {:pcap, to_string(dir)}
+        other -> other
+      end
+
+    case target do
+      {:pid, pid} when is_pid(pid) ->
+        NIF.Connection.tap(conn, target)
+
+      {:pcap, _dir} ->
+        NIF.Connection.tap(conn, target)
+
+      _ ->
+        :ok
+    end
+  end
+
+  # `qlog_pid` is a pid or a registered name
+  # `trace_context` is `{module, function}`, called with the ConnectionState to return
+  # `{trace_id, span_id, flags}` of the span the connection belongs to, or nil.
+  # the traceparent is put in the logger metadata of the connection process.
+  defp put_trace_context(state) do
+    case Config.get!(state.handler, :trace_context) do
+      {module, function} ->
+        case apply(module, function, [state.conn_state]) do
+          {_trace_id, _span_id, _flags} = context ->
+            Logger.metadata(traceparent: traceparent(context))
+            %{state | conn_state: %{state.conn_state | trace_context: context}}
+
+          nil ->
+            state
+        end
+
+      nil ->
+        state
+    end
+  end
+
+  defp traceparent({trace_id, span_id, flags}) do
+    "00-#{Base.encode16(trace_id, case: :lower)}-#{Base.encode16(span_id, case: :lower)}-" <>
+      Base.encode16(<<flags>>, case: :lower)
+  end
+
+  defp set_trace_context(state, conn) do
+    case state.conn_state.trace_context do
+      nil -> :ok
+      context -> NIF.Connection.set_trace_context(conn, context)
+    end
+  end
+
+  defp enable_qlog(state, conn) do
+    pid =
+      case Config.get!(state.handler, :qlog_pid) do
+        name when is_atom(name) and name != nil -> Process.whereis(name)
+        pid -> pid
+      end
+
+    if is_pid(pid) do
+      case NIF.Connection.stream_qlog(conn, pid) do
+        :ok ->
+          :ok
+
+        {:error, reason} ->
+          Logger.error("<Requiem.Connection> failed to stream qlog: #{inspect(reason)}")
+      end
+    end
+
+    case Config.get!(state.handler, :qlog_dir) do
+      nil ->
+        :ok
+
+      dir ->
+        case NIF.Connection.qlog_to_file(
+               conn,
+               to_string(dir),
+               Config.get!(state.handler, :qlog_max_file_size),
+               Config.get!(state.handler, :qlog_max_total_size),
+               Config.get!(state.handler, :qlog_gzip)
+             ) do
+          :ok ->
+            :ok
+
+          {:error, reason} ->
+            Logger.error("<Requiem.Connection> failed to write qlog: #{inspect(reason)}")
+        end
+    end
+  end
+
   defp new(opts) do
     dcid = Keyword.fetch!(opts, :dcid)
     scid = Keyword.fetch!(opts, :scid)
@@ -777,6 +1658,7 @@ defmodule Requiem.Connection do
       trace_id: trace_id,
       conn_state: ConnectionState.new(address, dcid, scid, odcid),
       conn: nil,
+      sender: nil,
       timer: nil
     }
   end
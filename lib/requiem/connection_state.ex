@@ -5,7 +5,11 @@ defmodule Requiem.ConnectionState do
           scid: binary,
           odcid: binary,
           stream_id_pod: non_neg_integer,
-          trapping_pids: MapSet.t()
+          trapping_pids: MapSet.t(),
+          close_info: Requiem.NIF.Connection.close_info() | nil,
+          trace_context: Requiem.NIF.Connection.trace_context() | nil,
+          peer_cert: binary | nil,
+          event_time: {non_neg_integer, non_neg_integer | nil} | nil
         }
 
   defstruct address: nil,
@@ -13,7 +17,17 @@ defmodule Requiem.ConnectionState do
             scid: "",
             odcid: "",
             stream_id_pod: 0,
-            trapping_pids: nil
+            trapping_pids: nil,
+            # who closed the connection and why, set when it's closed
+            close_info: nil,
+            # the span the connection belongs to, from the trace_context option
+            trace_context: nil,
+            # the DER certificate of the client, set before init with verify_peer,
+            # once the handshake has completed
+            peer_cert: nil,
+            # {emitted_at, received_at} of the last event, in microseconds of
+            # native_monotonic_time(), with event_timestamps
+            event_time: nil
 
   def new(address, dcid, scid, odcid) do
     %__MODULE__{
@@ -26,6 +40,17 @@ defmodule Requiem.ConnectionState do
     }
   end
 
+  @doc """
+  Microseconds since the packet of the event being handled was received, nil without
+  `event_timestamps` or when the event came from a timer.
+  """
+  @spec event_latency(t) :: non_neg_integer | nil
+  def event_latency(%{event_time: {_emitted_at, received_at}}) when is_integer(received_at) do
+    Requiem.NIF.Bridge.native_monotonic_time() - received_at
+  end
+
+  def event_latency(_conn), do: nil
+
   @spec should_delegate_exit?(t, pid) :: boolean
   def should_delegate_exit?(conn, pid) do
     MapSet.member?(conn.trapping_pids, pid)
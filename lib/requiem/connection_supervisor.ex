@@ -35,7 +35,9 @@ defmodule Requiem.ConnectionSupervisor do
           binary,
           binary,
           integer,
-          pid
+          pid,
+          integer,
+          non_neg_integer
         ) ::
           :ok | {:error, :system_error}
   def create_connection(
@@ -45,7 +47,9 @@ defmodule Requiem.ConnectionSupervisor do
         dcid,
         odcid,
         config_ptr,
-        sender_pid
+        sender_pid,
+        socket_ptr,
+        sender_index
       ) do
     Tracer.trace(__MODULE__, "create cnonection: DCID:#{Base.encode16(dcid)}")
 
@@ -58,7 +62,9 @@ defmodule Requiem.ConnectionSupervisor do
           scid: scid,
           odcid: odcid,
           config_ptr: config_ptr,
-          sender_pid: sender_pid
+          sender_pid: sender_pid,
+          socket_ptr: socket_ptr,
+          sender_index: sender_index
         ]
 
         case start_child(opts) do
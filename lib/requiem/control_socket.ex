@@ -0,0 +1,73 @@
+defmodule Requiem.ControlSocket do
+  @moduledoc """
+  Serves an admin interface for a handler on a unix domain socket.
+
+  Started by `Requiem.Supervisor` when `control_socket` is set to the path of
+  the socket. It's served from a native thread, so it still answers when the
+  BEAM is overloaded. The protocol is line based, each reply ending with `ok`
+  or `error <reason>`:
+
+      $ socat - UNIX-CONNECT:/tmp/requiem.sock
+      connections
+      4f2a...c1 native
+      ok
+      close 4f2a...c1 0x10
+      ok
+
+  The commands are `connections`, `stats`, `close <trace_id> [code]`,
+  `qlog <trace_id> on` (native datapath only, writing into the `qlog_dir` of the
+  handler with its other `qlog_*` options), `qlog <trace_id> off` and `help`.
+
+  The socket is only accessible by the owner of the node, and a file at `path`
+  which isn't a socket is left alone, the handler failing to start.
+  """
+  use GenServer
+  require Logger
+
+  alias Requiem.Config
+  alias Requiem.NIF.Bridge
+
+  def start_link(opts) do
+    name = opts |> Keyword.fetch!(:handler) |> name()
+    GenServer.start_link(__MODULE__, opts, name: name)
+  end
+
+  @impl GenServer
+  def init(opts) do
+    handler = Keyword.fetch!(opts, :handler)
+    path = Keyword.fetch!(opts, :path)
+
+    case Bridge.control_socket_open(
+           handler,
+           path,
+           qlog_dir(handler),
+           Config.get!(handler, :qlog_max_file_size),
+           Config.get!(handler, :qlog_max_total_size),
+           Config.get!(handler, :qlog_gzip)
+         ) do
+      {:ok, control_ptr} ->
+        Process.flag(:trap_exit, true)
+        {:ok, control_ptr}
+
+      {:error, reason} ->
+        Logger.error("<Requiem.ControlSocket> failed to open #{path}: #{inspect(reason)}")
+        {:stop, reason}
+    end
+  end
+
+  @impl GenServer
+  def terminate(_reason, control_ptr) do
+    Bridge.control_socket_close(control_ptr)
+    :ok
+  end
+
+  defp qlog_dir(handler) do
+    case Config.get!(handler, :qlog_dir) do
+      nil -> nil
+      dir -> to_string(dir)
+    end
+  end
+
+  defp name(handler),
+    do: Module.concat(handler, __MODULE__)
+end
@@ -36,7 +36,8 @@ defmodule Requiem.DispatcherSupervisor do
          handler: Keyword.fetch!(opts, :handler),
          token_secret: Keyword.fetch!(opts, :token_secret),
          conn_id_secret: Keyword.fetch!(opts, :conn_id_secret),
-         number_of_sockets: Keyword.fetch!(opts, :number_of_sockets)
+         number_of_sockets: Keyword.fetch!(opts, :number_of_sockets),
+         socket_ptr: Keyword.fetch!(opts, :socket_ptr)
        ]}
     end)
     |> Enum.reduce([], fn x, acc -> [x | acc] end)
@@ -4,6 +4,7 @@ defmodule Requiem.DispatcherWorker do
   use GenServer
 
   alias Requiem.Address
+  alias Requiem.Config
   alias Requiem.Connection
   alias Requiem.ConnectionID
   alias Requiem.ConnectionSupervisor
@@ -20,6 +21,8 @@ defmodule Requiem.DispatcherWorker do
           conn_id_secret: binary,
           worker_index: non_neg_integer,
           number_of_sockets: non_neg_integer,
+          socket_ptr: integer,
+          sender_index: non_neg_integer,
           config_ptr: integer,
           sender_pid: pid,
           packet_builder: integer,
@@ -31,6 +34,8 @@ defmodule Requiem.DispatcherWorker do
             conn_id_secret: "",
             worker_index: 0,
             number_of_sockets: 0,
+            socket_ptr: 0,
+            sender_index: 0,
             config_ptr: 0,
             sender_pid: nil,
             packet_builder: 0,
@@ -63,7 +68,7 @@ defmodule Requiem.DispatcherWorker do
 
     sender_idx = rem(state.worker_index, state.number_of_sockets)
     {:ok, sender_pid} = SenderRegistry.lookup(state.handler, sender_idx)
-    state = %{state | sender_pid: sender_pid}
+    state = %{state | sender_pid: sender_pid, sender_index: sender_idx}
 
     {:ok, config} = NIF.Config.new()
 
@@ -157,6 +162,7 @@ defmodule Requiem.DispatcherWorker do
       handler: Keyword.fetch!(opts, :handler),
       worker_index: Keyword.fetch!(opts, :worker_index),
       number_of_sockets: Keyword.fetch!(opts, :number_of_sockets),
+      socket_ptr: Keyword.fetch!(opts, :socket_ptr),
       token_secret: Keyword.fetch!(opts, :token_secret),
       conn_id_secret: Keyword.fetch!(opts, :conn_id_secret),
       config_ptr: 0,
@@ -230,16 +236,13 @@ defmodule Requiem.DispatcherWorker do
       {:ok, odcid} ->
         Tracer.trace(__MODULE__, state.trace_id, "@validate_success")
 
-        case create_connection_if_needed(
-               address,
-               scid,
-               dcid,
-               odcid,
-               state
-             ) do
+        case accept_or_drop(address, packet, scid, dcid, odcid, state) do
           :ok ->
             handle_regular_packet(address, packet, scid, dcid, state)
 
+          :dropped ->
+            :ok
+
           {:error, :system_error} ->
             :error
         end
@@ -273,6 +276,28 @@ defmodule Requiem.DispatcherWorker do
     end
   end
 
+  # beyond `max_connections` live connections, the Initial packets of the new ones
+  # are dropped, their address having been validated by the retry
+  defp accept_or_drop(address, packet, scid, dcid, odcid, state) do
+    case Config.get!(state.handler, :max_connections) do
+      max when is_integer(max) ->
+        if NIF.Bridge.module_connections(state.handler) >= max do
+          drop_initial(state)
+        else
+          create_connection_if_needed(address, scid, dcid, odcid, state)
+        end
+
+      _ ->
+        create_connection_if_needed(address, scid, dcid, odcid, state)
+    end
+  end
+
+  defp drop_initial(state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@drop_initial")
+    NIF.Connection.count_refused(state.handler)
+    :dropped
+  end
+
   defp create_connection_if_needed(_address, _scid, <<>>, _odcid, _state) do
     :ok
   end
@@ -285,7 +310,9 @@ defmodule Requiem.DispatcherWorker do
       dcid,
       odcid,
       state.config_ptr,
-      state.sender_pid
+      state.sender_pid,
+      state.socket_ptr,
+      state.sender_index
     )
   end
 
@@ -0,0 +1,76 @@
+defmodule Requiem.LoadGen do
+  @moduledoc """
+  Drives in-process client connections against a listening handler and reports
+  the throughput and latencies, to catch performance regressions of the native
+  layer without an external harness.
+
+  Only available when the NIF is built with the `loadgen` feature:
+
+      config :requiem, nif_features: ["loadgen"]
+
+  Each connection makes a handshake, opens a WebTransport session on `path` with an
+  extended CONNECT, then opens `streams` bidi streams in it sending `message_size`
+  bytes each, and sends `dgram_rate` datagrams per second, at most 1_000_000, until
+  `duration` milliseconds have passed. The latencies are in microseconds. Those of
+  the streams and the datagrams are round trips, so the handler under test has to
+  reply to them, as `RequiemEcho.Handler` does: a stream's ends with the first bytes
+  back on it and a datagram's with its echo, the ones with no reply being left out.
+
+      Requiem.LoadGen.run(address: "127.0.0.1:4433", connections: 100, streams: 10)
+  """
+  alias Requiem.NIF.Bridge
+
+  @type report :: %{
+          connected: non_neg_integer,
+          failed: non_neg_integer,
+          handshake_latency: percentiles,
+          sessions: non_neg_integer,
+          streams_replied: non_neg_integer,
+          stream_round_trip: percentiles,
+          dgrams_sent: non_neg_integer,
+          dgrams_echoed: non_neg_integer,
+          dgram_round_trip: percentiles,
+          bytes_sent: non_neg_integer,
+          elapsed: non_neg_integer
+        }
+
+  @type percentiles :: %{p50: non_neg_integer, p90: non_neg_integer, p99: non_neg_integer}
+
+  @spec run(Keyword.t()) :: {:ok, report} | {:error, :not_supported | atom}
+  def run(opts) do
+    result =
+      Bridge.loadgen_run(
+        Keyword.fetch!(opts, :address),
+        Keyword.get(opts, :server_name, "localhost"),
+        Keyword.get(opts, :path, "/"),
+        Keyword.get(opts, :connections, 10),
+        Keyword.get(opts, :streams, 1),
+        Keyword.get(opts, :message_size, 1024),
+        Keyword.get(opts, :dgram_rate, 0),
+        Keyword.get(opts, :duration, 5_000)
+      )
+
+    with {:ok, report} <- result, do: {:ok, report_map(report)}
+  end
+
+  defp report_map(
+         {connected, failed, handshake, sessions, {streams_replied, stream},
+          {dgrams_sent, dgrams_echoed, dgram}, {bytes_sent, elapsed}}
+       ) do
+    %{
+      connected: connected,
+      failed: failed,
+      handshake_latency: percentiles(handshake),
+      sessions: sessions,
+      streams_replied: streams_replied,
+      stream_round_trip: percentiles(stream),
+      dgrams_sent: dgrams_sent,
+      dgrams_echoed: dgrams_echoed,
+      dgram_round_trip: percentiles(dgram),
+      bytes_sent: bytes_sent,
+      elapsed: elapsed
+    }
+  end
+
+  defp percentiles({p50, p90, p99}), do: %{p50: p50, p90: p90, p99: p99}
+end
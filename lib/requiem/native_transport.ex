@@ -0,0 +1,194 @@
+defmodule Requiem.NativeTransport do
+  @moduledoc """
+  Listener for the native datapath (`datapath: :native`).
+
+  The UDP socket is owned by a native thread which receives, routes, processes
+  and sends all the packets by itself. This process only starts
+  a Requiem.Connection for each connection accepted on that thread.
+
+  With `datapath_workers` greater than 1, that many SO_REUSEPORT sockets are
+  opened on the same port, each with its own native thread.
+
+  With `datapath_idle_reap_timeout` (milliseconds) greater than 0, connections
+  which have received neither a packet nor a command from their owner for that
+  long are closed and dropped by the native thread, so that connections left by
+  killed owners don't leak.
+
+  With `datapath_socket`, the native threads take over a UDP socket already bound
+  on the Elixir side instead of binding `host` and `port` themselves: a file
+  descriptor (e.g. 3 with systemd socket activation), a `:gen_udp` port or
+  a `:socket`. Nothing should read from it on the Elixir side afterwards.
+
+  The owner processes are monitored, the connection of an owner which has died
+  without closing it is closed with `owner_down_close_code` and dropped at once.
+  """
+  use GenServer
+  require Logger
+  require Requiem.Tracer
+
+  alias Requiem.Address
+  alias Requiem.Config
+  alias Requiem.ConnectionSupervisor
+  alias Requiem.NIF
+  alias Requiem.Tracer
+
+  @type t :: %__MODULE__{
+          handler: module,
+          datapath: reference | nil,
+          owners: %{reference => non_neg_integer}
+        }
+
+  defstruct handler: nil,
+            datapath: nil,
+            owners: %{}
+
+  def start_link(opts) do
+    name = Keyword.fetch!(opts, :handler) |> name()
+    GenServer.start_link(__MODULE__, opts, name: name)
+  end
+
+  @impl GenServer
+  def init(opts) do
+    handler = Keyword.fetch!(opts, :handler)
+    host = Keyword.fetch!(opts, :host)
+    port = Keyword.fetch!(opts, :port)
+
+    # each worker has its own config, so that they never contend on accept
+    configs = new_configs(handler, workers(handler), [])
+
+    # the configs are owned by the datapath from here
+    case NIF.Datapath.start(
+           configs,
+           host,
+           port,
+           self(),
+           handler: handler,
+           conn_id_secret: Config.get!(handler, :connection_id_secret),
+           token_secret: Config.get!(handler, :token_secret),
+           stream_buf_size: Config.get!(handler, :stream_buffer_size),
+           stream_buf_min_size: Config.get!(handler, :stream_buffer_min_size),
+           stream_buf_max_size: Config.get!(handler, :stream_buffer_max_size),
+           max_field_section_size: Config.get!(handler, :max_field_section_size),
+           max_requests: Config.get!(handler, :max_concurrent_requests),
+           max_header_count: Config.get!(handler, :max_header_count),
+           max_connections: Config.get!(handler, :max_connections),
+           recv_batch_size: Config.get!(handler, :datapath_recv_batch_size),
+           send_batch_size: Config.get!(handler, :datapath_send_batch_size),
+           gso: Config.get!(handler, :datapath_gso),
+           gro: Config.get!(handler, :datapath_gro),
+           idle_reap_timeout: Config.get!(handler, :datapath_idle_reap_timeout),
+           fd: socket_fd(Config.get!(handler, :datapath_socket))
+         ) do
+      {:ok, datapath} ->
+        Logger.info("<Requiem.NativeTransport> socket started on #{host}:#{port}")
+        Process.flag(:trap_exit, true)
+        {:ok, %__MODULE__{handler: handler, datapath: datapath}}
+
+      {:error, :cant_bind} ->
+        Logger.error(
+          "<Requiem.NativeTransport> failed to bind UDP port, make sure that the values for this host(#{host}) and port(#{port}) are correct and that the port(#{port}) is not already in use."
+        )
+
+        {:stop, :normal}
+
+      {:error, reason} ->
+        Logger.error(
+          "<Requiem.NativeTransport> failed to open UDP port #{to_string(port)}: #{inspect(reason)}"
+        )
+
+        {:stop, :normal}
+    end
+  end
+
+  @impl GenServer
+  def handle_info({:__accept__, conn_id, peer, scid, dcid, odcid}, state) do
+    Tracer.trace(__MODULE__, "@accept: DCID:#{Base.encode16(dcid)}")
+
+    opts = [
+      handler: state.handler,
+      address: Address.from_rust_peer(peer),
+      dcid: dcid,
+      scid: scid,
+      odcid: odcid,
+      datapath: state.datapath,
+      conn_id: conn_id
+    ]
+
+    case ConnectionSupervisor.start_child(opts) do
+      {:ok, pid} ->
+        ref = Process.monitor(pid)
+        {:noreply, %{state | owners: Map.put(state.owners, ref, conn_id)}}
+
+      other ->
+        Tracer.trace(__MODULE__, "@accept: failed to start connection: #{inspect(other)}")
+        forget(state, conn_id)
+        {:noreply, state}
+    end
+  end
+
+  def handle_info({:DOWN, ref, :process, _pid, _reason}, state) do
+    # a connection already closed and forgotten by its owner is just not found
+    {conn_id, owners} = Map.pop(state.owners, ref)
+
+    if conn_id != nil do
+      forget(state, conn_id)
+    end
+
+    {:noreply, %{state | owners: owners}}
+  end
+
+  @impl GenServer
+  def terminate(reason, state) do
+    Logger.info("<Requiem.NativeTransport> @terminate: #{inspect(reason)}")
+    NIF.Datapath.stop(state.datapath)
+    :ok
+  end
+
+  defp socket_fd(nil), do: nil
+  defp socket_fd(fd) when is_integer(fd), do: fd
+
+  defp socket_fd(port) when is_port(port) do
+    {:ok, fd} = :inet.getfd(port)
+    fd
+  end
+
+  defp socket_fd(socket) do
+    {:ok, fd} = :socket.getopt(socket, :otp, :fd)
+    fd
+  end
+
+  defp forget(state, conn_id) do
+    NIF.Datapath.forget(
+      state.datapath,
+      conn_id,
+      Config.get!(state.handler, :owner_down_close_code)
+    )
+  end
+
+  defp name(handler),
+    do: Module.concat(handler, __MODULE__)
+
+  defp new_configs(_handler, 0, configs), do: configs
+
+  defp new_configs(handler, num, configs) do
+    {:ok, config} = NIF.Config.new()
+
+    try do
+      NIF.init_config(handler, config)
+    rescue
+      err ->
+        Enum.each([config | configs], &NIF.Config.destroy/1)
+        raise err
+    end
+
+    new_configs(handler, num - 1, [config | configs])
+  end
+
+  # one SO_REUSEPORT socket and thread per worker, 0 means one per CPU
+  defp workers(handler) do
+    case Config.get!(handler, :datapath_workers) do
+      0 -> NIF.Socket.cpu_num()
+      num -> num
+    end
+  end
+end
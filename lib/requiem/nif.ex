@@ -72,6 +72,8 @@ defmodule Requiem.NIF do
       end
     end
 
+    # quiche 0.12 has no setting for how many session tickets are issued per connection
+    # nor for their lifetime, those are the defaults of BoringSSL
     Requiem.NIF.Config.set_application_protos(ptr, [@http3_alpn])
 
     # default is inifinite
@@ -3,6 +3,7 @@ defmodule Requiem.NIF.Bridge do
     otp_app: :requiem,
     crate: "requiem_nif",
     mode: :release,
+    features: Application.compile_env(:requiem, :nif_features, []),
     load_data: Requiem.Tracer.traceable()
 
   @spec config_new() ::
@@ -97,21 +98,54 @@ defmodule Requiem.NIF.Bridge do
           :ok | {:error, :system_error | :not_found}
   def config_enable_dgram(_ptr, _enabled, _recv_queue_len, _send_queue_len), do: error()
 
-  @spec connection_accept(integer, binary, binary, term, pid, non_neg_integer) ::
-          {:ok, integer} | {:error, :system_error | :not_found}
-  def connection_accept(_config_ptr, _scid, _odcid, _peer, _sender_pid, _stream_buf_size),
-    do: error()
+  @spec connection_accept(module, integer, binary, binary, term, pid, non_neg_integer) ::
+          {:ok, integer} | {:error, Requiem.NIF.Connection.quic_error() | :not_found}
+  def connection_accept(
+        _handler,
+        _config_ptr,
+        _scid,
+        _odcid,
+        _peer,
+        _sender_pid,
+        _stream_buf_size
+      ),
+      do: error()
+
+  @spec connection_count_refused(module) :: :ok
+  def connection_count_refused(_handler), do: error()
 
   @spec connection_open_stream(integer, boolean) ::
-          {:ok, non_neg_integer, non_neg_integer} | {:error, :system_error | :already_closed}
+          {:ok, non_neg_integer, non_neg_integer | :infinity} | {:error, atom}
   def connection_open_stream(_conn_ptr, _is_bidi), do: error()
 
+  @spec connection_set_owner(integer, pid) :: :ok
+  def connection_set_owner(_conn_ptr, _pid), do: error()
+
+  @spec connection_set_subscriber(
+          integer,
+          :packets | :streams | :dgrams | :lifecycle,
+          pid | nil
+        ) :: :ok | {:error, :bad_format | :not_supported}
+  def connection_set_subscriber(_conn_ptr, _class, _pid), do: error()
+
+  @spec connection_stream_qlog(integer, pid) ::
+          :ok | {:error, :already_exists | :not_supported}
+  def connection_stream_qlog(_conn_ptr, _pid), do: error()
+
+  @spec connection_qlog_to_file(integer, binary, pos_integer, pos_integer, boolean) ::
+          :ok | {:error, :system_error | :not_supported}
+  def connection_qlog_to_file(_conn_ptr, _dir, _max_file_size, _max_total_size, _gzip),
+    do: error()
+
+  @spec connection_qlog_disable(integer) :: :ok | {:error, :not_supported}
+  def connection_qlog_disable(_conn_ptr), do: error()
+
   @spec connection_accept_connect_request(integer) ::
-          {:ok, non_neg_integer} | {:error, :system_error | :already_closed}
+          {:ok, non_neg_integer | :infinity} | {:error, atom}
   def connection_accept_connect_request(_conn_ptr), do: error()
 
   @spec connection_reject_connect_request(integer, integer) ::
-          {:ok, non_neg_integer} | {:error, :system_error | :already_closed}
+          {:ok, non_neg_integer | :infinity} | {:error, atom}
   def connection_reject_connect_request(_conn_ptr, _code), do: error()
 
   @spec connection_destroy(integer) ::
@@ -119,28 +153,206 @@ defmodule Requiem.NIF.Bridge do
   def connection_destroy(_conn_ptr), do: error()
 
   @spec connection_close(integer, boolean, non_neg_integer, binary) ::
-          {:ok, non_neg_integer} | {:error, :system_error | :already_closed}
+          {:ok, non_neg_integer | :infinity}
+          | {:error, Requiem.NIF.Connection.quic_error() | :already_closed}
   def connection_close(_conn, _app, _err, _reason), do: error()
 
   @spec connection_is_closed(integer) :: boolean
   def connection_is_closed(_conn), do: error()
 
+  @spec connection_close_info(integer) :: Requiem.NIF.Connection.close_info() | nil
+  def connection_close_info(_conn), do: error()
+
+  @spec connection_enable_congestion_events(integer) :: :ok
+  def connection_enable_congestion_events(_conn), do: error()
+
+  @spec connection_enable_pmtu_events(integer) :: :ok
+  def connection_enable_pmtu_events(_conn), do: error()
+
+  @spec connection_enable_event_timestamps(integer) :: :ok
+  def connection_enable_event_timestamps(_conn), do: error()
+
+  @spec connection_enable_stream_close_events(integer) :: :ok
+  def connection_enable_stream_close_events(_conn), do: error()
+
+  @spec connection_tap_pid(integer, pid) :: :ok | {:error, :system_error}
+  def connection_tap_pid(_conn, _pid), do: error()
+
+  @spec connection_tap_pcap(integer, binary) :: :ok | {:error, :system_error}
+  def connection_tap_pcap(_conn, _dir), do: error()
+
+  @spec connection_untap(integer) :: :ok
+  def connection_untap(_conn), do: error()
+
+  @spec connection_set_trace_context(integer, binary, binary, non_neg_integer) ::
+          :ok | {:error, :bad_format}
+  def connection_set_trace_context(_conn, _trace_id, _span_id, _flags), do: error()
+
+  @spec connection_enable_sampling(integer, non_neg_integer, non_neg_integer) :: :ok
+  def connection_enable_sampling(_conn, _interval_ms, _capacity), do: error()
+
+  @spec connection_samples(integer) :: {:ok, [Requiem.NIF.Connection.sample_tuple()]}
+  def connection_samples(_conn), do: error()
+
+  @spec connection_set_stream_framing(integer, Requiem.NIF.Connection.stream_framing()) ::
+          :ok | {:error, :bad_format}
+  def connection_set_stream_framing(_conn, _framing), do: error()
+
+  @spec connection_set_impairment(integer, Requiem.NIF.Connection.impairment() | nil) :: :ok
+  def connection_set_impairment(_conn, _impairment), do: error()
+
+  @spec connection_set_stream_recv_window(integer, non_neg_integer, non_neg_integer | nil) ::
+          :ok
+  def connection_set_stream_recv_window(_conn, _window, _reset_code), do: error()
+
+  @spec connection_set_stream_priority(integer, non_neg_integer, 0..7, boolean) ::
+          :ok | {:error, atom}
+  def connection_set_stream_priority(_conn, _stream_id, _urgency, _incremental), do: error()
+
+  @spec connection_set_send_order(
+          integer,
+          :dgram_first | :stream_first | {:dgram_share, 0..100}
+        ) :: :ok | {:error, :bad_format}
+  def connection_set_send_order(_conn, _order), do: error()
+
+  @spec connection_set_stream_pull_mode(integer, boolean) :: :ok
+  def connection_set_stream_pull_mode(_conn, _enabled), do: error()
+
+  @spec connection_stream_read(pid, integer, non_neg_integer, non_neg_integer) ::
+          {:ok, binary, non_neg_integer | :infinity} | {:error, atom}
+  def connection_stream_read(_pid, _conn, _stream_id, _max_bytes), do: error()
+
+  @spec connection_h3_recv_body(pid, integer, non_neg_integer, non_neg_integer) ::
+          {:ok, binary, non_neg_integer | :infinity} | {:error, atom}
+  def connection_h3_recv_body(_pid, _conn, _stream_id, _max_bytes), do: error()
+
+  @spec connection_set_send_deadline(
+          integer,
+          non_neg_integer | nil,
+          {non_neg_integer, non_neg_integer} | nil
+        ) :: :ok
+  def connection_set_send_deadline(_conn, _stream_id, _deadline), do: error()
+
+  @spec connection_set_dgram_budget(integer, pos_integer | nil) :: :ok
+  def connection_set_dgram_budget(_conn, _budget), do: error()
+
+  @spec connection_set_keepalive(integer, pos_integer | nil) :: :ok | {:error, :bad_format}
+  def connection_set_keepalive(_conn, _interval_ms), do: error()
+
+  @spec connection_set_lifetime_policy(
+          integer,
+          {pos_integer, non_neg_integer} | nil,
+          {pos_integer, non_neg_integer} | nil
+        ) :: :ok
+  def connection_set_lifetime_policy(_conn, _max_lifetime, _max_inactivity), do: error()
+
+  @spec connection_set_dgram_pull_mode(integer, boolean) :: :ok
+  def connection_set_dgram_pull_mode(_conn, _enabled), do: error()
+
+  @spec connection_dgram_peek(integer, non_neg_integer) ::
+          {:ok, binary, non_neg_integer} | {:error, atom}
+  def connection_dgram_peek(_conn, _max_len), do: error()
+
+  @spec connection_dgram_recv(pid, integer, boolean) ::
+          {:ok, non_neg_integer | :infinity} | {:error, atom}
+  def connection_dgram_recv(_pid, _conn, _deliver), do: error()
+
+  @spec connection_probe_path(integer, non_neg_integer) ::
+          {:ok, non_neg_integer | :infinity} | {:error, atom}
+  def connection_probe_path(_conn, _timeout_ms), do: error()
+
+  @spec connection_stream_ack(pid, integer, non_neg_integer, non_neg_integer) ::
+          {:ok, non_neg_integer | :infinity} | {:error, atom}
+  def connection_stream_ack(_pid, _conn, _stream_id, _bytes), do: error()
+
+  @spec connection_stream_stats(integer, non_neg_integer) ::
+          {:ok, Requiem.NIF.Connection.stream_stats_tuple()} | {:error, :not_found}
+  def connection_stream_stats(_conn, _stream_id), do: error()
+
+  @spec connection_stream_counts(integer) ::
+          {:ok, Requiem.NIF.Connection.stream_counts_tuple()}
+  def connection_stream_counts(_conn), do: error()
+
+  @spec connection_pmtu(integer) :: {:ok, non_neg_integer}
+  def connection_pmtu(_conn), do: error()
+
+  @spec connection_readable(integer) :: {:ok, [non_neg_integer]}
+  def connection_readable(_conn), do: error()
+
+  @spec connection_writable(integer) :: {:ok, [non_neg_integer]}
+  def connection_writable(_conn), do: error()
+
+  @spec connection_handoff_state(integer) :: {:ok, Requiem.NIF.Connection.handoff_state()}
+  def connection_handoff_state(_conn), do: error()
+
+  @spec connection_peer_cert(integer) :: {:ok, binary | nil}
+  def connection_peer_cert(_conn), do: error()
+
+  @spec connection_set_request_limits(
+          integer,
+          non_neg_integer,
+          non_neg_integer,
+          non_neg_integer
+        ) :: :ok
+  def connection_set_request_limits(
+        _conn,
+        _max_field_section_size,
+        _max_requests,
+        _max_header_count
+      ),
+      do: error()
+
+  @spec connection_stream_buffer_resize(integer, pos_integer) :: :ok | {:error, :bad_format}
+  def connection_stream_buffer_resize(_conn, _size), do: error()
+
+  @spec connection_set_stream_buffer_range(integer, non_neg_integer, non_neg_integer) ::
+          :ok | {:error, :bad_format}
+  def connection_set_stream_buffer_range(_conn, _min, _max), do: error()
+
   @spec connection_on_packet(pid, integer, binary, term) ::
-          {:ok, non_neg_integer} | {:error, :system_error | :already_closed}
+          {:ok, non_neg_integer | :infinity} | {:error, atom}
   def connection_on_packet(_pid, _conn, _packet, _peer), do: error()
 
-  @spec connection_on_timeout(integer) ::
-          {:ok, non_neg_integer} | {:error, :system_error | :already_closed}
-  def connection_on_timeout(_conn), do: error()
+  @spec connection_on_packet_sync(pid, integer, binary, term) ::
+          {:ok, non_neg_integer | :infinity, [{term, binary}]} | {:error, atom}
+  def connection_on_packet_sync(_pid, _conn, _packet, _peer), do: error()
+
+  @spec connection_on_timeout(pid, integer) ::
+          {:ok, non_neg_integer | :infinity} | {:error, atom}
+  def connection_on_timeout(_pid, _conn), do: error()
 
   @spec connection_stream_send(integer, non_neg_integer, binary, boolean) ::
-          {:ok, non_neg_integer} | {:error, :system_error | :already_closed}
+          {:ok, non_neg_integer | :infinity} | {:continue, pos_integer} | {:error, atom}
   def connection_stream_send(_conn, _stream_id, _data, _fin), do: error()
 
+  @spec connection_stream_send_sync(integer, non_neg_integer, binary, boolean) ::
+          {:ok, non_neg_integer | :infinity, [{term, binary}]}
+          | {:continue, pos_integer, [{term, binary}]}
+          | {:error, atom, [{term, binary}]}
+          | {:error, atom}
+  def connection_stream_send_sync(_conn, _stream_id, _data, _fin), do: error()
+
+  @spec connection_stream_send_vectored(integer, non_neg_integer, [binary], boolean) ::
+          {:ok, non_neg_integer | :infinity} | {:error, atom}
+  def connection_stream_send_vectored(_conn, _stream_id, _data, _fin), do: error()
+
+  @spec connection_stream_send_full(integer, non_neg_integer, [binary], boolean) ::
+          {:ok, non_neg_integer | :infinity} | {:error, atom}
+  def connection_stream_send_full(_conn, _stream_id, _data, _fin), do: error()
+
   @spec connection_dgram_send(integer, binary) ::
-          {:ok, non_neg_integer} | {:error, :system_error | :already_closed}
+          {:ok, non_neg_integer | :infinity} | {:error, atom}
   def connection_dgram_send(_conn, _data), do: error()
 
+  @spec connection_dgram_send_batch(integer, [binary]) ::
+          {:ok, non_neg_integer, non_neg_integer | :infinity} | {:error, atom}
+  def connection_dgram_send_batch(_conn, _data), do: error()
+
+  @spec connection_h3_dgram_send(integer, non_neg_integer, binary) ::
+          {:ok, non_neg_integer | :infinity}
+          | {:error, :system_error | :already_closed | :bad_format | :not_supported}
+  def connection_h3_dgram_send(_conn, _flow_id, _data), do: error()
+
   @spec packet_builder_new() ::
           {:ok, integer} | {:error, :system_error}
   def packet_builder_new(), do: error()
@@ -157,6 +369,266 @@ defmodule Requiem.NIF.Bridge do
           {:ok, binary} | {:error, :system_error}
   def packet_builder_build_retry(_builder, _scid, _dcid, _new_scid, _token, _version), do: error()
 
+  @spec datapath_start(
+          module,
+          [integer],
+          binary,
+          integer,
+          pid,
+          {binary, binary},
+          {pos_integer, non_neg_integer, non_neg_integer, non_neg_integer, non_neg_integer,
+           non_neg_integer, non_neg_integer},
+          {pos_integer, pos_integer, boolean, boolean, non_neg_integer}
+        ) ::
+          {:ok, reference} | {:error, :bad_format | :cant_bind | :socket_error | :system_error}
+  def datapath_start(_handler, _config_ptrs, _address, _fd, _listener, _secrets, _limits, _io),
+    do: error()
+
+  @spec datapath_stop(reference) :: :ok
+  def datapath_stop(_datapath), do: error()
+
+  @spec datapath_set_owner(reference, non_neg_integer, pid) :: :ok | {:error, atom}
+  def datapath_set_owner(_datapath, _conn_id, _pid), do: error()
+
+  @spec datapath_set_subscriber(reference, non_neg_integer, atom, pid | nil) ::
+          :ok | {:error, atom}
+  def datapath_set_subscriber(_datapath, _conn_id, _class, _pid), do: error()
+
+  @spec datapath_stream_qlog(reference, non_neg_integer, pid) :: :ok | {:error, atom}
+  def datapath_stream_qlog(_datapath, _conn_id, _pid), do: error()
+
+  @spec datapath_qlog_to_file(
+          reference,
+          non_neg_integer,
+          binary,
+          pos_integer,
+          pos_integer,
+          boolean
+        ) :: :ok | {:error, atom}
+  def datapath_qlog_to_file(
+        _datapath,
+        _conn_id,
+        _dir,
+        _max_file_size,
+        _max_total_size,
+        _gzip
+      ),
+      do: error()
+
+  @spec datapath_qlog_disable(reference, non_neg_integer) :: :ok | {:error, atom}
+  def datapath_qlog_disable(_datapath, _conn_id), do: error()
+
+  @spec datapath_accept_connect_request(reference, non_neg_integer) :: :ok | {:error, atom}
+  def datapath_accept_connect_request(_datapath, _conn_id), do: error()
+
+  @spec datapath_reject_connect_request(reference, non_neg_integer, integer) ::
+          :ok | {:error, atom}
+  def datapath_reject_connect_request(_datapath, _conn_id, _code), do: error()
+
+  @spec datapath_open_stream(reference, non_neg_integer, boolean) ::
+          {:ok, non_neg_integer} | {:error, atom}
+  def datapath_open_stream(_datapath, _conn_id, _is_bidi), do: error()
+
+  @spec datapath_enable_congestion_events(reference, non_neg_integer) :: :ok | {:error, atom}
+  def datapath_enable_congestion_events(_datapath, _conn_id), do: error()
+
+  @spec datapath_enable_pmtu_events(reference, non_neg_integer) :: :ok | {:error, atom}
+  def datapath_enable_pmtu_events(_datapath, _conn_id), do: error()
+
+  @spec datapath_enable_event_timestamps(reference, non_neg_integer) :: :ok | {:error, atom}
+  def datapath_enable_event_timestamps(_datapath, _conn_id), do: error()
+
+  @spec datapath_enable_stream_close_events(reference, non_neg_integer) :: :ok | {:error, atom}
+  def datapath_enable_stream_close_events(_datapath, _conn_id), do: error()
+
+  @spec datapath_tap_pid(reference, non_neg_integer, pid) :: :ok | {:error, atom}
+  def datapath_tap_pid(_datapath, _conn_id, _pid), do: error()
+
+  @spec datapath_tap_pcap(reference, non_neg_integer, binary) :: :ok | {:error, atom}
+  def datapath_tap_pcap(_datapath, _conn_id, _dir), do: error()
+
+  @spec datapath_untap(reference, non_neg_integer) :: :ok | {:error, atom}
+  def datapath_untap(_datapath, _conn_id), do: error()
+
+  @spec datapath_enable_sampling(reference, non_neg_integer, non_neg_integer, non_neg_integer) ::
+          :ok | {:error, atom}
+  def datapath_enable_sampling(_datapath, _conn_id, _interval_ms, _capacity), do: error()
+
+  @spec datapath_samples(reference, non_neg_integer) ::
+          {:ok, [Requiem.NIF.Connection.sample_tuple()]} | {:error, atom}
+  def datapath_samples(_datapath, _conn_id), do: error()
+
+  @spec datapath_set_stream_framing(
+          reference,
+          non_neg_integer,
+          Requiem.NIF.Connection.stream_framing()
+        ) ::
+          :ok | {:error, :bad_format | atom}
+  def datapath_set_stream_framing(_datapath, _conn_id, _framing), do: error()
+
+  @spec datapath_set_impairment(
+          reference,
+          non_neg_integer,
+          Requiem.NIF.Connection.impairment() | nil
+        ) ::
+          :ok | {:error, atom}
+  def datapath_set_impairment(_datapath, _conn_id, _impairment), do: error()
+
+  @spec datapath_set_stream_recv_window(
+          reference,
+          non_neg_integer,
+          non_neg_integer,
+          non_neg_integer | nil
+        ) :: :ok | {:error, atom}
+  def datapath_set_stream_recv_window(_datapath, _conn_id, _window, _reset_code),
+    do: error()
+
+  @spec datapath_set_trace_context(reference, non_neg_integer, binary, binary, non_neg_integer) ::
+          :ok | {:error, atom}
+  def datapath_set_trace_context(_datapath, _conn_id, _trace_id, _span_id, _flags),
+    do: error()
+
+  @spec datapath_set_stream_priority(
+          reference,
+          non_neg_integer,
+          non_neg_integer,
+          0..7,
+          boolean
+        ) ::
+          :ok | {:error, atom}
+  def datapath_set_stream_priority(_datapath, _conn_id, _stream_id, _urgency, _incremental),
+    do: error()
+
+  @spec datapath_set_send_order(
+          reference,
+          non_neg_integer,
+          :dgram_first | :stream_first | {:dgram_share, 0..100}
+        ) :: :ok | {:error, atom}
+  def datapath_set_send_order(_datapath, _conn_id, _order), do: error()
+
+  @spec datapath_set_stream_pull_mode(reference, non_neg_integer, boolean) ::
+          :ok | {:error, atom}
+  def datapath_set_stream_pull_mode(_datapath, _conn_id, _enabled), do: error()
+
+  @spec datapath_stream_read(reference, non_neg_integer, non_neg_integer, non_neg_integer) ::
+          {:ok, binary} | {:error, atom}
+  def datapath_stream_read(_datapath, _conn_id, _stream_id, _max_bytes), do: error()
+
+  @spec datapath_h3_recv_body(reference, non_neg_integer, non_neg_integer, non_neg_integer) ::
+          {:ok, binary} | {:error, atom}
+  def datapath_h3_recv_body(_datapath, _conn_id, _stream_id, _max_bytes), do: error()
+
+  @spec datapath_set_send_deadline(
+          reference,
+          non_neg_integer,
+          non_neg_integer | nil,
+          {non_neg_integer, non_neg_integer} | nil
+        ) :: :ok | {:error, atom}
+  def datapath_set_send_deadline(_datapath, _conn_id, _stream_id, _deadline), do: error()
+
+  @spec datapath_set_dgram_budget(reference, non_neg_integer, pos_integer | nil) ::
+          :ok | {:error, atom}
+  def datapath_set_dgram_budget(_datapath, _conn_id, _budget), do: error()
+
+  @spec datapath_set_keepalive(reference, non_neg_integer, pos_integer | nil) ::
+          :ok | {:error, atom}
+  def datapath_set_keepalive(_datapath, _conn_id, _interval_ms), do: error()
+
+  @spec datapath_set_lifetime_policy(
+          reference,
+          non_neg_integer,
+          {pos_integer, non_neg_integer} | nil,
+          {pos_integer, non_neg_integer} | nil
+        ) :: :ok | {:error, atom}
+  def datapath_set_lifetime_policy(_datapath, _conn_id, _max_lifetime, _max_inactivity),
+    do: error()
+
+  @spec datapath_set_dgram_pull_mode(reference, non_neg_integer, boolean) ::
+          :ok | {:error, atom}
+  def datapath_set_dgram_pull_mode(_datapath, _conn_id, _enabled), do: error()
+
+  @spec datapath_dgram_peek(reference, non_neg_integer, non_neg_integer) ::
+          {:ok, binary, non_neg_integer} | {:error, atom}
+  def datapath_dgram_peek(_datapath, _conn_id, _max_len), do: error()
+
+  @spec datapath_dgram_recv(reference, non_neg_integer, boolean) :: :ok | {:error, atom}
+  def datapath_dgram_recv(_datapath, _conn_id, _deliver), do: error()
+
+  @spec datapath_probe_path(reference, non_neg_integer, non_neg_integer) :: :ok | {:error, atom}
+  def datapath_probe_path(_datapath, _conn_id, _timeout_ms), do: error()
+
+  @spec datapath_stream_ack(reference, non_neg_integer, non_neg_integer, non_neg_integer) ::
+          :ok | {:error, atom}
+  def datapath_stream_ack(_datapath, _conn_id, _stream_id, _bytes), do: error()
+
+  @spec datapath_stream_stats(reference, non_neg_integer, non_neg_integer) ::
+          {:ok, Requiem.NIF.Connection.stream_stats_tuple()} | {:error, atom}
+  def datapath_stream_stats(_datapath, _conn_id, _stream_id), do: error()
+
+  @spec datapath_stream_counts(reference, non_neg_integer) ::
+          {:ok, Requiem.NIF.Connection.stream_counts_tuple()} | {:error, atom}
+  def datapath_stream_counts(_datapath, _conn_id), do: error()
+
+  @spec datapath_pmtu(reference, non_neg_integer) :: {:ok, non_neg_integer} | {:error, atom}
+  def datapath_pmtu(_datapath, _conn_id), do: error()
+
+  @spec datapath_readable(reference, non_neg_integer) :: {:ok, [non_neg_integer]} | {:error, atom}
+  def datapath_readable(_datapath, _conn_id), do: error()
+
+  @spec datapath_writable(reference, non_neg_integer) :: {:ok, [non_neg_integer]} | {:error, atom}
+  def datapath_writable(_datapath, _conn_id), do: error()
+
+  @spec datapath_handoff_state(reference, non_neg_integer) ::
+          {:ok, Requiem.NIF.Connection.handoff_state()} | {:error, atom}
+  def datapath_handoff_state(_datapath, _conn_id), do: error()
+
+  @spec datapath_peer_cert(reference, non_neg_integer) :: {:ok, binary | nil} | {:error, atom}
+  def datapath_peer_cert(_datapath, _conn_id), do: error()
+
+  @spec datapath_stream_send(reference, non_neg_integer, non_neg_integer, binary, boolean) ::
+          {:ok, non_neg_integer} | {:continue, pos_integer} | {:error, atom}
+  def datapath_stream_send(_datapath, _conn_id, _stream_id, _data, _fin), do: error()
+
+  @spec datapath_stream_send_vectored(
+          reference,
+          non_neg_integer,
+          non_neg_integer,
+          [binary],
+          boolean
+        ) ::
+          :ok | {:error, atom}
+  def datapath_stream_send_vectored(_datapath, _conn_id, _stream_id, _data, _fin),
+    do: error()
+
+  @spec datapath_stream_send_full(
+          reference,
+          non_neg_integer,
+          non_neg_integer,
+          [binary],
+          boolean
+        ) ::
+          :ok | {:error, atom}
+  def datapath_stream_send_full(_datapath, _conn_id, _stream_id, _data, _fin),
+    do: error()
+
+  @spec datapath_dgram_send(reference, non_neg_integer, binary) :: :ok | {:error, atom}
+  def datapath_dgram_send(_datapath, _conn_id, _data), do: error()
+
+  @spec datapath_dgram_send_batch(reference, non_neg_integer, [binary]) ::
+          {:ok, non_neg_integer} | {:error, atom}
+  def datapath_dgram_send_batch(_datapath, _conn_id, _data), do: error()
+
+  @spec datapath_h3_dgram_send(reference, non_neg_integer, non_neg_integer, binary) ::
+          :ok | {:error, atom}
+  def datapath_h3_dgram_send(_datapath, _conn_id, _flow_id, _data), do: error()
+
+  @spec datapath_close(reference, non_neg_integer, boolean, non_neg_integer, binary) ::
+          :ok | {:error, atom}
+  def datapath_close(_datapath, _conn_id, _app, _err, _reason), do: error()
+
+  @spec datapath_forget(reference, non_neg_integer, non_neg_integer) :: :ok | {:error, atom}
+  def datapath_forget(_datapath, _conn_id, _err), do: error()
+
   @spec cpu_num() ::
           integer | {:error, :system_error | :not_found}
   def cpu_num(), do: error()
@@ -194,5 +666,107 @@ defmodule Requiem.NIF.Bridge do
           {:ok, term} | {:error, :bad_format}
   def socket_address_from_string(_address), do: error()
 
+  @spec memory_stats() :: [{atom, non_neg_integer}]
+  def memory_stats(), do: error()
+
+  @spec allocator_stats() ::
+          {:ok, :jemalloc | :mimalloc | :system, [{atom, non_neg_integer}]}
+          | {:error, :system_error}
+  def allocator_stats(), do: error()
+
+  @spec diagnostics_enable(boolean) :: :ok
+  def diagnostics_enable(_enabled), do: error()
+
+  @spec diagnostics() :: [{atom, non_neg_integer, non_neg_integer, [non_neg_integer]}]
+  def diagnostics(), do: error()
+
+  @spec diagnostics_watch_slow_calls(pid, pos_integer) ::
+          :ok | {:error, :bad_format | :system_error}
+  def diagnostics_watch_slow_calls(_pid, _threshold_us), do: error()
+
+  @spec diagnostics_unwatch_slow_calls() :: :ok
+  def diagnostics_unwatch_slow_calls(), do: error()
+
+  @spec logger_forward(pid, :error | :warn | :info | :debug | :trace) ::
+          :ok | {:error, :bad_format | :system_error}
+  def logger_forward(_pid, _level), do: error()
+
+  @spec logger_stop_forwarding() :: :ok
+  def logger_stop_forwarding(), do: error()
+
+  @spec telemetry_snapshot(module) :: [{atom, non_neg_integer}]
+  def telemetry_snapshot(_handler), do: error()
+
+  @spec telemetry_drops(module) :: [{atom, non_neg_integer}]
+  def telemetry_drops(_handler), do: error()
+
+  @spec module_shutdown(module, non_neg_integer, binary) :: non_neg_integer
+  def module_shutdown(_handler, _err_code, _reason), do: error()
+
+  @spec module_connections(module) :: non_neg_integer
+  def module_connections(_handler), do: error()
+
+  @spec native_versions() ::
+          {binary, binary, [non_neg_integer], [binary], [atom]}
+  def native_versions(), do: error()
+
+  @spec native_monotonic_time() :: non_neg_integer
+  def native_monotonic_time(), do: error()
+
+  @spec prometheus_render() :: binary
+  def prometheus_render(), do: error()
+
+  @spec json_render([{module, Keyword.t()}]) :: binary
+  def json_render(_configs), do: error()
+
+  @spec statsd_start(module, binary, non_neg_integer, binary, boolean) ::
+          {:ok, integer} | {:error, :bad_format | :cant_bind | :socket_error}
+  def statsd_start(_handler, _address, _interval, _prefix, _tags), do: error()
+
+  @spec statsd_stop(integer) :: :ok
+  def statsd_stop(_pusher_ptr), do: error()
+
+  @spec control_socket_open(
+          module,
+          binary,
+          binary | nil,
+          non_neg_integer,
+          non_neg_integer,
+          boolean
+        ) ::
+          {:ok, integer} | {:error, :cant_bind}
+  def control_socket_open(_handler, _path, _qlog_dir, _max_file_size, _max_total_size, _gzip),
+    do: error()
+
+  @spec control_socket_close(integer) :: :ok
+  def control_socket_close(_control_ptr), do: error()
+
+  @spec loadgen_run(
+          binary,
+          binary,
+          binary,
+          non_neg_integer,
+          non_neg_integer,
+          non_neg_integer,
+          non_neg_integer,
+          non_neg_integer
+        ) :: {:ok, tuple} | {:error, :not_supported | atom}
+  def loadgen_run(
+        _address,
+        _server_name,
+        _path,
+        _connections,
+        _streams,
+        _message_size,
+        _dgram_rate,
+        _duration
+      ),
+      do: error()
+
+  # {:error, :not_supported} unless the NIF is built with the fuzzing feature
+  @spec fuzz_input(integer, :header | :token | :recv, binary) ::
+          :ok | {:error, :bad_format | :not_supported}
+  def fuzz_input(_config_ptr, _target, _data), do: error()
+
   defp error(), do: :erlang.nif_error(:nif_not_loaded)
 end
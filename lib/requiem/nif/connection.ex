@@ -1,67 +1,968 @@
 defmodule Requiem.NIF.Connection do
   alias Requiem.NIF.Bridge
+  alias Requiem.NIF.Datapath
 
-  @spec accept(integer, binary, binary, term, pid, non_neg_integer) ::
-          {:ok, term} | {:error, :system_error | :not_found}
-  def accept(config_ptr, scid, odcid, peer, sender_pid, stream_buf_size) do
-    Bridge.connection_accept(config_ptr, scid, odcid, peer, sender_pid, stream_buf_size)
+  # connections owned by the native datapath are driven by the native thread,
+  # so they never need a timer on the elixir side.
+  @type t :: integer | {:native, reference, non_neg_integer}
+
+  # quiche::Error, returned as the reason of the failed calls
+  @type quic_error ::
+          :done
+          | :buffer_too_short
+          | :unknown_version
+          | :invalid_frame
+          | :invalid_packet
+          | :invalid_state
+          | :invalid_stream_state
+          | :invalid_transport_param
+          | :crypto_fail
+          | :tls_fail
+          | :flow_control
+          | :stream_limit
+          | :stream_stopped
+          | :stream_reset
+          | :final_size
+          | :congestion_control
+          | :transport_error
+
+  @type stream_stats_tuple ::
+          {non_neg_integer, non_neg_integer, boolean, boolean, non_neg_integer | nil,
+           non_neg_integer | nil}
+
+  @type stream_stats :: %{
+          bytes_sent: non_neg_integer,
+          bytes_recv: non_neg_integer,
+          fin_sent: boolean,
+          fin_recv: boolean,
+          reset_code: non_neg_integer | nil,
+          stop_code: non_neg_integer | nil
+        }
+
+  @type stream_counts_tuple ::
+          {{non_neg_integer, non_neg_integer}, {non_neg_integer, non_neg_integer},
+           {non_neg_integer, non_neg_integer}}
+
+  @type stream_counts :: %{
+          local_bidi: non_neg_integer,
+          local_uni: non_neg_integer,
+          remote_bidi: non_neg_integer,
+          remote_uni: non_neg_integer,
+          peer_bidi_left: non_neg_integer,
+          peer_uni_left: non_neg_integer
+        }
+
+  @type trace_context :: {trace_id :: <<_::128>>, span_id :: <<_::64>>, flags :: byte}
+
+  @type stream_framing ::
+          :raw | :length_prefix | {:length_prefix, 1 | 2 | 4 | 8} | :varint_prefix | :newline
+
+  @type impairment ::
+          {loss :: non_neg_integer, duplicate :: non_neg_integer, reorder :: non_neg_integer,
+           delay_ms :: non_neg_integer, seed :: non_neg_integer}
+
+  @type close_info ::
+          {:local | :remote, :transport | :application, non_neg_integer, binary}
+
+  @type handoff_state :: %{
+          trace_id: binary,
+          source_id: binary,
+          destination_id: binary,
+          peer: binary,
+          alpn: binary,
+          server_name: binary | nil,
+          session_id: non_neg_integer | nil,
+          traceparent: binary | nil,
+          established: boolean
+        }
+
+  @type sample_tuple :: {non_neg_integer, non_neg_integer, non_neg_integer, non_neg_integer}
+
+  @type sample :: %{
+          timestamp: non_neg_integer,
+          rtt: non_neg_integer,
+          cwnd: non_neg_integer,
+          delivery_rate: non_neg_integer
+        }
+
+  @spec accept(module, integer, binary, binary, term, pid, non_neg_integer) ::
+          {:ok, term} | {:error, quic_error | :not_found}
+  def accept(handler, config_ptr, scid, odcid, peer, sender_pid, stream_buf_size) do
+    Bridge.connection_accept(handler, config_ptr, scid, odcid, peer, sender_pid, stream_buf_size)
+  end
+
+  @doc """
+  Counts the Initial of a new connection which an overloaded server drops, rather than
+  accepting the connection only to close it. Nothing is sent to the client, quiche can't
+  build a CONNECTION_CLOSE without the state of a connection, so the client times out.
+  """
+  @spec count_refused(module) :: :ok
+  def count_refused(handler) do
+    Bridge.connection_count_refused(handler)
+  end
+
+  @doc """
+  Sends the events of the connection, `{:__stream_recv__, ...}` and the others, to `pid`
+  from now on, whichever process makes the calls, so that an acceptor can hand the
+  connection over to a dedicated handler process. The packets still go to the sender.
+  A connection of the BEAM path accepted by `Requiem.Connection` is still freed once that
+  process is down, handing it over doesn't keep it alive.
+  """
+  @spec set_owner(t, pid) :: :ok | {:error, atom}
+  def set_owner({:native, datapath, conn_id}, pid) do
+    Datapath.set_owner(datapath, conn_id, pid)
+  end
+
+  def set_owner(conn, pid) do
+    Bridge.connection_set_owner(conn, pid)
+  end
+
+  @doc """
+  Sends a class of the events of the connection to `pid` rather than to its owner, `nil`
+  giving them back: `:packets` the `{:__drain__, peer, packet}` passed to the sender,
+  `:streams` the data, ends, resets and closes of the streams, `:dgrams` the received
+  datagrams and `:lifecycle` the handshake and close transitions. The packets of the
+  native datapath go to its socket, `:packets` isn't supported there.
+  """
+  @spec set_subscriber(t, :packets | :streams | :dgrams | :lifecycle, pid | nil) ::
+          :ok | {:error, :bad_format | :not_supported | atom}
+  def set_subscriber({:native, datapath, conn_id}, class, pid) do
+    Datapath.set_subscriber(datapath, conn_id, class, pid)
+  end
+
+  def set_subscriber(conn, class, pid) do
+    Bridge.connection_set_subscriber(conn, class, pid)
+  end
+
+  @doc """
+  Sends the qlog events of the connection to `pid` as `{:qlog, trace_id, record}`,
+  each record being a JSON text. Needs the NIF built with the `qlog` feature.
+  """
+  @spec stream_qlog(t, pid) :: :ok | {:error, :already_exists | :not_supported | atom}
+  def stream_qlog({:native, datapath, conn_id}, pid) do
+    Datapath.stream_qlog(datapath, conn_id, pid)
+  end
+
+  def stream_qlog(conn, pid) do
+    Bridge.connection_stream_qlog(conn, pid)
+  end
+
+  @doc """
+  Writes the qlog events of the connection to `<trace_id>.<index>.sqlog` files in `dir`,
+  gzipped with a `.gz` suffix when `gzip` is true. A file is rotated after `max_file_size`
+  bytes, and the oldest rotated files in `dir` are removed beyond `max_total_size` bytes.
+  Needs the NIF built with the `qlog` feature.
+  """
+  @spec qlog_to_file(t, binary, pos_integer, pos_integer, boolean) ::
+          :ok | {:error, :system_error | :not_supported | atom}
+  def qlog_to_file({:native, datapath, conn_id}, dir, max_file_size, max_total_size, gzip) do
+    Datapath.qlog_to_file(datapath, conn_id, dir, max_file_size, max_total_size, gzip)
+  end
+
+  def qlog_to_file(conn, dir, max_file_size, max_total_size, gzip) do
+    Bridge.connection_qlog_to_file(conn, dir, max_file_size, max_total_size, gzip)
+  end
+
+  @doc """
+  Turns qlog on for this connection alone, while it's running: into `pid` as with
+  `stream_qlog/2`, or into files in the directory as with `qlog_to_file/5`, the
+  options being `:max_file_size`, `:max_total_size` and `:gzip`.
+  """
+  @spec qlog_enable(t, pid | binary, Keyword.t()) :: :ok | {:error, atom}
+  def qlog_enable(conn, pid_or_dir, opts \\ [])
+
+  def qlog_enable(conn, pid, _opts) when is_pid(pid) do
+    stream_qlog(conn, pid)
+  end
+
+  def qlog_enable(conn, dir, opts) when is_binary(dir) do
+    qlog_to_file(
+      conn,
+      dir,
+      Keyword.get(opts, :max_file_size, 10_000_000),
+      Keyword.get(opts, :max_total_size, 1_000_000_000),
+      Keyword.get(opts, :gzip, false)
+    )
+  end
+
+  @doc """
+  Turns qlog off for the connection, closing its files. It can be turned on again,
+  the new files and subscribers getting the qlog header first.
+  """
+  @spec qlog_disable(t) :: :ok | {:error, :not_supported | atom}
+  def qlog_disable({:native, datapath, conn_id}) do
+    Datapath.qlog_disable(datapath, conn_id)
+  end
+
+  def qlog_disable(conn) do
+    Bridge.connection_qlog_disable(conn)
+  end
+
+  @spec accept_connect_request(t) ::
+          {:ok, non_neg_integer | :infinity} | {:error, atom}
+  def accept_connect_request({:native, datapath, conn_id}) do
+    with :ok <- Datapath.accept_connect_request(datapath, conn_id), do: {:ok, :infinity}
   end
 
-  @spec accept_connect_request(integer) ::
-          {:ok, non_neg_integer} | {:error, :system_error | :already_closed}
   def accept_connect_request(conn) do
     Bridge.connection_accept_connect_request(conn)
   end
 
-  @spec reject_connect_request(integer, integer) ::
-          {:ok, non_neg_integer} | {:error, :system_error | :already_closed}
+  @spec reject_connect_request(t, integer) ::
+          {:ok, non_neg_integer | :infinity} | {:error, atom}
+  def reject_connect_request({:native, datapath, conn_id}, code) do
+    with :ok <- Datapath.reject_connect_request(datapath, conn_id, code), do: {:ok, :infinity}
+  end
+
   def reject_connect_request(conn, code) do
     Bridge.connection_reject_connect_request(conn, code)
   end
 
-  @spec destroy(integer) ::
+  @doc """
+  Frees the connection. Unless it's closed already, it's closed first with
+  the application error `err`, so that the peer doesn't wait for the idle timeout.
+  """
+  @spec destroy(t, non_neg_integer) ::
           :ok | {:error, :system_error | :already_closed}
-  def destroy(conn) do
+  def destroy({:native, datapath, conn_id}, err) do
+    Datapath.forget(datapath, conn_id, err)
+  end
+
+  def destroy(conn, err) do
+    if not is_closed?(conn) do
+      Bridge.connection_close(conn, true, err, "")
+    end
+
     Bridge.connection_destroy(conn)
   end
 
-  @spec close(integer, boolean, non_neg_integer, binary) ::
-          {:ok, non_neg_integer} | {:error, :system_error | :already_closed}
+  @spec close(t, boolean, non_neg_integer, binary) ::
+          {:ok, non_neg_integer | :infinity}
+          | {:error, quic_error | :already_closed | :system_error}
+  def close({:native, datapath, conn_id}, app, err, reason) do
+    with :ok <- Datapath.close(datapath, conn_id, app, err, reason), do: {:ok, :infinity}
+  end
+
   def close(conn, app, err, reason) do
     Bridge.connection_close(conn, app, err, reason)
   end
 
+  @doc """
+  Makes the connection send `{:__congestion__, state, cwnd}` to its owner when the
+  state of the congestion controller changes, `state` being one of `:slow_start`,
+  `:congestion_avoidance`, `:recovery` and `:persistent_congestion`.
+
+  quiche doesn't expose that state, it's inferred from the lost packets and the window.
+  """
+  @spec enable_congestion_events(t) :: :ok | {:error, atom}
+  def enable_congestion_events({:native, datapath, conn_id}) do
+    Datapath.enable_congestion_events(datapath, conn_id)
+  end
+
+  def enable_congestion_events(conn) do
+    Bridge.connection_enable_congestion_events(conn)
+  end
+
+  @doc """
+  Makes the connection send `{:__pmtu__, pmtu}` to its owner when the path MTU
+  changes, the first time with the PMTU the path has.
+
+  The PMTU can't grow beyond `max_udp_payload_size`, which has to be raised for
+  quiche to use jumbo frames.
+  """
+  @spec enable_pmtu_events(t) :: :ok | {:error, atom}
+  def enable_pmtu_events({:native, datapath, conn_id}) do
+    Datapath.enable_pmtu_events(datapath, conn_id)
+  end
+
+  def enable_pmtu_events(conn) do
+    Bridge.connection_enable_pmtu_events(conn)
+  end
+
+  @doc """
+  Makes the connection wrap the events of its streams, datagrams and lifecycle as
+  `{:__at__, emitted_at, received_at, event}`, `received_at` being when the packet
+  which caused the event was received, nil for the events of the timers. Both are in
+  microseconds of a monotonic clock of the NIF, `Requiem.NIF.Bridge.native_monotonic_time/0`.
+  """
+  @spec enable_event_timestamps(t) :: :ok | {:error, atom}
+  def enable_event_timestamps({:native, datapath, conn_id}) do
+    Datapath.enable_event_timestamps(datapath, conn_id)
+  end
+
+  def enable_event_timestamps(conn) do
+    Bridge.connection_enable_event_timestamps(conn)
+  end
+
+  @doc """
+  Returns the path MTU of the connection, 0 until quiche has one.
+  """
+  @spec pmtu(t) :: {:ok, non_neg_integer} | {:error, atom}
+  def pmtu({:native, datapath, conn_id}), do: Datapath.pmtu(datapath, conn_id)
+  def pmtu(conn), do: Bridge.connection_pmtu(conn)
+
+  @doc """
+  Makes the connection send `{:__stream_closed__, stream_id}` to its owner once quiche
+  has collected a stream, both of its sides being done with, so that what the owner
+  keeps for the stream can be dropped. Only the streams seen from then on are reported.
+  """
+  @spec enable_stream_close_events(t) :: :ok | {:error, atom}
+  def enable_stream_close_events({:native, datapath, conn_id}) do
+    Datapath.enable_stream_close_events(datapath, conn_id)
+  end
+
+  def enable_stream_close_events(conn) do
+    Bridge.connection_enable_stream_close_events(conn)
+  end
+
+  @doc """
+  Copies the datagrams of the connection, still encrypted, for debugging.
+
+  - `{:pid, pid}` sends each of them to `pid` as
+    `{:tap, :inbound | :outbound, timestamp_us, "address:port", datagram}`
+  - `{:pcap, dir}` writes them to `<dir>/<trace_id>.pcap`, with IP and UDP
+    headers made up around them, the local address being `0.0.0.0:0` or `[::]:0`
+  - `nil` stops copying
+  """
+  @spec tap(t, {:pid, pid} | {:pcap, binary} | nil) :: :ok | {:error, atom}
+  def tap({:native, datapath, conn_id}, target) do
+    Datapath.tap(datapath, conn_id, target)
+  end
+
+  def tap(conn, {:pid, pid}), do: Bridge.connection_tap_pid(conn, pid)
+  def tap(conn, {:pcap, dir}), do: Bridge.connection_tap_pcap(conn, dir)
+  def tap(conn, nil), do: Bridge.connection_untap(conn)
+
+  @doc """
+  Attaches the W3C trace context of a span to the connection. Set before qlog is
+  enabled, its traceparent is written in the description of the qlog trace.
+  """
+  @spec set_trace_context(t, trace_context) :: :ok | {:error, atom}
+  def set_trace_context({:native, datapath, conn_id}, {trace_id, span_id, flags}) do
+    Datapath.set_trace_context(datapath, conn_id, trace_id, span_id, flags)
+  end
+
+  def set_trace_context(conn, {trace_id, span_id, flags}) do
+    Bridge.connection_set_trace_context(conn, trace_id, span_id, flags)
+  end
+
+  @doc """
+  Passes the data of the streams as whole messages instead of the chunks read, and
+  frames the chunks of each `stream_send_vectored/4` as a message:
+  `:length_prefix` for a 2-byte big-endian length before each message, as DNS over
+  QUIC, `{:length_prefix, width}` for a length of 1, 2, 4 or 8 bytes, `:varint_prefix`
+  for a QUIC variable-length integer and `:newline` for an LF after each message.
+  The framing isn't passed. A stream announcing a message over 16MB is no longer read,
+  and a message which the framing can't carry isn't sent.
+  """
+  @spec set_stream_framing(t, stream_framing) :: :ok | {:error, :bad_format | atom}
+  def set_stream_framing({:native, datapath, conn_id}, framing) do
+    Datapath.set_stream_framing(datapath, conn_id, framing)
+  end
+
+  def set_stream_framing(conn, framing) do
+    Bridge.connection_set_stream_framing(conn, framing)
+  end
+
+  @doc """
+  Simulates a bad network between the connection and its socket, for tests: each
+  packet, in both directions, is lost, duplicated or held 10ms longer than the others
+  with the given probabilities in parts per million, and delayed by `delay_ms`.
+  The same seed makes the same decisions. `nil` lets the packets through again.
+  """
+  @spec set_impairment(t, impairment | nil) :: :ok | {:error, atom}
+  def set_impairment({:native, datapath, conn_id}, impairment) do
+    Datapath.set_impairment(datapath, conn_id, impairment)
+  end
+
+  def set_impairment(conn, impairment) do
+    Bridge.connection_set_impairment(conn, impairment)
+  end
+
+  @doc """
+  Makes the delivery of stream data credit-based: once `window` bytes of a stream are
+  passed to the owner, the rest stays in the connection, and the flow control of the
+  stream holds the peer, until they're acknowledged with `stream_ack/3`.
+  With a `reset_code`, a stream whose peer sends more meanwhile is stopped with it
+  (STOP_SENDING) instead.
+  """
+  @spec set_stream_recv_window(t, non_neg_integer, non_neg_integer | nil) ::
+          :ok | {:error, atom}
+  def set_stream_recv_window(conn, window, reset_code \\ nil)
+
+  def set_stream_recv_window({:native, datapath, conn_id}, window, reset_code) do
+    Datapath.set_stream_recv_window(datapath, conn_id, window, reset_code)
+  end
+
+  def set_stream_recv_window(conn, window, reset_code) do
+    Bridge.connection_set_stream_recv_window(conn, window, reset_code)
+  end
+
+  @doc """
+  Passes at most `budget` datagrams to the owner per packet received, nil for all of
+  them. The others wait in the queue of quiche for the next packet, which drops the
+  oldest of them once it's full.
+  """
+  @spec set_dgram_budget(t, pos_integer | nil) :: :ok | {:error, atom}
+  def set_dgram_budget({:native, datapath, conn_id}, budget) do
+    Datapath.set_dgram_budget(datapath, conn_id, budget)
+  end
+
+  def set_dgram_budget(conn, budget) do
+    Bridge.connection_set_dgram_budget(conn, budget)
+  end
+
+  @doc """
+  Sends an ack-eliciting PATH_CHALLENGE once the connection has sent nothing for
+  `interval_ms`, so that the NAT bindings and the idle timeout of the peer don't
+  expire, nil stopping them. They are scheduled with the timeouts of the connection,
+  and an interval of 0 fails with `:bad_format`.
+  """
+  @spec set_keepalive(t, pos_integer | nil) :: :ok | {:error, atom}
+  def set_keepalive({:native, datapath, conn_id}, interval_ms) do
+    Datapath.set_keepalive(datapath, conn_id, interval_ms)
+  end
+
+  def set_keepalive(conn, interval_ms) do
+    Bridge.connection_set_keepalive(conn, interval_ms)
+  end
+
+  @doc """
+  Closes the connection with an application error once it has lived `max_lifetime`,
+  or has gone `max_inactivity` without stream data nor datagrams either way, each being
+  `{milliseconds, app_error_code}` or nil. The limits are counted from this call, and
+  enforced with the timeouts of the connection.
+  """
+  @spec set_lifetime_policy(
+          t,
+          {pos_integer, non_neg_integer} | nil,
+          {pos_integer, non_neg_integer} | nil
+        ) :: :ok | {:error, atom}
+  def set_lifetime_policy({:native, datapath, conn_id}, max_lifetime, max_inactivity) do
+    Datapath.set_lifetime_policy(datapath, conn_id, max_lifetime, max_inactivity)
+  end
+
+  def set_lifetime_policy(conn, max_lifetime, max_inactivity) do
+    Bridge.connection_set_lifetime_policy(conn, max_lifetime, max_inactivity)
+  end
+
+  @doc """
+  Leaves the received datagrams in the connection, which sends `{:__dgram_readable__}`
+  to the owner once, until it has taken all of them with `dgram_recv/2`.
+  """
+  @spec set_dgram_pull_mode(t, boolean) :: :ok | {:error, atom}
+  def set_dgram_pull_mode({:native, datapath, conn_id}, enabled) do
+    Datapath.set_dgram_pull_mode(datapath, conn_id, enabled)
+  end
+
+  def set_dgram_pull_mode(conn, enabled) do
+    Bridge.connection_set_dgram_pull_mode(conn, enabled)
+  end
+
+  @doc """
+  Returns up to `max_len` bytes of the next datagram in pull mode, without taking it,
+  and its whole length. The bytes are those received, the quarter stream id of the
+  session or the request before the payload.
+  """
+  @spec dgram_peek(t, non_neg_integer) ::
+          {:ok, binary, non_neg_integer} | {:error, :not_found | atom}
+  def dgram_peek({:native, datapath, conn_id}, max_len) do
+    Datapath.dgram_peek(datapath, conn_id, max_len)
+  end
+
+  def dgram_peek(conn, max_len) do
+    Bridge.connection_dgram_peek(conn, max_len)
+  end
+
+  @doc """
+  Takes the next datagram in pull mode. With `deliver`, it's passed to the owner as
+  the datagrams are without pull mode, otherwise it's dropped without being copied.
+  """
+  @spec dgram_recv(t, boolean) ::
+          {:ok, non_neg_integer | :infinity} | {:error, :not_found | atom}
+  def dgram_recv({:native, datapath, conn_id}, deliver) do
+    with :ok <- Datapath.dgram_recv(datapath, conn_id, deliver), do: {:ok, :infinity}
+  end
+
+  def dgram_recv(conn, deliver) do
+    Bridge.connection_dgram_recv(self(), conn, deliver)
+  end
+
+  @doc """
+  Sends a PATH_CHALLENGE on the path of the peer, the owner getting
+  `{:__path_validated__, peer}` when its PATH_RESPONSE comes back within `timeout_ms`,
+  `{:__path_failed__, peer}` otherwise. One probe at a time.
+  """
+  @spec probe_path(t, non_neg_integer) ::
+          {:ok, non_neg_integer | :infinity} | {:error, :already_exists | atom}
+  def probe_path({:native, datapath, conn_id}, timeout_ms) do
+    with :ok <- Datapath.probe_path(datapath, conn_id, timeout_ms), do: {:ok, :infinity}
+  end
+
+  def probe_path(conn, timeout_ms) do
+    Bridge.connection_probe_path(conn, timeout_ms)
+  end
+
+  @doc """
+  Sets the priority of a stream (RFC 9218): `urgency` from 0, the most urgent, to 7,
+  3 by default, and whether its data may be interleaved with that of the streams of
+  the same urgency. quiche schedules the frames of the streams with it, and the data
+  which the connection holds or queues is written to quiche the most urgent first.
+  """
+  @spec set_stream_priority(t, non_neg_integer, 0..7, boolean) :: :ok | {:error, atom}
+  def set_stream_priority({:native, datapath, conn_id}, stream_id, urgency, incremental) do
+    Datapath.set_stream_priority(datapath, conn_id, stream_id, urgency, incremental)
+  end
+
+  def set_stream_priority(conn, stream_id, urgency, incremental) do
+    Bridge.connection_set_stream_priority(conn, stream_id, urgency, incremental)
+  end
+
+  @doc """
+  Which of the DATAGRAMs and the stream data sent at the same time goes out first.
+  `:dgram_first`, the default of quiche, puts the DATAGRAMs first in the packets,
+  `:stream_first` sends them in what the stream data leaves of the congestion window,
+  `{:dgram_share, percent}` sends up to that percent of the window of DATAGRAMs before
+  the stream data and the rest of them after it.
+  """
+  @spec set_send_order(t, :dgram_first | :stream_first | {:dgram_share, 0..100}) ::
+          :ok | {:error, atom}
+  def set_send_order({:native, datapath, conn_id}, order) do
+    Datapath.set_send_order(datapath, conn_id, order)
+  end
+
+  def set_send_order(conn, order) do
+    Bridge.connection_set_send_order(conn, order)
+  end
+
+  @doc """
+  Leaves the data of the streams in the connection, which sends
+  `{:__stream_readable__, stream_id}` to the owner once, until it reads the stream
+  with `stream_read/3`. The framing and the receive window don't apply to it.
+  """
+  @spec set_stream_pull_mode(t, boolean) :: :ok | {:error, atom}
+  def set_stream_pull_mode({:native, datapath, conn_id}, enabled) do
+    Datapath.set_stream_pull_mode(datapath, conn_id, enabled)
+  end
+
+  def set_stream_pull_mode(conn, enabled) do
+    Bridge.connection_set_stream_pull_mode(conn, enabled)
+  end
+
+  @doc """
+  Reads up to `max_bytes`, at most 16 MiB, of a stream in pull mode. Less than that
+  means that the stream has nothing more until the next
+  `{:__stream_readable__, stream_id}`. A stream which fails to be read returns
+  `{:error, reason}` once the data read before the failure has been returned.
+  """
+  @spec stream_read(t, non_neg_integer, non_neg_integer) ::
+          {:ok, binary, non_neg_integer | :infinity} | {:error, :bad_state | atom}
+  def stream_read({:native, datapath, conn_id}, stream_id, max_bytes) do
+    with {:ok, data} <- Datapath.stream_read(datapath, conn_id, stream_id, max_bytes),
+         do: {:ok, data, :infinity}
+  end
+
+  def stream_read(conn, stream_id, max_bytes) do
+    Bridge.connection_stream_read(self(), conn, stream_id, max_bytes)
+  end
+
+  @doc """
+  Reads up to `max_bytes`, at most 16 MiB, of the body of an HTTP/3 request which
+  isn't WebTransport's, after `{:__h3_data__, stream_id}`. As much as `max_bytes`
+  means that there may be more, which no other `{:__h3_data__, stream_id}` tells of.
+  """
+  @spec h3_recv_body(t, non_neg_integer, non_neg_integer) ::
+          {:ok, binary, non_neg_integer | :infinity} | {:error, atom}
+  def h3_recv_body({:native, datapath, conn_id}, stream_id, max_bytes) do
+    with {:ok, data} <- Datapath.h3_recv_body(datapath, conn_id, stream_id, max_bytes),
+         do: {:ok, data, :infinity}
+  end
+
+  def h3_recv_body(conn, stream_id, max_bytes) do
+    Bridge.connection_h3_recv_body(self(), conn, stream_id, max_bytes)
+  end
+
+  @doc """
+  Gives the data which the connection holds for a stream, when the flow control
+  doesn't let it out, `timeout_ms` to leave. Past that, the data is dropped, the
+  stream is reset with `code`, and the owner gets
+  `{:__stream_expired__, stream_id, code}`. A nil `stream_id` sets the deadline of
+  all the streams, a stream's own deadline takes over from it, and a nil deadline
+  removes it.
+  """
+  @spec set_send_deadline(
+          t,
+          non_neg_integer | nil,
+          {non_neg_integer, non_neg_integer} | nil
+        ) :: :ok | {:error, atom}
+  def set_send_deadline({:native, datapath, conn_id}, stream_id, deadline) do
+    Datapath.set_send_deadline(datapath, conn_id, stream_id, deadline)
+  end
+
+  def set_send_deadline(conn, stream_id, deadline) do
+    Bridge.connection_set_send_deadline(conn, stream_id, deadline)
+  end
+
+  @doc """
+  Gives back the credit of `bytes` of a stream, which the owner has handled.
+  The data held for the stream is passed again to the calling process.
+  """
+  @spec stream_ack(t, non_neg_integer, non_neg_integer) ::
+          {:ok, non_neg_integer | :infinity} | {:error, atom}
+  def stream_ack({:native, datapath, conn_id}, stream_id, bytes) do
+    with :ok <- Datapath.stream_ack(datapath, conn_id, stream_id, bytes),
+         do: {:ok, :infinity}
+  end
+
+  def stream_ack(conn, stream_id, bytes) do
+    Bridge.connection_stream_ack(self(), conn, stream_id, bytes)
+  end
+
+  @doc """
+  Makes the connection keep the last `capacity` samples of its path, taken at most
+  once every `interval_ms` when it sends or receives packets, so that they can be
+  looked at after a latency spike with `samples/1`.
+  """
+  @spec enable_sampling(t, non_neg_integer, non_neg_integer) :: :ok | {:error, atom}
+  def enable_sampling({:native, datapath, conn_id}, interval_ms, capacity) do
+    Datapath.enable_sampling(datapath, conn_id, interval_ms, capacity)
+  end
+
+  def enable_sampling(conn, interval_ms, capacity) do
+    Bridge.connection_enable_sampling(conn, interval_ms, capacity)
+  end
+
+  @doc """
+  Returns the samples kept since `enable_sampling/3`, oldest first. `timestamp` is in
+  milliseconds since the epoch, `rtt` in microseconds, `cwnd` in bytes and
+  `delivery_rate` in bytes per second.
+
+  quiche doesn't expose the bytes in flight, so they aren't sampled.
+  """
+  @spec samples(t) :: {:ok, [sample]} | {:error, atom}
+  def samples({:native, datapath, conn_id}) do
+    with {:ok, samples} <- Datapath.samples(datapath, conn_id),
+         do: {:ok, Enum.map(samples, &sample_map/1)}
+  end
+
+  def samples(conn) do
+    with {:ok, samples} <- Bridge.connection_samples(conn),
+         do: {:ok, Enum.map(samples, &sample_map/1)}
+  end
+
+  defp sample_map({timestamp, rtt, cwnd, delivery_rate}) do
+    %{timestamp: timestamp, rtt: rtt, cwnd: cwnd, delivery_rate: delivery_rate}
+  end
+
+  @doc """
+  Returns the bytes sent and received on a stream, whether FIN has been sent and
+  received, and the application error codes of RESET_STREAM and STOP_SENDING
+  received from the peer. Completed streams are forgotten beyond the last 1024.
+  """
+  @spec stream_stats(t, non_neg_integer) :: {:ok, stream_stats} | {:error, :not_found | atom}
+  def stream_stats({:native, datapath, conn_id}, stream_id) do
+    with {:ok, stats} <- Datapath.stream_stats(datapath, conn_id, stream_id),
+         do: {:ok, stream_stats_map(stats)}
+  end
+
+  def stream_stats(conn, stream_id) do
+    with {:ok, stats} <- Bridge.connection_stream_stats(conn, stream_id),
+         do: {:ok, stream_stats_map(stats)}
+  end
+
+  @doc """
+  Returns the open streams of the session, opened by the server (`local_*`) and by
+  the client (`remote_*`), and how many more streams the client lets the server open
+  (`peer_*_left`). The client may open as many streams as `initial_max_streams_bidi`
+  and `initial_max_streams_uni` at once.
+  """
+  @spec stream_counts(t) :: {:ok, stream_counts} | {:error, atom}
+  def stream_counts({:native, datapath, conn_id}) do
+    with {:ok, counts} <- Datapath.stream_counts(datapath, conn_id),
+         do: {:ok, stream_counts_map(counts)}
+  end
+
+  def stream_counts(conn) do
+    with {:ok, counts} <- Bridge.connection_stream_counts(conn),
+         do: {:ok, stream_counts_map(counts)}
+  end
+
+  defp stream_counts_map(
+         {{local_bidi, local_uni}, {remote_bidi, remote_uni}, {peer_bidi_left, peer_uni_left}}
+       ) do
+    %{
+      local_bidi: local_bidi,
+      local_uni: local_uni,
+      remote_bidi: remote_bidi,
+      remote_uni: remote_uni,
+      peer_bidi_left: peer_bidi_left,
+      peer_uni_left: peer_uni_left
+    }
+  end
+
+  @doc """
+  Returns the streams with data which the connection hasn't read yet. The data of
+  the streams is read as their packets come, so these are the streams left unread
+  by the pull mode or the receive window, to read with `stream_read/3`.
+  """
+  @spec readable(t) :: {:ok, [non_neg_integer]} | {:error, atom}
+  def readable({:native, datapath, conn_id}), do: Datapath.readable(datapath, conn_id)
+  def readable(conn), do: Bridge.connection_readable(conn)
+
+  @doc """
+  Returns the streams whose flow control has room for more data, leaving out those
+  for which the connection holds data already.
+  """
+  @spec writable(t) :: {:ok, [non_neg_integer]} | {:error, atom}
+  def writable({:native, datapath, conn_id}), do: Datapath.writable(datapath, conn_id)
+  def writable(conn), do: Bridge.connection_writable(conn)
+
+  defp stream_stats_map({bytes_sent, bytes_recv, fin_sent, fin_recv, reset_code, stop_code}) do
+    %{
+      bytes_sent: bytes_sent,
+      bytes_recv: bytes_recv,
+      fin_sent: fin_sent,
+      fin_recv: fin_recv,
+      reset_code: reset_code,
+      stop_code: stop_code
+    }
+  end
+
+  @doc """
+  Exports what another node needs to take the client of the connection over, its
+  connection IDs, peer, ALPN, server name, WebTransport session and trace context,
+  as `:erlang.term_to_binary/1` of a `t:handoff_state/0`.
+
+  QUIC has no way for a server to move a connection once it's established, the client
+  comes back to the other node with a new handshake, see `handoff/2` of the handler.
+  """
+  @spec handoff_state(t) :: {:ok, binary} | {:error, atom}
+  def handoff_state({:native, datapath, conn_id}) do
+    with {:ok, state} <- Datapath.handoff_state(datapath, conn_id),
+         do: {:ok, :erlang.term_to_binary(state)}
+  end
+
+  def handoff_state(conn) do
+    with {:ok, state} <- Bridge.connection_handoff_state(conn),
+         do: {:ok, :erlang.term_to_binary(state)}
+  end
+
+  @doc """
+  Returns the DER certificate the client presented, nil when it didn't present one.
+
+  The server only asks the client for a certificate with `verify_peer`, and BoringSSL
+  fails the handshake when the certificate doesn't chain to `verify_locations_file` or
+  `verify_locations_directory`, so that it's only there on established connections.
+
+  This only partly gives the application the decision on the certificate: quiche 0.12
+  has no callback into the TLS handshake, so the handler sees the certificate once the
+  handshake has completed, and can only close the connection then, not fail the
+  handshake.
+  """
+  @spec peer_cert(t) :: {:ok, binary | nil} | {:error, atom}
+  def peer_cert({:native, datapath, conn_id}), do: Datapath.peer_cert(datapath, conn_id)
+  def peer_cert(conn), do: Bridge.connection_peer_cert(conn)
+
   @spec is_closed?(integer) :: boolean
   def is_closed?(conn) do
     Bridge.connection_is_closed(conn)
   end
 
-  @spec dgram_send(integer, binary) ::
-          {:ok, non_neg_integer} | {:error, :system_error | :already_closed}
+  @doc """
+  Returns who closed the connection, whether with a transport or application error,
+  its code and its reason, or nil while the connection is open or when quiche has
+  closed it by itself, e.g. on the idle timeout.
+
+  The connections of the native datapath are dropped once closed, the same info is
+  passed with their closed event, see `Requiem.ConnectionState`.
+  """
+  @spec close_info(integer) :: close_info | nil
+  def close_info(conn) do
+    Bridge.connection_close_info(conn)
+  end
+
+  @spec set_request_limits(integer, non_neg_integer, non_neg_integer, non_neg_integer) :: :ok
+  def set_request_limits(conn, max_field_section_size, max_requests, max_header_count) do
+    Bridge.connection_set_request_limits(
+      conn,
+      max_field_section_size,
+      max_requests,
+      max_header_count
+    )
+  end
+
+  @doc """
+  Sets the size of the buffer each stream read is made into, up to 16 MiB, a larger
+  size failing with `:bad_format`.
+  """
+  @spec stream_buffer_resize(integer, pos_integer) :: :ok | {:error, :bad_format}
+  def stream_buffer_resize(conn, size) do
+    Bridge.connection_stream_buffer_resize(conn, size)
+  end
+
+  @doc """
+  Lets the stream buffer size follow the amount of data read per stream event,
+  between `min` and `max`. Either of them being 0 clears the range set before, keeping
+  the size where it is, and `max` is at most 16 MiB as in `stream_buffer_resize/2`.
+  """
+  @spec set_stream_buffer_range(integer, non_neg_integer, non_neg_integer) ::
+          :ok | {:error, :bad_format}
+  def set_stream_buffer_range(conn, min, max) do
+    Bridge.connection_set_stream_buffer_range(conn, min, max)
+  end
+
+  @spec dgram_send(t, binary) ::
+          {:ok, non_neg_integer | :infinity} | {:error, atom}
+  def dgram_send({:native, datapath, conn_id}, data) do
+    with :ok <- Datapath.dgram_send(datapath, conn_id, data), do: {:ok, :infinity}
+  end
+
   def dgram_send(conn, data) do
     Bridge.connection_dgram_send(conn, data)
   end
 
-  @spec open_stream(integer, boolean) ::
-          {:ok, non_neg_integer, non_neg_integer} | {:error, :system_error | :already_closed}
+  @doc """
+  Sends a burst of datagrams in one call, draining the connection once. They're taken
+  in order until one is turned down, when the send queue is full say, and the rest is
+  dropped. Returns how many were taken, the native datapath queuing all of them.
+  """
+  @spec dgram_send_batch(t, [binary]) ::
+          {:ok, non_neg_integer, non_neg_integer | :infinity} | {:error, atom}
+  def dgram_send_batch({:native, datapath, conn_id}, data) do
+    with {:ok, accepted} <- Datapath.dgram_send_batch(datapath, conn_id, data),
+         do: {:ok, accepted, :infinity}
+  end
+
+  def dgram_send_batch(conn, data) do
+    Bridge.connection_dgram_send_batch(conn, data)
+  end
+
+  @spec h3_dgram_send(t, non_neg_integer, binary) ::
+          {:ok, non_neg_integer | :infinity}
+          | {:error, :system_error | :already_closed | :bad_format | :not_supported}
+  def h3_dgram_send({:native, datapath, conn_id}, flow_id, data) do
+    with :ok <- Datapath.h3_dgram_send(datapath, conn_id, flow_id, data), do: {:ok, :infinity}
+  end
+
+  def h3_dgram_send(conn, flow_id, data) do
+    Bridge.connection_h3_dgram_send(conn, flow_id, data)
+  end
+
+  @spec open_stream(t, boolean) ::
+          {:ok, non_neg_integer, non_neg_integer | :infinity} | {:error, atom}
+  def open_stream({:native, datapath, conn_id}, is_bidi) do
+    with {:ok, stream_id} <- Datapath.open_stream(datapath, conn_id, is_bidi),
+         do: {:ok, stream_id, :infinity}
+  end
+
   def open_stream(conn, is_bidi) do
     Bridge.connection_open_stream(conn, is_bidi)
   end
 
-  @spec stream_send(integer, non_neg_integer, binary, boolean) ::
-          {:ok, non_neg_integer} | {:error, :system_error | :already_closed}
+  @doc """
+  Sends data on a stream. What the flow control of the peer doesn't accept yet is
+  held by the connection and written as the peer gives credit. Once 1MB is held for
+  the connection, the sends fail with `{:error, :stream_blocked}` and can be retried
+  later. The native datapath queues the sends, so they only log that failure.
+  """
+  @spec stream_send(t, non_neg_integer, binary, boolean) ::
+          {:ok, non_neg_integer | :infinity} | {:error, atom}
+  def stream_send({:native, datapath, conn_id}, stream_id, data, fin) do
+    with :ok <- Datapath.stream_send(datapath, conn_id, stream_id, data, fin),
+         do: {:ok, :infinity}
+  end
+
   def stream_send(conn, stream_id, data, fin) do
-    Bridge.connection_stream_send(conn, stream_id, data, fin)
+    case Bridge.connection_stream_send(conn, stream_id, data, fin) do
+      {:continue, sent} ->
+        # large data is sent in several calls, so that each of them returns quickly
+        rest = binary_part(data, sent, byte_size(data) - sent)
+        stream_send(conn, stream_id, rest, fin)
+
+      result ->
+        result
+    end
+  end
+
+  @doc """
+  Same as `stream_send/4`, but the packets produced are returned as
+  `[{peer, packet}]` instead of being sent to the sender process. When a later
+  slice of large data fails, the packets of the slices sent before it are
+  returned with the error, as they still have to be sent.
+  """
+  @spec stream_send_sync(integer, non_neg_integer, binary, boolean) ::
+          {:ok, non_neg_integer | :infinity, [{term, binary}]}
+          | {:error, atom, [{term, binary}]}
+          | {:error, atom}
+  def stream_send_sync(conn, stream_id, data, fin) do
+    stream_send_sync(conn, stream_id, data, fin, [])
+  end
+
+  defp stream_send_sync(conn, stream_id, data, fin, acc) do
+    case Bridge.connection_stream_send_sync(conn, stream_id, data, fin) do
+      {:continue, sent, packets} ->
+        rest = binary_part(data, sent, byte_size(data) - sent)
+        stream_send_sync(conn, stream_id, rest, fin, [packets | acc])
+
+      {:ok, next_timeout, packets} ->
+        {:ok, next_timeout, Enum.reverse([packets | acc]) |> Enum.concat()}
+
+      {:error, reason, packets} ->
+        {:error, reason, Enum.reverse([packets | acc]) |> Enum.concat()}
+
+      error ->
+        error
+    end
+  end
+
+  @spec stream_send_vectored(t, non_neg_integer, [binary], boolean) ::
+          {:ok, non_neg_integer | :infinity} | {:error, atom}
+  def stream_send_vectored({:native, datapath, conn_id}, stream_id, data, fin) do
+    with :ok <- Datapath.stream_send_vectored(datapath, conn_id, stream_id, data, fin),
+         do: {:ok, :infinity}
+  end
+
+  def stream_send_vectored(conn, stream_id, data, fin) do
+    Bridge.connection_stream_send_vectored(conn, stream_id, data, fin)
+  end
+
+  @doc """
+  Sends the chunks, a header and its body say, as they are and in a single call,
+  so that no other send on the connection comes in between. Unlike `stream_send/4`,
+  the data isn't sent in several calls, and unlike `stream_send_vectored/4`, the
+  framing of the connection doesn't apply. Either all of it is written or held,
+  or none of it is and the send fails, with `:stream_blocked` or the state of the
+  stream.
+  """
+  @spec stream_send_full(t, non_neg_integer, [binary], boolean) ::
+          {:ok, non_neg_integer | :infinity} | {:error, atom}
+  def stream_send_full({:native, datapath, conn_id}, stream_id, data, fin) do
+    with :ok <- Datapath.stream_send_full(datapath, conn_id, stream_id, data, fin),
+         do: {:ok, :infinity}
+  end
+
+  def stream_send_full(conn, stream_id, data, fin) do
+    Bridge.connection_stream_send_full(conn, stream_id, data, fin)
   end
 
   @spec on_packet(integer, binary, term) ::
-          {:ok, non_neg_integer} | {:error, :system_error | :already_closed}
+          {:ok, non_neg_integer | :infinity} | {:error, atom}
   def on_packet(conn, packet, peer) do
     Bridge.connection_on_packet(self(), conn, packet, peer)
   end
 
-  @spec on_timeout(integer) :: {:ok, non_neg_integer} | {:error, :system_error | :already_closed}
+  @doc """
+  Same as `on_packet/3`, but the packets produced are returned as
+  `[{peer, packet}]` instead of being sent to the sender process.
+  """
+  @spec on_packet_sync(integer, binary, term) ::
+          {:ok, non_neg_integer | :infinity, [{term, binary}]} | {:error, atom}
+  def on_packet_sync(conn, packet, peer) do
+    Bridge.connection_on_packet_sync(self(), conn, packet, peer)
+  end
+
+  @spec on_timeout(integer) :: {:ok, non_neg_integer | :infinity} | {:error, atom}
   def on_timeout(conn) do
-    Bridge.connection_on_timeout(conn)
+    Bridge.connection_on_timeout(self(), conn)
   end
 end
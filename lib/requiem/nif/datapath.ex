@@ -0,0 +1,343 @@
+defmodule Requiem.NIF.Datapath do
+  alias Requiem.NIF.Bridge
+
+  @doc """
+  Opens the UDP sockets in the native layer and starts the threads which own them,
+  one for each config.
+
+  The configs are owned by the datapath after this call, don't destroy them.
+
+  With the `fd` option, the descriptor of an already bound UDP socket, that socket is
+  used by all the threads instead of binding `host:port`.
+  """
+  @spec start([integer], binary, non_neg_integer, pid, Keyword.t()) ::
+          {:ok, reference} | {:error, :bad_format | :cant_bind | :socket_error | :system_error}
+  def start(config_ptrs, host, port, listener, opts) do
+    Bridge.datapath_start(
+      Keyword.fetch!(opts, :handler),
+      config_ptrs,
+      "#{host}:#{port}",
+      Keyword.get(opts, :fd) || -1,
+      listener,
+      {Keyword.fetch!(opts, :conn_id_secret), Keyword.fetch!(opts, :token_secret)},
+      {
+        Keyword.fetch!(opts, :stream_buf_size),
+        Keyword.fetch!(opts, :stream_buf_min_size),
+        Keyword.fetch!(opts, :stream_buf_max_size),
+        Keyword.fetch!(opts, :max_field_section_size),
+        Keyword.fetch!(opts, :max_requests),
+        Keyword.fetch!(opts, :max_header_count),
+        Keyword.get(opts, :max_connections) || 0
+      },
+      {
+        Keyword.fetch!(opts, :recv_batch_size),
+        Keyword.fetch!(opts, :send_batch_size),
+        Keyword.fetch!(opts, :gso),
+        Keyword.fetch!(opts, :gro),
+        Keyword.fetch!(opts, :idle_reap_timeout)
+      }
+    )
+  end
+
+  @doc """
+  Stops the threads, and the connections, which their owners are told of with
+  `:__closed__`. The datapath itself is freed once nothing refers to it, the calls made
+  on it after this return `{:error, :bad_state}`.
+  """
+  @spec stop(reference) :: :ok
+  def stop(datapath) do
+    Bridge.datapath_stop(datapath)
+  end
+
+  @spec set_owner(reference, non_neg_integer, pid) :: :ok | {:error, atom}
+  def set_owner(datapath, conn_id, pid) do
+    Bridge.datapath_set_owner(datapath, conn_id, pid)
+  end
+
+  @spec set_subscriber(reference, non_neg_integer, atom, pid | nil) :: :ok | {:error, atom}
+  def set_subscriber(datapath, conn_id, class, pid) do
+    Bridge.datapath_set_subscriber(datapath, conn_id, class, pid)
+  end
+
+  @spec stream_qlog(reference, non_neg_integer, pid) :: :ok | {:error, atom}
+  def stream_qlog(datapath, conn_id, pid) do
+    Bridge.datapath_stream_qlog(datapath, conn_id, pid)
+  end
+
+  @spec qlog_to_file(reference, non_neg_integer, binary, pos_integer, pos_integer, boolean) ::
+          :ok | {:error, atom}
+  def qlog_to_file(datapath, conn_id, dir, max_file_size, max_total_size, gzip) do
+    Bridge.datapath_qlog_to_file(datapath, conn_id, dir, max_file_size, max_total_size, gzip)
+  end
+
+  @spec qlog_disable(reference, non_neg_integer) :: :ok | {:error, atom}
+  def qlog_disable(datapath, conn_id) do
+    Bridge.datapath_qlog_disable(datapath, conn_id)
+  end
+
+  @spec accept_connect_request(reference, non_neg_integer) :: :ok | {:error, atom}
+  def accept_connect_request(datapath, conn_id) do
+    Bridge.datapath_accept_connect_request(datapath, conn_id)
+  end
+
+  @spec reject_connect_request(reference, non_neg_integer, integer) :: :ok | {:error, atom}
+  def reject_connect_request(datapath, conn_id, code) do
+    Bridge.datapath_reject_connect_request(datapath, conn_id, code)
+  end
+
+  @spec open_stream(reference, non_neg_integer, boolean) ::
+          {:ok, non_neg_integer} | {:error, atom}
+  def open_stream(datapath, conn_id, is_bidi) do
+    Bridge.datapath_open_stream(datapath, conn_id, is_bidi)
+  end
+
+  @spec enable_congestion_events(reference, non_neg_integer) :: :ok | {:error, atom}
+  def enable_congestion_events(datapath, conn_id) do
+    Bridge.datapath_enable_congestion_events(datapath, conn_id)
+  end
+
+  @spec enable_pmtu_events(reference, non_neg_integer) :: :ok | {:error, atom}
+  def enable_pmtu_events(datapath, conn_id) do
+    Bridge.datapath_enable_pmtu_events(datapath, conn_id)
+  end
+
+  @spec enable_event_timestamps(reference, non_neg_integer) :: :ok | {:error, atom}
+  def enable_event_timestamps(datapath, conn_id) do
+    Bridge.datapath_enable_event_timestamps(datapath, conn_id)
+  end
+
+  @spec enable_stream_close_events(reference, non_neg_integer) :: :ok | {:error, atom}
+  def enable_stream_close_events(datapath, conn_id) do
+    Bridge.datapath_enable_stream_close_events(datapath, conn_id)
+  end
+
+  @spec tap(reference, non_neg_integer, {:pid, pid} | {:pcap, binary} | nil) ::
+          :ok | {:error, atom}
+  def tap(datapath, conn_id, {:pid, pid}), do: Bridge.datapath_tap_pid(datapath, conn_id, pid)
+  def tap(datapath, conn_id, {:pcap, dir}), do: Bridge.datapath_tap_pcap(datapath, conn_id, dir)
+  def tap(datapath, conn_id, nil), do: Bridge.datapath_untap(datapath, conn_id)
+
+  @spec enable_sampling(reference, non_neg_integer, non_neg_integer, non_neg_integer) ::
+          :ok | {:error, atom}
+  def enable_sampling(datapath, conn_id, interval_ms, capacity) do
+    Bridge.datapath_enable_sampling(datapath, conn_id, interval_ms, capacity)
+  end
+
+  @spec samples(reference, non_neg_integer) ::
+          {:ok, [Requiem.NIF.Connection.sample_tuple()]} | {:error, atom}
+  def samples(datapath, conn_id) do
+    Bridge.datapath_samples(datapath, conn_id)
+  end
+
+  @spec set_stream_framing(reference, non_neg_integer, Requiem.NIF.Connection.stream_framing()) ::
+          :ok | {:error, atom}
+  def set_stream_framing(datapath, conn_id, framing) do
+    Bridge.datapath_set_stream_framing(datapath, conn_id, framing)
+  end
+
+  @spec set_impairment(reference, non_neg_integer, Requiem.NIF.Connection.impairment() | nil) ::
+          :ok | {:error, atom}
+  def set_impairment(datapath, conn_id, impairment) do
+    Bridge.datapath_set_impairment(datapath, conn_id, impairment)
+  end
+
+  @spec set_stream_recv_window(
+          reference,
+          non_neg_integer,
+          non_neg_integer,
+          non_neg_integer | nil
+        ) :: :ok | {:error, atom}
+  def set_stream_recv_window(datapath, conn_id, window, reset_code) do
+    Bridge.datapath_set_stream_recv_window(datapath, conn_id, window, reset_code)
+  end
+
+  @spec set_trace_context(reference, non_neg_integer, binary, binary, non_neg_integer) ::
+          :ok | {:error, atom}
+  def set_trace_context(datapath, conn_id, trace_id, span_id, flags) do
+    Bridge.datapath_set_trace_context(datapath, conn_id, trace_id, span_id, flags)
+  end
+
+  @spec set_stream_priority(reference, non_neg_integer, non_neg_integer, 0..7, boolean) ::
+          :ok | {:error, atom}
+  def set_stream_priority(datapath, conn_id, stream_id, urgency, incremental) do
+    Bridge.datapath_set_stream_priority(datapath, conn_id, stream_id, urgency, incremental)
+  end
+
+  @spec set_send_order(
+          reference,
+          non_neg_integer,
+          :dgram_first | :stream_first | {:dgram_share, 0..100}
+        ) :: :ok | {:error, atom}
+  def set_send_order(datapath, conn_id, order) do
+    Bridge.datapath_set_send_order(datapath, conn_id, order)
+  end
+
+  @spec set_stream_pull_mode(reference, non_neg_integer, boolean) :: :ok | {:error, atom}
+  def set_stream_pull_mode(datapath, conn_id, enabled) do
+    Bridge.datapath_set_stream_pull_mode(datapath, conn_id, enabled)
+  end
+
+  @spec stream_read(reference, non_neg_integer, non_neg_integer, non_neg_integer) ::
+          {:ok, binary} | {:error, atom}
+  def stream_read(datapath, conn_id, stream_id, max_bytes) do
+    Bridge.datapath_stream_read(datapath, conn_id, stream_id, max_bytes)
+  end
+
+  @spec h3_recv_body(reference, non_neg_integer, non_neg_integer, non_neg_integer) ::
+          {:ok, binary} | {:error, atom}
+  def h3_recv_body(datapath, conn_id, stream_id, max_bytes) do
+    Bridge.datapath_h3_recv_body(datapath, conn_id, stream_id, max_bytes)
+  end
+
+  @spec set_send_deadline(
+          reference,
+          non_neg_integer,
+          non_neg_integer | nil,
+          {non_neg_integer, non_neg_integer} | nil
+        ) :: :ok | {:error, atom}
+  def set_send_deadline(datapath, conn_id, stream_id, deadline) do
+    Bridge.datapath_set_send_deadline(datapath, conn_id, stream_id, deadline)
+  end
+
+  @spec set_dgram_budget(reference, non_neg_integer, pos_integer | nil) :: :ok | {:error, atom}
+  def set_dgram_budget(datapath, conn_id, budget) do
+    Bridge.datapath_set_dgram_budget(datapath, conn_id, budget)
+  end
+
+  @spec set_keepalive(reference, non_neg_integer, pos_integer | nil) :: :ok | {:error, atom}
+  def set_keepalive(datapath, conn_id, interval_ms) do
+    Bridge.datapath_set_keepalive(datapath, conn_id, interval_ms)
+  end
+
+  @spec set_lifetime_policy(
+          reference,
+          non_neg_integer,
+          {pos_integer, non_neg_integer} | nil,
+          {pos_integer, non_neg_integer} | nil
+        ) :: :ok | {:error, atom}
+  def set_lifetime_policy(datapath, conn_id, max_lifetime, max_inactivity) do
+    Bridge.datapath_set_lifetime_policy(datapath, conn_id, max_lifetime, max_inactivity)
+  end
+
+  @spec set_dgram_pull_mode(reference, non_neg_integer, boolean) :: :ok | {:error, atom}
+  def set_dgram_pull_mode(datapath, conn_id, enabled) do
+    Bridge.datapath_set_dgram_pull_mode(datapath, conn_id, enabled)
+  end
+
+  @spec dgram_peek(reference, non_neg_integer, non_neg_integer) ::
+          {:ok, binary, non_neg_integer} | {:error, atom}
+  def dgram_peek(datapath, conn_id, max_len) do
+    Bridge.datapath_dgram_peek(datapath, conn_id, max_len)
+  end
+
+  @spec dgram_recv(reference, non_neg_integer, boolean) :: :ok | {:error, atom}
+  def dgram_recv(datapath, conn_id, deliver) do
+    Bridge.datapath_dgram_recv(datapath, conn_id, deliver)
+  end
+
+  @spec probe_path(reference, non_neg_integer, non_neg_integer) :: :ok | {:error, atom}
+  def probe_path(datapath, conn_id, timeout_ms) do
+    Bridge.datapath_probe_path(datapath, conn_id, timeout_ms)
+  end
+
+  @spec stream_ack(reference, non_neg_integer, non_neg_integer, non_neg_integer) ::
+          :ok | {:error, atom}
+  def stream_ack(datapath, conn_id, stream_id, bytes) do
+    Bridge.datapath_stream_ack(datapath, conn_id, stream_id, bytes)
+  end
+
+  @spec stream_stats(reference, non_neg_integer, non_neg_integer) ::
+          {:ok, Requiem.NIF.Connection.stream_stats_tuple()} | {:error, atom}
+  def stream_stats(datapath, conn_id, stream_id) do
+    Bridge.datapath_stream_stats(datapath, conn_id, stream_id)
+  end
+
+  @spec stream_counts(reference, non_neg_integer) ::
+          {:ok, Requiem.NIF.Connection.stream_counts_tuple()} | {:error, atom}
+  def stream_counts(datapath, conn_id) do
+    Bridge.datapath_stream_counts(datapath, conn_id)
+  end
+
+  @spec pmtu(reference, non_neg_integer) :: {:ok, non_neg_integer} | {:error, atom}
+  def pmtu(datapath, conn_id) do
+    Bridge.datapath_pmtu(datapath, conn_id)
+  end
+
+  @spec readable(reference, non_neg_integer) :: {:ok, [non_neg_integer]} | {:error, atom}
+  def readable(datapath, conn_id) do
+    Bridge.datapath_readable(datapath, conn_id)
+  end
+
+  @spec writable(reference, non_neg_integer) :: {:ok, [non_neg_integer]} | {:error, atom}
+  def writable(datapath, conn_id) do
+    Bridge.datapath_writable(datapath, conn_id)
+  end
+
+  @spec handoff_state(reference, non_neg_integer) ::
+          {:ok, Requiem.NIF.Connection.handoff_state()} | {:error, atom}
+  def handoff_state(datapath, conn_id) do
+    Bridge.datapath_handoff_state(datapath, conn_id)
+  end
+
+  @spec peer_cert(reference, non_neg_integer) :: {:ok, binary | nil} | {:error, atom}
+  def peer_cert(datapath, conn_id) do
+    Bridge.datapath_peer_cert(datapath, conn_id)
+  end
+
+  @spec stream_send(reference, non_neg_integer, non_neg_integer, binary, boolean) ::
+          :ok | {:error, atom}
+  def stream_send(datapath, conn_id, stream_id, data, fin) do
+    case Bridge.datapath_stream_send(datapath, conn_id, stream_id, data, fin) do
+      {:ok, _len} ->
+        :ok
+
+      {:continue, sent} ->
+        # large data is passed to the datapath in several calls
+        rest = binary_part(data, sent, byte_size(data) - sent)
+        stream_send(datapath, conn_id, stream_id, rest, fin)
+
+      error ->
+        error
+    end
+  end
+
+  @spec stream_send_vectored(reference, non_neg_integer, non_neg_integer, [binary], boolean) ::
+          :ok | {:error, atom}
+  def stream_send_vectored(datapath, conn_id, stream_id, data, fin) do
+    Bridge.datapath_stream_send_vectored(datapath, conn_id, stream_id, data, fin)
+  end
+
+  @spec stream_send_full(reference, non_neg_integer, non_neg_integer, [binary], boolean) ::
+          :ok | {:error, atom}
+  def stream_send_full(datapath, conn_id, stream_id, data, fin) do
+    Bridge.datapath_stream_send_full(datapath, conn_id, stream_id, data, fin)
+  end
+
+  @spec dgram_send(reference, non_neg_integer, binary) :: :ok | {:error, atom}
+  def dgram_send(datapath, conn_id, data) do
+    Bridge.datapath_dgram_send(datapath, conn_id, data)
+  end
+
+  @spec dgram_send_batch(reference, non_neg_integer, [binary]) ::
+          {:ok, non_neg_integer} | {:error, atom}
+  def dgram_send_batch(datapath, conn_id, data) do
+    Bridge.datapath_dgram_send_batch(datapath, conn_id, data)
+  end
+
+  @spec h3_dgram_send(reference, non_neg_integer, non_neg_integer, binary) ::
+          :ok | {:error, atom}
+  def h3_dgram_send(datapath, conn_id, flow_id, data) do
+    Bridge.datapath_h3_dgram_send(datapath, conn_id, flow_id, data)
+  end
+
+  @spec close(reference, non_neg_integer, boolean, non_neg_integer, binary) ::
+          :ok | {:error, atom}
+  def close(datapath, conn_id, app, err, reason) do
+    Bridge.datapath_close(datapath, conn_id, app, err, reason)
+  end
+
+  @spec forget(reference, non_neg_integer, non_neg_integer) :: :ok | {:error, atom}
+  def forget(datapath, conn_id, err) do
+    Bridge.datapath_forget(datapath, conn_id, err)
+  end
+end
@@ -0,0 +1,220 @@
+defmodule Requiem.NIF.Stats do
+  alias Requiem.Config
+  alias Requiem.NIF.Bridge
+
+  @type memory :: %{
+          connections: non_neg_integer,
+          connection_buffer_bytes: non_neg_integer,
+          datapath_buffer_bytes: non_neg_integer,
+          quiche_connection_bytes: non_neg_integer
+        }
+
+  @doc """
+  Returns the memory held by the native layer, which `:erlang.memory/0` doesn't include.
+
+  - `connections` - live connections of all the handlers and datapaths
+  - `connection_buffer_bytes` - packet buffers owned by those connections
+  - `datapath_buffer_bytes` - recv/send batch buffers of the native datapath workers
+  - `quiche_connection_bytes` - estimated from the size of quiche's connection struct,
+    the state which quiche allocates for each connection is not included.
+  """
+  @spec memory() :: memory
+  def memory() do
+    Bridge.memory_stats() |> Map.new()
+  end
+
+  @doc """
+  Returns the statistics of the allocator used by the native layer.
+
+  The allocator is chosen when the NIF is compiled:
+
+      config :requiem, nif_features: ["jemalloc"]
+
+  - `"jemalloc"` reports `allocated`, `active` and `resident` bytes
+  - `"mimalloc"` reports `active` (committed) and `resident` bytes
+  - without them, the system allocator is used and no statistics are reported
+  """
+  @spec allocator() ::
+          {:ok, :jemalloc | :mimalloc | :system, %{atom => non_neg_integer}}
+          | {:error, :system_error}
+  def allocator() do
+    with {:ok, allocator, stats} <- Bridge.allocator_stats(),
+         do: {:ok, allocator, Map.new(stats)}
+  end
+
+  @type versions :: %{
+          nif: binary,
+          quiche: binary,
+          quic_versions: [non_neg_integer],
+          cc_algorithms: [binary],
+          features: [atom]
+        }
+
+  @doc """
+  Returns the versions of the native layer, for bug reports and for adapting to it
+  at runtime.
+
+  - `nif` - version of the NIF crate
+  - `quiche` - version of the bundled quiche
+  - `quic_versions` - QUIC versions which quiche supports, e.g. `0x1` for QUIC v1
+  - `cc_algorithms` - names accepted by the `cc_algorithm_name` option
+  - `features` - `:dgram`, `:h3` and `:webtransport`, with `:qlog`, `:jemalloc` or
+    `:mimalloc` when the NIF is compiled with them
+  """
+  @spec versions() :: versions
+  def versions() do
+    {nif, quiche, quic_versions, cc_algorithms, features} = Bridge.native_versions()
+
+    %{
+      nif: nif,
+      quiche: quiche,
+      quic_versions: quic_versions,
+      cc_algorithms: cc_algorithms,
+      features: features
+    }
+  end
+
+  @doc """
+  Turns the latency instrumentation of the native layer on or off.
+
+  It's off by default. Turning it on clears the recorded samples.
+  """
+  @spec enable_diagnostics(boolean) :: :ok
+  def enable_diagnostics(enabled) do
+    Bridge.diagnostics_enable(enabled)
+  end
+
+  @doc """
+  Returns the latency histograms recorded since the instrumentation has been enabled.
+
+  Each probe is a NIF call (`connection_on_packet`, `connection_stream_send`, ...) or
+  a wait of the native datapath. Of the connection calls, only accepting, receiving,
+  timeouts and sending stream data or datagrams are timed, the others only read or set
+  the state of the connection. `datapath_command` times every call handing a command
+  to a worker of the native datapath. `datapath_command_wait` is the time a command spends
+  in the queue of its worker, `datapath_open_stream_wait` the time open_stream waits
+  for the reply. The n-th bucket counts the samples shorter than 2^n microseconds,
+  the last one also counts the longer ones.
+  """
+  @spec diagnostics() :: %{
+          atom => %{
+            count: non_neg_integer,
+            total_us: non_neg_integer,
+            buckets: [non_neg_integer]
+          }
+        }
+  def diagnostics() do
+    Bridge.diagnostics()
+    |> Map.new(fn {name, count, total_us, buckets} ->
+      {name, %{count: count, total_us: total_us, buckets: buckets}}
+    end)
+  end
+
+  @doc """
+  Sends `{:slow_call, name, trace_id, elapsed_us}` to `pid` whenever a probed call of the
+  native layer takes `threshold_us` or longer, whether the instrumentation is enabled or not.
+
+  `name` is the probe, as in `diagnostics/0`, and `trace_id` the connection the call was
+  for, `nil` for the NIF calls of the native datapath, which only hand a command over to
+  its worker. A call which holds a scheduler for milliseconds starves the other
+  processes, the warnings show which connection does it before the node stalls.
+  Only one process watches, it replaces the previous one. The warnings are dropped
+  when the process doesn't keep up.
+  """
+  @spec watch_slow_calls(pid, pos_integer) :: :ok | {:error, :bad_format | :system_error}
+  def watch_slow_calls(pid, threshold_us) do
+    Bridge.diagnostics_watch_slow_calls(pid, threshold_us)
+  end
+
+  @doc """
+  Stops sending the slow call warnings.
+  """
+  @spec unwatch_slow_calls() :: :ok
+  def unwatch_slow_calls() do
+    Bridge.diagnostics_unwatch_slow_calls()
+  end
+
+  @type telemetry :: %{
+          accepted: non_neg_integer,
+          refused: non_neg_integer,
+          packets_in: non_neg_integer,
+          packets_out: non_neg_integer,
+          bytes_in: non_neg_integer,
+          bytes_out: non_neg_integer,
+          handshake_failures: non_neg_integer,
+          dgram_drops: non_neg_integer
+        }
+
+  @doc """
+  Returns the counters of the connections of a handler, summed since the NIF was loaded,
+  so that :telemetry metrics can be polled from them instead of being sent for each event.
+
+  - `accepted` - accepted connections
+  - `refused` - Initial packets of new connections dropped beyond `max_connections`
+  - `packets_in`, `bytes_in` - UDP payloads passed to the connections
+  - `packets_out`, `bytes_out` - UDP payloads produced by the connections
+  - `handshake_failures` - connections dropped before the handshake completed
+  - `dgram_drops` - datagrams which failed to be queued for sending
+  """
+  @spec telemetry(module) :: telemetry
+  def telemetry(handler) do
+    Bridge.telemetry_snapshot(handler) |> Map.new()
+  end
+
+  @type drops :: %{
+          decrypt_failed: non_neg_integer,
+          unknown_cid: non_neg_integer,
+          invalid_header: non_neg_integer,
+          buffer_too_short: non_neg_integer,
+          other: non_neg_integer
+        }
+
+  @doc """
+  Returns the received packets of a handler dropped by reason, summed since the NIF was loaded.
+
+  - `decrypt_failed` - packets which failed to be decrypted
+  - `unknown_cid` - non-Initial packets for no connection, counted by the native datapath only
+  - `invalid_header` - packets whose header failed to be parsed
+  - `buffer_too_short` - truncated packets
+  - `other` - packets rejected by the connection for any other error
+  """
+  @spec drops(module) :: drops
+  def drops(handler) do
+    Bridge.telemetry_drops(handler) |> Map.new()
+  end
+
+  @doc """
+  Renders the counters of all the handlers, the packet drops, the live connections
+  and the native memory in the Prometheus text format, e.g. for a Plug endpoint:
+
+      conn
+      |> put_resp_content_type("text/plain; version=0.0.4")
+      |> send_resp(200, Requiem.NIF.Stats.prometheus())
+
+  The metrics are labelled with the `module` of the handler. Connections are
+  owned by their processes and threads, so there is no gauge for each of them.
+  """
+  @spec prometheus() :: binary
+  def prometheus() do
+    Bridge.prometheus_render()
+  end
+
+  @doc """
+  Renders the handlers, their configs, live connections, counters and packet drops,
+  the totals of all of them and the native memory as a single JSON document, for
+  monitoring agents and support bundles:
+
+      {"nif": "0.4.3", "quiche": "...", "memory": {...},
+       "totals": {"modules": 1, "connections": 12, "counters": {...}, "packet_drops": {...}},
+       "modules": [{"module": "MyApp.Handler", "connections": 12, "counters": {...},
+                    "packet_drops": {...}, "config": {...}}]}
+
+  The secrets of the configs are redacted, see `Requiem.Config.dump/1`.
+  """
+  @spec json() :: binary
+  def json() do
+    Config.handlers()
+    |> Enum.map(&{&1, Config.dump(&1)})
+    |> Bridge.json_render()
+  end
+end
@@ -0,0 +1,54 @@
+defmodule Requiem.NIFLogger do
+  @moduledoc """
+  Passes the log records of the NIF, quiche's included, to Logger.
+
+  The NIF logs to stderr until this process is started, which is global to
+  all the handlers, so it belongs to the supervision tree of the application:
+
+      children = [
+        {Requiem.NIFLogger, level: :info},
+        MyApp.Handler
+      ]
+
+  `level` is one of `:error`, `:warn`, `:info`, `:debug` and `:trace`.
+  Records are dropped rather than blocking the native threads when this
+  process can't keep up.
+  """
+  use GenServer
+  require Logger
+
+  alias Requiem.NIF.Bridge
+
+  def start_link(opts) do
+    GenServer.start_link(__MODULE__, opts, name: __MODULE__)
+  end
+
+  @impl GenServer
+  def init(opts) do
+    case Bridge.logger_forward(self(), Keyword.get(opts, :level, :warn)) do
+      :ok ->
+        Process.flag(:trap_exit, true)
+        {:ok, nil}
+
+      {:error, reason} ->
+        {:stop, reason}
+    end
+  end
+
+  @impl GenServer
+  def handle_info({:__log__, level, target, message}, state) do
+    Logger.bare_log(logger_level(level), message, nif_target: target)
+    {:noreply, state}
+  end
+
+  @impl GenServer
+  def terminate(_reason, _state) do
+    Bridge.logger_stop_forwarding()
+    :ok
+  end
+
+  defp logger_level(:error), do: :error
+  defp logger_level(:warn), do: :warn
+  defp logger_level(:info), do: :info
+  defp logger_level(_), do: :debug
+end
@@ -0,0 +1,46 @@
+defmodule Requiem.Shutdown do
+  @moduledoc """
+  Closes all the connections of a handler, e.g. to drain a node in a rolling deploy.
+  """
+  require Logger
+
+  alias Requiem.NIF.Bridge
+
+  @poll_interval 100
+
+  @doc """
+  Sends CONNECTION_CLOSE with the application error `code` and `reason` on every live
+  connection of the handler, then waits up to `timeout` ms for them to be gone.
+
+  The connections on the BEAM are closed by their processes, those of the native
+  datapath by its workers. The progress is logged as the connections go away.
+  New connections are still accepted, stop the listeners first to drain them.
+  """
+  @spec run(module, non_neg_integer, atom | binary, non_neg_integer) ::
+          :ok | {:timeout, non_neg_integer}
+  def run(handler, code, reason, timeout) do
+    closing = Bridge.module_shutdown(handler, code, to_string(reason))
+    Logger.info("<Requiem.Shutdown:#{inspect(handler)}> closing #{closing} connections")
+    wait(handler, closing, System.monotonic_time(:millisecond) + timeout)
+  end
+
+  defp wait(handler, last, deadline) do
+    case Bridge.module_connections(handler) do
+      0 ->
+        Logger.info("<Requiem.Shutdown:#{inspect(handler)}> all connections closed")
+        :ok
+
+      remaining ->
+        if remaining != last do
+          Logger.info("<Requiem.Shutdown:#{inspect(handler)}> #{remaining} connections remaining")
+        end
+
+        if System.monotonic_time(:millisecond) >= deadline do
+          {:timeout, remaining}
+        else
+          Process.sleep(@poll_interval)
+          wait(handler, remaining, deadline)
+        end
+    end
+  end
+end
@@ -0,0 +1,55 @@
+defmodule Requiem.StatsdPusher do
+  @moduledoc """
+  Pushes the counters of a handler to a StatsD server.
+
+  Started by `Requiem.Supervisor` when `statsd_address` ("host:port") is
+  configured. The counters are sent over UDP from a native thread every
+  `statsd_interval` milliseconds, as the increments since the previous push,
+  along with the number of live connections as a gauge.
+
+  With `statsd_tags` the lines are DogStatsD ones, tagged with the handler
+  (`requiem.packets_received:3|c|#module:MyApp.Handler`), otherwise the
+  handler is part of the metric name (`requiem.MyApp.Handler.packets_received:3|c`).
+  """
+  use GenServer
+  require Logger
+
+  alias Requiem.Config
+  alias Requiem.NIF.Bridge
+
+  def start_link(opts) do
+    name = opts |> Keyword.fetch!(:handler) |> name()
+    GenServer.start_link(__MODULE__, opts, name: name)
+  end
+
+  @impl GenServer
+  def init(opts) do
+    handler = Keyword.fetch!(opts, :handler)
+    address = Keyword.fetch!(opts, :address)
+
+    case Bridge.statsd_start(
+           handler,
+           address,
+           Config.get!(handler, :statsd_interval),
+           Config.get!(handler, :statsd_prefix),
+           Config.get!(handler, :statsd_tags)
+         ) do
+      {:ok, pusher_ptr} ->
+        Process.flag(:trap_exit, true)
+        {:ok, pusher_ptr}
+
+      {:error, reason} ->
+        Logger.error("<Requiem.StatsdPusher> failed to start for #{address}: #{inspect(reason)}")
+        {:stop, reason}
+    end
+  end
+
+  @impl GenServer
+  def terminate(_reason, pusher_ptr) do
+    Bridge.statsd_stop(pusher_ptr)
+    :ok
+  end
+
+  defp name(handler),
+    do: Module.concat(handler, __MODULE__)
+end
@@ -8,10 +8,13 @@ defmodule Requiem.Supervisor do
   alias Requiem.NIF
   alias Requiem.ConnectionRegistry
   alias Requiem.ConnectionSupervisor
+  alias Requiem.ControlSocket
   alias Requiem.DispatcherSupervisor
   alias Requiem.DispatcherRegistry
+  alias Requiem.NativeTransport
   alias Requiem.SenderSupervisor
   alias Requiem.SenderRegistry
+  alias Requiem.StatsdPusher
   alias Requiem.Transport
 
   @spec child_spec(module, atom) :: Supervisor.child_spec()
@@ -38,6 +41,44 @@ defmodule Requiem.Supervisor do
 
   @spec children(module) :: [:supervisor.child_spec() | {module, term} | module]
   def children(handler) do
+    children =
+      case Config.get!(handler, :datapath) do
+        :native -> native_children(handler)
+        _ -> socket_children(handler)
+      end
+
+    children ++ statsd_children(handler) ++ control_children(handler)
+  end
+
+  defp statsd_children(handler) do
+    case Config.get!(handler, :statsd_address) do
+      nil -> []
+      address -> [{StatsdPusher, [handler: handler, address: address]}]
+    end
+  end
+
+  defp control_children(handler) do
+    case Config.get!(handler, :control_socket) do
+      nil -> []
+      path -> [{ControlSocket, [handler: handler, path: path]}]
+    end
+  end
+
+  # the native thread owns the socket, routes and processes all the packets.
+  defp native_children(handler) do
+    [
+      {Registry, keys: :unique, name: ConnectionRegistry.name(handler)},
+      {ConnectionSupervisor, handler},
+      {NativeTransport,
+       [
+         handler: handler,
+         host: handler |> Config.get!(:host),
+         port: handler |> Config.get!(:port)
+       ]}
+    ]
+  end
+
+  defp socket_children(handler) do
     socket_pool_size = Config.get!(handler, :socket_pool_size)
 
     num_socket =
@@ -72,7 +113,8 @@ defmodule Requiem.Supervisor do
              token_secret: handler |> Config.get!(:token_secret),
              conn_id_secret: handler |> Config.get!(:connection_id_secret),
              number_of_dispatchers: dispatcher_pool_size,
-             number_of_sockets: num_socket
+             number_of_sockets: num_socket,
+             socket_ptr: socket_ptr
            ]},
           {Transport,
            [
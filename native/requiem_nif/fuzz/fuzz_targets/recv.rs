@@ -0,0 +1,42 @@
+#![no_main]
+use std::cell::RefCell;
+
+use libfuzzer_sys::fuzz_target;
+
+// the certificate of the tests, so that the handshake goes past the ClientHello
+fn config() -> quiche::Config {
+    let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION).unwrap();
+    config
+        .load_cert_chain_from_pem_file(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../../../test/support/cert.crt"
+        ))
+        .unwrap();
+    config
+        .load_priv_key_from_pem_file(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../../../test/support/cert.key"
+        ))
+        .unwrap();
+    config
+        .set_application_protos(quiche::h3::APPLICATION_PROTOCOL)
+        .unwrap();
+    config.set_initial_max_data(1_000_000);
+    config.set_initial_max_stream_data_bidi_remote(100_000);
+    config.set_initial_max_stream_data_uni(100_000);
+    config.set_initial_max_streams_bidi(100);
+    config.set_initial_max_streams_uni(100);
+    config.enable_dgram(true, 1_000, 1_000);
+    config
+}
+
+// loaded once, the PEM files aren't what's fuzzed
+thread_local! {
+    static CONFIG: RefCell<quiche::Config> = RefCell::new(config());
+}
+
+fuzz_target!(|data: &[u8]| {
+    CONFIG.with(|config| {
+        let _ = requiem_nif::fuzz::recv(&mut config.borrow_mut(), data);
+    });
+});
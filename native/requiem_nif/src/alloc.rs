@@ -0,0 +1,74 @@
+use rustler::{Atom, NifResult};
+
+use crate::common::{self, atoms};
+
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+// jemalloc wins when both features are enabled
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+#[global_allocator]
+static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
+#[cfg(feature = "jemalloc")]
+fn stats() -> Result<(Atom, Vec<(Atom, u64)>), Atom> {
+    use tikv_jemalloc_ctl::{epoch, stats};
+
+    // the statistics are cached by jemalloc until the epoch is advanced
+    epoch::advance().map_err(|_| atoms::system_error())?;
+    let allocated = stats::allocated::read().map_err(|_| atoms::system_error())?;
+    let active = stats::active::read().map_err(|_| atoms::system_error())?;
+    let resident = stats::resident::read().map_err(|_| atoms::system_error())?;
+    Ok((
+        atoms::jemalloc(),
+        vec![
+            (atoms::allocated(), allocated as u64),
+            (atoms::active(), active as u64),
+            (atoms::resident(), resident as u64),
+        ],
+    ))
+}
+
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+fn stats() -> Result<(Atom, Vec<(Atom, u64)>), Atom> {
+    let mut elapsed = 0;
+    let mut user = 0;
+    let mut system = 0;
+    let mut current_rss = 0;
+    let mut peak_rss = 0;
+    let mut current_commit = 0;
+    let mut peak_commit = 0;
+    let mut page_faults = 0;
+    unsafe {
+        libmimalloc_sys::mi_process_info(
+            &mut elapsed,
+            &mut user,
+            &mut system,
+            &mut current_rss,
+            &mut peak_rss,
+            &mut current_commit,
+            &mut peak_commit,
+            &mut page_faults,
+        );
+    }
+    Ok((
+        atoms::mimalloc(),
+        vec![
+            (atoms::active(), current_commit as u64),
+            (atoms::resident(), current_rss as u64),
+        ],
+    ))
+}
+
+// the system allocator has no statistics to report
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+fn stats() -> Result<(Atom, Vec<(Atom, u64)>), Atom> {
+    Ok((atoms::system(), vec![]))
+}
+
+#[rustler::nif]
+pub fn allocator_stats() -> NifResult<(Atom, Atom, Vec<(Atom, u64)>)> {
+    let (allocator, stats) = stats().map_err(common::error_term)?;
+    Ok((atoms::ok(), allocator, stats))
+}
@@ -0,0 +1,375 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use mio::net::UdpSocket;
+
+use crate::stats;
+
+#[cfg(target_os = "linux")]
+use socket2::SockAddr;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+#[cfg(target_os = "linux")]
+use std::{mem, ptr};
+
+const MAX_DATAGRAM_SIZE: usize = 1500;
+
+// a GRO/GSO message carries up to 64 segments in a single UDP datagram
+const MAX_SEGMENTS: usize = 64;
+const MAX_SEGMENTED_SIZE: usize = 65535;
+
+// from linux/udp.h
+#[cfg(target_os = "linux")]
+const UDP_SEGMENT: libc::c_int = 103;
+#[cfg(target_os = "linux")]
+const UDP_GRO: libc::c_int = 104;
+
+// enough for one cmsghdr carrying an int, in u64 words to keep it aligned
+#[cfg(target_os = "linux")]
+const CONTROL_WORDS: usize = 8;
+
+// asks the kernel to merge incoming datagrams of a flow (UDP_GRO)
+#[cfg(target_os = "linux")]
+pub fn enable_gro(sock: &UdpSocket) -> bool {
+    let on: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            libc::SOL_UDP,
+            UDP_GRO,
+            &on as *const _ as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    ret == 0
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn enable_gro(_sock: &UdpSocket) -> bool {
+    false
+}
+
+// checks if the kernel can split the datagrams we send (UDP_SEGMENT)
+#[cfg(target_os = "linux")]
+pub fn gso_supported(sock: &UdpSocket) -> bool {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            sock.as_raw_fd(),
+            libc::SOL_UDP,
+            UDP_SEGMENT,
+            &mut value as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    ret == 0
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn gso_supported(_sock: &UdpSocket) -> bool {
+    false
+}
+
+// receives up to `batch_size` datagrams per syscall with recvmmsg(2) on linux,
+// falls back to recv_from() for each datagram on other platforms.
+// with GRO, each of them may hold several packets of `segment_size` bytes.
+pub struct RecvBatch {
+    bufs: Vec<Vec<u8>>,
+    lens: Vec<usize>,
+    segment_sizes: Vec<usize>,
+    addrs: Vec<Option<SocketAddr>>,
+}
+
+impl RecvBatch {
+    pub fn new(batch_size: usize, gro: bool) -> Self {
+        let batch_size = batch_size.max(1);
+        let buf_size = if gro {
+            MAX_SEGMENTED_SIZE
+        } else {
+            MAX_DATAGRAM_SIZE
+        };
+        stats::datapath_buffer_allocated(batch_size * buf_size);
+        Self {
+            bufs: vec![vec![0; buf_size]; batch_size],
+            lens: vec![0; batch_size],
+            segment_sizes: vec![0; batch_size],
+            addrs: vec![None; batch_size],
+        }
+    }
+
+    // returns the number of received datagrams, or WouldBlock if there is nothing to read
+    #[cfg(target_os = "linux")]
+    pub fn recv(&mut self, sock: &UdpSocket) -> io::Result<usize> {
+        let n = self.bufs.len();
+        let mut names: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; n];
+        let mut controls: Vec<u64> = vec![0; n * CONTROL_WORDS];
+        let mut iovecs: Vec<libc::iovec> = self
+            .bufs
+            .iter_mut()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            })
+            .collect();
+        let mut msgs: Vec<libc::mmsghdr> = (0..n)
+            .map(|i| {
+                let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+                msg.msg_hdr.msg_name = &mut names[i] as *mut _ as *mut libc::c_void;
+                msg.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as u32;
+                msg.msg_hdr.msg_iov = &mut iovecs[i];
+                msg.msg_hdr.msg_iovlen = 1;
+                msg.msg_hdr.msg_control =
+                    controls[i * CONTROL_WORDS..].as_mut_ptr() as *mut libc::c_void;
+                msg.msg_hdr.msg_controllen = (CONTROL_WORDS * mem::size_of::<u64>()) as _;
+                msg
+            })
+            .collect();
+
+        let ret = unsafe {
+            libc::recvmmsg(
+                sock.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                n as libc::c_uint,
+                libc::MSG_DONTWAIT,
+                ptr::null_mut(),
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let received = ret as usize;
+        for i in 0..received {
+            let len = msgs[i].msg_len as usize;
+            self.lens[i] = len;
+            self.segment_sizes[i] = gro_segment_size(&msgs[i].msg_hdr).unwrap_or(len);
+            let addr = unsafe { SockAddr::new(names[i], msgs[i].msg_hdr.msg_namelen) };
+            self.addrs[i] = addr.as_socket();
+        }
+        Ok(received)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn recv(&mut self, sock: &UdpSocket) -> io::Result<usize> {
+        let mut received = 0;
+        while received < self.bufs.len() {
+            match sock.recv_from(&mut self.bufs[received]) {
+                Ok((len, from)) => {
+                    self.lens[received] = len;
+                    self.segment_sizes[received] = len;
+                    self.addrs[received] = Some(from);
+                    received += 1;
+                }
+                Err(e) => {
+                    if received == 0 {
+                        return Err(e);
+                    }
+                    break;
+                }
+            }
+        }
+        Ok(received)
+    }
+
+    // returns the received datagram and the size of the packets merged in it
+    pub fn get_mut(&mut self, i: usize) -> Option<(&mut [u8], usize, SocketAddr)> {
+        let from = self.addrs[i]?;
+        let segment_size = self.segment_sizes[i].max(1);
+        Some((&mut self.bufs[i][..self.lens[i]], segment_size, from))
+    }
+}
+
+impl Drop for RecvBatch {
+    fn drop(&mut self) {
+        stats::datapath_buffer_freed(self.bufs.iter().map(|buf| buf.len()).sum());
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn gro_segment_size(hdr: &libc::msghdr) -> Option<usize> {
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(hdr) };
+    while !cmsg.is_null() {
+        let (level, ty) = unsafe { ((*cmsg).cmsg_level, (*cmsg).cmsg_type) };
+        if level == libc::SOL_UDP && ty == UDP_GRO {
+            let size = unsafe { ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int) };
+            return Some(size as usize);
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(hdr, cmsg) };
+    }
+    None
+}
+
+// queues outgoing datagrams and sends up to `batch_size` messages per syscall
+// with sendmmsg(2) on linux, falls back to send_to() for each datagram elsewhere.
+// with GSO, consecutive datagrams of the same size for the same peer are sent as
+// one message and split by the kernel (or the NIC).
+pub struct SendBatch {
+    sock: Arc<UdpSocket>,
+    batch_size: usize,
+    gso: bool,
+    buf: Vec<u8>,
+    segments: Vec<(usize, usize, SocketAddr)>,
+}
+
+impl SendBatch {
+    pub fn new(sock: Arc<UdpSocket>, batch_size: usize, gso: bool) -> Self {
+        let batch_size = batch_size.max(1);
+        let buf = Vec::with_capacity(batch_size * MAX_DATAGRAM_SIZE);
+        stats::datapath_buffer_allocated(buf.capacity());
+        Self {
+            sock,
+            batch_size,
+            gso,
+            buf,
+            segments: Vec::with_capacity(batch_size),
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8], to: SocketAddr) {
+        let offset = self.buf.len();
+        let capacity = self.buf.capacity();
+        self.buf.extend_from_slice(data);
+        if self.buf.capacity() > capacity {
+            stats::datapath_buffer_allocated(self.buf.capacity() - capacity);
+        }
+        self.segments.push((offset, data.len(), to));
+        let limit = if self.gso {
+            self.batch_size * MAX_SEGMENTS
+        } else {
+            self.batch_size
+        };
+        if self.segments.len() >= limit {
+            self.flush();
+        }
+    }
+
+    // returns the end of the run of segments which can be sent as one message
+    fn next_message(&self, start: usize) -> usize {
+        let (_, size, to) = self.segments[start];
+        let mut end = start + 1;
+        if !self.gso {
+            return end;
+        }
+        let mut total = size;
+        while end < self.segments.len() && end - start < MAX_SEGMENTS {
+            let (_, len, next_to) = self.segments[end];
+            if next_to != to || len > size || total + len > MAX_SEGMENTED_SIZE {
+                break;
+            }
+            total += len;
+            end += 1;
+            // only the last segment can be shorter
+            if len < size {
+                break;
+            }
+        }
+        end
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn flush(&mut self) {
+        let mut sent = 0;
+        while sent < self.segments.len() {
+            let mut messages: Vec<(usize, usize)> = Vec::with_capacity(self.batch_size);
+            let mut start = sent;
+            while start < self.segments.len() && messages.len() < self.batch_size {
+                let end = self.next_message(start);
+                messages.push((start, end));
+                start = end;
+            }
+
+            let names: Vec<SockAddr> = messages
+                .iter()
+                .map(|(start, _)| self.segments[*start].2.into())
+                .collect();
+            let mut controls: Vec<u64> = vec![0; messages.len() * CONTROL_WORDS];
+            let mut iovecs: Vec<libc::iovec> = messages
+                .iter()
+                .map(|(start, end)| {
+                    let offset = self.segments[*start].0;
+                    let (last_offset, last_len, _) = self.segments[*end - 1];
+                    libc::iovec {
+                        iov_base: self.buf[offset..].as_ptr() as *mut libc::c_void,
+                        iov_len: last_offset + last_len - offset,
+                    }
+                })
+                .collect();
+            let mut msgs: Vec<libc::mmsghdr> = messages
+                .iter()
+                .enumerate()
+                .map(|(i, (start, end))| {
+                    let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+                    msg.msg_hdr.msg_name = names[i].as_ptr() as *mut libc::c_void;
+                    msg.msg_hdr.msg_namelen = names[i].len();
+                    msg.msg_hdr.msg_iov = &mut iovecs[i];
+                    msg.msg_hdr.msg_iovlen = 1;
+                    if end - start > 1 {
+                        let segment_size = self.segments[*start].1 as u16;
+                        set_gso_segment_size(
+                            &mut msg.msg_hdr,
+                            &mut controls[i * CONTROL_WORDS..(i + 1) * CONTROL_WORDS],
+                            segment_size,
+                        );
+                    }
+                    msg
+                })
+                .collect();
+
+            let ret = unsafe {
+                libc::sendmmsg(
+                    self.sock.as_raw_fd(),
+                    msgs.as_mut_ptr(),
+                    msgs.len() as libc::c_uint,
+                    0,
+                )
+            };
+            if ret <= 0 {
+                let e = io::Error::last_os_error();
+                // the NIC can't offload segmentation, send them one by one
+                if self.gso && e.raw_os_error() == Some(libc::EIO) {
+                    warn!("UDP GSO is not available on this interface, disabled");
+                    self.gso = false;
+                    continue;
+                }
+                // QUIC recovers lost packets, drop the rest of this batch
+                debug!("sendmmsg error: {:?}", e);
+                break;
+            }
+            sent = messages[ret as usize - 1].1;
+        }
+        self.buf.clear();
+        self.segments.clear();
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn flush(&mut self) {
+        for (offset, len, to) in self.segments.iter() {
+            if let Err(e) = self.sock.send_to(&self.buf[*offset..*offset + *len], *to) {
+                debug!("send_to error: {:?}", e);
+            }
+        }
+        self.buf.clear();
+        self.segments.clear();
+    }
+}
+
+impl Drop for SendBatch {
+    fn drop(&mut self) {
+        stats::datapath_buffer_freed(self.buf.capacity());
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_gso_segment_size(hdr: &mut libc::msghdr, control: &mut [u64], segment_size: u16) {
+    hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    hdr.msg_controllen = unsafe { libc::CMSG_SPACE(mem::size_of::<u16>() as u32) } as _;
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(hdr);
+        (*cmsg).cmsg_level = libc::SOL_UDP;
+        (*cmsg).cmsg_type = UDP_SEGMENT;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as _;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, segment_size);
+    }
+}
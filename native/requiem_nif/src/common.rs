@@ -1,8 +1,16 @@
-use rustler::Atom;
+use std::thread;
+
+use crossbeam_channel::{bounded, Sender};
+use quiche::h3::webtransport;
+use rustler::env::OwnedEnv;
+use rustler::types::LocalPid;
+use rustler::{Atom, Env, NifResult, Term};
 
 pub(crate) mod atoms {
     rustler::atoms! {
         ok,
+        continue_ = "continue",
+        infinity,
         system_error,
         socket_error,
         cant_receive,
@@ -12,15 +20,151 @@ pub(crate) mod atoms {
         bad_format,
         bad_state,
         not_found,
+        not_supported,
+        // HTTP/3 errors (RFC 9114 Section 8.1)
+        h3_no_error,
+        general_protocol_error,
+        internal_error,
+        stream_creation_error,
+        closed_critical_stream,
+        frame_unexpected,
+        frame_error,
+        excessive_load,
+        id_error,
+        settings_error,
+        missing_settings,
+        request_rejected,
+        request_cancelled,
+        request_incomplete,
+        message_error,
+        connect_error,
+        version_fallback,
+        qpack_decompression_failed,
+        stream_blocked,
+        buffer_too_short,
+        transport_error,
+        unknown_error,
+        // QUIC transport errors (quiche::Error)
+        done,
+        unknown_version,
+        invalid_frame,
+        invalid_packet,
+        invalid_state,
+        invalid_stream_state,
+        invalid_transport_param,
+        crypto_fail,
+        tls_fail,
+        flow_control,
+        stream_limit,
+        stream_stopped,
+        stream_reset,
+        final_size,
+        congestion_control,
+        too_many_requests,
+        too_many_headers,
+        field_section_too_large,
+        connections,             // memory stats
+        connection_buffer_bytes, // memory stats
+        datapath_buffer_bytes,   // memory stats
+        quiche_connection_bytes, // memory stats
+        jemalloc,                // allocator
+        mimalloc,                // allocator
+        system,                  // allocator
+        allocated,               // allocator stats
+        active,                  // allocator stats
+        resident,                // allocator stats
+        qlog,                    // qlog event streamed to the subscriber
+        slow_call,               // NIF call exceeded the threshold of the watcher
+        accepted,                // telemetry
+        refused,                 // telemetry
+        packets_in,              // telemetry
+        packets_out,             // telemetry
+        bytes_in,                // telemetry
+        bytes_out,               // telemetry
+        handshake_failures,      // telemetry
+        dgram_drops,             // telemetry
+        decrypt_failed,          // packet drop reason
+        unknown_cid,             // packet drop reason
+        invalid_header,          // packet drop reason
+        other,                   // packet drop reason
+        slow_start,              // congestion state
+        congestion_avoidance,    // congestion state
+        recovery,                // congestion state
+        persistent_congestion,   // congestion state
+        tap,                     // datagram copied by a packet tap
+        inbound,                 // packet tap direction
+        outbound,                // packet tap direction
+        error,                   // log level
+        warn,                    // log level
+        info,                    // log level
+        debug,                   // log level
+        trace,                   // log level
+        local,                   // side which closed the connection
+        remote,                  // side which closed the connection
+        transport,               // kind of the connection close error
+        application,             // kind of the connection close error
+        dgram,                   // native feature
+        h3,                      // native feature
+        webtransport,            // native feature
+        trace_id,                // handoff state
+        source_id,               // handoff state
+        destination_id,          // handoff state
+        peer,                    // handoff state
+        alpn,                    // handoff state
+        server_name,             // handoff state
+        session_id,              // handoff state
+        traceparent,             // handoff state
+        established,             // handoff state
+        raw,                     // stream framing
+        length_prefix,           // stream framing
+        varint_prefix,           // stream framing
+        newline,                 // stream framing
+        header,                  // fuzz target
+        token,                   // fuzz target
+        recv,                    // fuzz target
+        dgram_first,             // send order
+        stream_first,            // send order
+        dgram_share,             // send order
+        packets,                 // event class
+        streams,                 // event class
+        dgrams,                  // event class
+        lifecycle,               // event class
         __drain__,
+        __log__, // log record forwarded to the elixir logger
         __packet__,
         __connect__, // webtransport connect request
         __reset__, // connected stream received http3 reset event
         __session_finished__, // connected stream received http3 finished event
         __stream_finished__, // connected stream received http3 finished event
         __goaway__, // connected stream received http3 goaway event
-        __stream_recv__,
-        __dgram_recv__,
+        __h3_headers__,  // http3 event which is not consumed by webtransport session
+        __h3_data__,     // http3 event which is not consumed by webtransport session
+        __h3_finished__, // http3 event which is not consumed by webtransport session
+        __h3_reset__,    // http3 event which is not consumed by webtransport session
+        __h3_goaway__,   // http3 event which is not consumed by webtransport session
+        __h3_priority_update__, // http3 event which is not consumed by webtransport session
+        __request_rejected__, // connect request exceeded the request limits
+        __wt_stream_recv__, // webtransport stream data with its session id
+        __wt_dgram_recv__,  // webtransport datagram with its session id
+        __h3_dgram_recv__,  // HTTP datagram which doesn't belong to webtransport session
+        __accept__,         // native datapath accepted new connection
+        __closed__,            // connection has been closed
+        __closing__,           // connection has started closing
+        __handshake_started__, // connection received its first packet
+        __handshake_done__,    // connection completed handshake
+        __path_migrated__,     // connection received a packet from a new peer address
+        __path_validated__,    // peer responded to the PATH_CHALLENGE of its path
+        __path_failed__,       // peer didn't respond to the PATH_CHALLENGE of a probe in time
+        __congestion__,        // congestion controller changed its state
+        __pmtu__,              // path MTU changed
+        __at__,                // an event with when it was emitted and its packet received
+        __connection_error__,  // quiche failed to write a packet, the connection is closed
+        __stream_reset__,      // peer reset a stream with an application error code
+        __stream_readable__,   // stream has data for stream_read() in pull mode
+        __dgram_readable__,    // datagrams are queued for dgram_recv() in pull mode
+        __stream_closed__,     // stream has been collected by quiche
+        __stream_expired__,    // stream was reset as its data missed the send deadline
+        __shutdown__,          // the module is shutting down, the owner closes the connection
         initial,             // packet type
         handshake,           // packet type
         retry,               // packet type
@@ -33,3 +177,156 @@ pub(crate) mod atoms {
 pub(crate) fn error_term(reason: Atom) -> rustler::Error {
     rustler::Error::Term(Box::new(reason))
 }
+
+// sends messages to erlang processes, either from inside a NIF call (Env)
+// or from a native thread (OwnedEnv).
+pub(crate) trait Emitter {
+    fn emit<F>(&mut self, pid: &LocalPid, f: F)
+    where
+        F: for<'a> FnOnce(Env<'a>) -> Term<'a>;
+}
+
+impl<'e> Emitter for Env<'e> {
+    fn emit<F>(&mut self, pid: &LocalPid, f: F)
+    where
+        F: for<'a> FnOnce(Env<'a>) -> Term<'a>,
+    {
+        self.send(pid, f(*self));
+    }
+}
+
+impl Emitter for OwnedEnv {
+    fn emit<F>(&mut self, pid: &LocalPid, f: F)
+    where
+        F: for<'a> FnOnce(Env<'a>) -> Term<'a>,
+    {
+        self.send_and_clear(pid, f);
+    }
+}
+
+// OwnedEnv can't send from the scheduler threads which run the NIFs, so what's queued on
+// the returned channel is sent to the process by a thread of its own, as the term
+// make_term builds. the thread ends when the channel is dropped, and what's queued
+// beyond capacity is for the callers to drop.
+pub(crate) fn spawn_sender<T, F>(
+    name: &str,
+    pid: LocalPid,
+    capacity: usize,
+    make_term: F,
+) -> NifResult<Sender<T>>
+where
+    T: Send + 'static,
+    F: for<'a> Fn(Env<'a>, T) -> Term<'a> + Send + 'static,
+{
+    let (tx, rx) = bounded::<T>(capacity);
+    thread::Builder::new()
+        .name(name.into())
+        .spawn(move || {
+            let mut env = OwnedEnv::new();
+            for item in rx.iter() {
+                env.send_and_clear(&pid, |env| make_term(env, item));
+            }
+        })
+        .map_err(|_| error_term(atoms::system_error()))?;
+    Ok(tx)
+}
+
+// HTTP/3 application error code on the wire to atom
+pub(crate) fn h3_error_code_atom(code: u64) -> Atom {
+    match code {
+        0x100 => atoms::h3_no_error(),
+        0x101 => atoms::general_protocol_error(),
+        0x102 => atoms::internal_error(),
+        0x103 => atoms::stream_creation_error(),
+        0x104 => atoms::closed_critical_stream(),
+        0x105 => atoms::frame_unexpected(),
+        0x106 => atoms::frame_error(),
+        0x107 => atoms::excessive_load(),
+        0x108 => atoms::id_error(),
+        0x109 => atoms::settings_error(),
+        0x10a => atoms::missing_settings(),
+        0x10b => atoms::request_rejected(),
+        0x10c => atoms::request_cancelled(),
+        0x10d => atoms::request_incomplete(),
+        0x10e => atoms::message_error(),
+        0x10f => atoms::connect_error(),
+        0x110 => atoms::version_fallback(),
+        0x200 => atoms::qpack_decompression_failed(),
+        _ => atoms::unknown_error(),
+    }
+}
+
+pub(crate) fn quiche_error_atom(e: &quiche::Error) -> Atom {
+    // the stream ID and error code of some variants are dropped,
+    // the callers which need them match the variants themselves
+    #[allow(unreachable_patterns)]
+    match e {
+        quiche::Error::Done => atoms::done(),
+        quiche::Error::BufferTooShort => atoms::buffer_too_short(),
+        quiche::Error::UnknownVersion => atoms::unknown_version(),
+        quiche::Error::InvalidFrame => atoms::invalid_frame(),
+        quiche::Error::InvalidPacket => atoms::invalid_packet(),
+        quiche::Error::InvalidState => atoms::invalid_state(),
+        quiche::Error::InvalidStreamState(_) => atoms::invalid_stream_state(),
+        quiche::Error::InvalidTransportParam => atoms::invalid_transport_param(),
+        quiche::Error::CryptoFail => atoms::crypto_fail(),
+        quiche::Error::TlsFail => atoms::tls_fail(),
+        quiche::Error::FlowControl => atoms::flow_control(),
+        quiche::Error::StreamLimit => atoms::stream_limit(),
+        quiche::Error::StreamStopped(_) => atoms::stream_stopped(),
+        quiche::Error::StreamReset(_) => atoms::stream_reset(),
+        quiche::Error::FinalSize => atoms::final_size(),
+        quiche::Error::CongestionControl => atoms::congestion_control(),
+        // added by later versions of quiche
+        _ => atoms::transport_error(),
+    }
+}
+
+pub(crate) fn h3_error_atom(e: &quiche::h3::Error) -> Atom {
+    match e {
+        quiche::h3::Error::TransportError(e) => quiche_error_atom(e),
+        quiche::h3::Error::StreamBlocked => atoms::stream_blocked(),
+        quiche::h3::Error::BufferTooShort => atoms::buffer_too_short(),
+        e => h3_error_code_atom(e.to_wire()),
+    }
+}
+
+pub(crate) fn webtransport_error_atom(e: &webtransport::Error) -> Atom {
+    match e {
+        webtransport::Error::HTTPError(e) => h3_error_atom(e),
+        webtransport::Error::TransportError(e) => quiche_error_atom(e),
+        webtransport::Error::BufferTooShort => atoms::buffer_too_short(),
+        _ => atoms::system_error(),
+    }
+}
+
+// decode QUIC variable-length integer (RFC 9000 Section 16),
+// returns the value and the number of bytes consumed.
+pub(crate) fn decode_varint(buf: &[u8]) -> Option<(u64, usize)> {
+    let first = *buf.first()?;
+    let len = 1usize << (first >> 6);
+    if buf.len() < len {
+        return None;
+    }
+    let mut v: u64 = (first & 0x3f) as u64;
+    for b in &buf[1..len] {
+        v = (v << 8) | (*b as u64);
+    }
+    Some((v, len))
+}
+
+// encode QUIC variable-length integer (RFC 9000 Section 16)
+pub(crate) fn encode_varint(v: u64, buf: &mut Vec<u8>) -> Result<(), Atom> {
+    if v < (1 << 6) {
+        buf.push(v as u8);
+    } else if v < (1 << 14) {
+        buf.extend_from_slice(&((v as u16) | 0x4000).to_be_bytes());
+    } else if v < (1 << 30) {
+        buf.extend_from_slice(&((v as u32) | 0x8000_0000).to_be_bytes());
+    } else if v < (1 << 62) {
+        buf.extend_from_slice(&(v | 0xc000_0000_0000_0000).to_be_bytes());
+    } else {
+        return Err(atoms::bad_format());
+    }
+    Ok(())
+}
@@ -30,7 +30,7 @@ pub fn config_destroy(conf_ptr: i64) -> NifResult<Atom> {
     Ok(atoms::ok())
 }
 
-#[rustler::nif]
+#[rustler::nif(schedule = "DirtyIo")]
 pub fn config_load_cert_chain_from_pem_file(conf_ptr: i64, file: Binary) -> NifResult<Atom> {
     let file = str::from_utf8(file.as_slice()).unwrap();
     let conf_ptr = conf_ptr as *mut quiche::Config;
@@ -38,7 +38,7 @@ pub fn config_load_cert_chain_from_pem_file(conf_ptr: i64, file: Binary) -> NifR
     set_config(cp, |config| config.load_cert_chain_from_pem_file(file))
 }
 
-#[rustler::nif]
+#[rustler::nif(schedule = "DirtyIo")]
 pub fn config_load_priv_key_from_pem_file(conf_ptr: i64, file: Binary) -> NifResult<Atom> {
     let file = str::from_utf8(file.as_slice()).unwrap();
     let conf_ptr = conf_ptr as *mut quiche::Config;
@@ -46,7 +46,7 @@ pub fn config_load_priv_key_from_pem_file(conf_ptr: i64, file: Binary) -> NifRes
     set_config(cp, |config| config.load_priv_key_from_pem_file(file))
 }
 
-#[rustler::nif]
+#[rustler::nif(schedule = "DirtyIo")]
 pub fn config_load_verify_locations_from_file(conf_ptr: i64, file: Binary) -> NifResult<Atom> {
     let file = str::from_utf8(file.as_slice()).unwrap();
     let conf_ptr = conf_ptr as *mut quiche::Config;
@@ -54,7 +54,7 @@ pub fn config_load_verify_locations_from_file(conf_ptr: i64, file: Binary) -> Ni
     set_config(cp, |config| config.load_verify_locations_from_file(file))
 }
 
-#[rustler::nif]
+#[rustler::nif(schedule = "DirtyIo")]
 pub fn config_load_verify_locations_from_directory(conf_ptr: i64, dir: Binary) -> NifResult<Atom> {
     let dir = str::from_utf8(dir.as_slice()).unwrap();
     let conf_ptr = conf_ptr as *mut quiche::Config;
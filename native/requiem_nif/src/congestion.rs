@@ -0,0 +1,74 @@
+use rustler::Atom;
+
+use crate::common::atoms;
+
+// quiche's minimum congestion window is 2 full-sized datagrams
+const MINIMUM_WINDOW_PACKETS: usize = 2;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum CongestionState {
+    SlowStart,
+    CongestionAvoidance,
+    Recovery,
+    PersistentCongestion,
+}
+
+impl CongestionState {
+    pub fn to_atom(self) -> Atom {
+        match self {
+            CongestionState::SlowStart => atoms::slow_start(),
+            CongestionState::CongestionAvoidance => atoms::congestion_avoidance(),
+            CongestionState::Recovery => atoms::recovery(),
+            CongestionState::PersistentCongestion => atoms::persistent_congestion(),
+        }
+    }
+}
+
+// quiche doesn't expose the state of its congestion controller,
+// so it's inferred from the deltas of the lost packets and the window
+pub struct CongestionTracker {
+    state: CongestionState,
+    lost: usize,
+    // the window when the recovery started, it's over once the window grows past it
+    recovery_cwnd: usize,
+}
+
+impl CongestionTracker {
+    pub fn new() -> Self {
+        Self {
+            state: CongestionState::SlowStart,
+            lost: 0,
+            recovery_cwnd: 0,
+        }
+    }
+
+    // the new state when it has changed
+    pub fn update(&mut self, lost: usize, cwnd: usize, mss: usize) -> Option<CongestionState> {
+        let new_lost = lost > self.lost;
+        self.lost = lost;
+        let state = if new_lost && cwnd <= MINIMUM_WINDOW_PACKETS * mss {
+            self.recovery_cwnd = cwnd;
+            CongestionState::PersistentCongestion
+        } else if new_lost {
+            if self.state != CongestionState::Recovery {
+                self.recovery_cwnd = cwnd;
+            }
+            CongestionState::Recovery
+        } else {
+            match self.state {
+                CongestionState::Recovery | CongestionState::PersistentCongestion
+                    if cwnd > self.recovery_cwnd =>
+                {
+                    CongestionState::CongestionAvoidance
+                }
+                state => state,
+            }
+        };
+        if state != self.state {
+            self.state = state;
+            Some(state)
+        } else {
+            None
+        }
+    }
+}
@@ -1,17 +1,39 @@
+use ring::rand::{SecureRandom, SystemRandom};
 use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::rc::Rc;
-use std::net::SocketAddr;
-use ring::rand::{SecureRandom, SystemRandom};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use rustler::types::binary::{Binary, OwnedBinary};
 use rustler::types::tuple::make_tuple;
 use rustler::types::{Encoder, LocalPid};
-use rustler::{Atom, Env, NifResult, ResourceArc};
+use rustler::{Atom, Env, ListIterator, NifResult, ResourceArc, Term};
 
-use crate::common::{self, atoms};
+use crate::batch::SendBatch;
+use crate::common::{self, atoms, Emitter};
+use crate::congestion::CongestionTracker;
+use crate::diagnostics::{self, Probe};
+use crate::framing::{Framing, StreamFramer, TooLarge};
+use crate::handoff::HandoffState;
+use crate::impairment::{Impairment, ImpairmentSpec, ImpairmentTuple};
+#[cfg(feature = "qlog")]
+use crate::qlog::{QlogFile, QlogSinks};
+use crate::qlog::{QlogFileOptions, QlogStream};
+use crate::registry::{self, Registration, Target};
+use crate::sampling::{Sample, SampleTuple, Sampler};
 use crate::socket::Peer;
+use crate::stats;
+use crate::subscribers::{EventClass, Subscribers};
+use crate::tap::{Direction, Tap, TapSpec};
+use crate::telemetry::{self, Counters, DropReason};
+use crate::timer::Timeout;
+use crate::timestamp::{self, Stamped};
+use crate::trace_context::TraceContext;
 use quiche::h3::webtransport::{Error, ServerEvent, ServerSession};
+use quiche::h3::{self, NameValue};
 
 pub struct AddressValidationState {
     validated: bool,
@@ -71,497 +93,3434 @@ impl AddressValidationState {
     }
 }
 
-pub struct Connection {
-    raw: Pin<Box<quiche::Connection>>,
-    peer: ResourceArc<Peer>,
-    sender: LocalPid,
-    dgram_buf: Vec<u8>,
-    stream_buf: Vec<u8>,
-    webtransport: Option<Rc<RefCell<ServerSession>>>,
-    is_established: bool,
-    address_validation: AddressValidationState,
+// a PATH_CHALLENGE sent by probe_path(), which fails unless its PATH_RESPONSE comes back
+// by the deadline
+struct PathProbe {
+    challenge: [u8; 8],
+    deadline: Instant,
 }
 
-impl Connection {
-    pub fn new(
-        raw: Pin<Box<quiche::Connection>>,
-        peer: ResourceArc<Peer>,
-        sender: LocalPid,
-        default_stream_buf_size: usize,
-    ) -> Self {
+// per-field overhead used to calculate the size of a field section (RFC 9114 Section 4.2.2)
+const FIELD_OVERHEAD: usize = 32;
+
+// larger stream sends are split over several NIF calls,
+// so that a giant binary can't hold a scheduler thread for long
+pub const STREAM_SEND_SLICE: usize = 256 * 1024;
+
+// the stream buffer is allocated for each read, it can't be resized beyond this
+pub const MAX_STREAM_BUF_SIZE: usize = 16 * 1024 * 1024;
+
+// stream data held per connection while the flow control of the peer blocks it,
+// the sends beyond it fail with :stream_blocked
+const MAX_PENDING_STREAM_BYTES: usize = 1024 * 1024;
+
+// the urgency of the streams without a priority of their own (RFC 9218)
+const DEFAULT_URGENCY: u8 = 3;
+const MAX_URGENCY: u8 = 7;
+
+// H3_REQUEST_REJECTED, the requests refused by the request limits are reset with it
+const H3_REQUEST_REJECTED: u64 = 0x10b;
+
+// the size of a field section as RFC 9114 Section 4.2.2 counts it
+fn field_section_size<'a>(fields: impl Iterator<Item = (&'a [u8], &'a [u8])>) -> usize {
+    fields
+        .map(|(name, value)| name.len() + value.len() + FIELD_OVERHEAD)
+        .sum()
+}
+
+pub struct RequestLimits {
+    max_field_section_size: usize,
+    max_requests: usize,
+    max_header_count: usize,
+    // the request streams neither finished nor reset yet
+    active: HashSet<u64>,
+}
+
+impl RequestLimits {
+    pub fn new() -> Self {
+        // 0 means unlimited
         Self {
-            raw,
-            peer,
-            sender,
-            dgram_buf: vec![0; 1500],
-            stream_buf: vec![0; default_stream_buf_size],
-            webtransport: None,
-            is_established: false,
-            address_validation: AddressValidationState::new(100),
+            max_field_section_size: 0,
+            max_requests: 0,
+            max_header_count: 0,
+            active: HashSet::new(),
         }
     }
 
-    pub fn initialize_webtransport(&mut self) -> Result<(), Atom> {
-        match ServerSession::with_transport(&mut self.raw) {
-            Ok(server) => {
-                self.webtransport = Some(Rc::new(RefCell::new(server)));
-                Ok(())
-            }
-            Err(e) => {
-                error!("failed to initialize webtransport: {:?}", e);
-                Err(atoms::system_error())
+    pub fn set(
+        &mut self,
+        max_field_section_size: usize,
+        max_requests: usize,
+        max_header_count: usize,
+    ) {
+        self.max_field_section_size = max_field_section_size;
+        self.max_requests = max_requests;
+        self.max_header_count = max_header_count;
+    }
+
+    // returns the reason when the request must be rejected, counts it as active otherwise.
+    // header_count is None when the fields of the request aren't all known.
+    pub fn check(
+        &mut self,
+        stream_id: u64,
+        header_count: Option<usize>,
+        field_section_size: usize,
+    ) -> Option<Atom> {
+        if self.max_requests > 0 && self.active.len() >= self.max_requests {
+            return Some(atoms::too_many_requests());
+        }
+
+        if let Some(count) = header_count {
+            if self.max_header_count > 0 && count > self.max_header_count {
+                return Some(atoms::too_many_headers());
             }
         }
+
+        if self.max_field_section_size > 0 && field_section_size > self.max_field_section_size {
+            return Some(atoms::field_section_too_large());
+        }
+
+        self.active.insert(stream_id);
+        None
     }
 
-    pub fn is_closed(&self) -> bool {
-        self.raw.is_closed()
+    // the request stream finished or was reset, it no longer counts against the limit
+    pub fn release(&mut self, stream_id: u64) {
+        self.active.remove(&stream_id);
     }
+}
 
-    pub fn process_packet(
-        &mut self,
-        env: &Env,
-        pid: &LocalPid,
-        packet: &mut [u8],
-        addr: SocketAddr,
-    ) -> Result<u64, Atom> {
+// size of the binary which stream data is read into.
+// with a range, it follows the amount of data read per event.
+pub struct StreamBufferSize {
+    size: usize,
+    range: Option<(usize, usize)>,
+    observed: usize,
+}
 
-        if self.peer.addr != addr {
-            self.peer = ResourceArc::new(Peer::new(addr));
-            let data = self.address_validation.invalidate();
-            self.raw.send_path_challenge(data);
+impl StreamBufferSize {
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            range: None,
+            observed: size,
         }
+    }
 
-        if !self.raw.is_closed() {
-            let info = quiche::RecvInfo {
-                from: self.peer.addr,
-            };
-            match self.raw.recv(packet, info) {
-                Ok(_len) => {
-                    if !self.is_established && self.raw.is_established() {
-                        info!("established QUIC connection, initialize webtransport.");
-                        self.is_established = true;
-                        self.initialize_webtransport()?;
-                    }
-                    if !self.address_validation.is_validated() {
-                        if let Some(resp) = self.raw.take_path_response() {
-                            self.address_validation.validate(&resp);
-                        }
-                    }
-                    self.poll_webtransport_events(env, pid)?;
-                    self.drain(env);
-                    self.next_timeout()
-                }
-                Err(e) => {
-                    error!("failed to conn.recv: {:?}", e);
-                    Err(atoms::system_error())
-                }
-            }
-        } else {
-            Err(atoms::already_closed())
-        }
+    pub fn get(&self) -> usize {
+        self.size
     }
 
-    pub fn accept_connect_request(&mut self, env: &Env) -> Result<u64, Atom> {
-        if let Some(transport) = &self.webtransport {
-            debug!("webtransport.accept_connect_request");
-            // TODO more extra headers
-            let result = transport
-                .borrow_mut()
-                .accept_connect_request(&mut self.raw, None);
-            match result {
-                Ok(()) => {
-                    self.drain(env);
-                    self.next_timeout()
-                }
-                Err(e) => {
-                    error!("failed to webtransport.accept_connect_request: {:?}", e);
-                    Err(atoms::system_error())
-                }
-            }
-        } else {
-            error!("invalid state: don't call this method before initialize webtransport");
-            Err(atoms::system_error())
-        }
+    pub fn set(&mut self, size: usize) {
+        self.size = size;
+        self.observed = size;
     }
 
-    pub fn reject_connect_request(&mut self, env: &Env, code: u32) -> Result<u64, Atom> {
-        if let Some(transport) = &self.webtransport {
-            debug!("webtransport.reject_connect_request");
-            // TODO more extra headers
-            let result = transport
-                .borrow_mut()
-                .reject_connect_request(&mut self.raw, code, None);
-            match result {
-                Ok(()) => {
-                    self.drain(env);
-                    self.next_timeout()
-                }
-                Err(e) => {
-                    error!("failed to webtransport.reject_connect_request: {:?}", e);
-                    Err(atoms::system_error())
-                }
-            }
-        } else {
-            error!("invalid state: don't call this method before initialize webtransport");
-            Err(atoms::system_error())
+    pub fn set_range(&mut self, min: usize, max: usize) {
+        self.range = Some((min, max));
+        self.size = self.size.clamp(min, max);
+    }
+
+    // the size is kept where the adaptive sizing left it
+    pub fn clear_range(&mut self) {
+        self.range = None;
+        self.observed = self.size;
+    }
+
+    pub fn observe(&mut self, len: usize) {
+        if let Some((min, max)) = self.range {
+            // moving average of the data read per event, rounded up to a power of two
+            self.observed = (self.observed * 7 + len) / 8;
+            self.size = self.observed.next_power_of_two().clamp(min, max);
         }
     }
+}
 
-    pub fn poll_webtransport_events(&mut self, env: &Env, pid: &LocalPid) -> Result<(), Atom> {
-        if let Some(transport) = &self.webtransport {
-            loop {
-                let mut t = transport.borrow_mut();
-                match t.poll(&mut self.raw) {
-                    Ok(ServerEvent::ConnectRequest(req)) => {
-                        let mut authority = OwnedBinary::new(req.authority().len()).unwrap();
-                        authority
-                            .as_mut_slice()
-                            .copy_from_slice(req.authority().as_ref());
+// where the packets built by quiche are delivered
+pub enum Output {
+    // passed to the sender process as `{:__drain__, peer, packet}`
+    Sender(LocalPid),
+    // queued on the send batch of the native datapath, which owns the socket
+    Batch(Rc<RefCell<SendBatch>>),
+}
 
-                        let mut path = OwnedBinary::new(req.path().len()).unwrap();
-                        path.as_mut_slice().copy_from_slice(req.path().as_ref());
+// the streams which have completed are forgotten beyond this, the oldest first
+const MAX_COMPLETED_STREAM_STATS: usize = 1024;
 
-                        let mut origin = OwnedBinary::new(req.origin().len()).unwrap();
-                        origin.as_mut_slice().copy_from_slice(req.origin().as_ref());
+#[derive(Clone, Default)]
+pub struct StreamStats {
+    pub bytes_sent: u64,
+    pub bytes_recv: u64,
+    pub fin_sent: bool,
+    pub fin_recv: bool,
+    // application error codes of RESET_STREAM and STOP_SENDING received from the peer
+    pub reset_code: Option<u64>,
+    pub stop_code: Option<u64>,
+}
 
-                        env.send(
-                            pid,
-                            make_tuple(
-                                *env,
-                                &[
-                                    atoms::__connect__().to_term(*env),
-                                    authority.release(*env).to_term(*env),
-                                    path.release(*env).to_term(*env),
-                                    origin.release(*env).to_term(*env),
-                                ],
-                            ),
-                        );
-                    }
-                    Ok(ServerEvent::StreamData(stream_id)) => {
-                        while let Ok(len) =
-                            t.recv_stream_data(&mut self.raw, stream_id, &mut self.stream_buf)
-                        {
-                            if len > 0 {
-                                let mut data = OwnedBinary::new(len).unwrap();
-                                data.as_mut_slice().copy_from_slice(&self.stream_buf[..len]);
-                                env.send(
-                                    pid,
-                                    make_tuple(
-                                        *env,
-                                        &[
-                                            atoms::__stream_recv__().to_term(*env),
-                                            stream_id.encode(*env),
-                                            data.release(*env).to_term(*env),
-                                        ],
-                                    ),
-                                );
-                            }
-                        }
-                    }
-                    Ok(ServerEvent::Datagram) => loop {
-                        match t.recv_dgram(&mut self.raw, &mut self.dgram_buf) {
-                            Ok((in_session, offset, total_len)) => if in_session {
-                                let len = total_len - offset;
-                                if len > 0 {
-                                    let mut data = OwnedBinary::new(len).unwrap();
-                                    data.as_mut_slice()
-                                        .copy_from_slice(&self.dgram_buf[offset..total_len]);
-
-                                    env.send(
-                                        pid,
-                                        make_tuple(
-                                            *env,
-                                            &[
-                                                atoms::__dgram_recv__().to_term(*env),
-                                                data.release(*env).to_term(*env),
-                                            ],
-                                        ),
-                                    );
-                                }
-                            }
-                            Err(Error::Done) => break,
-                            Err(e) => {
-                                error!("failed to receive dgram: {:?}", e);
-                                return Err(atoms::system_error());
-                            }
-                        }
-                    },
-                    Ok(ServerEvent::SessionReset(_e)) => {
-                        env.send(pid, atoms::__reset__().to_term(*env));
-                    }
-                    Ok(ServerEvent::SessionFinished) => {
-                        env.send(pid, atoms::__session_finished__().to_term(*env));
-                    }
-                    Ok(ServerEvent::StreamFinished(stream_id)) => {
-                        env.send(
-                            pid,
-                            make_tuple(
-                                *env,
-                                &[
-                                    atoms::__stream_finished__().to_term(*env),
-                                    stream_id.encode(*env),
-                                ],
-                            ),
-                        );
-                    }
-                    Ok(ServerEvent::SessionGoAway) => {
-                        env.send(pid, atoms::__goaway__().to_term(*env));
-                    }
-                    Ok(ServerEvent::Other(sid, ev)) => {
-                        debug!("an event which is not related to WebTransport: stream_id({}), event({:?})", sid, ev);
-                    }
-                    Err(Error::Done) => break,
-                    Err(e) => {
-                        error!("poll http3 event caught error: :{:?}", e);
-                        return Err(atoms::system_error());
-                    }
-                }
-            }
-        }
-        Ok(())
+// {bytes_sent, bytes_recv, fin_sent, fin_recv, reset_code, stop_code}
+pub type StreamStatsTuple = (u64, u64, bool, bool, Option<u64>, Option<u64>);
+
+impl StreamStats {
+    pub fn to_tuple(&self) -> StreamStatsTuple {
+        (
+            self.bytes_sent,
+            self.bytes_recv,
+            self.fin_sent,
+            self.fin_recv,
+            self.reset_code,
+            self.stop_code,
+        )
     }
 
-    pub fn execute_timeout(&mut self, env: &Env) -> Result<u64, Atom> {
-        if !self.raw.is_closed() {
-            self.raw.on_timeout();
-            self.drain(env);
-            self.next_timeout()
-        } else {
-            Err(atoms::already_closed())
-        }
+    fn is_completed(&self) -> bool {
+        (self.fin_recv || self.reset_code.is_some()) && (self.fin_sent || self.stop_code.is_some())
     }
+}
 
-    pub fn open_stream(&mut self, env: &Env, is_bidi: bool) -> Result<(u64, u64), Atom> {
-        if !self.raw.is_closed() {
-            if let Some(transport) = &self.webtransport {
-                let transport = Rc::clone(transport);
-                let mut transport = transport.borrow_mut();
-                match transport.open_stream(&mut self.raw, is_bidi) {
-                    Ok(stream_id) => {
-                        info!("opened new stream with stream-id: {}", stream_id);
-                        self.drain(env);
-                        self.next_timeout()
-                            .map(|next_timeout| (stream_id, next_timeout))
-                    }
-                    Err(e) => {
-                        error!("failed to open stream: {:?}", e);
-                        Err(atoms::system_error())
-                    }
+#[derive(Default)]
+struct StreamStatsTable {
+    streams: HashMap<u64, StreamStats>,
+    completed: VecDeque<u64>,
+}
+
+impl StreamStatsTable {
+    fn update<F: FnOnce(&mut StreamStats)>(&mut self, stream_id: u64, f: F) {
+        let stats = self.streams.entry(stream_id).or_default();
+        let was_completed = stats.is_completed();
+        f(stats);
+        if !was_completed && stats.is_completed() {
+            self.completed.push_back(stream_id);
+            if self.completed.len() > MAX_COMPLETED_STREAM_STATS {
+                if let Some(oldest) = self.completed.pop_front() {
+                    self.streams.remove(&oldest);
                 }
-            } else {
-                // TODO better error atom
-                Err(atoms::system_error())
             }
-        } else {
-            Err(atoms::already_closed())
         }
     }
 
-    pub fn send_stream_data(
+    fn get(&self, stream_id: u64) -> Option<StreamStats> {
+        self.streams.get(&stream_id).cloned()
+    }
+}
+
+// the streams of the session which quiche hasn't collected yet, by who opened them and
+// their direction, and how many more of them the peer lets the connection open
+#[derive(Default)]
+pub struct StreamCounts {
+    pub local_bidi: u64,
+    pub local_uni: u64,
+    pub remote_bidi: u64,
+    pub remote_uni: u64,
+    pub peer_bidi_left: u64,
+    pub peer_uni_left: u64,
+}
+
+// {{local_bidi, local_uni}, {remote_bidi, remote_uni}, {peer_bidi_left, peer_uni_left}}
+pub type StreamCountsTuple = ((u64, u64), (u64, u64), (u64, u64));
+
+impl StreamCounts {
+    pub fn to_tuple(&self) -> StreamCountsTuple {
+        (
+            (self.local_bidi, self.local_uni),
+            (self.remote_bidi, self.remote_uni),
+            (self.peer_bidi_left, self.peer_uni_left),
+        )
+    }
+}
+
+// the rest of the sends to a stream which the flow control of the peer didn't accept yet,
+// written in order as it gives credit
+#[derive(Default)]
+struct PendingStream {
+    chunks: VecDeque<Vec<u8>>,
+    fin: bool,
+    // when the stream is reset with the code, if the data is still held then
+    expires: Option<(Instant, u64)>,
+}
+
+// what flushing the data held for a stream came to
+#[derive(Debug, PartialEq)]
+enum Flushed {
+    // the flow control took a part of it, the rest is still held
+    Blocked,
+    // all the data is out, and the FIN is still held if fin
+    Drained { fin: bool },
+    // the stream is reset or stopped, the data held for it went with it
+    Failed,
+}
+
+// the streams of a connection with data held, and their bytes which count against
+// MAX_PENDING_STREAM_BYTES
+#[derive(Default)]
+struct PendingStreams {
+    streams: HashMap<u64, PendingStream>,
+    bytes: usize,
+}
+
+impl PendingStreams {
+    fn contains(&self, stream_id: u64) -> bool {
+        self.streams.contains_key(&stream_id)
+    }
+
+    fn stream_ids(&self) -> Vec<u64> {
+        self.streams.keys().copied().collect()
+    }
+
+    // whether len bytes would hold more than MAX_PENDING_STREAM_BYTES, beyond the
+    // capacity the flow control of the stream has now
+    fn over_budget(&self, len: usize, capacity: usize) -> bool {
+        len > capacity && self.bytes + len - capacity > MAX_PENDING_STREAM_BYTES
+    }
+
+    // behind the data already held for the stream. expires is its deadline when it
+    // starts being held now
+    fn hold(
         &mut self,
-        env: &Env,
         stream_id: u64,
-        data: &[u8],
+        chunks: VecDeque<Vec<u8>>,
         fin: bool,
-    ) -> Result<u64, Atom> {
-        let size = data.len();
-        if !self.raw.is_closed() {
-            if let Some(transport) = &self.webtransport {
-                let transport = Rc::clone(transport);
-                let mut pos = 0;
-                loop {
-                    match transport
-                        .borrow_mut()
-                        .send_stream_data(&mut self.raw, stream_id, data)
-                    {
-                        Ok(len) => {
-                            pos += len;
-                            self.drain(env);
-                            if pos >= size {
-                                break;
-                            }
-                        }
-                        Err(Error::Done) => break,
-                        Err(e) => {
-                            error!("failed to send stream data: {:?}", e);
-                            return Err(atoms::system_error());
-                        }
-                    }
+        expires: Option<(Instant, u64)>,
+    ) {
+        self.bytes += chunks.iter().map(Vec::len).sum::<usize>();
+        let pending = self
+            .streams
+            .entry(stream_id)
+            .or_insert_with(|| PendingStream {
+                expires,
+                ..Default::default()
+            });
+        pending.chunks.extend(chunks);
+        pending.fin |= fin;
+    }
+
+    fn remove(&mut self, stream_id: u64) -> Option<PendingStream> {
+        let pending = self.streams.remove(&stream_id)?;
+        self.bytes -= pending.chunks.iter().map(Vec::len).sum::<usize>();
+        Some(pending)
+    }
+
+    // passes the chunks held for the stream to write in order, which returns how much
+    // of a chunk the flow control took. the stream is kept until remove() once drained
+    fn flush<F>(&mut self, stream_id: u64, mut write: F) -> Flushed
+    where
+        F: FnMut(&[u8]) -> Result<usize, Atom>,
+    {
+        let pending = match self.streams.get_mut(&stream_id) {
+            Some(pending) => pending,
+            None => return Flushed::Drained { fin: false },
+        };
+        while let Some(chunk) = pending.chunks.front_mut() {
+            match write(chunk) {
+                Ok(written) if written < chunk.len() => {
+                    chunk.drain(..written);
+                    self.bytes -= written;
+                    return Flushed::Blocked;
                 }
-                if fin {
-                    let _ = self.raw.stream_send(stream_id, b"", true);
+                Ok(written) => {
+                    pending.chunks.pop_front();
+                    self.bytes -= written;
+                }
+                Err(_reason) => {
+                    self.remove(stream_id);
+                    return Flushed::Failed;
                 }
-                self.next_timeout()
-            } else {
-                // TODO better error atom
-                Err(atoms::system_error())
             }
-        } else {
-            Err(atoms::already_closed())
         }
+        Flushed::Drained { fin: pending.fin }
     }
 
-    pub fn send_dgram(&mut self, env: &Env, data: &[u8]) -> Result<u64, Atom> {
-        if !self.raw.is_closed() {
-            if let Some(transport) = &self.webtransport {
-                let transport = Rc::clone(transport);
-                let mut transport = transport.borrow_mut();
-                match transport.send_dgram(&mut self.raw, data) {
-                    Ok(()) => {
-                        self.drain(env);
-                        self.next_timeout()
-                    }
-                    Err(_e) => Err(atoms::system_error()),
-                }
+    // the streams whose deadline has come, with the code they're reset with
+    fn expired(&self, now: Instant) -> Vec<(u64, u64)> {
+        self.streams
+            .iter()
+            .filter_map(|(stream_id, pending)| match pending.expires {
+                Some((at, code)) if at <= now => Some((*stream_id, code)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn next_expiry(&self) -> Option<Instant> {
+        self.streams
+            .values()
+            .filter_map(|pending| pending.expires)
+            .map(|(at, _)| at)
+            .min()
+    }
+}
+
+// how long the data of a stream may be held for the flow control of the peer,
+// and the application error code the stream is reset with after that
+#[derive(Clone, Copy)]
+pub struct SendDeadline {
+    pub timeout: Duration,
+    pub code: u64,
+}
+
+impl SendDeadline {
+    // {timeout_ms, code}
+    pub fn from_tuple((timeout_ms, code): (u64, u64)) -> Self {
+        Self {
+            timeout: Duration::from_millis(timeout_ms),
+            code,
+        }
+    }
+}
+
+// which of the DATAGRAMs and the stream data written at the same time goes out first.
+// quiche puts the DATAGRAM frames first in a packet, StreamFirst gives them what the
+// stream data leaves of the congestion window instead, and Share the given percent of
+// the window before the stream data, the rest after it.
+#[derive(Clone, Copy, PartialEq)]
+pub enum SendOrder {
+    DgramFirst,
+    StreamFirst,
+    Share(u8),
+}
+
+impl SendOrder {
+    // :dgram_first, :stream_first or {:dgram_share, percent}
+    pub fn from_term(term: Term) -> Result<Self, Atom> {
+        if let Ok(atom) = term.decode::<Atom>() {
+            if atom == atoms::dgram_first() {
+                Ok(SendOrder::DgramFirst)
+            } else if atom == atoms::stream_first() {
+                Ok(SendOrder::StreamFirst)
             } else {
-                // TODO better error atom
-                Err(atoms::system_error())
+                Err(atoms::bad_format())
             }
         } else {
-            Err(atoms::already_closed())
+            match term.decode::<(Atom, u8)>() {
+                Ok((atom, percent)) if atom == atoms::dgram_share() && percent <= 100 => {
+                    Ok(SendOrder::Share(percent))
+                }
+                _ => Err(atoms::bad_format()),
+            }
         }
     }
+}
 
-    pub fn close(&mut self, env: &Env, app: bool, err: u64, reason: &[u8]) -> Result<u64, Atom> {
-        if !self.raw.is_closed() {
-            match self.raw.close(app, err, reason) {
-                Ok(()) => {
-                    self.drain(env);
-                    self.next_timeout()
-                }
-
-                Err(quiche::Error::Done) => self.next_timeout(),
+// a PATH_CHALLENGE is sent when the connection has sent nothing for `interval`, so that the NAT
+// bindings on the path and the idle timeout of the peer don't expire
+struct Keepalive {
+    interval: Duration,
+    due: Instant,
+}
 
-                Err(_e) => Err(atoms::system_error()),
-            }
-        } else {
-            Err(atoms::already_closed())
+impl Keepalive {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            due: Instant::now() + interval,
         }
     }
 
-    fn drain(&mut self, env: &Env) {
-        loop {
-            match self.raw.send(&mut self.dgram_buf) {
-                Ok((len, _send_info)) => {
-                    if self.address_validation.check_sendable() {
-                        let mut packet = OwnedBinary::new(len).unwrap();
-                        packet
-                            .as_mut_slice()
-                            .copy_from_slice(&self.dgram_buf[..len]);
-                        env.send(
-                            &self.sender,
-                            make_tuple(
-                                *env,
-                                &[
-                                    atoms::__drain__().to_term(*env),
-                                    self.peer.encode(*env),
-                                    packet.release(*env).to_term(*env),
-                                ],
-                            ),
-                        );
+    // a packet has been sent, the next keepalive waits for another interval
+    fn touch(&mut self) {
+        self.due = Instant::now() + self.interval;
+    }
+
+    // whether a keepalive is due, the one after it being scheduled if so
+    fn poll(&mut self, now: Instant) -> bool {
+        if self.due <= now {
+            self.due = now + self.interval;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// closes the connection with an application error once it has lived `max_lifetime` since
+// the policy was set, or once no stream data nor datagram has gone either way for
+// `max_inactivity`, each a (limit, code)
+struct LifetimePolicy {
+    expires: Option<(Instant, u64)>,
+    max_inactivity: Option<(Duration, u64)>,
+    last_active: Instant,
+}
+
+impl LifetimePolicy {
+    fn new(max_lifetime: Option<(Duration, u64)>, max_inactivity: Option<(Duration, u64)>) -> Self {
+        let now = Instant::now();
+        Self {
+            expires: max_lifetime.map(|(lifetime, code)| (now + lifetime, code)),
+            max_inactivity,
+            last_active: now,
+        }
+    }
+
+    fn active(&mut self) {
+        self.last_active = Instant::now();
+    }
+
+    // when the connection is closed next unless it's active meanwhile
+    fn due(&self) -> Option<Instant> {
+        let idle = self
+            .max_inactivity
+            .map(|(inactivity, _)| self.last_active + inactivity);
+        [self.expires.map(|(at, _)| at), idle]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    // the code and the reason to close the connection with, once a limit is exceeded
+    fn exceeded(&self, now: Instant) -> Option<(u64, &'static [u8])> {
+        match (self.expires, self.max_inactivity) {
+            (Some((at, code)), _) if at <= now => Some((code, b"max lifetime")),
+            (_, Some((inactivity, code))) if self.last_active + inactivity <= now => {
+                Some((code, b"max inactivity"))
+            }
+            _ => None,
+        }
+    }
+}
+
+// credit-based delivery of stream data, set by set_stream_recv_window()
+struct RecvCredit {
+    window: usize,
+    // a stream with more to read at the window is stopped with this code, instead of
+    // being left to the flow control
+    reset_code: Option<u64>,
+    // bytes passed to the owner and not acknowledged yet
+    unacked: HashMap<u64, usize>,
+    // streams whose reading has stopped at the window
+    paused: HashSet<u64>,
+}
+
+impl RecvCredit {
+    fn new(window: usize, reset_code: Option<u64>) -> Self {
+        Self {
+            window,
+            reset_code,
+            unacked: HashMap::new(),
+            paused: HashSet::new(),
+        }
+    }
+
+    fn available(&self, stream_id: u64) -> usize {
+        self.window
+            .saturating_sub(self.unacked.get(&stream_id).copied().unwrap_or(0))
+    }
+
+    fn delivered(&mut self, stream_id: u64, len: usize) {
+        let unacked = self.unacked.entry(stream_id).or_insert(0);
+        *unacked += len;
+        if *unacked >= self.window {
+            self.paused.insert(stream_id);
+        }
+    }
+
+    // whether the stream was paused
+    fn ack(&mut self, stream_id: u64, len: usize) -> bool {
+        if let Some(unacked) = self.unacked.get_mut(&stream_id) {
+            *unacked = unacked.saturating_sub(len);
+            if *unacked == 0 {
+                self.unacked.remove(&stream_id);
+            }
+        }
+        self.paused.remove(&stream_id)
+    }
+
+    fn forget(&mut self, stream_id: u64) {
+        self.unacked.remove(&stream_id);
+        self.paused.remove(&stream_id);
+    }
+}
+
+// who closed the connection and why
+#[derive(Clone)]
+pub struct CloseInfo {
+    remote: bool,
+    is_app: bool,
+    error_code: u64,
+    reason: Vec<u8>,
+}
+
+impl CloseInfo {
+    // {:local | :remote, :transport | :application, error_code, reason}
+    fn to_term<'a>(&self, env: Env<'a>) -> Term<'a> {
+        let mut bin = OwnedBinary::new(self.reason.len()).unwrap();
+        bin.as_mut_slice().copy_from_slice(&self.reason);
+        let side = if self.remote {
+            atoms::remote()
+        } else {
+            atoms::local()
+        };
+        let kind = if self.is_app {
+            atoms::application()
+        } else {
+            atoms::transport()
+        };
+        make_tuple(
+            env,
+            &[
+                side.to_term(env),
+                kind.to_term(env),
+                self.error_code.encode(env),
+                bin.release(env).to_term(env),
+            ],
+        )
+    }
+}
+
+// lifecycle transitions already passed to the owner
+#[derive(Default)]
+struct Lifecycle {
+    handshake_started: bool,
+    handshake_done: bool,
+    closing: bool,
+    closed: bool,
+}
+
+// application data queued by the owner, written to quiche on the packet path
+pub enum Outbound {
+    Stream(u64, Vec<Vec<u8>>, bool),
+    // written whole or not at all, see send_stream_full
+    StreamFull(u64, Vec<Vec<u8>>, bool),
+    Dgram(Vec<u8>),
+    H3Dgram(u64, Vec<u8>),
+}
+
+pub struct Connection {
+    raw: Pin<Box<quiche::Connection>>,
+    peer: ResourceArc<Peer>,
+    output: Output,
+    dgram_buf: Vec<u8>,
+    recv_buf: Vec<u8>,
+    // spare binary for the packets passed to the sender process
+    packet_buf: Option<OwnedBinary>,
+    // packets returned by the *_sync NIFs instead of being passed to the sender process
+    collected: Option<Vec<(ResourceArc<Peer>, OwnedBinary)>>,
+    stream_buf_size: StreamBufferSize,
+    webtransport: Option<ServerSession>,
+    session_id: Option<u64>,
+    is_established: bool,
+    address_validation: AddressValidationState,
+    request_limits: RequestLimits,
+    outbound: VecDeque<Outbound>,
+    pending_streams: PendingStreams,
+    // set by set_send_deadline(), for all the streams and for some of them
+    send_deadline: Option<SendDeadline>,
+    stream_send_deadlines: HashMap<u64, SendDeadline>,
+    // set by set_stream_priority(), the streams written to quiche the most urgent first
+    stream_urgency: HashMap<u64, u8>,
+    // set by set_send_order(), and the DATAGRAMs it holds until the stream data is out
+    send_order: SendOrder,
+    deferred_dgrams: VecDeque<Outbound>,
+    qlog: Option<QlogStream>,
+    counters: Arc<Counters>,
+    // the process receiving the lifecycle events, known from the packet and timeout calls
+    owner: Option<LocalPid>,
+    // set by set_owner(), the process getting the events instead of the calling one
+    routed_owner: Option<LocalPid>,
+    // set by set_subscriber(), the processes getting a class of the events instead
+    subscribers: Subscribers,
+    lifecycle: Lifecycle,
+    stream_stats: StreamStatsTable,
+    // set by enable_congestion_events()
+    congestion: Option<CongestionTracker>,
+    // set by enable_pmtu_events(), the PMTU reported last, 0 before the first report
+    pmtu_events: Option<usize>,
+    // set by enable_event_timestamps(), and when the packet being received came
+    event_timestamps: bool,
+    received_at: Option<u64>,
+    tap: Option<Tap>,
+    // set by enable_sampling()
+    sampler: Option<Sampler>,
+    recv_credit: Option<RecvCredit>,
+    // set by set_stream_framing() with another framing than raw
+    framer: Option<StreamFramer>,
+    // set by set_stream_pull_mode(), the streams notified as readable and not read since
+    pull: Option<HashSet<u64>>,
+    // set by set_dgram_pull_mode(), whether the queued datagrams have been notified
+    dgram_pull: Option<bool>,
+    // set by set_dgram_budget(), the datagrams passed to the owner per packet at most,
+    // and what's left of it in the current one
+    dgram_budget: Option<usize>,
+    dgram_budget_left: usize,
+    // set by set_keepalive()
+    keepalive: Option<Keepalive>,
+    // set by set_lifetime_policy()
+    lifetime_policy: Option<LifetimePolicy>,
+    path_probe: Option<PathProbe>,
+    // the streams not collected by quiche yet, notified once they are after
+    // enable_stream_close_events()
+    live_streams: HashSet<u64>,
+    stream_close_events: bool,
+    // set by set_impairment(), in tests only
+    impairment: Option<Impairment>,
+    // the CONNECTION_CLOSE sent by close()
+    local_error: Option<CloseInfo>,
+    trace_context: Option<TraceContext>,
+    // keeps the connection in the registry of its module until it's dropped
+    registration: Option<Registration>,
+    #[cfg(feature = "qlog")]
+    qlog_sinks: Option<QlogSinks>,
+}
+
+impl Connection {
+    pub fn new(
+        raw: Pin<Box<quiche::Connection>>,
+        peer: ResourceArc<Peer>,
+        output: Output,
+        stream_chunk_size: usize,
+        counters: Arc<Counters>,
+    ) -> Self {
+        let dgram_buf = vec![0; 1500];
+        let recv_buf = vec![0; 1500];
+        stats::connection_created(dgram_buf.len() + recv_buf.len());
+        Self {
+            raw,
+            peer,
+            output,
+            dgram_buf,
+            recv_buf,
+            packet_buf: None,
+            collected: None,
+            stream_buf_size: StreamBufferSize::new(stream_chunk_size.min(MAX_STREAM_BUF_SIZE)),
+            webtransport: None,
+            session_id: None,
+            is_established: false,
+            address_validation: AddressValidationState::new(100),
+            request_limits: RequestLimits::new(),
+            outbound: VecDeque::new(),
+            pending_streams: PendingStreams::default(),
+            send_deadline: None,
+            stream_send_deadlines: HashMap::new(),
+            stream_urgency: HashMap::new(),
+            send_order: SendOrder::DgramFirst,
+            deferred_dgrams: VecDeque::new(),
+            qlog: None,
+            counters,
+            owner: None,
+            routed_owner: None,
+            subscribers: Subscribers::default(),
+            lifecycle: Lifecycle::default(),
+            stream_stats: StreamStatsTable::default(),
+            congestion: None,
+            pmtu_events: None,
+            event_timestamps: false,
+            received_at: None,
+            tap: None,
+            sampler: None,
+            recv_credit: None,
+            framer: None,
+            pull: None,
+            dgram_pull: None,
+            dgram_budget: None,
+            dgram_budget_left: 0,
+            keepalive: None,
+            lifetime_policy: None,
+            path_probe: None,
+            live_streams: HashSet::new(),
+            stream_close_events: false,
+            impairment: None,
+            local_error: None,
+            trace_context: None,
+            registration: None,
+            #[cfg(feature = "qlog")]
+            qlog_sinks: None,
+        }
+    }
+
+    pub fn initialize_webtransport(&mut self) -> Result<(), Atom> {
+        match ServerSession::with_transport(&mut self.raw) {
+            Ok(server) => {
+                self.webtransport = Some(server);
+                Ok(())
+            }
+            Err(e) => {
+                error!("failed to initialize webtransport: {:?}", e);
+                Err(common::webtransport_error_atom(&e))
+            }
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.raw.is_closed()
+    }
+
+    pub fn is_established(&self) -> bool {
+        self.is_established
+    }
+
+    // the CONNECTION_CLOSE sent by the peer, or else the one sent by close().
+    // none when quiche has closed the connection by itself, e.g. on the idle timeout.
+    pub fn close_info(&self) -> Option<CloseInfo> {
+        match self.raw.peer_error() {
+            Some(e) => Some(CloseInfo {
+                remote: true,
+                is_app: e.is_app,
+                error_code: e.error_code,
+                reason: e.reason.clone(),
+            }),
+            None => self.local_error.clone(),
+        }
+    }
+
+    pub fn trace_id(&self) -> &str {
+        self.raw.trace_id()
+    }
+
+    // the CONNECT request received, which the owner is told of with __connect__
+    #[cfg(feature = "fuzzing")]
+    pub fn session_id(&self) -> Option<u64> {
+        self.session_id
+    }
+
+    pub fn handoff_state(&self) -> HandoffState {
+        HandoffState {
+            trace_id: self.raw.trace_id().to_string(),
+            source_id: self.raw.source_id().as_ref().to_vec(),
+            destination_id: self.raw.destination_id().as_ref().to_vec(),
+            peer: self.peer.addr,
+            alpn: self.raw.application_proto().to_vec(),
+            server_name: self.raw.server_name().map(|name| name.to_string()),
+            session_id: self.session_id,
+            traceparent: self.trace_context.as_ref().map(|tc| tc.traceparent()),
+            established: self.is_established,
+        }
+    }
+
+    // the DER certificate the client presented, only asked for with verify_peer,
+    // and only there once it passed the verify locations
+    pub fn peer_cert(&self) -> Option<OwnedBinary> {
+        self.raw.peer_cert().map(|cert| {
+            let mut bin = OwnedBinary::new(cert.len()).unwrap();
+            bin.as_mut_slice().copy_from_slice(&cert);
+            bin
+        })
+    }
+
+    // the events go to `pid` from now on, whichever process calls into the connection,
+    // so that an acceptor can hand the connection over to a handler process
+    pub fn set_owner(&mut self, pid: LocalPid) {
+        self.owner = Some(pid.clone());
+        self.routed_owner = Some(pid);
+    }
+
+    // the process which gets the events of a call made by `pid`
+    fn route(&self, pid: &LocalPid) -> LocalPid {
+        self.routed_owner.clone().unwrap_or_else(|| pid.clone())
+    }
+
+    // the events of the class go to `pid` rather than to the owner, None gives them back.
+    // the packets are passed to a process only on the BEAM path.
+    pub fn set_subscriber(&mut self, class: EventClass, pid: Option<LocalPid>) -> Result<(), Atom> {
+        if class == EventClass::Packets && matches!(self.output, Output::Batch(_)) {
+            return Err(atoms::not_supported());
+        }
+        self.subscribers.set(class, pid);
+        Ok(())
+    }
+
+    // the process which gets an event of the class otherwise going to `pid`
+    pub fn subscriber(&self, class: EventClass, pid: &LocalPid) -> LocalPid {
+        self.subscribers
+            .get(class)
+            .cloned()
+            .unwrap_or_else(|| pid.clone())
+    }
+
+    pub fn set_registration(&mut self, registration: Registration) {
+        self.registration = Some(registration);
+    }
+
+    // set before qlog is enabled, so that the qlog trace refers to the span
+    pub fn set_trace_context(&mut self, context: TraceContext) {
+        self.trace_context = Some(context);
+    }
+
+    pub fn resize_stream_buf(&mut self, size: usize) {
+        self.stream_buf_size.set(size);
+    }
+
+    pub fn set_stream_buf_range(&mut self, min: usize, max: usize) {
+        self.stream_buf_size.set_range(min, max);
+    }
+
+    pub fn clear_stream_buf_range(&mut self) {
+        self.stream_buf_size.clear_range();
+    }
+
+    #[cfg(feature = "qlog")]
+    fn add_qlog_sink(&mut self, sink: Box<dyn std::io::Write + Send + Sync>) {
+        if self.qlog_sinks.is_none() {
+            let sinks = QlogSinks::default();
+            let description = match &self.trace_context {
+                Some(context) => format!(
+                    "requiem id={} traceparent={}",
+                    self.raw.trace_id(),
+                    context.traceparent()
+                ),
+                None => format!("requiem id={}", self.raw.trace_id()),
+            };
+            self.raw
+                .set_qlog(Box::new(sinks.clone()), "requiem".to_string(), description);
+            self.qlog_sinks = Some(sinks);
+        }
+        if let Some(sinks) = &self.qlog_sinks {
+            sinks.add(sink);
+        }
+    }
+
+    // qlog events are passed to the pid as `{:qlog, trace_id, record}` on each drain
+    #[cfg(feature = "qlog")]
+    pub fn stream_qlog(&mut self, pid: LocalPid) -> Result<(), Atom> {
+        if self.qlog.is_some() {
+            return Err(atoms::already_exists());
+        }
+        let qlog = QlogStream::new(pid);
+        self.add_qlog_sink(qlog.writer());
+        self.qlog = Some(qlog);
+        Ok(())
+    }
+
+    #[cfg(not(feature = "qlog"))]
+    pub fn stream_qlog(&mut self, _pid: LocalPid) -> Result<(), Atom> {
+        Err(atoms::not_supported())
+    }
+
+    #[cfg(feature = "qlog")]
+    pub fn qlog_to_file(&mut self, options: &QlogFileOptions) -> Result<(), Atom> {
+        let file = QlogFile::new(options, self.raw.trace_id()).map_err(|e| {
+            debug!("failed to open qlog directory: {}", e);
+            atoms::system_error()
+        })?;
+        self.add_qlog_sink(Box::new(file));
+        Ok(())
+    }
+
+    #[cfg(not(feature = "qlog"))]
+    pub fn qlog_to_file(&mut self, _options: &QlogFileOptions) -> Result<(), Atom> {
+        Err(atoms::not_supported())
+    }
+
+    // stops passing the qlog to the pid and closes the files. quiche keeps its writer,
+    // into no sink, and stream_qlog() or qlog_to_file() start over from there.
+    #[cfg(feature = "qlog")]
+    pub fn qlog_disable(&mut self) -> Result<(), Atom> {
+        self.qlog = None;
+        if let Some(sinks) = &self.qlog_sinks {
+            sinks.clear();
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "qlog"))]
+    pub fn qlog_disable(&mut self) -> Result<(), Atom> {
+        Err(atoms::not_supported())
+    }
+
+    pub fn set_request_limits(
+        &mut self,
+        max_field_section_size: usize,
+        max_requests: usize,
+        max_header_count: usize,
+    ) {
+        self.request_limits
+            .set(max_field_section_size, max_requests, max_header_count);
+    }
+
+    pub fn process_packet<E: Emitter>(
+        &mut self,
+        emitter: &mut E,
+        pid: &LocalPid,
+        packet: &[u8],
+        addr: SocketAddr,
+    ) -> Result<Timeout, Atom> {
+        let pid = &self.route(pid);
+        self.owner = Some(pid.clone());
+        if self.peer.addr != addr {
+            self.peer = ResourceArc::new(Peer::new(addr));
+            let data = self.address_validation.invalidate();
+            self.raw.send_path_challenge(data);
+            // {:__path_migrated__, peer}
+            let peer = self.peer.clone();
+            emitter.emit(pid, move |env| {
+                make_tuple(
+                    env,
+                    &[atoms::__path_migrated__().to_term(env), peer.encode(env)],
+                )
+            });
+        }
+
+        if !self.raw.is_closed() {
+            match self.impairment.as_mut() {
+                Some(impairment) => {
+                    impairment.hold_inbound(packet, addr);
+                    self.recv_held(emitter, pid)?;
+                    self.drain(emitter)?;
+                    self.next_timeout()
+                }
+                None => self.recv(emitter, pid, packet, addr),
+            }
+        } else {
+            Err(atoms::already_closed())
+        }
+    }
+
+    // the packets held by the impairment which are due, errors being those of a bad network
+    fn recv_held<E: Emitter>(&mut self, emitter: &mut E, pid: &LocalPid) -> Result<(), Atom> {
+        let packets = self
+            .impairment
+            .as_mut()
+            .map_or_else(Vec::new, |impairment| impairment.take_inbound());
+        for (packet, addr) in packets {
+            if self.raw.is_closed() {
+                return Err(atoms::already_closed());
+            }
+            if let Err(reason) = self.recv(emitter, pid, &packet, addr) {
+                debug!("impaired packet not received: {:?}", reason);
+            }
+        }
+        Ok(())
+    }
+
+    fn recv<E: Emitter>(
+        &mut self,
+        emitter: &mut E,
+        pid: &LocalPid,
+        packet: &[u8],
+        addr: SocketAddr,
+    ) -> Result<Timeout, Atom> {
+        let info = quiche::RecvInfo {
+            from: self.peer.addr,
+        };
+        // quiche decrypts packets in place, so copy it into the reusable inbox buffer
+        let len = packet.len();
+        self.counters.packet_in(len);
+        if self.event_timestamps {
+            self.received_at = Some(timestamp::monotonic_us());
+        }
+        if let Some(tap) = self.tap.as_mut() {
+            tap.capture(Direction::In, addr, packet);
+        }
+        if self.recv_buf.len() < len {
+            stats::connection_buffer_grown(len - self.recv_buf.len());
+            self.recv_buf.resize(len, 0);
+        }
+        self.recv_buf[..len].copy_from_slice(packet);
+        match self.raw.recv(&mut self.recv_buf[..len], info) {
+            Ok(_len) => {
+                if !self.is_established && self.raw.is_established() {
+                    info!("established QUIC connection, initialize webtransport.");
+                    self.is_established = true;
+                    self.initialize_webtransport()?;
+                }
+                if !self.address_validation.is_validated() || self.path_probe.is_some() {
+                    if let Some(resp) = self.raw.take_path_response() {
+                        self.on_path_response(emitter, pid, &resp);
                     }
                 }
-                Err(quiche::Error::Done) => {
-                    break;
+                self.poll_webtransport_events(emitter, pid)?;
+                self.write_outbound();
+                self.drain(emitter)?;
+                self.next_timeout()
+            }
+            Err(e) => {
+                error!("failed to conn.recv: {:?}", e);
+                self.counters
+                    .packet_dropped(DropReason::from_recv_error(&e));
+                Err(common::quiche_error_atom(&e))
+            }
+        }
+    }
+
+    pub fn accept_connect_request<E: Emitter>(&mut self, emitter: &mut E) -> Result<Timeout, Atom> {
+        if let Some(transport) = self.webtransport.as_mut() {
+            debug!("webtransport.accept_connect_request");
+            // TODO more extra headers
+            match transport.accept_connect_request(&mut self.raw, None) {
+                Ok(()) => {
+                    self.drain(emitter)?;
+                    self.next_timeout()
                 }
-                Err(_e) => {
-                    self.raw.close(false, 0x1, b"fail").ok();
-                    break;
+                Err(e) => {
+                    error!("failed to webtransport.accept_connect_request: {:?}", e);
+                    Err(common::webtransport_error_atom(&e))
                 }
             }
+        } else {
+            error!("invalid state: don't call this method before initialize webtransport");
+            Err(atoms::bad_state())
         }
     }
 
-    fn next_timeout(&mut self) -> Result<u64, Atom> {
-        if let Some(timeout) = self.raw.timeout() {
-            let to: u64 = timeout.as_millis() as u64;
-            Ok(to)
-        } else if self.raw.is_closed() {
-            Err(atoms::already_closed())
+    pub fn reject_connect_request<E: Emitter>(
+        &mut self,
+        emitter: &mut E,
+        code: u32,
+    ) -> Result<Timeout, Atom> {
+        if let Some(transport) = self.webtransport.as_mut() {
+            debug!("webtransport.reject_connect_request");
+            // TODO more extra headers
+            match transport.reject_connect_request(&mut self.raw, code, None) {
+                Ok(()) => {
+                    self.drain(emitter)?;
+                    self.next_timeout()
+                }
+                Err(e) => {
+                    error!("failed to webtransport.reject_connect_request: {:?}", e);
+                    Err(common::webtransport_error_atom(&e))
+                }
+            }
+        } else {
+            error!("invalid state: don't call this method before initialize webtransport");
+            Err(atoms::bad_state())
+        }
+    }
+
+    // the events of the streams and the datagrams, stamped with enable_event_timestamps()
+    pub fn poll_webtransport_events<E: Emitter>(
+        &mut self,
+        emitter: &mut E,
+        pid: &LocalPid,
+    ) -> Result<(), Atom> {
+        let received_at = self.received_at.take();
+        if self.event_timestamps {
+            self.poll_events(&mut Stamped::new(emitter, received_at), pid)
         } else {
-            // unreachable if 'idle_timeout' is set
-            Ok(60000)
+            self.poll_events(emitter, pid)
         }
     }
+
+    fn poll_events<E: Emitter>(&mut self, emitter: &mut E, pid: &LocalPid) -> Result<(), Atom> {
+        if self.webtransport.is_some() {
+            if let Some(budget) = self.dgram_budget {
+                self.dgram_budget_left = budget;
+                // the datagrams left over by the previous packet go first
+                if self.dgram_pull.is_none() {
+                    self.deliver_dgrams(emitter, pid)?;
+                }
+            }
+            loop {
+                // borrowed again on each event, read_stream() needs the whole connection
+                let t = self.webtransport.as_mut().unwrap();
+                match t.poll(&mut self.raw) {
+                    Ok(ServerEvent::ConnectRequest(req)) => {
+                        // the session decodes the request itself and only passes these
+                        // fields on, so their size is a lower bound of the section's,
+                        // and the fields can't be counted
+                        let fields: [(&[u8], &[u8]); 3] = [
+                            (b":authority", req.authority().as_ref()),
+                            (b":path", req.path().as_ref()),
+                            (b"origin", req.origin().as_ref()),
+                        ];
+                        let size = field_section_size(fields.into_iter());
+                        let stream_id = t.session_id().unwrap_or_default();
+                        if let Some(reason) = self.request_limits.check(stream_id, None, size) {
+                            info!("reject connect request which exceeds the request limits");
+                            let code = if reason == atoms::field_section_too_large() {
+                                431
+                            } else {
+                                429
+                            };
+                            if let Err(e) = t.reject_connect_request(&mut self.raw, code, None) {
+                                error!("failed to webtransport.reject_connect_request: {:?}", e);
+                            }
+                            emitter.emit(pid, |env| {
+                                make_tuple(
+                                    env,
+                                    &[
+                                        atoms::__request_rejected__().to_term(env),
+                                        reason.to_term(env),
+                                    ],
+                                )
+                            });
+                            continue;
+                        }
+
+                        // the CONNECT stream's id identifies the session
+                        self.session_id = t.session_id();
+
+                        let mut authority = OwnedBinary::new(req.authority().len()).unwrap();
+                        authority
+                            .as_mut_slice()
+                            .copy_from_slice(req.authority().as_ref());
+
+                        let mut path = OwnedBinary::new(req.path().len()).unwrap();
+                        path.as_mut_slice().copy_from_slice(req.path().as_ref());
+
+                        let mut origin = OwnedBinary::new(req.origin().len()).unwrap();
+                        origin.as_mut_slice().copy_from_slice(req.origin().as_ref());
+
+                        emitter.emit(pid, move |env| {
+                            make_tuple(
+                                env,
+                                &[
+                                    atoms::__connect__().to_term(env),
+                                    authority.release(env).to_term(env),
+                                    path.release(env).to_term(env),
+                                    origin.release(env).to_term(env),
+                                ],
+                            )
+                        });
+                    }
+                    Ok(ServerEvent::StreamData(stream_id)) => {
+                        self.track_stream(stream_id);
+                        self.read_stream(emitter, pid, stream_id);
+                    }
+                    Ok(ServerEvent::Datagram) => {
+                        if let Some(notified) = self.dgram_pull.as_mut() {
+                            // once, until the owner has taken the datagrams queued
+                            if !*notified {
+                                *notified = true;
+                                let pid = &self.subscriber(EventClass::Dgrams, pid);
+                                emitter.emit(pid, |env| atoms::__dgram_readable__().to_term(env));
+                            }
+                        } else {
+                            self.deliver_dgrams(emitter, pid)?;
+                        }
+                    }
+                    Ok(ServerEvent::SessionReset(e)) => {
+                        if let Some(session_id) = self.session_id {
+                            self.request_limits.release(session_id);
+                        }
+                        emitter.emit(pid, |env| {
+                            make_tuple(
+                                env,
+                                &[
+                                    atoms::__reset__().to_term(env),
+                                    common::h3_error_code_atom(e).to_term(env),
+                                ],
+                            )
+                        });
+                    }
+                    Ok(ServerEvent::SessionFinished) => {
+                        if let Some(session_id) = self.session_id {
+                            self.request_limits.release(session_id);
+                        }
+                        emitter.emit(pid, |env| atoms::__session_finished__().to_term(env));
+                    }
+                    Ok(ServerEvent::StreamFinished(stream_id)) => {
+                        self.stream_stats
+                            .update(stream_id, |stats| stats.fin_recv = true);
+                        if let Some(framer) = self.framer.as_mut() {
+                            let dropped = framer.forget(stream_id);
+                            if dropped > 0 {
+                                debug!(
+                                    "stream {} finished within a message, {} bytes dropped",
+                                    stream_id, dropped
+                                );
+                            }
+                        }
+                        let pid = &self.subscriber(EventClass::Streams, pid);
+                        emitter.emit(pid, |env| {
+                            make_tuple(
+                                env,
+                                &[
+                                    atoms::__stream_finished__().to_term(env),
+                                    stream_id.encode(env),
+                                ],
+                            )
+                        });
+                    }
+                    Ok(ServerEvent::SessionGoAway) => {
+                        emitter.emit(pid, |env| atoms::__goaway__().to_term(env));
+                    }
+                    Ok(ServerEvent::Other(sid, ev)) => {
+                        debug!("an event which is not related to WebTransport: stream_id({}), event({:?})", sid, ev);
+                        match &ev {
+                            h3::Event::Headers { list, .. } => {
+                                let size =
+                                    field_section_size(list.iter().map(|h| (h.name(), h.value())));
+                                if let Some(reason) =
+                                    self.request_limits.check(sid, Some(list.len()), size)
+                                {
+                                    info!("reset request {} which exceeds the request limits", sid);
+                                    let _ = self.raw.stream_shutdown(
+                                        sid,
+                                        quiche::Shutdown::Read,
+                                        H3_REQUEST_REJECTED,
+                                    );
+                                    let _ = self.raw.stream_shutdown(
+                                        sid,
+                                        quiche::Shutdown::Write,
+                                        H3_REQUEST_REJECTED,
+                                    );
+                                    emitter.emit(pid, |env| {
+                                        make_tuple(
+                                            env,
+                                            &[
+                                                atoms::__request_rejected__().to_term(env),
+                                                reason.to_term(env),
+                                            ],
+                                        )
+                                    });
+                                    continue;
+                                }
+                            }
+                            h3::Event::Finished | h3::Event::Reset(_) => {
+                                self.request_limits.release(sid);
+                            }
+                            _ => {}
+                        }
+                        send_h3_event(emitter, pid, sid, ev);
+                    }
+                    Err(Error::Done) => break,
+                    Err(e) => {
+                        error!("poll http3 event caught error: :{:?}", e);
+                        return Err(common::webtransport_error_atom(&e));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // passes the readable data of a stream to the owner,
+    // no more than its credit when the delivery is credit-based
+    fn read_stream<E: Emitter>(&mut self, emitter: &mut E, pid: &LocalPid, stream_id: u64) {
+        let pid = &self.subscriber(EventClass::Streams, pid);
+        if let Some(notified) = self.pull.as_mut() {
+            // left in quiche until the owner calls stream_read(), notified once until then
+            if notified.insert(stream_id) {
+                emitter.emit(pid, move |env| {
+                    make_tuple(
+                        env,
+                        &[
+                            atoms::__stream_readable__().to_term(env),
+                            stream_id.encode(env),
+                        ],
+                    )
+                });
+            }
+            return;
+        }
+        let limit = self
+            .recv_credit
+            .as_ref()
+            .map_or(usize::MAX, |credit| credit.available(stream_id));
+        if limit == 0 {
+            if let Some(credit) = self.recv_credit.as_mut() {
+                match credit.reset_code {
+                    Some(code) => {
+                        debug!("stream {} overflows its receive window, stop it", stream_id);
+                        credit.forget(stream_id);
+                        if let Some(framer) = self.framer.as_mut() {
+                            framer.forget(stream_id);
+                        }
+                        let _ = self
+                            .raw
+                            .stream_shutdown(stream_id, quiche::Shutdown::Read, code);
+                    }
+                    // left in quiche, whose flow control holds the peer until stream_ack()
+                    None => {
+                        credit.paused.insert(stream_id);
+                    }
+                }
+            }
+            return;
+        }
+        let t = match self.webtransport.as_mut() {
+            Some(t) => t,
+            None => return,
+        };
+        // read the readable data directly into the binary which will be
+        // passed to the owner in one message, growing it by the chunk size up to
+        // the credit of the stream, then shrink it to the received length.
+        let chunk_size = self.stream_buf_size.get().min(limit);
+        let mut data = OwnedBinary::new(chunk_size).unwrap();
+        let mut total = 0;
+        let mut reset_code = None;
+        loop {
+            if total == data.len() {
+                if total >= limit {
+                    break;
+                }
+                data.realloc_or_copy((total + chunk_size).min(limit));
+            }
+            match t.recv_stream_data(&mut self.raw, stream_id, &mut data.as_mut_slice()[total..]) {
+                Ok(0) => break,
+                Ok(len) => total += len,
+                Err(Error::TransportError(quiche::Error::StreamReset(code))) => {
+                    self.stream_stats
+                        .update(stream_id, |stats| stats.reset_code = Some(code));
+                    reset_code = Some(code);
+                    break;
+                }
+                Err(Error::Done) => break,
+                Err(e) => {
+                    error!("failed to read stream {}: {:?}", stream_id, e);
+                    break;
+                }
+            }
+        }
+        self.stream_stats
+            .update(stream_id, |stats| stats.bytes_recv += total as u64);
+        self.stream_buf_size.observe(total);
+        if total > 0 {
+            self.mark_active();
+            if let Some(framer) = self.framer.as_mut() {
+                // each complete message in a message of its own, the credit counting their payload
+                match framer.feed(stream_id, &data.as_slice()[..total]) {
+                    Ok(messages) => {
+                        for message in messages {
+                            if let Some(credit) = self.recv_credit.as_mut() {
+                                credit.delivered(stream_id, message.len());
+                            }
+                            let mut bin = OwnedBinary::new(message.len()).unwrap();
+                            bin.as_mut_slice().copy_from_slice(&message);
+                            emit_stream_data(emitter, pid, self.session_id, stream_id, bin);
+                        }
+                    }
+                    Err(TooLarge) => {
+                        debug!(
+                            "stream {} announced a message too large, stop reading",
+                            stream_id
+                        );
+                        let _ = self
+                            .raw
+                            .stream_shutdown(stream_id, quiche::Shutdown::Read, 0);
+                    }
+                }
+            } else {
+                if total < data.len() {
+                    data.realloc_or_copy(total);
+                }
+                if let Some(credit) = self.recv_credit.as_mut() {
+                    credit.delivered(stream_id, total);
+                }
+                emit_stream_data(emitter, pid, self.session_id, stream_id, data);
+            }
+        }
+        // after the data read before the reset, no more comes
+        if let Some(code) = reset_code {
+            if let Some(credit) = self.recv_credit.as_mut() {
+                credit.forget(stream_id);
+            }
+            if let Some(framer) = self.framer.as_mut() {
+                framer.forget(stream_id);
+            }
+            emit_stream_reset(emitter, pid, stream_id, code);
+        }
+    }
+
+    // reads a datagram from quiche and passes it to the owner, false when none is left
+    fn recv_dgram<E: Emitter>(&mut self, emitter: &mut E, pid: &LocalPid) -> Result<bool, Atom> {
+        let pid = &self.subscriber(EventClass::Dgrams, pid);
+        let t = self.webtransport.as_mut().ok_or_else(atoms::bad_state)?;
+        match t.recv_dgram(&mut self.raw, &mut self.dgram_buf) {
+            Ok((in_session, offset, total_len)) => {
+                if in_session {
+                    let len = total_len - offset;
+                    if len > 0 {
+                        // datagram payload is prefixed with the quarter stream id
+                        let session_id = common::decode_varint(&self.dgram_buf[..offset])
+                            .map(|(quarter_id, _)| quarter_id * 4)
+                            .or(self.session_id);
+
+                        let mut data = OwnedBinary::new(len).unwrap();
+                        data.as_mut_slice()
+                            .copy_from_slice(&self.dgram_buf[offset..total_len]);
+
+                        emitter.emit(pid, move |env| {
+                            make_tuple(
+                                env,
+                                &[
+                                    atoms::__wt_dgram_recv__().to_term(env),
+                                    session_id.encode(env),
+                                    data.release(env).to_term(env),
+                                ],
+                            )
+                        });
+                    }
+                } else if let Some((quarter_id, prefix_len)) =
+                    common::decode_varint(&self.dgram_buf[..total_len])
+                {
+                    // HTTP datagram (RFC 9297) for other request stream
+                    let mut data = OwnedBinary::new(total_len - prefix_len).unwrap();
+                    data.as_mut_slice()
+                        .copy_from_slice(&self.dgram_buf[prefix_len..total_len]);
+
+                    emitter.emit(pid, move |env| {
+                        make_tuple(
+                            env,
+                            &[
+                                atoms::__h3_dgram_recv__().to_term(env),
+                                (quarter_id * 4).encode(env),
+                                data.release(env).to_term(env),
+                            ],
+                        )
+                    });
+                }
+                self.mark_active();
+                Ok(true)
+            }
+            Err(Error::Done) => Ok(false),
+            Err(e) => {
+                error!("failed to receive dgram: {:?}", e);
+                Err(common::webtransport_error_atom(&e))
+            }
+        }
+    }
+
+    // the datagrams queued in quiche, up to what's left of the budget
+    fn deliver_dgrams<E: Emitter>(&mut self, emitter: &mut E, pid: &LocalPid) -> Result<(), Atom> {
+        while self.dgram_budget.is_none() || self.dgram_budget_left > 0 {
+            if !self.recv_dgram(emitter, pid)? {
+                break;
+            }
+            self.dgram_budget_left = self.dgram_budget_left.saturating_sub(1);
+        }
+        Ok(())
+    }
+
+    // the datagrams beyond the budget of a packet stay in the queue of quiche for the
+    // next one, which drops the oldest of them once it holds dgram_recv_max_queue_len
+    pub fn set_dgram_budget(&mut self, budget: Option<usize>) {
+        self.dgram_budget = budget;
+    }
+
+    // probes the peer after `interval` without sending anything, None stops them
+    pub fn set_keepalive(&mut self, interval: Option<Duration>) {
+        self.keepalive = interval.map(Keepalive::new);
+    }
+
+    // the limits are counted from now, None for both removes the policy
+    pub fn set_lifetime_policy(
+        &mut self,
+        max_lifetime: Option<(Duration, u64)>,
+        max_inactivity: Option<(Duration, u64)>,
+    ) {
+        self.lifetime_policy = if max_lifetime.is_none() && max_inactivity.is_none() {
+            None
+        } else {
+            Some(LifetimePolicy::new(max_lifetime, max_inactivity))
+        };
+    }
+
+    // stream data or a datagram has gone one way or the other
+    fn mark_active(&mut self) {
+        if let Some(policy) = self.lifetime_policy.as_mut() {
+            policy.active();
+        }
+    }
+
+    // leaves the datagrams in quiche, the owner is sent {:__dgram_readable__} once and
+    // takes them one by one with dgram_recv(), looking at them first with dgram_peek()
+    pub fn set_dgram_pull_mode(&mut self, enabled: bool) {
+        self.dgram_pull = if enabled { Some(false) } else { None };
+    }
+
+    // up to `max_len` bytes of the next datagram as received, with the quarter stream id
+    // before the payload, and the length of the whole of it
+    pub fn dgram_peek(&self, max_len: usize) -> Result<(OwnedBinary, usize), Atom> {
+        let total_len = self
+            .raw
+            .dgram_recv_front_len()
+            .ok_or_else(atoms::not_found)?;
+        let mut data = OwnedBinary::new(max_len.min(total_len)).unwrap();
+        let len = data.len();
+        self.raw
+            .dgram_recv_peek(data.as_mut_slice(), len)
+            .map_err(|e| common::quiche_error_atom(&e))?;
+        Ok((data, total_len))
+    }
+
+    // takes the next datagram in pull mode, passed to the owner as in push mode when
+    // `deliver`, dropped without a copy otherwise
+    pub fn dgram_recv<E: Emitter>(
+        &mut self,
+        emitter: &mut E,
+        pid: &LocalPid,
+        deliver: bool,
+    ) -> Result<Timeout, Atom> {
+        if self.raw.is_closed() {
+            return Err(atoms::already_closed());
+        }
+        let pid = &self.route(pid);
+        if self.dgram_pull.is_none() {
+            return Err(atoms::bad_state());
+        }
+        let received = if deliver {
+            self.recv_dgram(emitter, pid)?
+        } else {
+            self.raw.dgram_recv(&mut self.dgram_buf).is_ok()
+        };
+        if !received {
+            return Err(atoms::not_found());
+        }
+        if self.raw.dgram_recv_front_len().is_none() {
+            // the next datagram is notified again
+            self.dgram_pull = Some(false);
+        }
+        self.next_timeout()
+    }
+
+    // the owner reads the streams with stream_read() when it wants, instead of getting
+    // their data as it arrives. framing and the receive window don't apply to it.
+    pub fn set_stream_pull_mode(&mut self, enabled: bool) {
+        self.pull = if enabled { Some(HashSet::new()) } else { None };
+    }
+
+    // up to `max_bytes` of the data of a stream in pull mode, at most MAX_STREAM_BUF_SIZE.
+    // less than that means the stream has nothing more until the next
+    // {:__stream_readable__, stream_id}.
+    pub fn stream_read<E: Emitter>(
+        &mut self,
+        emitter: &mut E,
+        pid: &LocalPid,
+        stream_id: u64,
+        max_bytes: usize,
+    ) -> Result<(OwnedBinary, Timeout), Atom> {
+        if self.raw.is_closed() {
+            return Err(atoms::already_closed());
+        }
+        let pid = &self.route(pid);
+        let notified = self.pull.as_mut().ok_or_else(atoms::bad_state)?;
+        notified.remove(&stream_id);
+        let t = self.webtransport.as_mut().ok_or_else(atoms::bad_state)?;
+        // the binary is allocated before the data is read, whatever the owner asks for
+        let max_bytes = max_bytes.min(MAX_STREAM_BUF_SIZE);
+        let mut data = OwnedBinary::new(max_bytes).unwrap();
+        let mut total = 0;
+        let mut reset_code = None;
+        while total < max_bytes {
+            match t.recv_stream_data(&mut self.raw, stream_id, &mut data.as_mut_slice()[total..]) {
+                Ok(0) => break,
+                Ok(len) => total += len,
+                Err(Error::TransportError(quiche::Error::StreamReset(code))) => {
+                    self.stream_stats
+                        .update(stream_id, |stats| stats.reset_code = Some(code));
+                    reset_code = Some(code);
+                    break;
+                }
+                Err(Error::Done) => break,
+                // the data read before the error is passed on, the next read fails
+                Err(e) if total == 0 => return Err(common::webtransport_error_atom(&e)),
+                Err(e) => {
+                    error!("failed to read stream {}: {:?}", stream_id, e);
+                    break;
+                }
+            }
+        }
+        self.stream_stats
+            .update(stream_id, |stats| stats.bytes_recv += total as u64);
+        if total > 0 {
+            self.mark_active();
+        }
+        if total < data.len() {
+            data.realloc_or_copy(total);
+        }
+        if let Some(code) = reset_code {
+            let stream_pid = self.subscriber(EventClass::Streams, pid);
+            emit_stream_reset(emitter, &stream_pid, stream_id, code);
+        }
+        // the end of the stream, and the flow control credit given back by the read
+        self.poll_webtransport_events(emitter, pid)?;
+        self.drain(emitter)?;
+        Ok((data, self.next_timeout()?))
+    }
+
+    // up to `max_bytes` of the body of an HTTP/3 request which isn't WebTransport's,
+    // after {:__h3_data__, stream_id}. as much as max_bytes means there may be more,
+    // quiche doesn't tell of it again until all of it has been read.
+    pub fn h3_recv_body<E: Emitter>(
+        &mut self,
+        emitter: &mut E,
+        pid: &LocalPid,
+        stream_id: u64,
+        max_bytes: usize,
+    ) -> Result<(OwnedBinary, Timeout), Atom> {
+        if self.raw.is_closed() {
+            return Err(atoms::already_closed());
+        }
+        let pid = &self.route(pid);
+        let t = self.webtransport.as_mut().ok_or_else(atoms::bad_state)?;
+        let max_bytes = max_bytes.min(MAX_STREAM_BUF_SIZE);
+        let mut data = OwnedBinary::new(max_bytes).unwrap();
+        let mut total = 0;
+        while total < max_bytes {
+            match t.recv_body(&mut self.raw, stream_id, &mut data.as_mut_slice()[total..]) {
+                Ok(0) | Err(Error::Done) => break,
+                Ok(len) => total += len,
+                Err(e) => return Err(common::webtransport_error_atom(&e)),
+            }
+        }
+        self.stream_stats
+            .update(stream_id, |stats| stats.bytes_recv += total as u64);
+        if total > 0 {
+            self.mark_active();
+        }
+        if total < data.len() {
+            data.realloc_or_copy(total);
+        }
+        // the end of the request, and the flow control credit given back by the read
+        self.poll_webtransport_events(emitter, pid)?;
+        self.drain(emitter)?;
+        Ok((data, self.next_timeout()?))
+    }
+
+    // the data of the streams is passed as the messages of the framing rather than as read
+    pub fn set_stream_framing(&mut self, framing: Framing) {
+        self.framer = match framing {
+            Framing::Raw => None,
+            framing => Some(StreamFramer::new(framing)),
+        };
+    }
+
+    // the owner gets no more than `window` bytes of a stream until it acknowledges them,
+    // the stream being stopped with `reset_code` when the peer sends more meanwhile
+    pub fn set_stream_recv_window(&mut self, window: usize, reset_code: Option<u64>) {
+        self.recv_credit = Some(RecvCredit::new(window, reset_code));
+    }
+
+    // gives back the credit of the bytes which the owner has handled,
+    // and reads the stream again if it was paused
+    pub fn stream_ack<E: Emitter>(
+        &mut self,
+        emitter: &mut E,
+        pid: &LocalPid,
+        stream_id: u64,
+        bytes: usize,
+    ) -> Result<Timeout, Atom> {
+        if self.raw.is_closed() {
+            return Err(atoms::already_closed());
+        }
+        let pid = &self.route(pid);
+        let resume = match self.recv_credit.as_mut() {
+            Some(credit) => credit.ack(stream_id, bytes),
+            None => return Err(atoms::bad_state()),
+        };
+        if resume {
+            self.read_stream(emitter, pid, stream_id);
+            // the events which waited for the data, such as the end of the stream
+            self.poll_webtransport_events(emitter, pid)?;
+        }
+        self.drain(emitter)?;
+        self.next_timeout()
+    }
+
+    // validates the path of the peer again, {:__path_validated__, peer} or
+    // {:__path_failed__, peer} telling the owner whether it responded within `timeout`
+    pub fn probe_path<E: Emitter>(
+        &mut self,
+        emitter: &mut E,
+        timeout: Duration,
+    ) -> Result<Timeout, Atom> {
+        if self.raw.is_closed() {
+            return Err(atoms::already_closed());
+        }
+        if self.path_probe.is_some() {
+            return Err(atoms::already_exists());
+        }
+        let mut challenge = [0u8; 8];
+        SystemRandom::new()
+            .fill(&mut challenge)
+            .map_err(|_| atoms::system_error())?;
+        self.raw.send_path_challenge(challenge);
+        self.path_probe = Some(PathProbe {
+            challenge,
+            deadline: Instant::now() + timeout,
+        });
+        self.drain(emitter)?;
+        self.next_timeout()
+    }
+
+    // the response validates the new address of a migrated peer, or the path probed
+    fn on_path_response<E: Emitter>(&mut self, emitter: &mut E, pid: &LocalPid, resp: &[u8; 8]) {
+        let migrated =
+            !self.address_validation.is_validated() && self.address_validation.validate(resp);
+        let probed = self
+            .path_probe
+            .as_ref()
+            .map_or(false, |probe| &probe.challenge == resp);
+        if probed {
+            self.path_probe = None;
+        }
+        if migrated || probed {
+            emit_path_event(emitter, pid, atoms::__path_validated__(), self.peer.clone());
+        }
+    }
+
+    fn expire_path_probe<E: Emitter>(&mut self, emitter: &mut E, pid: &LocalPid) {
+        if let Some(probe) = &self.path_probe {
+            if probe.deadline <= Instant::now() {
+                self.path_probe = None;
+                emit_path_event(emitter, pid, atoms::__path_failed__(), self.peer.clone());
+            }
+        }
+    }
+
+    pub fn execute_timeout<E: Emitter>(
+        &mut self,
+        emitter: &mut E,
+        pid: &LocalPid,
+    ) -> Result<Timeout, Atom> {
+        let pid = &self.route(pid);
+        self.owner = Some(pid.clone());
+        if !self.raw.is_closed() {
+            let exceeded = self
+                .lifetime_policy
+                .as_ref()
+                .and_then(|policy| policy.exceeded(Instant::now()));
+            if let Some((code, reason)) = exceeded {
+                info!("close the connection: {}", String::from_utf8_lossy(reason));
+                return self.close(emitter, true, code, reason);
+            }
+            self.raw.on_timeout();
+            // polled before the handshake too, so that a due left there is re-armed
+            // instead of making every timeout fire at once
+            let keepalive_due = self
+                .keepalive
+                .as_mut()
+                .map_or(false, |keepalive| keepalive.poll(Instant::now()));
+            // while a probe is on, its response is the one in the single slot of
+            // take_path_response, and its challenge keeps the connection alive anyway
+            if keepalive_due && self.raw.is_established() && self.path_probe.is_none() {
+                // quiche 0.12 can't send a PING on its own, a PATH_CHALLENGE on the
+                // current path is ack-eliciting too, and its response is ignored
+                let mut challenge = [0u8; 8];
+                if SystemRandom::new().fill(&mut challenge).is_ok() {
+                    self.raw.send_path_challenge(challenge);
+                }
+            }
+            self.expire_path_probe(emitter, pid);
+            self.recv_held(emitter, pid)?;
+            self.poll_webtransport_events(emitter, pid)?;
+            self.flush_pending_streams();
+            self.drain(emitter)?;
+            self.next_timeout()
+        } else {
+            Err(atoms::already_closed())
+        }
+    }
+
+    pub fn open_stream<E: Emitter>(
+        &mut self,
+        emitter: &mut E,
+        is_bidi: bool,
+    ) -> Result<(u64, Timeout), Atom> {
+        if !self.raw.is_closed() {
+            if let Some(transport) = self.webtransport.as_mut() {
+                match transport.open_stream(&mut self.raw, is_bidi) {
+                    Ok(stream_id) => {
+                        info!("opened new stream with stream-id: {}", stream_id);
+                        self.track_stream(stream_id);
+                        self.drain(emitter)?;
+                        self.next_timeout()
+                            .map(|next_timeout| (stream_id, next_timeout))
+                    }
+                    Err(e) => {
+                        error!("failed to open stream: {:?}", e);
+                        Err(common::webtransport_error_atom(&e))
+                    }
+                }
+            } else {
+                Err(atoms::bad_state())
+            }
+        } else {
+            Err(atoms::already_closed())
+        }
+    }
+
+    pub fn send_stream_data<E: Emitter>(
+        &mut self,
+        emitter: &mut E,
+        stream_id: u64,
+        data: &[u8],
+        fin: bool,
+    ) -> Result<Timeout, Atom> {
+        self.send_stream_data_vectored(emitter, stream_id, &[data], fin)
+    }
+
+    // the chunks are a message of the framing of the connection, sent as they are when raw
+    pub fn send_stream_message<E: Emitter>(
+        &mut self,
+        emitter: &mut E,
+        stream_id: u64,
+        chunks: &[&[u8]],
+        fin: bool,
+    ) -> Result<Timeout, Atom> {
+        let (prefix, suffix) = self.frame_message(chunks)?;
+        let mut framed = Vec::with_capacity(chunks.len() + 2);
+        if !prefix.is_empty() {
+            framed.push(prefix.as_slice());
+        }
+        framed.extend_from_slice(chunks);
+        if !suffix.is_empty() {
+            framed.push(suffix);
+        }
+        self.send_stream_data_vectored(emitter, stream_id, &framed, fin)
+    }
+
+    // what the framing puts before and after a message, :bad_format when it can't carry it
+    pub fn frame_message(&self, chunks: &[&[u8]]) -> Result<(Vec<u8>, &'static [u8]), Atom> {
+        match &self.framer {
+            Some(framer) => framer.framing().frame(chunks).ok_or_else(atoms::bad_format),
+            None => Ok((Vec::new(), &b""[..])),
+        }
+    }
+
+    pub fn send_stream_data_vectored<E: Emitter>(
+        &mut self,
+        emitter: &mut E,
+        stream_id: u64,
+        chunks: &[&[u8]],
+        fin: bool,
+    ) -> Result<Timeout, Atom> {
+        if !self.raw.is_closed() {
+            self.write_stream_data(stream_id, chunks, fin)?;
+            self.drain(emitter)?;
+            self.next_timeout()
+        } else {
+            Err(atoms::already_closed())
+        }
+    }
+
+    // the chunks as they are, a header and a body say, without the framing of the
+    // connection and in a single call: all of them are written or held, or the send
+    // fails before any of them is, so that no other send comes in between
+    pub fn send_stream_full<E: Emitter>(
+        &mut self,
+        emitter: &mut E,
+        stream_id: u64,
+        chunks: &[&[u8]],
+        fin: bool,
+    ) -> Result<Timeout, Atom> {
+        if !self.raw.is_closed() {
+            self.write_stream_full(stream_id, chunks, fin)?;
+            self.drain(emitter)?;
+            self.next_timeout()
+        } else {
+            Err(atoms::already_closed())
+        }
+    }
+
+    fn write_stream_full(
+        &mut self,
+        stream_id: u64,
+        chunks: &[&[u8]],
+        fin: bool,
+    ) -> Result<(), Atom> {
+        // a stream which is stopped, finished or unknown would fail in the middle
+        if !self.pending_streams.contains(stream_id) {
+            if let Err(e) = self.raw.stream_capacity(stream_id) {
+                return Err(common::quiche_error_atom(&e));
+            }
+        }
+        self.write_stream_data(stream_id, chunks, fin)
+    }
+
+    // writes what the flow control of the stream allows and holds the rest,
+    // behind the data already held for the stream
+    fn write_stream_data(
+        &mut self,
+        stream_id: u64,
+        chunks: &[&[u8]],
+        fin: bool,
+    ) -> Result<(), Atom> {
+        if self.webtransport.is_none() {
+            return Err(atoms::bad_state());
+        }
+        let blocked = self.pending_streams.contains(stream_id);
+        let len: usize = chunks.iter().map(|chunk| chunk.len()).sum();
+        self.check_stream_budget(stream_id, len)?;
+
+        if len > 0 {
+            self.mark_active();
+        }
+        let mut rest: VecDeque<Vec<u8>> = VecDeque::new();
+        let mut sent = false;
+        for data in chunks {
+            if blocked || !rest.is_empty() {
+                rest.push_back(data.to_vec());
+                continue;
+            }
+            let written = match self.write_stream_chunk(stream_id, data) {
+                Ok(written) => written,
+                // the chunks before have gone out, so the rest is held rather than failing
+                // half sent, and goes with the stream when the flush fails on it
+                Err(_reason) if sent => 0,
+                Err(reason) => return Err(reason),
+            };
+            sent |= written > 0;
+            if written < data.len() {
+                rest.push_back(data[written..].to_vec());
+            }
+        }
+
+        // the FIN only goes once all the data has, and is held with it otherwise
+        if blocked || !rest.is_empty() || (fin && !self.send_fin(stream_id)) {
+            // the clock starts when the stream starts being held
+            let deadline = self
+                .stream_send_deadlines
+                .get(&stream_id)
+                .or(self.send_deadline.as_ref())
+                .map(|deadline| (Instant::now() + deadline.timeout, deadline.code));
+            self.pending_streams.hold(stream_id, rest, fin, deadline);
+        }
+        Ok(())
+    }
+
+    // :stream_blocked when len bytes would hold more than MAX_PENDING_STREAM_BYTES,
+    // beyond what the flow control of the stream allows now
+    pub fn check_stream_budget(&self, stream_id: u64, len: usize) -> Result<(), Atom> {
+        let capacity = if self.pending_streams.contains(stream_id) {
+            0
+        } else {
+            self.raw.stream_capacity(stream_id).unwrap_or(0)
+        };
+        if self.pending_streams.over_budget(len, capacity) {
+            return Err(atoms::stream_blocked());
+        }
+        Ok(())
+    }
+
+    // writes as much of the data as the flow control of the stream allows
+    fn write_stream_chunk(&mut self, stream_id: u64, data: &[u8]) -> Result<usize, Atom> {
+        let transport = self.webtransport.as_mut().ok_or_else(atoms::bad_state)?;
+        let mut pos = 0;
+        let mut result = Ok(());
+        while pos < data.len() {
+            match transport.send_stream_data(&mut self.raw, stream_id, &data[pos..]) {
+                Ok(0) | Err(Error::Done) => break,
+                Ok(len) => pos += len,
+                Err(e) => {
+                    error!("failed to send stream data: {:?}", e);
+                    if let Error::TransportError(quiche::Error::StreamStopped(code)) = e {
+                        self.stream_stats
+                            .update(stream_id, |stats| stats.stop_code = Some(code));
+                    }
+                    result = Err(common::webtransport_error_atom(&e));
+                    break;
+                }
+            }
+        }
+        self.stream_stats
+            .update(stream_id, |stats| stats.bytes_sent += pos as u64);
+        result.map(|()| pos)
+    }
+
+    // false when quiche can't take the FIN now either, and it has to be held
+    fn send_fin(&mut self, stream_id: u64) -> bool {
+        match self.raw.stream_send(stream_id, b"", true) {
+            Err(quiche::Error::Done) => return false,
+            Ok(_) => {
+                self.stream_stats
+                    .update(stream_id, |stats| stats.fin_sent = true);
+            }
+            // the stream is stopped or gone, there's nothing left to finish
+            Err(e) => debug!("failed to finish stream {}: {:?}", stream_id, e),
+        }
+        self.stream_send_deadlines.remove(&stream_id);
+        self.stream_urgency.remove(&stream_id);
+        true
+    }
+
+    // writes the data held for the streams which the peer has given credit to since
+    fn flush_pending_streams(&mut self) {
+        let mut stream_ids = self.pending_streams.stream_ids();
+        // the most urgent first, they take the credit of the connection before the others
+        stream_ids.sort_by_key(|stream_id| (self.urgency(*stream_id), *stream_id));
+        // taken out while write_stream_chunk borrows the connection
+        let mut pending_streams = std::mem::take(&mut self.pending_streams);
+        for stream_id in stream_ids {
+            let flushed =
+                pending_streams.flush(stream_id, |chunk| self.write_stream_chunk(stream_id, chunk));
+            if let Flushed::Drained { fin } = flushed {
+                if !fin || self.send_fin(stream_id) {
+                    pending_streams.remove(stream_id);
+                }
+            }
+        }
+        self.pending_streams = pending_streams;
+    }
+
+    // the deadline of the data held from now on for a stream, or for all the streams
+    // without a deadline of their own when None. a deadline of None removes it.
+    pub fn set_send_deadline(&mut self, stream_id: Option<u64>, deadline: Option<SendDeadline>) {
+        match (stream_id, deadline) {
+            (Some(stream_id), Some(deadline)) => {
+                self.stream_send_deadlines.insert(stream_id, deadline);
+            }
+            (Some(stream_id), None) => {
+                self.stream_send_deadlines.remove(&stream_id);
+            }
+            (None, deadline) => self.send_deadline = deadline,
+        }
+    }
+
+    // resets the streams whose data is still held at their deadline,
+    // and sends {:__stream_expired__, stream_id, code} to the owner
+    fn expire_pending_streams<E: Emitter>(&mut self, emitter: &mut E) {
+        let expired = self.pending_streams.expired(Instant::now());
+        for (stream_id, code) in expired {
+            self.pending_streams.remove(stream_id);
+            self.stream_send_deadlines.remove(&stream_id);
+            debug!("stream {} missed its send deadline, reset it", stream_id);
+            let _ = self
+                .raw
+                .stream_shutdown(stream_id, quiche::Shutdown::Write, code);
+            if let Some(owner) = &self.owner {
+                let owner = self.subscriber(EventClass::Streams, owner);
+                emitter.emit(&owner, move |env| {
+                    make_tuple(
+                        env,
+                        &[
+                            atoms::__stream_expired__().to_term(env),
+                            stream_id.encode(env),
+                            code.encode(env),
+                        ],
+                    )
+                });
+            }
+        }
+    }
+
+    pub fn send_dgram<E: Emitter>(
+        &mut self,
+        emitter: &mut E,
+        data: &[u8],
+    ) -> Result<Timeout, Atom> {
+        if !self.raw.is_closed() {
+            if self.defers_dgrams() {
+                self.deferred_dgrams
+                    .push_back(Outbound::Dgram(data.to_vec()));
+            } else {
+                self.write_dgram(data)?;
+            }
+            self.drain(emitter)?;
+            self.next_timeout()
+        } else {
+            Err(atoms::already_closed())
+        }
+    }
+
+    // a burst of datagrams written together and drained once: quiche takes them in
+    // order until it turns one down, its queue being full say, and the rest is dropped.
+    // returns how many it took.
+    pub fn send_dgram_batch<E: Emitter>(
+        &mut self,
+        emitter: &mut E,
+        dgrams: &[&[u8]],
+    ) -> Result<(usize, Timeout), Atom> {
+        if !self.raw.is_closed() {
+            let mut accepted = 0;
+            for data in dgrams {
+                if self.defers_dgrams() {
+                    self.deferred_dgrams
+                        .push_back(Outbound::Dgram(data.to_vec()));
+                } else if self.write_dgram(data).is_err() {
+                    break;
+                }
+                accepted += 1;
+            }
+            self.drain(emitter)?;
+            self.next_timeout()
+                .map(|next_timeout| (accepted, next_timeout))
+        } else {
+            Err(atoms::already_closed())
+        }
+    }
+
+    fn write_dgram(&mut self, data: &[u8]) -> Result<(), Atom> {
+        let result = if let Some(transport) = self.webtransport.as_mut() {
+            transport
+                .send_dgram(&mut self.raw, data)
+                .map_err(|e| common::webtransport_error_atom(&e))
+        } else {
+            Err(atoms::bad_state())
+        };
+        match result {
+            Ok(()) => self.mark_active(),
+            Err(_) => self.counters.dgram_dropped(),
+        }
+        result
+    }
+
+    pub fn send_h3_dgram<E: Emitter>(
+        &mut self,
+        emitter: &mut E,
+        flow_id: u64,
+        data: &[u8],
+    ) -> Result<Timeout, Atom> {
+        if !self.raw.is_closed() {
+            if self.defers_dgrams() {
+                self.deferred_dgrams
+                    .push_back(Outbound::H3Dgram(flow_id, data.to_vec()));
+            } else {
+                self.write_h3_dgram(flow_id, data)?;
+            }
+            self.drain(emitter)?;
+            self.next_timeout()
+        } else {
+            Err(atoms::already_closed())
+        }
+    }
+
+    fn write_h3_dgram(&mut self, flow_id: u64, data: &[u8]) -> Result<(), Atom> {
+        // flow id is the id of the client-initiated bidirectional request stream
+        if flow_id % 4 != 0 {
+            return Err(atoms::bad_format());
+        }
+        // the h3 layer announces H3_DATAGRAM in its SETTINGS as DATAGRAM frames are
+        // enabled on the transport, the peer has to have enabled them too
+        if self.raw.dgram_max_writable_len().is_none() {
+            return Err(atoms::not_supported());
+        }
+        let mut buf = Vec::with_capacity(8 + data.len());
+        common::encode_varint(flow_id / 4, &mut buf)?;
+        buf.extend_from_slice(data);
+        self.raw.dgram_send(&buf).map_err(|e| {
+            error!("failed to send http datagram: {:?}", e);
+            self.counters.dgram_dropped();
+            common::quiche_error_atom(&e)
+        })?;
+        self.mark_active();
+        Ok(())
+    }
+
+    // queues the data without touching quiche, it's written by the next
+    // process_packet or flush_outbound, so that bursts of sends are packetized together.
+    pub fn enqueue(&mut self, outbound: Outbound) -> Result<(), Atom> {
+        if self.raw.is_closed() {
+            return Err(atoms::already_closed());
+        }
+        self.outbound.push_back(outbound);
+        Ok(())
+    }
+
+    pub fn has_outbound(&self) -> bool {
+        !self.outbound.is_empty()
+    }
+
+    pub fn flush_outbound<E: Emitter>(&mut self, emitter: &mut E) -> Result<Timeout, Atom> {
+        if !self.raw.is_closed() {
+            self.write_outbound();
+            self.drain(emitter)?;
+            self.next_timeout()
+        } else {
+            self.outbound.clear();
+            Err(atoms::already_closed())
+        }
+    }
+
+    fn write_outbound(&mut self) {
+        self.flush_pending_streams();
+        let mut queued: Vec<Outbound> = self.outbound.drain(..).collect();
+        // the sort is stable, the writes of a stream stay in the order they were queued
+        queued.sort_by_key(|outbound| self.send_rank(outbound));
+        for outbound in queued {
+            let is_dgram = matches!(outbound, Outbound::Dgram(_) | Outbound::H3Dgram(_, _));
+            if is_dgram && self.defers_dgrams() {
+                self.deferred_dgrams.push_back(outbound);
+                continue;
+            }
+            if self.write_queued(&outbound).is_err() {
+                debug!("failed to write queued data");
+            }
+        }
+    }
+
+    // the DATAGRAMs wait in deferred_dgrams until drain gives them their part of the window
+    fn defers_dgrams(&self) -> bool {
+        self.send_order != SendOrder::DgramFirst
+    }
+
+    // under Share, the deferred DATAGRAMs which fit in their percent of the congestion
+    // window are written before the stream data
+    fn write_dgram_share(&mut self) {
+        let percent = match self.send_order {
+            SendOrder::Share(percent) => percent as usize,
+            _ => return,
+        };
+        let cwnd = self.raw.stats().paths.first().map_or(0, |path| path.cwnd);
+        let mut budget = cwnd * percent / 100;
+        while let Some(outbound) = self.deferred_dgrams.front() {
+            let len = match outbound {
+                Outbound::Dgram(data) | Outbound::H3Dgram(_, data) => data.len(),
+                _ => 0,
+            };
+            if len > budget {
+                break;
+            }
+            budget -= len;
+            let outbound = self.deferred_dgrams.pop_front().unwrap();
+            if self.write_queued(&outbound).is_err() {
+                debug!("failed to write deferred datagram");
+            }
+        }
+    }
+
+    fn write_queued(&mut self, outbound: &Outbound) -> Result<(), Atom> {
+        match outbound {
+            Outbound::Stream(stream_id, data, fin) => {
+                let chunks: Vec<&[u8]> = data.iter().map(|chunk| chunk.as_slice()).collect();
+                self.write_stream_data(*stream_id, &chunks, *fin)
+            }
+            Outbound::StreamFull(stream_id, data, fin) => {
+                let chunks: Vec<&[u8]> = data.iter().map(|chunk| chunk.as_slice()).collect();
+                self.write_stream_full(*stream_id, &chunks, *fin)
+            }
+            Outbound::Dgram(data) => self.write_dgram(data),
+            Outbound::H3Dgram(flow_id, data) => self.write_h3_dgram(*flow_id, data),
+        }
+    }
+
+    // the queued writes go to quiche in this order: the DATAGRAMs before or after the
+    // stream data as the send order says, the streams the most urgent first
+    fn send_rank(&self, outbound: &Outbound) -> (u8, u8) {
+        let streams_first = self.send_order == SendOrder::StreamFirst;
+        match outbound {
+            Outbound::Stream(stream_id, _, _) | Outbound::StreamFull(stream_id, _, _) => {
+                (!streams_first as u8, self.urgency(*stream_id))
+            }
+            Outbound::Dgram(_) | Outbound::H3Dgram(_, _) => (streams_first as u8, 0),
+        }
+    }
+
+    fn urgency(&self, stream_id: u64) -> u8 {
+        self.stream_urgency
+            .get(&stream_id)
+            .copied()
+            .unwrap_or(DEFAULT_URGENCY)
+    }
+
+    // the urgency, 0 the most urgent to 7, and the incrementality of RFC 9218: quiche
+    // interleaves the frames of the streams with them, and the data which the connection
+    // holds or queues is written to quiche in their order
+    pub fn set_stream_priority(
+        &mut self,
+        stream_id: u64,
+        urgency: u8,
+        incremental: bool,
+    ) -> Result<(), Atom> {
+        if urgency > MAX_URGENCY {
+            return Err(atoms::bad_format());
+        }
+        self.raw
+            .stream_priority(stream_id, urgency, incremental)
+            .map_err(|e| common::quiche_error_atom(&e))?;
+        self.stream_urgency.insert(stream_id, urgency);
+        Ok(())
+    }
+
+    pub fn set_send_order(&mut self, order: SendOrder) {
+        self.send_order = order;
+    }
+
+    pub fn close<E: Emitter>(
+        &mut self,
+        emitter: &mut E,
+        app: bool,
+        err: u64,
+        reason: &[u8],
+    ) -> Result<Timeout, Atom> {
+        if !self.raw.is_closed() {
+            match self.raw.close(app, err, reason) {
+                Ok(()) => {
+                    self.local_error = Some(CloseInfo {
+                        remote: false,
+                        is_app: app,
+                        error_code: err,
+                        reason: reason.to_vec(),
+                    });
+                    self.drain(emitter)?;
+                    self.next_timeout()
+                }
+
+                Err(quiche::Error::Done) => self.next_timeout(),
+
+                Err(e) => Err(common::quiche_error_atom(&e)),
+            }
+        } else {
+            Err(atoms::already_closed())
+        }
+    }
+
+    // when quiche fails to write a packet, the connection is closed and the owner gets
+    // {:__connection_error__, reason}
+    fn drain<E: Emitter>(&mut self, emitter: &mut E) -> Result<(), Atom> {
+        let held = self
+            .impairment
+            .as_mut()
+            .map_or_else(Vec::new, |impairment| impairment.take_outbound());
+        for (packet, to) in held {
+            self.output(emitter, &packet, to);
+        }
+        self.expire_pending_streams(emitter);
+        self.write_dgram_share();
+        let mut failure = self.send_packets(emitter);
+        if failure.is_none() && !self.deferred_dgrams.is_empty() {
+            // the stream data has taken what it could of the congestion window,
+            // the DATAGRAMs go in what it left
+            for outbound in std::mem::take(&mut self.deferred_dgrams) {
+                if self.write_queued(&outbound).is_err() {
+                    debug!("failed to write deferred datagram");
+                }
+            }
+            failure = self.send_packets(emitter);
+        }
+        if let (Some(reason), Some(owner)) = (failure, &self.owner) {
+            emitter.emit(owner, move |env| {
+                make_tuple(
+                    env,
+                    &[
+                        atoms::__connection_error__().to_term(env),
+                        reason.to_term(env),
+                    ],
+                )
+            });
+        }
+        if let Some(qlog) = &self.qlog {
+            qlog.emit(emitter, self.raw.trace_id());
+        }
+        if let Some(tap) = self.tap.as_mut() {
+            tap.emit(emitter);
+        }
+        if self.event_timestamps {
+            self.notify_lifecycle(&mut Stamped::new(emitter, None));
+        } else {
+            self.notify_lifecycle(emitter);
+        }
+        self.notify_congestion(emitter);
+        self.notify_pmtu(emitter);
+        self.notify_closed_streams(emitter);
+        self.sample();
+        failure.map_or(Ok(()), Err)
+    }
+
+    // writes the packets of quiche until it has nothing more to send, the error
+    // which closed the connection if it failed to
+    fn send_packets<E: Emitter>(&mut self, emitter: &mut E) -> Option<Atom> {
+        let mut failure = None;
+        loop {
+            let result = match &self.output {
+                Output::Sender(sender) => {
+                    let sender = self.subscribers.get(EventClass::Packets).unwrap_or(sender);
+                    // quiche writes the packet directly into the binary passed to the sender,
+                    // the binary is kept for the next drain when nothing has been written.
+                    let mut packet = self
+                        .packet_buf
+                        .take()
+                        .unwrap_or_else(|| OwnedBinary::new(self.dgram_buf.len()).unwrap());
+                    match self.raw.send(packet.as_mut_slice()) {
+                        Ok((len, _send_info)) => {
+                            if self.address_validation.check_sendable() {
+                                if let Some(impairment) = self.impairment.as_mut() {
+                                    impairment.hold_outbound(&packet[..len], self.peer.addr);
+                                    self.packet_buf = Some(packet);
+                                    continue;
+                                }
+                                if len < packet.len() {
+                                    packet.realloc_or_copy(len);
+                                }
+                                self.counters.packet_out(len);
+                                if let Some(keepalive) = self.keepalive.as_mut() {
+                                    keepalive.touch();
+                                }
+                                if let Some(tap) = self.tap.as_mut() {
+                                    tap.capture(Direction::Out, self.peer.addr, &packet);
+                                }
+                                let peer = self.peer.clone();
+                                if let Some(collected) = self.collected.as_mut() {
+                                    collected.push((peer, packet));
+                                    continue;
+                                }
+                                emitter.emit(sender, move |env| {
+                                    make_tuple(
+                                        env,
+                                        &[
+                                            atoms::__drain__().to_term(env),
+                                            peer.encode(env),
+                                            packet.release(env).to_term(env),
+                                        ],
+                                    )
+                                });
+                            } else {
+                                self.packet_buf = Some(packet);
+                            }
+                            Ok(())
+                        }
+                        Err(e) => {
+                            self.packet_buf = Some(packet);
+                            Err(e)
+                        }
+                    }
+                }
+                Output::Batch(batch) => {
+                    self.raw.send(&mut self.dgram_buf).map(|(len, send_info)| {
+                        if self.address_validation.check_sendable() {
+                            if let Some(impairment) = self.impairment.as_mut() {
+                                impairment.hold_outbound(&self.dgram_buf[..len], send_info.to);
+                                return;
+                            }
+                            self.counters.packet_out(len);
+                            if let Some(keepalive) = self.keepalive.as_mut() {
+                                keepalive.touch();
+                            }
+                            if let Some(tap) = self.tap.as_mut() {
+                                tap.capture(Direction::Out, send_info.to, &self.dgram_buf[..len]);
+                            }
+                            batch
+                                .borrow_mut()
+                                .push(&self.dgram_buf[..len], send_info.to);
+                        }
+                    })
+                }
+            };
+            match result {
+                Ok(()) => {}
+                Err(quiche::Error::Done) => {
+                    break;
+                }
+                Err(e) => {
+                    error!("failed to conn.send: {:?}", e);
+                    self.raw.close(false, 0x1, b"fail").ok();
+                    failure = Some(common::quiche_error_atom(&e));
+                    break;
+                }
+            }
+        }
+        failure
+    }
+
+    // a packet released by the impairment, written as drain() writes those of quiche
+    fn output<E: Emitter>(&mut self, emitter: &mut E, data: &[u8], to: SocketAddr) {
+        self.counters.packet_out(data.len());
+        if let Some(tap) = self.tap.as_mut() {
+            tap.capture(Direction::Out, to, data);
+        }
+        match &self.output {
+            Output::Sender(sender) => {
+                let sender = self.subscribers.get(EventClass::Packets).unwrap_or(sender);
+                let mut packet = OwnedBinary::new(data.len()).unwrap();
+                packet.as_mut_slice().copy_from_slice(data);
+                let peer = self.peer.clone();
+                if let Some(collected) = self.collected.as_mut() {
+                    collected.push((peer, packet));
+                    return;
+                }
+                emitter.emit(sender, move |env| {
+                    make_tuple(
+                        env,
+                        &[
+                            atoms::__drain__().to_term(env),
+                            peer.encode(env),
+                            packet.release(env).to_term(env),
+                        ],
+                    )
+                });
+            }
+            Output::Batch(batch) => batch.borrow_mut().push(data, to),
+        }
+    }
+
+    // drops, duplicates, reorders and delays the packets in both directions from now on.
+    // None lets them through again, the packets still held being lost.
+    pub fn set_impairment(&mut self, spec: Option<ImpairmentSpec>) {
+        self.impairment = spec.map(Impairment::new);
+    }
+
+    // replaces the tap of the connection, None removes it
+    pub fn set_tap(&mut self, spec: Option<TapSpec>) -> Result<(), Atom> {
+        self.tap = match spec {
+            Some(spec) => Some(Tap::open(spec, self.raw.trace_id()).map_err(|e| {
+                debug!("failed to open packet tap: {}", e);
+                atoms::system_error()
+            })?),
+            None => None,
+        };
+        Ok(())
+    }
+
+    pub fn enable_congestion_events(&mut self) {
+        if self.congestion.is_none() {
+            self.congestion = Some(CongestionTracker::new());
+        }
+    }
+
+    pub fn enable_event_timestamps(&mut self) {
+        self.event_timestamps = true;
+    }
+
+    pub fn enable_pmtu_events(&mut self) {
+        if self.pmtu_events.is_none() {
+            self.pmtu_events = Some(0);
+        }
+    }
+
+    // the PMTU of the path, 0 until quiche has one
+    pub fn pmtu(&self) -> usize {
+        self.raw.stats().paths.first().map_or(0, |path| path.pmtu)
+    }
+
+    // the streams seen from now on are reported once quiche has collected them
+    pub fn enable_stream_close_events(&mut self) {
+        self.stream_close_events = true;
+    }
+
+    fn track_stream(&mut self, stream_id: u64) {
+        self.live_streams.insert(stream_id);
+    }
+
+    // quiche knows no more of a collected stream, which reads as finished
+    fn is_collected(&self, stream_id: u64) -> bool {
+        matches!(
+            self.raw.stream_capacity(stream_id),
+            Err(quiche::Error::InvalidStreamState(_))
+        ) && self.raw.stream_finished(stream_id)
+    }
+
+    // forgets the streams which quiche has collected, both of their sides being done
+    // with, and passes them to the owner as {:__stream_closed__, stream_id} if enabled
+    fn notify_closed_streams<E: Emitter>(&mut self, emitter: &mut E) {
+        let closed: Vec<u64> = self
+            .live_streams
+            .iter()
+            .copied()
+            .filter(|stream_id| self.is_collected(*stream_id))
+            .collect();
+        for stream_id in closed.iter() {
+            self.live_streams.remove(stream_id);
+        }
+        let owner = match &self.owner {
+            Some(owner) if self.stream_close_events => self.subscriber(EventClass::Streams, owner),
+            _ => return,
+        };
+        for stream_id in closed {
+            emitter.emit(&owner, move |env| {
+                make_tuple(
+                    env,
+                    &[
+                        atoms::__stream_closed__().to_term(env),
+                        stream_id.encode(env),
+                    ],
+                )
+            });
+        }
+    }
+
+    // keeps the last `capacity` samples of the path, taken at most once per `interval`
+    pub fn enable_sampling(&mut self, interval: Duration, capacity: usize) {
+        self.sampler = Some(Sampler::new(interval, capacity));
+    }
+
+    // empty when the sampling isn't enabled
+    pub fn samples(&self) -> Vec<Sample> {
+        self.sampler
+            .as_ref()
+            .map_or_else(Vec::new, |sampler| sampler.samples())
+    }
+
+    fn sample(&mut self) {
+        let sampler = match self.sampler.as_mut() {
+            Some(sampler) => sampler,
+            None => return,
+        };
+        let now = Instant::now();
+        if !sampler.is_due(now) {
+            return;
+        }
+        if let Some(path) = self.raw.stats().paths.first() {
+            sampler.record(now, path.rtt, path.cwnd, path.delivery_rate);
+        }
+    }
+
+    // {:__congestion__, state, cwnd}
+    fn notify_congestion<E: Emitter>(&mut self, emitter: &mut E) {
+        let (tracker, owner) = match (self.congestion.as_mut(), &self.owner) {
+            (Some(tracker), Some(owner)) => (tracker, owner),
+            _ => return,
+        };
+        let stats = self.raw.stats();
+        let path = match stats.paths.first() {
+            Some(path) => path,
+            None => return,
+        };
+        let cwnd = path.cwnd;
+        if let Some(state) = tracker.update(stats.lost, cwnd, path.pmtu) {
+            emitter.emit(owner, move |env| {
+                make_tuple(
+                    env,
+                    &[
+                        atoms::__congestion__().to_term(env),
+                        state.to_atom().to_term(env),
+                        cwnd.encode(env),
+                    ],
+                )
+            });
+        }
+    }
+
+    // {:__pmtu__, pmtu}, the first one with the PMTU the path has when it's enabled
+    fn notify_pmtu<E: Emitter>(&mut self, emitter: &mut E) {
+        let reported = match self.pmtu_events {
+            Some(reported) => reported,
+            None => return,
+        };
+        let owner = match &self.owner {
+            Some(owner) => owner,
+            None => return,
+        };
+        let pmtu = self.pmtu();
+        if pmtu == 0 || pmtu == reported {
+            return;
+        }
+        self.pmtu_events = Some(pmtu);
+        emitter.emit(owner, move |env| {
+            make_tuple(env, &[atoms::__pmtu__().to_term(env), pmtu.encode(env)])
+        });
+    }
+
+    // __handshake_started__, __handshake_done__, __closing__ and {:__closed__, peer_error},
+    // each at most once and in this order
+    fn notify_lifecycle<E: Emitter>(&mut self, emitter: &mut E) {
+        let owner = match &self.owner {
+            Some(owner) => self.subscriber(EventClass::Lifecycle, owner),
+            None => return,
+        };
+        if !self.lifecycle.handshake_started {
+            self.lifecycle.handshake_started = true;
+            emitter.emit(&owner, |env| atoms::__handshake_started__().to_term(env));
+        }
+        if !self.lifecycle.handshake_done && self.raw.is_established() {
+            self.lifecycle.handshake_done = true;
+            emitter.emit(&owner, |env| atoms::__handshake_done__().to_term(env));
+        }
+        if !self.lifecycle.closing && (self.raw.is_draining() || self.raw.is_closed()) {
+            self.lifecycle.closing = true;
+            emitter.emit(&owner, |env| atoms::__closing__().to_term(env));
+        }
+        if !self.lifecycle.closed && self.raw.is_closed() {
+            self.lifecycle.closed = true;
+            let close_info = self.close_info();
+            emitter.emit(&owner, move |env| closed_term(env, close_info));
+        }
+    }
+
+    pub fn stream_stats(&self, stream_id: u64) -> Option<StreamStats> {
+        self.stream_stats.get(stream_id)
+    }
+
+    pub fn stream_counts(&self) -> StreamCounts {
+        let mut counts = StreamCounts {
+            peer_bidi_left: self.raw.peer_streams_left_bidi(),
+            peer_uni_left: self.raw.peer_streams_left_uni(),
+            ..Default::default()
+        };
+        for stream_id in self.live_streams.iter().copied() {
+            if self.is_collected(stream_id) {
+                continue;
+            }
+            // the streams of the server have the lowest bit set, the unidirectional
+            // ones the second (RFC 9000 Section 2.1)
+            match (stream_id & 0x1 != 0, stream_id & 0x2 != 0) {
+                (true, false) => counts.local_bidi += 1,
+                (true, true) => counts.local_uni += 1,
+                (false, false) => counts.remote_bidi += 1,
+                (false, true) => counts.remote_uni += 1,
+            }
+        }
+        counts
+    }
+
+    // the streams with data which quiche hasn't passed on yet, pull mode or a
+    // receive window leave some, the other streams are read as their packets come
+    pub fn readable_streams(&self) -> Vec<u64> {
+        self.raw.readable().collect()
+    }
+
+    // the streams which quiche has room for in their flow control, but those which the
+    // connection holds data for already, as more would wait behind it
+    pub fn writable_streams(&self) -> Vec<u64> {
+        self.raw
+            .writable()
+            .filter(|stream_id| !self.pending_streams.contains(*stream_id))
+            .collect()
+    }
+
+    pub fn closed_notified(&self) -> bool {
+        self.lifecycle.closed
+    }
+
+    // packets drained until take_collected() are kept instead of being passed to the sender
+    pub fn collect_packets(&mut self) {
+        self.collected = Some(Vec::new());
+    }
+
+    pub fn take_collected<'a>(&mut self, env: Env<'a>) -> Vec<Term<'a>> {
+        self.collected
+            .take()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(peer, packet)| {
+                make_tuple(env, &[peer.encode(env), packet.release(env).to_term(env)])
+            })
+            .collect()
+    }
+
+    pub fn next_timeout(&mut self) -> Result<Timeout, Atom> {
+        let now = Instant::now();
+        let held = self
+            .impairment
+            .as_ref()
+            .and_then(|impairment| impairment.timeout());
+        let expires = self
+            .pending_streams
+            .next_expiry()
+            .map(|at| at.saturating_duration_since(now));
+        let keepalive = self
+            .keepalive
+            .as_ref()
+            .map(|keepalive| keepalive.due.saturating_duration_since(now));
+        let policy = self
+            .lifetime_policy
+            .as_ref()
+            .and_then(LifetimePolicy::due)
+            .map(|due| due.saturating_duration_since(now));
+        let probe = self
+            .path_probe
+            .as_ref()
+            .map(|probe| probe.deadline.saturating_duration_since(now));
+        let timeout = [self.raw.timeout(), held, expires, keepalive, policy, probe]
+            .into_iter()
+            .flatten()
+            .min();
+        match timeout {
+            None if self.raw.is_closed() => Err(atoms::already_closed()),
+            timeout => Ok(Timeout::from_duration(timeout)),
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        stats::connection_destroyed(self.dgram_buf.len() + self.recv_buf.len());
+        if !self.raw.is_established() {
+            self.counters.handshake_failed();
+        }
+    }
+}
+
+// {:__closed__, nil | {:local | :remote, :transport | :application, error_code, reason}}
+pub fn closed_term(env: Env, close_info: Option<CloseInfo>) -> Term {
+    let info = match close_info {
+        Some(info) => info.to_term(env),
+        None => rustler::types::atom::nil().to_term(env),
+    };
+    make_tuple(env, &[atoms::__closed__().to_term(env), info])
+}
+
+// {:__wt_stream_recv__, session_id, stream_id, data}
+fn emit_stream_data<E: Emitter>(
+    emitter: &mut E,
+    pid: &LocalPid,
+    session_id: Option<u64>,
+    stream_id: u64,
+    data: OwnedBinary,
+) {
+    emitter.emit(pid, move |env| {
+        make_tuple(
+            env,
+            &[
+                atoms::__wt_stream_recv__().to_term(env),
+                session_id.encode(env),
+                stream_id.encode(env),
+                data.release(env).to_term(env),
+            ],
+        )
+    });
+}
+
+// {:__path_validated__, peer} or {:__path_failed__, peer}
+fn emit_path_event<E: Emitter>(
+    emitter: &mut E,
+    pid: &LocalPid,
+    event: Atom,
+    peer: ResourceArc<Peer>,
+) {
+    emitter.emit(pid, move |env| {
+        make_tuple(env, &[event.to_term(env), peer.encode(env)])
+    });
+}
+
+// {:__stream_reset__, stream_id, code}
+fn emit_stream_reset<E: Emitter>(emitter: &mut E, pid: &LocalPid, stream_id: u64, code: u64) {
+    emitter.emit(pid, move |env| {
+        make_tuple(
+            env,
+            &[
+                atoms::__stream_reset__().to_term(env),
+                stream_id.encode(env),
+                code.encode(env),
+            ],
+        )
+    });
+}
+
+// forward HTTP/3 events which are not consumed by webtransport session
+fn send_h3_event<E: Emitter>(emitter: &mut E, pid: &LocalPid, stream_id: u64, ev: h3::Event) {
+    let tag = match &ev {
+        h3::Event::Headers { .. } => atoms::__h3_headers__(),
+        h3::Event::Data => atoms::__h3_data__(),
+        h3::Event::Finished => atoms::__h3_finished__(),
+        h3::Event::Reset(_) => atoms::__h3_reset__(),
+        h3::Event::GoAway => atoms::__h3_goaway__(),
+        // the new priority itself stays in the h3 connection of the session
+        h3::Event::PriorityUpdate => atoms::__h3_priority_update__(),
+        _ => return,
+    };
+    emitter.emit(pid, move |env| {
+        let mut elems = vec![tag.to_term(env), stream_id.encode(env)];
+        match ev {
+            h3::Event::Headers { list, has_body } => {
+                let headers: Vec<Term> = list
+                    .iter()
+                    .map(|h| {
+                        let mut name = OwnedBinary::new(h.name().len()).unwrap();
+                        name.as_mut_slice().copy_from_slice(h.name());
+                        let mut value = OwnedBinary::new(h.value().len()).unwrap();
+                        value.as_mut_slice().copy_from_slice(h.value());
+                        make_tuple(
+                            env,
+                            &[
+                                name.release(env).to_term(env),
+                                value.release(env).to_term(env),
+                            ],
+                        )
+                    })
+                    .collect();
+                elems.push(headers.encode(env));
+                elems.push(has_body.encode(env));
+            }
+            h3::Event::Reset(code) => {
+                elems.push(common::h3_error_code_atom(code).to_term(env));
+            }
+            _ => {}
+        }
+        make_tuple(env, &elems)
+    });
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+pub fn connection_accept(
+    env: Env,
+    module: Atom,
+    conf_ptr: i64,
+    scid: Binary,
+    odcid: Binary,
+    peer: ResourceArc<Peer>,
+    sender_pid: LocalPid,
+    stream_buf_size: u64,
+) -> NifResult<(Atom, i64)> {
+    let mut timer = diagnostics::time(Probe::ConnectionAccept);
+    let scid = scid.as_slice();
+    let odcid = odcid.as_slice();
+
+    let conf_ptr = conf_ptr as *mut quiche::Config;
+    let conf = unsafe { &mut *conf_ptr };
+
+    let scid = quiche::ConnectionId::from_ref(scid);
+    let odcid = quiche::ConnectionId::from_ref(odcid);
+
+    match quiche::accept(&scid, Some(&odcid), peer.addr, conf) {
+        Ok(raw_conn) => {
+            let trace_id = raw_conn.trace_id().to_string();
+            timer.set_trace_id(&trace_id);
+            let counters = telemetry::counters(env, module)?;
+            counters.accepted();
+            let mut conn = Connection::new(
+                raw_conn,
+                peer,
+                Output::Sender(sender_pid),
+                stream_buf_size as usize,
+                counters,
+            );
+            // the calling process is the owner of the connection
+            let registration =
+                registry::registry(env, module)?.register(trace_id, Target::Owner(env.pid()));
+            conn.set_registration(registration);
+            Ok((atoms::ok(), Box::into_raw(Box::new(conn)) as i64))
+        }
+
+        Err(e) => Err(common::error_term(common::quiche_error_atom(&e))),
+    }
+}
+
+// an overloaded server drops the Initial of a new connection rather than accepting it
+// only to close it, which would cost as much as the handshake. quiche can't build a
+// CONNECTION_CLOSE without the state of the connection, so nothing is sent, the client
+// just times out. this only counts the dropped Initial.
+#[rustler::nif]
+pub fn connection_count_refused(env: Env, module: Atom) -> NifResult<Atom> {
+    telemetry::counters(env, module)?.refused();
+    Ok(atoms::ok())
+}
+
+#[rustler::nif]
+pub fn connection_set_owner(conn_ptr: i64, pid: LocalPid) -> NifResult<Atom> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    conn.set_owner(pid);
+    Ok(atoms::ok())
+}
+
+#[rustler::nif]
+pub fn connection_set_subscriber(
+    conn_ptr: i64,
+    class: Atom,
+    pid: Option<LocalPid>,
+) -> NifResult<Atom> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    let class = EventClass::from_atom(class).map_err(common::error_term)?;
+    match conn.set_subscriber(class, pid) {
+        Ok(()) => Ok(atoms::ok()),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
+
+#[rustler::nif]
+pub fn connection_stream_qlog(conn_ptr: i64, pid: LocalPid) -> NifResult<Atom> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    match conn.stream_qlog(pid) {
+        Ok(()) => Ok(atoms::ok()),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
+
+#[rustler::nif]
+pub fn connection_qlog_to_file(
+    conn_ptr: i64,
+    dir: String,
+    max_file_size: u64,
+    max_total_size: u64,
+    gzip: bool,
+) -> NifResult<Atom> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    let options = QlogFileOptions {
+        dir: dir.into(),
+        max_file_size,
+        max_total_size,
+        gzip,
+    };
+    match conn.qlog_to_file(&options) {
+        Ok(()) => Ok(atoms::ok()),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
+
+#[rustler::nif]
+pub fn connection_qlog_disable(conn_ptr: i64) -> NifResult<Atom> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    match conn.qlog_disable() {
+        Ok(()) => Ok(atoms::ok()),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
+
+#[rustler::nif]
+pub fn connection_accept_connect_request(env: Env, conn_ptr: i64) -> NifResult<(Atom, Timeout)> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    let mut env = env;
+    match conn.accept_connect_request(&mut env) {
+        Ok(next_timeout) => Ok((atoms::ok(), next_timeout)),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
+
+#[rustler::nif]
+pub fn connection_reject_connect_request(
+    env: Env,
+    conn_ptr: i64,
+    code: u32,
+) -> NifResult<(Atom, Timeout)> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    let mut env = env;
+    match conn.reject_connect_request(&mut env, code) {
+        Ok(next_timeout) => Ok((atoms::ok(), next_timeout)),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
+
+#[rustler::nif]
+pub fn connection_destroy(conn_ptr: i64) -> NifResult<Atom> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    unsafe { drop(Box::from_raw(conn_ptr)) };
+    Ok(atoms::ok())
+}
+
+#[rustler::nif]
+pub fn connection_close(
+    env: Env,
+    conn_ptr: i64,
+    app: bool,
+    err: u64,
+    reason: Binary,
+) -> NifResult<(Atom, Timeout)> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+
+    let mut env = env;
+    match conn.close(&mut env, app, err, reason.as_slice()) {
+        Ok(next_timeout) => Ok((atoms::ok(), next_timeout)),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
+
+#[rustler::nif]
+pub fn connection_enable_congestion_events(conn_ptr: i64) -> NifResult<Atom> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    conn.enable_congestion_events();
+    Ok(atoms::ok())
+}
+
+#[rustler::nif]
+pub fn connection_enable_event_timestamps(conn_ptr: i64) -> NifResult<Atom> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    conn.enable_event_timestamps();
+    Ok(atoms::ok())
+}
+
+#[rustler::nif]
+pub fn connection_enable_pmtu_events(conn_ptr: i64) -> NifResult<Atom> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    conn.enable_pmtu_events();
+    Ok(atoms::ok())
+}
+
+#[rustler::nif]
+pub fn connection_enable_stream_close_events(conn_ptr: i64) -> NifResult<Atom> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    conn.enable_stream_close_events();
+    Ok(atoms::ok())
+}
+
+// a deadline of {timeout_ms, code} for the stream, or for all the streams when nil
+#[rustler::nif]
+pub fn connection_set_send_deadline(
+    conn_ptr: i64,
+    stream_id: Option<u64>,
+    deadline: Option<(u64, u64)>,
+) -> NifResult<Atom> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    conn.set_send_deadline(stream_id, deadline.map(SendDeadline::from_tuple));
+    Ok(atoms::ok())
+}
+
+#[rustler::nif]
+pub fn connection_set_stream_framing(conn_ptr: i64, framing: Term) -> NifResult<Atom> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    match Framing::from_term(framing) {
+        Ok(framing) => {
+            conn.set_stream_framing(framing);
+            Ok(atoms::ok())
+        }
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
+
+// nil lets the packets through again
+#[rustler::nif]
+pub fn connection_set_impairment(conn_ptr: i64, spec: Option<ImpairmentTuple>) -> NifResult<Atom> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    conn.set_impairment(spec.map(ImpairmentSpec::from));
+    Ok(atoms::ok())
+}
+
+#[rustler::nif]
+pub fn connection_set_stream_recv_window(
+    conn_ptr: i64,
+    window: usize,
+    reset_code: Option<u64>,
+) -> NifResult<Atom> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    conn.set_stream_recv_window(window, reset_code);
+    Ok(atoms::ok())
+}
+
+#[rustler::nif]
+pub fn connection_set_stream_priority(
+    conn_ptr: i64,
+    stream_id: u64,
+    urgency: u8,
+    incremental: bool,
+) -> NifResult<Atom> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    match conn.set_stream_priority(stream_id, urgency, incremental) {
+        Ok(()) => Ok(atoms::ok()),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
+
+#[rustler::nif]
+pub fn connection_set_send_order(conn_ptr: i64, order: Term) -> NifResult<Atom> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    let order = SendOrder::from_term(order).map_err(common::error_term)?;
+    conn.set_send_order(order);
+    Ok(atoms::ok())
+}
+
+#[rustler::nif]
+pub fn connection_set_stream_pull_mode(conn_ptr: i64, enabled: bool) -> NifResult<Atom> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    conn.set_stream_pull_mode(enabled);
+    Ok(atoms::ok())
+}
+
+#[rustler::nif]
+pub fn connection_stream_read<'a>(
+    env: Env<'a>,
+    pid: LocalPid,
+    conn_ptr: i64,
+    stream_id: u64,
+    max_bytes: usize,
+) -> NifResult<(Atom, Binary<'a>, Timeout)> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    let mut emitter = env;
+    match conn.stream_read(&mut emitter, &pid, stream_id, max_bytes) {
+        Ok((data, next_timeout)) => Ok((atoms::ok(), data.release(env), next_timeout)),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
+
+#[rustler::nif]
+pub fn connection_h3_recv_body<'a>(
+    env: Env<'a>,
+    pid: LocalPid,
+    conn_ptr: i64,
+    stream_id: u64,
+    max_bytes: usize,
+) -> NifResult<(Atom, Binary<'a>, Timeout)> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    let mut emitter = env;
+    match conn.h3_recv_body(&mut emitter, &pid, stream_id, max_bytes) {
+        Ok((data, next_timeout)) => Ok((atoms::ok(), data.release(env), next_timeout)),
+        Err(reason) => Err(common::error_term(reason)),
+    }
 }
 
 #[rustler::nif]
-pub fn connection_accept(
-    conf_ptr: i64,
-    scid: Binary,
-    odcid: Binary,
-    peer: ResourceArc<Peer>,
-    sender_pid: LocalPid,
-    stream_buf_size: u64,
-) -> NifResult<(Atom, i64)> {
-    let scid = scid.as_slice();
-    let odcid = odcid.as_slice();
+pub fn connection_set_dgram_budget(conn_ptr: i64, budget: Option<usize>) -> NifResult<Atom> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    conn.set_dgram_budget(budget);
+    Ok(atoms::ok())
+}
 
-    let conf_ptr = conf_ptr as *mut quiche::Config;
-    let conf = unsafe { &mut *conf_ptr };
+#[rustler::nif]
+pub fn connection_set_keepalive(conn_ptr: i64, interval_ms: Option<u64>) -> NifResult<Atom> {
+    // an interval of 0 would make the keepalive due at every timeout
+    if interval_ms == Some(0) {
+        return Err(common::error_term(atoms::bad_format()));
+    }
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    conn.set_keepalive(interval_ms.map(Duration::from_millis));
+    Ok(atoms::ok())
+}
 
-    let scid = quiche::ConnectionId::from_ref(scid);
-    let odcid = quiche::ConnectionId::from_ref(odcid);
+// each of the limits is {milliseconds, app_error_code} or nil
+#[rustler::nif]
+pub fn connection_set_lifetime_policy(
+    conn_ptr: i64,
+    max_lifetime: Option<(u64, u64)>,
+    max_inactivity: Option<(u64, u64)>,
+) -> NifResult<Atom> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    conn.set_lifetime_policy(
+        max_lifetime.map(|(ms, code)| (Duration::from_millis(ms), code)),
+        max_inactivity.map(|(ms, code)| (Duration::from_millis(ms), code)),
+    );
+    Ok(atoms::ok())
+}
 
-    match quiche::accept(&scid, Some(&odcid), peer.addr, conf) {
-        Ok(raw_conn) => {
-            let conn = Connection::new(raw_conn, peer, sender_pid, stream_buf_size as usize);
-            Ok((atoms::ok(), Box::into_raw(Box::new(conn)) as i64))
-        }
+#[rustler::nif]
+pub fn connection_set_dgram_pull_mode(conn_ptr: i64, enabled: bool) -> NifResult<Atom> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    conn.set_dgram_pull_mode(enabled);
+    Ok(atoms::ok())
+}
 
-        Err(_) => Err(common::error_term(atoms::system_error())),
+#[rustler::nif]
+pub fn connection_dgram_peek<'a>(
+    env: Env<'a>,
+    conn_ptr: i64,
+    max_len: usize,
+) -> NifResult<(Atom, Binary<'a>, usize)> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    match conn.dgram_peek(max_len) {
+        Ok((data, total_len)) => Ok((atoms::ok(), data.release(env), total_len)),
+        Err(reason) => Err(common::error_term(reason)),
     }
 }
 
 #[rustler::nif]
-pub fn connection_accept_connect_request(env: Env, conn_ptr: i64) -> NifResult<(Atom, u64)> {
+pub fn connection_probe_path(
+    env: Env,
+    conn_ptr: i64,
+    timeout_ms: u64,
+) -> NifResult<(Atom, Timeout)> {
     let conn_ptr = conn_ptr as *mut Connection;
     let conn = unsafe { &mut *conn_ptr };
-    match conn.accept_connect_request(&env) {
+    let mut emitter = env;
+    match conn.probe_path(&mut emitter, Duration::from_millis(timeout_ms)) {
         Ok(next_timeout) => Ok((atoms::ok(), next_timeout)),
         Err(reason) => Err(common::error_term(reason)),
     }
 }
 
 #[rustler::nif]
-pub fn connection_reject_connect_request(
+pub fn connection_dgram_recv(
     env: Env,
+    pid: LocalPid,
     conn_ptr: i64,
-    code: u32,
-) -> NifResult<(Atom, u64)> {
+    deliver: bool,
+) -> NifResult<(Atom, Timeout)> {
     let conn_ptr = conn_ptr as *mut Connection;
     let conn = unsafe { &mut *conn_ptr };
-    match conn.reject_connect_request(&env, code) {
+    let mut emitter = env;
+    match conn.dgram_recv(&mut emitter, &pid, deliver) {
         Ok(next_timeout) => Ok((atoms::ok(), next_timeout)),
         Err(reason) => Err(common::error_term(reason)),
     }
 }
 
 #[rustler::nif]
-pub fn connection_destroy(conn_ptr: i64) -> NifResult<Atom> {
+pub fn connection_stream_ack(
+    env: Env,
+    pid: LocalPid,
+    conn_ptr: i64,
+    stream_id: u64,
+    bytes: usize,
+) -> NifResult<(Atom, Timeout)> {
     let conn_ptr = conn_ptr as *mut Connection;
-    unsafe { drop(Box::from_raw(conn_ptr)) };
+    let conn = unsafe { &mut *conn_ptr };
+    let mut env = env;
+    match conn.stream_ack(&mut env, &pid, stream_id, bytes) {
+        Ok(next_timeout) => Ok((atoms::ok(), next_timeout)),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
+
+#[rustler::nif]
+pub fn connection_set_trace_context(
+    conn_ptr: i64,
+    trace_id: Binary,
+    span_id: Binary,
+    flags: u8,
+) -> NifResult<Atom> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    let context = TraceContext::new(trace_id.as_slice(), span_id.as_slice(), flags)
+        .map_err(common::error_term)?;
+    conn.set_trace_context(context);
     Ok(atoms::ok())
 }
 
 #[rustler::nif]
-pub fn connection_close(
-    env: Env,
+pub fn connection_enable_sampling(
     conn_ptr: i64,
-    app: bool,
-    err: u64,
-    reason: Binary,
-) -> NifResult<(Atom, u64)> {
+    interval_ms: u64,
+    capacity: usize,
+) -> NifResult<Atom> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    conn.enable_sampling(Duration::from_millis(interval_ms), capacity);
+    Ok(atoms::ok())
+}
+
+#[rustler::nif]
+pub fn connection_samples(conn_ptr: i64) -> NifResult<(Atom, Vec<SampleTuple>)> {
     let conn_ptr = conn_ptr as *mut Connection;
     let conn = unsafe { &mut *conn_ptr };
+    let samples = conn.samples().into_iter().map(Sample::to_tuple).collect();
+    Ok((atoms::ok(), samples))
+}
 
-    match conn.close(&env, app, err, reason.as_slice()) {
-        Ok(next_timeout) => Ok((atoms::ok(), next_timeout)),
+#[rustler::nif]
+pub fn connection_tap_pid(conn_ptr: i64, pid: LocalPid) -> NifResult<Atom> {
+    set_tap(conn_ptr, Some(TapSpec::Pid(pid)))
+}
+
+#[rustler::nif]
+pub fn connection_tap_pcap(conn_ptr: i64, dir: String) -> NifResult<Atom> {
+    set_tap(conn_ptr, Some(TapSpec::Pcap(dir.into())))
+}
+
+#[rustler::nif]
+pub fn connection_untap(conn_ptr: i64) -> NifResult<Atom> {
+    set_tap(conn_ptr, None)
+}
+
+fn set_tap(conn_ptr: i64, spec: Option<TapSpec>) -> NifResult<Atom> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    match conn.set_tap(spec) {
+        Ok(()) => Ok(atoms::ok()),
         Err(reason) => Err(common::error_term(reason)),
     }
 }
 
+#[rustler::nif]
+pub fn connection_stream_stats(
+    conn_ptr: i64,
+    stream_id: u64,
+) -> NifResult<(Atom, StreamStatsTuple)> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    match conn.stream_stats(stream_id) {
+        Some(stats) => Ok((atoms::ok(), stats.to_tuple())),
+        None => Err(common::error_term(atoms::not_found())),
+    }
+}
+
+#[rustler::nif]
+pub fn connection_stream_counts(conn_ptr: i64) -> NifResult<(Atom, StreamCountsTuple)> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    Ok((atoms::ok(), conn.stream_counts().to_tuple()))
+}
+
+#[rustler::nif]
+pub fn connection_pmtu(conn_ptr: i64) -> NifResult<(Atom, usize)> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    Ok((atoms::ok(), conn.pmtu()))
+}
+
+#[rustler::nif]
+pub fn connection_readable(conn_ptr: i64) -> NifResult<(Atom, Vec<u64>)> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    Ok((atoms::ok(), conn.readable_streams()))
+}
+
+#[rustler::nif]
+pub fn connection_writable(conn_ptr: i64) -> NifResult<(Atom, Vec<u64>)> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    Ok((atoms::ok(), conn.writable_streams()))
+}
+
 #[rustler::nif]
 pub fn connection_is_closed(conn_ptr: i64) -> bool {
     let conn_ptr = conn_ptr as *mut Connection;
@@ -570,30 +3529,60 @@ pub fn connection_is_closed(conn_ptr: i64) -> bool {
 }
 
 #[rustler::nif]
+pub fn connection_handoff_state(env: Env, conn_ptr: i64) -> NifResult<(Atom, Term)> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    Ok((atoms::ok(), conn.handoff_state().to_term(env)?))
+}
+
+#[rustler::nif]
+pub fn connection_peer_cert<'a>(
+    env: Env<'a>,
+    conn_ptr: i64,
+) -> NifResult<(Atom, Option<Binary<'a>>)> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    Ok((atoms::ok(), conn.peer_cert().map(|cert| cert.release(env))))
+}
+
+// nil until the connection is closed, or when quiche has closed it by itself
+#[rustler::nif]
+pub fn connection_close_info(env: Env, conn_ptr: i64) -> Term {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    match conn.close_info().filter(|_| conn.is_closed()) {
+        Some(info) => info.to_term(env),
+        None => rustler::types::atom::nil().to_term(env),
+    }
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
 pub fn connection_on_packet(
     env: Env,
     pid: LocalPid,
     conn_ptr: i64,
     packet: Binary,
     peer: ResourceArc<Peer>,
-) -> NifResult<(Atom, u64)> {
+) -> NifResult<(Atom, Timeout)> {
     let conn_ptr = conn_ptr as *mut Connection;
     let conn = unsafe { &mut *conn_ptr };
+    let _timer = diagnostics::time_conn(Probe::ConnectionOnPacket, conn.trace_id());
 
-    let mut packet = packet.to_owned().unwrap();
-
-    match conn.process_packet(&env, &pid, packet.as_mut_slice(), peer.addr) {
+    let mut env = env;
+    match conn.process_packet(&mut env, &pid, packet.as_slice(), peer.addr) {
         Ok(next_timeout) => Ok((atoms::ok(), next_timeout)),
         Err(reason) => Err(common::error_term(reason)),
     }
 }
 
 #[rustler::nif]
-pub fn connection_on_timeout(env: Env, conn_ptr: i64) -> NifResult<(Atom, u64)> {
+pub fn connection_on_timeout(env: Env, pid: LocalPid, conn_ptr: i64) -> NifResult<(Atom, Timeout)> {
     let conn_ptr = conn_ptr as *mut Connection;
     let conn = unsafe { &mut *conn_ptr };
+    let _timer = diagnostics::time_conn(Probe::ConnectionOnTimeout, conn.trace_id());
 
-    match conn.execute_timeout(&env) {
+    let mut env = env;
+    match conn.execute_timeout(&mut env, &pid) {
         Ok(next_timeout) => Ok((atoms::ok(), next_timeout)),
         Err(reason) => Err(common::error_term(reason)),
     }
@@ -604,14 +3593,15 @@ pub fn connection_open_stream(
     env: Env,
     conn_ptr: i64,
     is_bidi: bool,
-) -> NifResult<(Atom, u64, u64)> {
+) -> NifResult<(Atom, u64, Timeout)> {
     let conn_ptr = conn_ptr as *mut Connection;
     let conn = unsafe { &mut *conn_ptr };
 
-    match conn.open_stream(&env, is_bidi) {
+    let mut env = env;
+    match conn.open_stream(&mut env, is_bidi) {
         Ok((stream_id, next_timeout)) => {
             debug!(
-                "open_stream returns stream_id:{}, next_timeout:{}",
+                "open_stream returns stream_id:{}, next_timeout:{:?}",
                 stream_id, next_timeout
             );
             Ok((atoms::ok(), stream_id, next_timeout))
@@ -620,29 +3610,399 @@ pub fn connection_open_stream(
     }
 }
 
-#[rustler::nif]
-pub fn connection_stream_send(
-    env: Env,
+// same as connection_on_packet, but returns the packets to send as [{peer, packet}]
+// instead of passing them to the sender process
+#[rustler::nif(schedule = "DirtyCpu")]
+pub fn connection_on_packet_sync<'a>(
+    env: Env<'a>,
+    pid: LocalPid,
+    conn_ptr: i64,
+    packet: Binary,
+    peer: ResourceArc<Peer>,
+) -> NifResult<(Atom, Timeout, Vec<Term<'a>>)> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    let _timer = diagnostics::time_conn(Probe::ConnectionOnPacket, conn.trace_id());
+
+    let mut env = env;
+    conn.collect_packets();
+    let result = conn.process_packet(&mut env, &pid, packet.as_slice(), peer.addr);
+    let packets = conn.take_collected(env);
+    match result {
+        Ok(next_timeout) => Ok((atoms::ok(), next_timeout, packets)),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+pub fn connection_stream_send<'a>(
+    env: Env<'a>,
+    conn_ptr: i64,
+    stream_id: u64,
+    data: Binary,
+    fin: bool,
+) -> NifResult<(Atom, Term<'a>)> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    let _timer = diagnostics::time_conn(Probe::ConnectionStreamSend, conn.trace_id());
+
+    let mut env = env;
+    let data = data.as_slice();
+    if data.len() > STREAM_SEND_SLICE {
+        // all the data is checked before the first slice is sent, so that the send
+        // doesn't run out of room halfway, with some of the data sent already
+        if let Err(reason) = conn.check_stream_budget(stream_id, data.len()) {
+            return Err(common::error_term(reason));
+        }
+        // {:continue, sent}, the caller sends the rest with another call
+        return match conn.send_stream_data(&mut env, stream_id, &data[..STREAM_SEND_SLICE], false) {
+            Ok(_next_timeout) => Ok((atoms::continue_(), (STREAM_SEND_SLICE as u64).encode(env))),
+            Err(reason) => Err(common::error_term(reason)),
+        };
+    }
+    match conn.send_stream_data(&mut env, stream_id, data, fin) {
+        Ok(next_timeout) => Ok((atoms::ok(), next_timeout.encode(env))),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
+
+// same as connection_stream_send, but returns the packets to send as [{peer, packet}]
+// instead of passing them to the sender process
+#[rustler::nif(schedule = "DirtyCpu")]
+pub fn connection_stream_send_sync<'a>(
+    env: Env<'a>,
     conn_ptr: i64,
     stream_id: u64,
     data: Binary,
     fin: bool,
-) -> NifResult<(Atom, u64)> {
+) -> NifResult<(Atom, Term<'a>, Vec<Term<'a>>)> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    let _timer = diagnostics::time_conn(Probe::ConnectionStreamSend, conn.trace_id());
+
+    let mut env = env;
+    let data = data.as_slice();
+    conn.collect_packets();
+    let result = if data.len() > STREAM_SEND_SLICE {
+        // {:continue, sent, packets}, the caller sends the rest with another call,
+        // all the data being checked before the first slice as in connection_stream_send
+        conn.check_stream_budget(stream_id, data.len())
+            .and_then(|()| {
+                conn.send_stream_data(&mut env, stream_id, &data[..STREAM_SEND_SLICE], false)
+            })
+            .map(|_next_timeout| (atoms::continue_(), (STREAM_SEND_SLICE as u64).encode(env)))
+    } else {
+        conn.send_stream_data(&mut env, stream_id, data, fin)
+            .map(|next_timeout| (atoms::ok(), next_timeout.encode(env)))
+    };
+    // {:error, reason, packets} on failure, the packets of the data sent before
+    // the failure still have to go out
+    let packets = conn.take_collected(env);
+    match result {
+        Ok((status, value)) => Ok((status, value, packets)),
+        Err(reason) => Ok((atoms::error(), reason.encode(env), packets)),
+    }
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+pub fn connection_stream_send_vectored(
+    env: Env,
+    conn_ptr: i64,
+    stream_id: u64,
+    data: ListIterator,
+    fin: bool,
+) -> NifResult<(Atom, Timeout)> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    let _timer = diagnostics::time_conn(Probe::ConnectionStreamSendVectored, conn.trace_id());
+
+    let chunks: Vec<Binary> = data
+        .map(|x| x.decode::<Binary>())
+        .collect::<NifResult<Vec<Binary>>>()
+        .map_err(|_| common::error_term(atoms::bad_format()))?;
+    let chunks: Vec<&[u8]> = chunks.iter().map(|chunk| chunk.as_slice()).collect();
+
+    let mut env = env;
+    match conn.send_stream_message(&mut env, stream_id, &chunks, fin) {
+        Ok(next_timeout) => Ok((atoms::ok(), next_timeout)),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+pub fn connection_stream_send_full(
+    env: Env,
+    conn_ptr: i64,
+    stream_id: u64,
+    data: ListIterator,
+    fin: bool,
+) -> NifResult<(Atom, Timeout)> {
     let conn_ptr = conn_ptr as *mut Connection;
     let conn = unsafe { &mut *conn_ptr };
+    let _timer = diagnostics::time_conn(Probe::ConnectionStreamSendVectored, conn.trace_id());
 
-    match conn.send_stream_data(&env, stream_id, data.as_slice(), fin) {
+    let chunks: Vec<Binary> = data
+        .map(|x| x.decode::<Binary>())
+        .collect::<NifResult<Vec<Binary>>>()
+        .map_err(|_| common::error_term(atoms::bad_format()))?;
+    let chunks: Vec<&[u8]> = chunks.iter().map(|chunk| chunk.as_slice()).collect();
+
+    let mut env = env;
+    match conn.send_stream_full(&mut env, stream_id, &chunks, fin) {
+        Ok(next_timeout) => Ok((atoms::ok(), next_timeout)),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
+
+#[rustler::nif]
+pub fn connection_dgram_send(env: Env, conn_ptr: i64, data: Binary) -> NifResult<(Atom, Timeout)> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    let _timer = diagnostics::time_conn(Probe::ConnectionDgramSend, conn.trace_id());
+    let mut env = env;
+    match conn.send_dgram(&mut env, data.as_slice()) {
         Ok(next_timeout) => Ok((atoms::ok(), next_timeout)),
         Err(reason) => Err(common::error_term(reason)),
     }
 }
 
 #[rustler::nif]
-pub fn connection_dgram_send(env: Env, conn_ptr: i64, data: Binary) -> NifResult<(Atom, u64)> {
+pub fn connection_dgram_send_batch(
+    env: Env,
+    conn_ptr: i64,
+    data: ListIterator,
+) -> NifResult<(Atom, usize, Timeout)> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    let _timer = diagnostics::time_conn(Probe::ConnectionDgramSend, conn.trace_id());
+
+    let dgrams: Vec<Binary> = data
+        .map(|x| x.decode::<Binary>())
+        .collect::<NifResult<Vec<Binary>>>()
+        .map_err(|_| common::error_term(atoms::bad_format()))?;
+    let dgrams: Vec<&[u8]> = dgrams.iter().map(|dgram| dgram.as_slice()).collect();
+
+    let mut env = env;
+    match conn.send_dgram_batch(&mut env, &dgrams) {
+        Ok((accepted, next_timeout)) => Ok((atoms::ok(), accepted, next_timeout)),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
+
+#[rustler::nif]
+pub fn connection_h3_dgram_send(
+    env: Env,
+    conn_ptr: i64,
+    flow_id: u64,
+    data: Binary,
+) -> NifResult<(Atom, Timeout)> {
     let conn_ptr = conn_ptr as *mut Connection;
     let conn = unsafe { &mut *conn_ptr };
-    match conn.send_dgram(&env, data.as_slice()) {
+    let mut env = env;
+    match conn.send_h3_dgram(&mut env, flow_id, data.as_slice()) {
         Ok(next_timeout) => Ok((atoms::ok(), next_timeout)),
         Err(reason) => Err(common::error_term(reason)),
     }
 }
+
+#[rustler::nif]
+pub fn connection_set_request_limits(
+    conn_ptr: i64,
+    max_field_section_size: u64,
+    max_requests: u64,
+    max_header_count: u64,
+) -> NifResult<Atom> {
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    conn.set_request_limits(
+        max_field_section_size as usize,
+        max_requests as usize,
+        max_header_count as usize,
+    );
+    Ok(atoms::ok())
+}
+
+// 0 for min or max disables adaptive sizing, clearing the range set before
+#[rustler::nif]
+pub fn connection_set_stream_buffer_range(conn_ptr: i64, min: u64, max: u64) -> NifResult<Atom> {
+    if min != 0 && max != 0 && (min > max || max > MAX_STREAM_BUF_SIZE as u64) {
+        return Err(common::error_term(atoms::bad_format()));
+    }
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    if min == 0 || max == 0 {
+        conn.clear_stream_buf_range();
+    } else {
+        conn.set_stream_buf_range(min as usize, max as usize);
+    }
+    Ok(atoms::ok())
+}
+
+#[rustler::nif]
+pub fn connection_stream_buffer_resize(conn_ptr: i64, size: u64) -> NifResult<Atom> {
+    if size == 0 || size > MAX_STREAM_BUF_SIZE as u64 {
+        return Err(common::error_term(atoms::bad_format()));
+    }
+    let conn_ptr = conn_ptr as *mut Connection;
+    let conn = unsafe { &mut *conn_ptr };
+    conn.resize_stream_buf(size as usize);
+    Ok(atoms::ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recv_credit_pauses_at_the_window() {
+        let mut credit = RecvCredit::new(10, None);
+        assert_eq!(credit.available(0), 10);
+        credit.delivered(0, 6);
+        assert_eq!(credit.available(0), 4);
+        assert!(!credit.paused.contains(&0));
+        credit.delivered(0, 4);
+        assert_eq!(credit.available(0), 0);
+        assert!(credit.paused.contains(&0));
+        // the other streams have a window of their own
+        assert_eq!(credit.available(4), 10);
+    }
+
+    #[test]
+    fn recv_credit_ack_resumes() {
+        let mut credit = RecvCredit::new(10, None);
+        credit.delivered(0, 12);
+        assert_eq!(credit.available(0), 0);
+        assert!(credit.ack(0, 5));
+        assert_eq!(credit.available(0), 3);
+        assert!(!credit.ack(0, 5));
+        // more than delivered
+        assert!(!credit.ack(0, 100));
+        assert_eq!(credit.available(0), 10);
+        assert!(credit.unacked.is_empty());
+    }
+
+    #[test]
+    fn recv_credit_forget() {
+        let mut credit = RecvCredit::new(10, Some(0x10));
+        credit.delivered(0, 10);
+        credit.forget(0);
+        assert_eq!(credit.available(0), 10);
+        assert!(credit.paused.is_empty());
+        assert!(!credit.ack(0, 1));
+    }
+
+    fn chunks(lens: &[usize]) -> VecDeque<Vec<u8>> {
+        lens.iter()
+            .enumerate()
+            .map(|(i, len)| vec![i as u8; *len])
+            .collect()
+    }
+
+    // takes at most credit bytes, as the flow control of a stream
+    fn write_with(credit: &mut usize) -> impl FnMut(&[u8]) -> Result<usize, Atom> + '_ {
+        move |chunk| {
+            let written = chunk.len().min(*credit);
+            *credit -= written;
+            Ok(written)
+        }
+    }
+
+    #[test]
+    fn pending_streams_budget_edge() {
+        let mut pending = PendingStreams::default();
+        assert!(!pending.over_budget(MAX_PENDING_STREAM_BYTES, 0));
+        assert!(pending.over_budget(MAX_PENDING_STREAM_BYTES + 1, 0));
+        // what the flow control takes now isn't held
+        assert!(!pending.over_budget(MAX_PENDING_STREAM_BYTES + 10, 10));
+        pending.hold(0, chunks(&[100]), false, None);
+        assert_eq!(pending.bytes, 100);
+        assert!(!pending.over_budget(MAX_PENDING_STREAM_BYTES - 100, 0));
+        assert!(pending.over_budget(MAX_PENDING_STREAM_BYTES - 99, 0));
+        pending.hold(4, chunks(&[MAX_PENDING_STREAM_BYTES - 100]), false, None);
+        assert!(pending.over_budget(1, 0));
+        assert!(!pending.over_budget(1, 1));
+    }
+
+    #[test]
+    fn pending_streams_partial_writes() {
+        let mut pending = PendingStreams::default();
+        pending.hold(0, chunks(&[10, 10]), false, None);
+        pending.hold(0, chunks(&[5]), true, None);
+        assert_eq!(pending.bytes, 25);
+
+        let mut credit = 15;
+        assert_eq!(pending.flush(0, write_with(&mut credit)), Flushed::Blocked);
+        assert_eq!(pending.bytes, 10);
+        let held = &pending.streams[&0].chunks;
+        assert_eq!(held.len(), 2);
+        assert_eq!(held[0], vec![1; 5]);
+
+        let mut credit = 0;
+        assert_eq!(pending.flush(0, write_with(&mut credit)), Flushed::Blocked);
+        assert_eq!(pending.bytes, 10);
+
+        let mut credit = 100;
+        assert_eq!(
+            pending.flush(0, write_with(&mut credit)),
+            Flushed::Drained { fin: true }
+        );
+        assert_eq!(pending.bytes, 0);
+        // kept for the FIN until it's removed
+        assert!(pending.contains(0));
+        assert!(pending.remove(0).is_some());
+        assert!(!pending.contains(0));
+    }
+
+    #[test]
+    fn pending_streams_reset_stream() {
+        let mut pending = PendingStreams::default();
+        pending.hold(0, chunks(&[10, 10]), true, None);
+        pending.hold(4, chunks(&[7]), false, None);
+        let mut calls = 0;
+        let flushed = pending.flush(0, |_chunk| {
+            calls += 1;
+            Err(atoms::stream_blocked())
+        });
+        assert_eq!(flushed, Flushed::Failed);
+        assert_eq!(calls, 1);
+        // the data held for the other streams is still counted
+        assert!(!pending.contains(0));
+        assert_eq!(pending.bytes, 7);
+        assert_eq!(pending.remove(4).map(|held| held.chunks.len()), Some(1));
+        assert_eq!(pending.bytes, 0);
+    }
+
+    #[test]
+    fn pending_streams_expiry() {
+        let mut pending = PendingStreams::default();
+        let now = Instant::now();
+        pending.hold(0, chunks(&[10]), false, Some((now, 0x10)));
+        // the deadline is the one of the stream when it started being held
+        pending.hold(0, chunks(&[10]), false, None);
+        pending.hold(
+            4,
+            chunks(&[10]),
+            false,
+            Some((now + Duration::from_secs(1), 0x11)),
+        );
+        pending.hold(8, chunks(&[10]), false, None);
+        assert_eq!(pending.next_expiry(), Some(now));
+        assert_eq!(pending.expired(now), vec![(0, 0x10)]);
+        pending.remove(0);
+        assert_eq!(pending.bytes, 20);
+        assert_eq!(pending.next_expiry(), Some(now + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn stream_buffer_size_clear_range() {
+        let mut size = StreamBufferSize::new(1024);
+        size.set_range(512, 4096);
+        for _ in 0..32 {
+            size.observe(4000);
+        }
+        assert_eq!(size.get(), 4096);
+        size.clear_range();
+        size.observe(10);
+        assert_eq!(size.get(), 4096);
+    }
+}
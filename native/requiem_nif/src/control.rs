@@ -0,0 +1,284 @@
+use std::fmt::Write as _;
+use std::fs::{self, Permissions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use rustler::env::OwnedEnv;
+use rustler::{Atom, Env, NifResult};
+
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender};
+
+use crate::common::{self, atoms};
+use crate::qlog::QlogFileOptions;
+use crate::registry::{self, Registry};
+use crate::telemetry::{self, Counters};
+
+// how often the thread looks for a new client and for the closer
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+// a client which sends nothing for this long is dropped for the next one
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+const HELP: &str = "\
+connections             the live connections: trace id and datapath
+stats                   the counters of the module
+close <trace_id> [code] closes the connection with the application error code
+qlog <trace_id> on      writes the qlog of a native datapath connection into qlog_dir
+qlog <trace_id> off     stops writing the qlog of the connection
+help                    this text
+";
+
+// what the control socket works on
+struct Module {
+    counters: Arc<Counters>,
+    registry: Arc<Registry>,
+    counter_names: Vec<String>,
+    drop_names: Vec<String>,
+    // the qlog command only writes here, the module's qlog_dir
+    qlog_dir: Option<PathBuf>,
+    qlog_max_file_size: u64,
+    qlog_max_total_size: u64,
+    qlog_gzip: bool,
+}
+
+impl Module {
+    // each reply ends with a line of its own, "ok" or "error <reason>"
+    fn execute(&self, env: &mut OwnedEnv, line: &str) -> String {
+        let mut out = String::new();
+        let mut args = line.split_whitespace();
+        let result = match args.next() {
+            Some("connections") => {
+                for (trace_id, native) in self.registry.list() {
+                    let datapath = if native { "native" } else { "beam" };
+                    let _ = writeln!(out, "{} {}", trace_id, datapath);
+                }
+                Ok(())
+            }
+            Some("stats") => {
+                let snapshot = self.counters.snapshot();
+                for (name, (_, value)) in self.counter_names.iter().zip(snapshot) {
+                    let _ = writeln!(out, "{} {}", name, value);
+                }
+                let drops = self.counters.drops();
+                for (reason, (_, value)) in self.drop_names.iter().zip(drops) {
+                    let _ = writeln!(out, "packet_drops.{} {}", reason, value);
+                }
+                let _ = writeln!(out, "live_connections {}", self.registry.len());
+                Ok(())
+            }
+            Some("close") => match (args.next(), parse_code(args.next())) {
+                (Some(trace_id), Some(code)) => {
+                    if self.registry.shutdown(env, trace_id, code, b"") {
+                        Ok(())
+                    } else {
+                        Err("not_found")
+                    }
+                }
+                _ => Err("bad_format"),
+            },
+            Some("qlog") => match (args.next(), args.next()) {
+                (Some(trace_id), Some("off")) => {
+                    self.registry.qlog_disable(trace_id).map_err(qlog_error)
+                }
+                (Some(trace_id), Some("on")) => match &self.qlog_dir {
+                    Some(dir) => {
+                        let options = QlogFileOptions {
+                            dir: dir.clone(),
+                            max_file_size: self.qlog_max_file_size,
+                            max_total_size: self.qlog_max_total_size,
+                            gzip: self.qlog_gzip,
+                        };
+                        self.registry
+                            .qlog_to_file(trace_id, options)
+                            .map_err(qlog_error)
+                    }
+                    None => Err("no_qlog_dir"),
+                },
+                _ => Err("bad_format"),
+            },
+            Some("help") => {
+                out.push_str(HELP);
+                Ok(())
+            }
+            _ => Err("unknown_command"),
+        };
+        match result {
+            Ok(()) => out.push_str("ok\n"),
+            Err(reason) => {
+                let _ = writeln!(out, "error {}", reason);
+            }
+        }
+        out
+    }
+}
+
+fn qlog_error(reason: Atom) -> &'static str {
+    if reason == atoms::not_supported() {
+        "not_supported"
+    } else {
+        "not_found"
+    }
+}
+
+// the error code of close, 0 when left out
+fn parse_code(arg: Option<&str>) -> Option<u64> {
+    match arg {
+        None => Some(0),
+        Some(code) => match code.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => code.parse().ok(),
+        },
+    }
+}
+
+// serves a line based admin protocol on a unix domain socket from a thread of its own,
+// so that connections can be inspected and closed even when the BEAM is overloaded.
+// one client is served at a time.
+pub struct ControlSocket {
+    path: PathBuf,
+    closer: Sender<()>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ControlSocket {
+    fn start(path: PathBuf, module: Module) -> io::Result<Self> {
+        // a socket file left by a previous run would make bind fail, anything else
+        // at the path is left alone
+        if let Ok(meta) = fs::symlink_metadata(&path) {
+            if !meta.file_type().is_socket() {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, "not a socket"));
+            }
+            fs::remove_file(&path)?;
+        }
+        let listener = UnixListener::bind(&path)?;
+        // the socket can close any connection, only the owner of the node may connect
+        if let Err(e) = fs::set_permissions(&path, Permissions::from_mode(0o600)) {
+            let _ = fs::remove_file(&path);
+            return Err(e);
+        }
+        listener.set_nonblocking(true)?;
+
+        let (closer_tx, closer_rx) = bounded::<()>(1);
+
+        let handle = thread::spawn(move || {
+            let mut env = OwnedEnv::new();
+            loop {
+                match closer_rx.recv_timeout(ACCEPT_INTERVAL) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    _ => break,
+                }
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        if let Err(e) = serve(stream, &module, &mut env, &closer_rx) {
+                            debug!("control socket client error: {:?}", e);
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => debug!("control socket accept error: {:?}", e),
+                }
+            }
+        });
+
+        Ok(Self {
+            path,
+            closer: closer_tx,
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = self.closer.send(());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn serve(
+    stream: UnixStream,
+    module: &Module,
+    env: &mut OwnedEnv,
+    closer: &Receiver<()>,
+) -> io::Result<()> {
+    // reads time out in steps of ACCEPT_INTERVAL, so that the closer is still heard
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(ACCEPT_INTERVAL))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    let mut idle = Duration::ZERO;
+    loop {
+        if !closer.is_empty() {
+            return Ok(());
+        }
+        match reader.read_line(&mut line) {
+            Ok(0) => return Ok(()),
+            Ok(_) => {
+                idle = Duration::ZERO;
+                if line.ends_with('\n') {
+                    if !line.trim().is_empty() {
+                        writer.write_all(module.execute(env, line.trim()).as_bytes())?;
+                    }
+                    line.clear();
+                }
+            }
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                idle += ACCEPT_INTERVAL;
+                if idle >= CLIENT_TIMEOUT {
+                    return Ok(());
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// the control socket of a module at `path`, the qlog options being those of the
+// files written by the `qlog` command
+#[rustler::nif]
+pub fn control_socket_open(
+    env: Env,
+    module: Atom,
+    path: String,
+    qlog_dir: Option<String>,
+    qlog_max_file_size: u64,
+    qlog_max_total_size: u64,
+    qlog_gzip: bool,
+) -> NifResult<(Atom, i64)> {
+    let counters = telemetry::counters(env, module)?;
+    let module = Module {
+        counter_names: telemetry::names(env, counters.snapshot())?,
+        drop_names: telemetry::names(env, counters.drops())?,
+        counters,
+        registry: registry::registry(env, module)?,
+        qlog_dir: qlog_dir.map(PathBuf::from),
+        qlog_max_file_size,
+        qlog_max_total_size,
+        qlog_gzip,
+    };
+
+    match ControlSocket::start(PathBuf::from(path), module) {
+        Ok(control) => Ok((atoms::ok(), Box::into_raw(Box::new(control)) as i64)),
+        Err(e) => {
+            error!("failed to open control socket: {:?}", e);
+            Err(common::error_term(atoms::cant_bind()))
+        }
+    }
+}
+
+// joins the thread, which may be serving a client for up to ACCEPT_INTERVAL
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn control_socket_close(control_ptr: i64) -> NifResult<Atom> {
+    let control_ptr = control_ptr as *mut ControlSocket;
+    unsafe { drop(Box::from_raw(control_ptr)) };
+    Ok(atoms::ok())
+}
@@ -0,0 +1,2192 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::mem::ManuallyDrop;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::rc::Rc;
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use mio::net::UdpSocket;
+use mio::{Events, Interest, Poll, Token, Waker};
+use ring::hmac;
+
+use rustler::env::OwnedEnv;
+use rustler::types::binary::{Binary, OwnedBinary};
+use rustler::types::tuple::make_tuple;
+use rustler::types::{Encoder, LocalPid};
+use rustler::{Atom, Env, ListIterator, NifResult, ResourceArc, Term};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crossbeam_channel::{bounded, unbounded, Receiver, Sender};
+
+use crate::batch::{self, RecvBatch, SendBatch};
+use crate::common::{self, atoms};
+use crate::connection::{
+    closed_term, Connection, Outbound, Output, SendDeadline, SendOrder, StreamCounts,
+    StreamCountsTuple, StreamStats, StreamStatsTuple, STREAM_SEND_SLICE,
+};
+use crate::diagnostics::{self, Probe};
+use crate::framing::Framing;
+use crate::handoff::HandoffState;
+use crate::impairment::{ImpairmentSpec, ImpairmentTuple};
+use crate::qlog::QlogFileOptions;
+use crate::registry::{self, Control, Registry, Target};
+use crate::sampling::{Sample, SampleTuple};
+use crate::socket::Peer;
+use crate::subscribers::EventClass;
+use crate::tap::TapSpec;
+use crate::telemetry::{self, Counters, DropReason};
+use crate::timer::{Timeout, TimerWheel};
+use crate::trace_context::TraceContext;
+
+const SOCKET: Token = Token(0);
+const WAKER: Token = Token(1);
+
+// packets kept for a connection until its owner process is attached
+const MAX_PENDING_PACKETS: usize = 16;
+
+// how long open_stream waits for the datapath thread
+const COMMAND_REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// connection timeouts are handled with this granularity
+const TIMER_TICK: Duration = Duration::from_millis(1);
+const TIMER_SLOTS: usize = 1024;
+
+// how often connections are checked for the idle reap timeout
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+// the first byte of the connection IDs we issue selects the worker
+const MAX_WORKERS: usize = 256;
+
+#[derive(Clone)]
+pub struct ConnectionSettings {
+    pub stream_buf_size: usize,
+    // adaptive stream buffer sizing, disabled when either is 0
+    pub stream_buf_min_size: usize,
+    pub stream_buf_max_size: usize,
+    pub max_field_section_size: usize,
+    pub max_requests: usize,
+    pub max_header_count: usize,
+    // beyond this many live connections of the module, new ones are dropped, 0 for none
+    pub max_connections: usize,
+    pub counters: Arc<Counters>,
+    pub registry: Arc<Registry>,
+}
+
+pub struct DatapathSettings {
+    pub conn_id_secret: Vec<u8>,
+    pub token_secret: Vec<u8>,
+    // how many datagrams are moved per recvmmsg/sendmmsg call
+    pub recv_batch_size: usize,
+    pub send_batch_size: usize,
+    // UDP segmentation offload, used only when the kernel supports it
+    pub gso: bool,
+    pub gro: bool,
+    // connections without packets or owner commands for this long are reaped, 0 disables it
+    pub idle_reap_timeout: Duration,
+    pub connection: ConnectionSettings,
+}
+
+// requests from the owner processes, executed on the datapath thread
+pub enum Command {
+    SetOwner(u64, LocalPid),
+    SetSubscriber(u64, EventClass, Option<LocalPid>),
+    StreamQlog(u64, LocalPid),
+    QlogToFile(u64, QlogFileOptions),
+    QlogDisable(u64),
+    EnableCongestionEvents(u64),
+    EnablePmtuEvents(u64),
+    EnableEventTimestamps(u64),
+    EnableStreamCloseEvents(u64),
+    Tap(u64, Option<TapSpec>),
+    EnableSampling(u64, Duration, usize),
+    Samples(u64, Sender<Result<Vec<Sample>, Atom>>),
+    AcceptConnectRequest(u64),
+    RejectConnectRequest(u64, u32),
+    OpenStream(u64, bool, Sender<Result<u64, Atom>>),
+    StreamStats(u64, u64, Sender<Result<StreamStats, Atom>>),
+    // true for the readable streams, false for the writable ones
+    PollStreams(u64, bool, Sender<Result<Vec<u64>, Atom>>),
+    StreamCounts(u64, Sender<Result<StreamCounts, Atom>>),
+    Pmtu(u64, Sender<Result<usize, Atom>>),
+    HandoffState(u64, Sender<Result<HandoffState, Atom>>),
+    PeerCert(u64, Sender<Result<Option<OwnedBinary>, Atom>>),
+    StreamSend(u64, u64, Vec<Vec<u8>>, bool),
+    // the chunks of a message, framed by the connection
+    StreamSendMessage(u64, u64, Vec<Vec<u8>>, bool),
+    StreamSendFull(u64, u64, Vec<Vec<u8>>, bool),
+    SetStreamRecvWindow(u64, usize, Option<u64>),
+    SetStreamFraming(u64, Framing),
+    SetImpairment(u64, Option<ImpairmentSpec>),
+    SetStreamPullMode(u64, bool),
+    SetStreamPriority(u64, u64, u8, bool),
+    SetSendOrder(u64, SendOrder),
+    SetSendDeadline(u64, Option<u64>, Option<SendDeadline>),
+    StreamRead(u64, u64, usize, Sender<Result<OwnedBinary, Atom>>),
+    H3RecvBody(u64, u64, usize, Sender<Result<OwnedBinary, Atom>>),
+    SetDgramPullMode(u64, bool),
+    SetDgramBudget(u64, Option<usize>),
+    SetKeepalive(u64, Option<Duration>),
+    SetLifetimePolicy(u64, Option<(Duration, u64)>, Option<(Duration, u64)>),
+    DgramPeek(u64, usize, Sender<Result<(OwnedBinary, usize), Atom>>),
+    DgramRecv(u64, bool, Sender<Result<(), Atom>>),
+    SetTraceContext(u64, TraceContext),
+    StreamAck(u64, u64, usize),
+    DgramSend(u64, Vec<u8>),
+    DgramSendBatch(u64, Vec<Vec<u8>>),
+    H3DgramSend(u64, u64, Vec<u8>),
+    Close(u64, bool, u64, Vec<u8>),
+    ProbePath(u64, Duration, Sender<Result<(), Atom>>),
+    // closed with the application error code, as nobody reads its events any more
+    Forget(u64, u64),
+    // a packet received by another worker for a connection ID of this worker
+    Packet(Vec<u8>, SocketAddr),
+}
+
+impl Command {
+    fn conn_id(&self) -> Option<u64> {
+        match self {
+            Command::SetOwner(conn_id, _)
+            | Command::SetSubscriber(conn_id, _, _)
+            | Command::StreamQlog(conn_id, _)
+            | Command::QlogToFile(conn_id, _)
+            | Command::QlogDisable(conn_id)
+            | Command::EnableCongestionEvents(conn_id)
+            | Command::EnablePmtuEvents(conn_id)
+            | Command::EnableEventTimestamps(conn_id)
+            | Command::EnableStreamCloseEvents(conn_id)
+            | Command::Tap(conn_id, _)
+            | Command::EnableSampling(conn_id, _, _)
+            | Command::Samples(conn_id, _)
+            | Command::AcceptConnectRequest(conn_id)
+            | Command::RejectConnectRequest(conn_id, _)
+            | Command::OpenStream(conn_id, _, _)
+            | Command::StreamStats(conn_id, _, _)
+            | Command::PollStreams(conn_id, _, _)
+            | Command::StreamCounts(conn_id, _)
+            | Command::Pmtu(conn_id, _)
+            | Command::HandoffState(conn_id, _)
+            | Command::PeerCert(conn_id, _)
+            | Command::StreamSend(conn_id, _, _, _)
+            | Command::StreamSendMessage(conn_id, _, _, _)
+            | Command::StreamSendFull(conn_id, _, _, _)
+            | Command::SetStreamRecvWindow(conn_id, _, _)
+            | Command::SetStreamFraming(conn_id, _)
+            | Command::SetImpairment(conn_id, _)
+            | Command::SetStreamPullMode(conn_id, _)
+            | Command::SetStreamPriority(conn_id, _, _, _)
+            | Command::SetSendOrder(conn_id, _)
+            | Command::SetSendDeadline(conn_id, _, _)
+            | Command::StreamRead(conn_id, _, _, _)
+            | Command::H3RecvBody(conn_id, _, _, _)
+            | Command::SetDgramPullMode(conn_id, _)
+            | Command::SetDgramBudget(conn_id, _)
+            | Command::SetKeepalive(conn_id, _)
+            | Command::SetLifetimePolicy(conn_id, _, _)
+            | Command::DgramPeek(conn_id, _, _)
+            | Command::DgramRecv(conn_id, _, _)
+            | Command::SetTraceContext(conn_id, _)
+            | Command::StreamAck(conn_id, _, _)
+            | Command::DgramSend(conn_id, _)
+            | Command::DgramSendBatch(conn_id, _)
+            | Command::H3DgramSend(conn_id, _, _)
+            | Command::Close(conn_id, _, _, _)
+            | Command::ProbePath(conn_id, _, _)
+            | Command::Forget(conn_id, _) => Some(*conn_id),
+            Command::Packet(_, _) => None,
+        }
+    }
+}
+
+// the command channel of a worker thread
+#[derive(Clone)]
+struct Mailbox {
+    // with the time it's queued, when diagnostics are enabled
+    commands: Sender<(Option<Instant>, Command)>,
+    waker: Arc<Waker>,
+}
+
+impl Mailbox {
+    fn send(&self, command: Command) -> Result<(), Atom> {
+        self.commands
+            .send((diagnostics::now(), command))
+            .map_err(|_| atoms::bad_state())?;
+        self.waker.wake().map_err(|_| atoms::system_error())
+    }
+}
+
+struct NativeConnection {
+    conn: Connection,
+    owner: Option<LocalPid>,
+    pending: Vec<(Vec<u8>, SocketAddr)>,
+    // the tick of the timer currently registered on the wheel
+    timer: Option<u64>,
+    cid: Vec<u8>,
+    // the last packet or command of this connection
+    last_activity: Instant,
+}
+
+// owns a socket and all the connections accepted on it.
+// packets are received, routed by DCID, processed and transmitted on this thread,
+// only the stream/dgram/lifecycle events are sent to erlang processes.
+// the kernel spreads packets over the SO_REUSEPORT sockets by address, so packets
+// carrying a connection ID issued by another worker are forwarded to it.
+struct Worker {
+    index: usize,
+    peers: Vec<Mailbox>,
+    sock: Arc<UdpSocket>,
+    // each worker has its own config, so accepting never waits for other workers
+    config: Box<quiche::Config>,
+    listener: LocalPid,
+    conn_id_key: hmac::Key,
+    token_key: hmac::Key,
+    settings: ConnectionSettings,
+    conns: HashMap<u64, NativeConnection>,
+    routes: HashMap<Vec<u8>, u64>,
+    // connections which have data queued by their owners
+    outbound: Vec<u64>,
+    next_conn_id: u64,
+    env: OwnedEnv,
+    timers: TimerWheel,
+    idle_reap_timeout: Duration,
+    next_sweep: Instant,
+    send_batch: Rc<RefCell<SendBatch>>,
+    out: Vec<u8>,
+}
+
+impl Worker {
+    fn run(
+        mut self,
+        mut poll: Poll,
+        mut recv_batch: RecvBatch,
+        commands: Receiver<(Option<Instant>, Command)>,
+        closer: Receiver<()>,
+    ) {
+        let mut events = Events::with_capacity(1024);
+        loop {
+            let mut deadline = self.timers.next_expiration();
+            if !self.idle_reap_timeout.is_zero() {
+                deadline = Some(deadline.map_or(self.next_sweep, |d| d.min(self.next_sweep)));
+            }
+            let timeout =
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+
+            if let Err(e) = poll.poll(&mut events, timeout) {
+                if e.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                error!("datapath poll error: {:?}", e);
+                break;
+            }
+
+            if closer.try_recv().is_ok() {
+                break;
+            }
+
+            for event in events.iter() {
+                if event.token() == SOCKET {
+                    self.recv_packets(&mut recv_batch);
+                }
+            }
+
+            while let Ok((queued, command)) = commands.try_recv() {
+                let trace_id = command
+                    .conn_id()
+                    .and_then(|conn_id| self.conns.get(&conn_id))
+                    .map(|entry| entry.conn.trace_id());
+                diagnostics::record(Probe::DatapathCommandWait, queued, trace_id);
+                self.execute(command);
+            }
+
+            self.flush_outbound();
+
+            self.execute_timeouts();
+
+            self.sweep();
+
+            self.send_batch.borrow_mut().flush();
+        }
+        // the owners learn that their connections are gone with the worker
+        let conn_ids: Vec<u64> = self.conns.keys().copied().collect();
+        for conn_id in conn_ids {
+            self.remove(conn_id);
+        }
+    }
+
+    fn recv_packets(&mut self, batch: &mut RecvBatch) {
+        loop {
+            match batch.recv(&self.sock) {
+                Ok(received) => {
+                    for i in 0..received {
+                        if let Some((buf, segment_size, from)) = batch.get_mut(i) {
+                            // packets merged by GRO are split back here
+                            for packet in buf.chunks_mut(segment_size) {
+                                if packet.len() >= 4 {
+                                    self.on_packet(packet, from);
+                                }
+                            }
+                        }
+                    }
+                    // send the responses before reading the next batch
+                    self.send_batch.borrow_mut().flush();
+                }
+                Err(e) => {
+                    if e.kind() != std::io::ErrorKind::WouldBlock {
+                        debug!("datapath recv error: {:?}", e);
+                    }
+                    break;
+                }
+            }
+        }
+    }
+
+    fn on_packet(&mut self, packet: &mut [u8], from: SocketAddr) {
+        let hdr = match quiche::Header::from_slice(packet, quiche::MAX_CONN_ID_LEN) {
+            Ok(hdr) => hdr,
+            // this is not a QUIC packet, ignore.
+            Err(_) => {
+                self.settings
+                    .counters
+                    .packet_dropped(DropReason::InvalidHeader);
+                return;
+            }
+        };
+
+        if let Some(conn_id) = self.routes.get(hdr.dcid.as_ref()).copied() {
+            self.deliver(conn_id, packet, from);
+            return;
+        }
+
+        let worker = self.worker_for_cid(hdr.dcid.as_ref());
+        if worker != self.index {
+            let _ = self.peers[worker].send(Command::Packet(packet.to_vec(), from));
+            return;
+        }
+
+        if hdr.ty != quiche::Type::Initial {
+            self.settings
+                .counters
+                .packet_dropped(DropReason::UnknownCid);
+            return;
+        }
+
+        let version = hdr.version;
+        let scid = hdr.scid.as_ref().to_vec();
+        let dcid = hdr.dcid.as_ref().to_vec();
+        let token = hdr.token.unwrap_or_default();
+
+        if !quiche::version_is_supported(version) {
+            let scid = quiche::ConnectionId::from_ref(&scid);
+            let dcid = quiche::ConnectionId::from_ref(&dcid);
+            if let Ok(n) = quiche::negotiate_version(&scid, &dcid, &mut self.out) {
+                self.send_out(n, from);
+            }
+            return;
+        }
+
+        if token.is_empty() {
+            let new_scid = self.conn_id_from_odcid(&dcid);
+            let token = self.mint_token(&from, &dcid, &new_scid);
+            let scid = quiche::ConnectionId::from_ref(&scid);
+            let dcid = quiche::ConnectionId::from_ref(&dcid);
+            let new_scid = quiche::ConnectionId::from_ref(&new_scid);
+            if let Ok(n) = quiche::retry(&scid, &dcid, &new_scid, &token, version, &mut self.out) {
+                self.send_out(n, from);
+            }
+            return;
+        }
+
+        match self.validate_token(&from, &dcid, &token) {
+            Some(_) if self.refuses() => {
+                // as connection_count_refused, the client times out
+                debug!("drop the Initial from {}", from);
+                self.settings.counters.refused();
+            }
+            Some(odcid) => self.accept(packet, from, scid, dcid, odcid),
+            None => debug!("invalid retry token from {}", from),
+        }
+    }
+
+    // the module has max_connections live connections, on all the workers
+    fn refuses(&self) -> bool {
+        let max = self.settings.max_connections;
+        max > 0 && self.settings.registry.len() >= max
+    }
+
+    fn accept(
+        &mut self,
+        packet: &[u8],
+        from: SocketAddr,
+        scid: Vec<u8>,
+        dcid: Vec<u8>,
+        odcid: Vec<u8>,
+    ) {
+        let raw = match quiche::accept(
+            &quiche::ConnectionId::from_ref(&dcid),
+            Some(&quiche::ConnectionId::from_ref(&odcid)),
+            from,
+            &mut self.config,
+        ) {
+            Ok(raw) => raw,
+            Err(e) => {
+                error!("failed to accept connection: {:?}", e);
+                return;
+            }
+        };
+
+        let conn_id = self.next_conn_id;
+        // keeps `conn_id % workers == index` so that commands find this worker
+        self.next_conn_id += self.peers.len() as u64;
+
+        let trace_id = raw.trace_id().to_string();
+        let peer = ResourceArc::new(Peer::new(from));
+        let mut conn = Connection::new(
+            raw,
+            peer.clone(),
+            Output::Batch(Rc::clone(&self.send_batch)),
+            self.settings.stream_buf_size,
+            Arc::clone(&self.settings.counters),
+        );
+        self.settings.counters.accepted();
+        conn.set_request_limits(
+            self.settings.max_field_section_size,
+            self.settings.max_requests,
+            self.settings.max_header_count,
+        );
+        let (min, max) = (
+            self.settings.stream_buf_min_size,
+            self.settings.stream_buf_max_size,
+        );
+        if min > 0 && max >= min {
+            conn.set_stream_buf_range(min, max);
+        }
+        // module_shutdown() and the control socket reach it through the mailbox of this worker
+        let mailbox = self.peers[self.index].clone();
+        let control = move |control| {
+            let command = match control {
+                Control::Close(err, reason) => Command::Close(conn_id, true, err, reason),
+                Control::QlogToFile(options) => Command::QlogToFile(conn_id, options),
+                Control::QlogDisable => Command::QlogDisable(conn_id),
+            };
+            let _ = mailbox.send(command);
+        };
+        conn.set_registration(
+            self.settings
+                .registry
+                .register(trace_id, Target::Worker(Box::new(control))),
+        );
+
+        self.routes.insert(dcid.clone(), conn_id);
+        self.conns.insert(
+            conn_id,
+            NativeConnection {
+                conn,
+                owner: None,
+                pending: vec![(packet.to_vec(), from)],
+                timer: None,
+                cid: dcid.clone(),
+                last_activity: Instant::now(),
+            },
+        );
+
+        // the listener starts the owner process, which attaches itself with SetOwner
+        let listener = self.listener.clone();
+        self.env.send_and_clear(&listener, move |env| {
+            make_tuple(
+                env,
+                &[
+                    atoms::__accept__().to_term(env),
+                    conn_id.encode(env),
+                    peer.encode(env),
+                    binary_term(env, &scid),
+                    binary_term(env, &dcid),
+                    binary_term(env, &odcid),
+                ],
+            )
+        });
+    }
+
+    fn deliver(&mut self, conn_id: u64, packet: &[u8], from: SocketAddr) {
+        if let Some(entry) = self.conns.get_mut(&conn_id) {
+            entry.last_activity = Instant::now();
+            if entry.owner.is_none() {
+                if entry.pending.len() < MAX_PENDING_PACKETS {
+                    entry.pending.push((packet.to_vec(), from));
+                }
+                return;
+            }
+        }
+        let result = with_connection(
+            &mut self.conns,
+            &mut self.env,
+            &mut self.timers,
+            conn_id,
+            |conn, env, owner| conn.process_packet(env, owner, packet, from),
+        );
+        self.settle(conn_id, result);
+    }
+
+    fn execute(&mut self, command: Command) {
+        if let Some(entry) = command.conn_id().and_then(|id| self.conns.get_mut(&id)) {
+            entry.last_activity = Instant::now();
+        }
+        match command {
+            Command::SetOwner(conn_id, pid) => {
+                let pending = match self.conns.get_mut(&conn_id) {
+                    Some(entry) => {
+                        entry.owner = Some(pid);
+                        std::mem::take(&mut entry.pending)
+                    }
+                    None => return,
+                };
+                for (packet, from) in pending {
+                    let result = with_connection(
+                        &mut self.conns,
+                        &mut self.env,
+                        &mut self.timers,
+                        conn_id,
+                        |conn, env, owner| conn.process_packet(env, owner, &packet, from),
+                    );
+                    self.settle(conn_id, result);
+                }
+            }
+            Command::SetSubscriber(conn_id, class, pid) => match self.conns.get_mut(&conn_id) {
+                Some(entry) => {
+                    if let Err(_reason) = entry.conn.set_subscriber(class, pid) {
+                        debug!("failed to set subscriber");
+                    }
+                }
+                None => debug!("failed to set subscriber: connection not found"),
+            },
+            Command::StreamQlog(conn_id, pid) => match self.conns.get_mut(&conn_id) {
+                Some(entry) => {
+                    if let Err(_reason) = entry.conn.stream_qlog(pid) {
+                        debug!("failed to stream qlog");
+                    }
+                }
+                None => debug!("failed to stream qlog: connection not found"),
+            },
+            Command::QlogToFile(conn_id, options) => match self.conns.get_mut(&conn_id) {
+                Some(entry) => {
+                    if let Err(_reason) = entry.conn.qlog_to_file(&options) {
+                        debug!("failed to write qlog to file");
+                    }
+                }
+                None => debug!("failed to write qlog to file: connection not found"),
+            },
+            Command::QlogDisable(conn_id) => {
+                if let Some(entry) = self.conns.get_mut(&conn_id) {
+                    if let Err(_reason) = entry.conn.qlog_disable() {
+                        debug!("failed to disable qlog");
+                    }
+                }
+            }
+            Command::EnableCongestionEvents(conn_id) => {
+                if let Some(entry) = self.conns.get_mut(&conn_id) {
+                    entry.conn.enable_congestion_events();
+                }
+            }
+            Command::EnablePmtuEvents(conn_id) => {
+                if let Some(entry) = self.conns.get_mut(&conn_id) {
+                    entry.conn.enable_pmtu_events();
+                }
+            }
+            Command::EnableEventTimestamps(conn_id) => {
+                if let Some(entry) = self.conns.get_mut(&conn_id) {
+                    entry.conn.enable_event_timestamps();
+                }
+            }
+            Command::EnableStreamCloseEvents(conn_id) => {
+                if let Some(entry) = self.conns.get_mut(&conn_id) {
+                    entry.conn.enable_stream_close_events();
+                }
+            }
+            Command::Tap(conn_id, spec) => match self.conns.get_mut(&conn_id) {
+                Some(entry) => {
+                    if let Err(_reason) = entry.conn.set_tap(spec) {
+                        debug!("failed to set packet tap");
+                    }
+                }
+                None => debug!("failed to set packet tap: connection not found"),
+            },
+            Command::EnableSampling(conn_id, interval, capacity) => {
+                if let Some(entry) = self.conns.get_mut(&conn_id) {
+                    entry.conn.enable_sampling(interval, capacity);
+                }
+            }
+            Command::Samples(conn_id, reply) => {
+                let result = self
+                    .conns
+                    .get(&conn_id)
+                    .map(|entry| entry.conn.samples())
+                    .ok_or_else(atoms::not_found);
+                let _ = reply.send(result);
+            }
+            Command::AcceptConnectRequest(conn_id) => {
+                let result =
+                    self.with_connection(conn_id, |conn, env, _| conn.accept_connect_request(env));
+                self.settle(conn_id, result);
+            }
+            Command::RejectConnectRequest(conn_id, code) => {
+                let result = self.with_connection(conn_id, |conn, env, _| {
+                    conn.reject_connect_request(env, code)
+                });
+                self.settle(conn_id, result);
+            }
+            Command::OpenStream(conn_id, is_bidi, reply) => {
+                let result = self
+                    .with_connection(conn_id, |conn, env, _| conn.open_stream(env, is_bidi))
+                    .map(|(stream_id, _next_timeout)| stream_id);
+                let _ = reply.send(result);
+            }
+            Command::StreamCounts(conn_id, reply) => {
+                let result = self
+                    .conns
+                    .get(&conn_id)
+                    .map(|entry| entry.conn.stream_counts())
+                    .ok_or_else(atoms::not_found);
+                let _ = reply.send(result);
+            }
+            Command::Pmtu(conn_id, reply) => {
+                let result = self
+                    .conns
+                    .get(&conn_id)
+                    .map(|entry| entry.conn.pmtu())
+                    .ok_or_else(atoms::not_found);
+                let _ = reply.send(result);
+            }
+            Command::PollStreams(conn_id, readable, reply) => {
+                let result = self
+                    .conns
+                    .get(&conn_id)
+                    .map(|entry| {
+                        if readable {
+                            entry.conn.readable_streams()
+                        } else {
+                            entry.conn.writable_streams()
+                        }
+                    })
+                    .ok_or_else(atoms::not_found);
+                let _ = reply.send(result);
+            }
+            Command::StreamStats(conn_id, stream_id, reply) => {
+                let result = self
+                    .conns
+                    .get(&conn_id)
+                    .ok_or_else(atoms::not_found)
+                    .and_then(|entry| {
+                        entry
+                            .conn
+                            .stream_stats(stream_id)
+                            .ok_or_else(atoms::not_found)
+                    });
+                let _ = reply.send(result);
+            }
+            Command::HandoffState(conn_id, reply) => {
+                let result = self
+                    .conns
+                    .get(&conn_id)
+                    .map(|entry| entry.conn.handoff_state())
+                    .ok_or_else(atoms::not_found);
+                let _ = reply.send(result);
+            }
+            Command::PeerCert(conn_id, reply) => {
+                let result = self
+                    .conns
+                    .get(&conn_id)
+                    .map(|entry| entry.conn.peer_cert())
+                    .ok_or_else(atoms::not_found);
+                let _ = reply.send(result);
+            }
+            Command::StreamSend(conn_id, stream_id, data, fin) => {
+                self.enqueue(conn_id, Outbound::Stream(stream_id, data, fin));
+            }
+            Command::StreamSendFull(conn_id, stream_id, data, fin) => {
+                self.enqueue(conn_id, Outbound::StreamFull(stream_id, data, fin));
+            }
+            Command::StreamSendMessage(conn_id, stream_id, mut data, fin) => {
+                let framed = self.conns.get(&conn_id).map(|entry| {
+                    let chunks: Vec<&[u8]> = data.iter().map(|chunk| chunk.as_slice()).collect();
+                    entry.conn.frame_message(&chunks)
+                });
+                match framed {
+                    Some(Ok((prefix, suffix))) => {
+                        if !prefix.is_empty() {
+                            data.insert(0, prefix);
+                        }
+                        if !suffix.is_empty() {
+                            data.push(suffix.to_vec());
+                        }
+                        self.enqueue(conn_id, Outbound::Stream(stream_id, data, fin));
+                    }
+                    Some(Err(_reason)) => {
+                        debug!("message can't be framed: stream_id({})", stream_id)
+                    }
+                    None => debug!("failed to send message: connection not found"),
+                }
+            }
+            Command::SetStreamRecvWindow(conn_id, window, reset_code) => {
+                if let Some(entry) = self.conns.get_mut(&conn_id) {
+                    entry.conn.set_stream_recv_window(window, reset_code);
+                }
+            }
+            Command::SetStreamFraming(conn_id, framing) => {
+                if let Some(entry) = self.conns.get_mut(&conn_id) {
+                    entry.conn.set_stream_framing(framing);
+                }
+            }
+            Command::SetImpairment(conn_id, spec) => {
+                if let Some(entry) = self.conns.get_mut(&conn_id) {
+                    entry.conn.set_impairment(spec);
+                }
+            }
+            Command::SetStreamPriority(conn_id, stream_id, urgency, incremental) => {
+                if let Some(entry) = self.conns.get_mut(&conn_id) {
+                    if entry
+                        .conn
+                        .set_stream_priority(stream_id, urgency, incremental)
+                        .is_err()
+                    {
+                        debug!("failed to set the priority of stream {}", stream_id);
+                    }
+                }
+            }
+            Command::SetSendOrder(conn_id, order) => {
+                if let Some(entry) = self.conns.get_mut(&conn_id) {
+                    entry.conn.set_send_order(order);
+                }
+            }
+            Command::SetStreamPullMode(conn_id, enabled) => {
+                if let Some(entry) = self.conns.get_mut(&conn_id) {
+                    entry.conn.set_stream_pull_mode(enabled);
+                }
+            }
+            Command::SetSendDeadline(conn_id, stream_id, deadline) => {
+                if let Some(entry) = self.conns.get_mut(&conn_id) {
+                    entry.conn.set_send_deadline(stream_id, deadline);
+                }
+            }
+            Command::StreamRead(conn_id, stream_id, max_bytes, reply) => {
+                let result = self
+                    .with_connection(conn_id, |conn, env, owner| {
+                        conn.stream_read(env, owner, stream_id, max_bytes)
+                    })
+                    .map(|(data, _next_timeout)| data);
+                let _ = reply.send(result);
+            }
+            Command::H3RecvBody(conn_id, stream_id, max_bytes, reply) => {
+                let result = self
+                    .with_connection(conn_id, |conn, env, owner| {
+                        conn.h3_recv_body(env, owner, stream_id, max_bytes)
+                    })
+                    .map(|(data, _next_timeout)| data);
+                let _ = reply.send(result);
+            }
+            Command::SetDgramPullMode(conn_id, enabled) => {
+                if let Some(entry) = self.conns.get_mut(&conn_id) {
+                    entry.conn.set_dgram_pull_mode(enabled);
+                }
+            }
+            Command::SetDgramBudget(conn_id, budget) => {
+                if let Some(entry) = self.conns.get_mut(&conn_id) {
+                    entry.conn.set_dgram_budget(budget);
+                }
+            }
+            Command::SetKeepalive(conn_id, interval) => {
+                if let Some(entry) = self.conns.get_mut(&conn_id) {
+                    entry.conn.set_keepalive(interval);
+                }
+            }
+            Command::SetLifetimePolicy(conn_id, max_lifetime, max_inactivity) => {
+                if let Some(entry) = self.conns.get_mut(&conn_id) {
+                    entry.conn.set_lifetime_policy(max_lifetime, max_inactivity);
+                }
+            }
+            Command::DgramPeek(conn_id, max_len, reply) => {
+                let result = self
+                    .conns
+                    .get(&conn_id)
+                    .ok_or_else(atoms::not_found)
+                    .and_then(|entry| entry.conn.dgram_peek(max_len));
+                let _ = reply.send(result);
+            }
+            Command::DgramRecv(conn_id, deliver, reply) => {
+                let result = self
+                    .with_connection(conn_id, |conn, env, owner| {
+                        conn.dgram_recv(env, owner, deliver)
+                    })
+                    .map(|_next_timeout| ());
+                let _ = reply.send(result);
+            }
+            Command::SetTraceContext(conn_id, context) => {
+                if let Some(entry) = self.conns.get_mut(&conn_id) {
+                    entry.conn.set_trace_context(context);
+                }
+            }
+            Command::StreamAck(conn_id, stream_id, bytes) => {
+                let result = self.with_connection(conn_id, |conn, env, owner| {
+                    conn.stream_ack(env, owner, stream_id, bytes)
+                });
+                match result {
+                    // an ack without the window set is a mistake of the owner, not of the peer
+                    Err(reason) if reason == atoms::bad_state() => {
+                        debug!("stream ack without a receive window")
+                    }
+                    result => self.settle(conn_id, result),
+                }
+            }
+            Command::DgramSend(conn_id, data) => {
+                self.enqueue(conn_id, Outbound::Dgram(data));
+            }
+            Command::DgramSendBatch(conn_id, dgrams) => {
+                for data in dgrams {
+                    self.enqueue(conn_id, Outbound::Dgram(data));
+                }
+            }
+            Command::H3DgramSend(conn_id, flow_id, data) => {
+                self.enqueue(conn_id, Outbound::H3Dgram(flow_id, data));
+            }
+            Command::Close(conn_id, app, err, reason) => {
+                let _ = self
+                    .with_connection(conn_id, |conn, env, _| conn.close(env, app, err, &reason));
+            }
+            Command::ProbePath(conn_id, timeout, reply) => {
+                let result = self
+                    .with_connection(conn_id, |conn, env, _| conn.probe_path(env, timeout))
+                    .map(|_next_timeout| ());
+                let _ = reply.send(result);
+            }
+            Command::Packet(mut packet, from) => {
+                self.on_packet(&mut packet, from);
+            }
+            Command::Forget(conn_id, err) => {
+                // the owner has gone, tell the peer and drop the connection
+                if let Some(mut entry) = self.conns.remove(&conn_id) {
+                    self.routes.remove(&entry.cid);
+                    let _ = entry.conn.close(&mut self.env, true, err, b"");
+                }
+            }
+        }
+    }
+
+    // sends are only queued by the commands and written here at once,
+    // unless a packet of the connection arrives first and writes them.
+    fn enqueue(&mut self, conn_id: u64, outbound: Outbound) {
+        match self.conns.get_mut(&conn_id) {
+            Some(entry) => {
+                if !entry.conn.has_outbound() {
+                    self.outbound.push(conn_id);
+                }
+                if entry.conn.enqueue(outbound).is_err() {
+                    debug!("failed to queue data: connection already closed");
+                }
+            }
+            None => debug!("failed to queue data: connection not found"),
+        }
+    }
+
+    fn flush_outbound(&mut self) {
+        for conn_id in std::mem::take(&mut self.outbound) {
+            let result = self.with_connection(conn_id, |conn, env, _| {
+                if conn.has_outbound() {
+                    conn.flush_outbound(env).map(|_| ())
+                } else {
+                    Ok(())
+                }
+            });
+            if result.is_err() {
+                debug!("failed to flush queued data");
+            }
+        }
+    }
+
+    fn execute_timeouts(&mut self) {
+        let mut expired = Vec::new();
+        self.timers.expire(Instant::now(), &mut expired);
+        for (conn_id, tick) in expired {
+            let entry = match self.conns.get_mut(&conn_id) {
+                // replaced timers are cancelled, this only skips the unexpected ones
+                Some(entry) if entry.timer == Some(tick) => entry,
+                _ => continue,
+            };
+            entry.timer = None;
+            if entry
+                .conn
+                .next_timeout()
+                .map_or(false, |timeout| timeout != Timeout::After(0))
+            {
+                // the deadline has been extended since
+                schedule_timer(&mut self.timers, conn_id, entry);
+                continue;
+            }
+            let result =
+                self.with_connection(conn_id, |conn, env, owner| conn.execute_timeout(env, owner));
+            self.settle(conn_id, result);
+        }
+    }
+
+    // reaps the connections which nobody has touched for the idle reap timeout,
+    // e.g. when the owner process has been killed without forgetting them,
+    // or the listener never started an owner.
+    fn sweep(&mut self) {
+        if self.idle_reap_timeout.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        if now < self.next_sweep {
+            return;
+        }
+        self.next_sweep = now + SWEEP_INTERVAL;
+
+        let idle: Vec<u64> = self
+            .conns
+            .iter()
+            .filter(|(_, entry)| now.duration_since(entry.last_activity) >= self.idle_reap_timeout)
+            .map(|(conn_id, _)| *conn_id)
+            .collect();
+        for conn_id in idle {
+            debug!("reap idle connection: conn_id({})", conn_id);
+            if let Some(entry) = self.conns.get_mut(&conn_id) {
+                let _ = entry.conn.close(&mut self.env, false, 0x0, b"");
+            }
+            self.remove(conn_id);
+        }
+    }
+
+    fn with_connection<T, F>(&mut self, conn_id: u64, f: F) -> Result<T, Atom>
+    where
+        F: FnOnce(&mut Connection, &mut OwnedEnv, &LocalPid) -> Result<T, Atom>,
+    {
+        let result = with_connection(&mut self.conns, &mut self.env, &mut self.timers, conn_id, f);
+        self.remove_if_closed(conn_id);
+        result
+    }
+
+    // errors on the packet path close the connection, as Requiem.Connection does.
+    fn settle(&mut self, conn_id: u64, result: Result<Timeout, Atom>) {
+        if let Err(reason) = result {
+            if reason != atoms::already_closed() {
+                let _ = with_connection(
+                    &mut self.conns,
+                    &mut self.env,
+                    &mut self.timers,
+                    conn_id,
+                    |conn, env, _| conn.close(env, false, 0x1, b"server_error"),
+                );
+            }
+        }
+        self.remove_if_closed(conn_id);
+    }
+
+    fn remove_if_closed(&mut self, conn_id: u64) {
+        if self
+            .conns
+            .get(&conn_id)
+            .map_or(false, |entry| entry.conn.is_closed())
+        {
+            self.remove(conn_id);
+        }
+    }
+
+    fn remove(&mut self, conn_id: u64) {
+        if let Some(entry) = self.conns.remove(&conn_id) {
+            self.routes.remove(&entry.cid);
+            if let Some(tick) = entry.timer {
+                self.timers.cancel(conn_id, tick);
+            }
+            // unless the connection has notified it already
+            if let Some(owner) = entry.owner.filter(|_| !entry.conn.closed_notified()) {
+                let owner = entry.conn.subscriber(EventClass::Lifecycle, &owner);
+                let close_info = entry.conn.close_info();
+                self.env
+                    .send_and_clear(&owner, move |env| closed_term(env, close_info));
+            }
+        }
+    }
+
+    fn send_out(&self, len: usize, to: SocketAddr) {
+        self.send_batch.borrow_mut().push(&self.out[..len], to);
+    }
+
+    fn conn_id_from_odcid(&self, odcid: &[u8]) -> Vec<u8> {
+        let tag = hmac::sign(&self.conn_id_key, odcid);
+        let mut cid = tag.as_ref()[..quiche::MAX_CONN_ID_LEN].to_vec();
+        cid[0] = self.index as u8;
+        cid
+    }
+
+    fn worker_for_cid(&self, cid: &[u8]) -> usize {
+        worker_for_cid(cid, self.index, self.peers.len())
+    }
+
+    fn mint_token(&self, from: &SocketAddr, odcid: &[u8], retry_scid: &[u8]) -> Vec<u8> {
+        mint_token(&self.token_key, from, odcid, retry_scid, unix_time())
+    }
+
+    fn validate_token(
+        &self,
+        from: &SocketAddr,
+        retry_scid: &[u8],
+        token: &[u8],
+    ) -> Option<Vec<u8>> {
+        validate_token(&self.token_key, from, retry_scid, token)
+    }
+}
+
+// connection IDs chosen by the client (before retry) are handled by the worker
+// which received them, the others by the worker whose index they start with
+fn worker_for_cid(cid: &[u8], index: usize, workers: usize) -> usize {
+    match cid.first() {
+        Some(first) if cid.len() == quiche::MAX_CONN_ID_LEN => *first as usize % workers,
+        _ => index,
+    }
+}
+
+// retry token: [odcid length][odcid][issued at][HMAC(peer address, odcid, retry scid,
+// issued at)], issued at being the seconds since the UNIX epoch in 8 big-endian bytes
+fn mint_token(
+    key: &hmac::Key,
+    from: &SocketAddr,
+    odcid: &[u8],
+    retry_scid: &[u8],
+    issued_at: u64,
+) -> Vec<u8> {
+    let issued_at = issued_at.to_be_bytes();
+    let msg = token_message(from, odcid, retry_scid, &issued_at);
+    let tag = hmac::sign(key, &msg);
+    let mut token = Vec::with_capacity(1 + odcid.len() + 8 + tag.as_ref().len());
+    token.push(odcid.len() as u8);
+    token.extend_from_slice(odcid);
+    token.extend_from_slice(&issued_at);
+    token.extend_from_slice(tag.as_ref());
+    token
+}
+
+// a retry token is answered by the client right away, one issued longer ago
+// than this is a replayed one
+const TOKEN_LIFETIME_SECS: u64 = 10;
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+// the odcid of a token minted by mint_token() which hasn't expired,
+// the fuzz targets call it too
+pub(crate) fn validate_token(
+    key: &hmac::Key,
+    from: &SocketAddr,
+    retry_scid: &[u8],
+    token: &[u8],
+) -> Option<Vec<u8>> {
+    let (odcid_len, rest) = token.split_first()?;
+    let odcid_len = *odcid_len as usize;
+    if odcid_len > quiche::MAX_CONN_ID_LEN || rest.len() < odcid_len + 8 {
+        return None;
+    }
+    let (odcid, rest) = rest.split_at(odcid_len);
+    let (issued_at, tag) = rest.split_at(8);
+    let msg = token_message(from, odcid, retry_scid, issued_at);
+    hmac::verify(key, &msg, tag).ok()?;
+    let issued_at = u64::from_be_bytes(issued_at.try_into().ok()?);
+    // a second of leeway for the wall clock stepping back
+    let now = unix_time();
+    if issued_at > now + 1 || now.saturating_sub(issued_at) > TOKEN_LIFETIME_SECS {
+        debug!("reject the expired retry token of {}", from);
+        return None;
+    }
+    Some(odcid.to_vec())
+}
+
+fn with_connection<T, F>(
+    conns: &mut HashMap<u64, NativeConnection>,
+    env: &mut OwnedEnv,
+    timers: &mut TimerWheel,
+    conn_id: u64,
+    f: F,
+) -> Result<T, Atom>
+where
+    F: FnOnce(&mut Connection, &mut OwnedEnv, &LocalPid) -> Result<T, Atom>,
+{
+    let entry = conns.get_mut(&conn_id).ok_or_else(atoms::not_found)?;
+    let owner = entry.owner.as_ref().ok_or_else(atoms::bad_state)?;
+    let result = f(&mut entry.conn, env, owner);
+    schedule_timer(timers, conn_id, entry);
+    result
+}
+
+// only an earlier timer is registered, a timer which fires before
+// the deadline of its connection is just registered again.
+fn schedule_timer(timers: &mut TimerWheel, conn_id: u64, entry: &mut NativeConnection) {
+    // no timer for Timeout::Infinity, nor for a deadline too far to be represented
+    let deadline = match entry.conn.next_timeout() {
+        Ok(Timeout::After(timeout)) => Instant::now().checked_add(Duration::from_millis(timeout)),
+        _ => None,
+    };
+    if let Some(deadline) = deadline {
+        let tick = timers.tick_for(deadline);
+        if entry.timer.map_or(true, |registered| tick < registered) {
+            if let Some(registered) = entry.timer {
+                timers.cancel(conn_id, registered);
+            }
+            timers.schedule(conn_id, tick);
+            entry.timer = Some(tick);
+        }
+    }
+}
+
+fn token_message(from: &SocketAddr, odcid: &[u8], retry_scid: &[u8], issued_at: &[u8]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(18 + odcid.len() + retry_scid.len() + issued_at.len());
+    match from.ip() {
+        IpAddr::V4(ip) => msg.extend_from_slice(&ip.octets()),
+        IpAddr::V6(ip) => msg.extend_from_slice(&ip.octets()),
+    }
+    msg.extend_from_slice(&from.port().to_be_bytes());
+    msg.extend_from_slice(odcid);
+    msg.extend_from_slice(retry_scid);
+    msg.extend_from_slice(issued_at);
+    msg
+}
+
+fn binary_term<'a>(env: Env<'a>, data: &[u8]) -> Term<'a> {
+    let mut bin = OwnedBinary::new(data.len()).unwrap();
+    bin.as_mut_slice().copy_from_slice(data);
+    bin.release(env).to_term(env)
+}
+
+struct WorkerHandle {
+    mailbox: Mailbox,
+    closer: Sender<()>,
+    handle: Mutex<Option<JoinHandle<()>>>,
+}
+
+// a resource held by the transport and by the processes of its connections, so that
+// it outlives all of them. stopping it only stops the workers, the commands sent after
+// that fail with :bad_state.
+pub struct Datapath {
+    workers: Vec<WorkerHandle>,
+}
+
+// where the sockets of the workers come from
+#[derive(Clone, Copy)]
+pub enum Bind {
+    Address(SocketAddr),
+    // a bound UDP socket handed over from elixir (gen_udp, socket, systemd activation),
+    // shared by all the workers. elixir may close its own descriptor after the start.
+    Fd(RawFd),
+}
+
+impl Datapath {
+    // starts one worker, with its own SO_REUSEPORT socket, for each config
+    pub fn start(
+        bind: Bind,
+        mut configs: Vec<Box<quiche::Config>>,
+        listener: LocalPid,
+        settings: DatapathSettings,
+    ) -> Result<Self, Atom> {
+        configs.truncate(MAX_WORKERS);
+        let num_workers = configs.len();
+        if num_workers == 0 {
+            return Err(atoms::bad_format());
+        }
+        let reuse_port = num_workers > 1;
+
+        let mut socks = Vec::with_capacity(num_workers);
+        let mut polls = Vec::with_capacity(num_workers);
+        let mut mailboxes = Vec::with_capacity(num_workers);
+        let mut receivers = Vec::with_capacity(num_workers);
+        for _ in 0..num_workers {
+            let sock = match bind {
+                Bind::Address(address) => bind_address(address, reuse_port)?,
+                Bind::Fd(fd) => adopt_fd(fd)?,
+            };
+            let mut sock = UdpSocket::from_std(sock);
+            let poll = Poll::new().map_err(|_| atoms::system_error())?;
+            poll.registry()
+                .register(&mut sock, SOCKET, Interest::READABLE)
+                .map_err(|_| atoms::socket_error())?;
+            let waker =
+                Arc::new(Waker::new(poll.registry(), WAKER).map_err(|_| atoms::system_error())?);
+            let (command_tx, command_rx) = unbounded::<(Option<Instant>, Command)>();
+            socks.push(sock);
+            polls.push(poll);
+            mailboxes.push(Mailbox {
+                commands: command_tx,
+                waker,
+            });
+            receivers.push(command_rx);
+        }
+
+        let gro = settings.gro && socks.iter().all(batch::enable_gro);
+        let gso = settings.gso && batch::gso_supported(&socks[0]);
+        info!(
+            "datapath workers({}), offload: GRO({}), GSO({})",
+            num_workers, gro, gso
+        );
+
+        let settings = Arc::new(settings);
+
+        let mut workers = Vec::with_capacity(num_workers);
+        let threads = socks
+            .into_iter()
+            .zip(polls)
+            .zip(receivers)
+            .zip(configs)
+            .enumerate();
+        for (index, (((sock, poll), command_rx), config)) in threads {
+            let (closer_tx, closer_rx) = bounded::<()>(1);
+            let peers = mailboxes.clone();
+            let listener = listener.clone();
+            let settings = Arc::clone(&settings);
+            let sock = Arc::new(sock);
+
+            let handle = thread::spawn(move || {
+                let recv_batch = RecvBatch::new(settings.recv_batch_size, gro);
+                let send_batch = SendBatch::new(Arc::clone(&sock), settings.send_batch_size, gso);
+                let worker = Worker {
+                    index,
+                    peers,
+                    sock,
+                    config,
+                    listener,
+                    conn_id_key: hmac::Key::new(hmac::HMAC_SHA256, &settings.conn_id_secret),
+                    token_key: hmac::Key::new(hmac::HMAC_SHA256, &settings.token_secret),
+                    settings: settings.connection.clone(),
+                    conns: HashMap::new(),
+                    routes: HashMap::new(),
+                    outbound: Vec::new(),
+                    next_conn_id: index as u64,
+                    env: OwnedEnv::new(),
+                    timers: TimerWheel::new(TIMER_TICK, TIMER_SLOTS),
+                    idle_reap_timeout: settings.idle_reap_timeout,
+                    next_sweep: Instant::now() + SWEEP_INTERVAL,
+                    send_batch: Rc::new(RefCell::new(send_batch)),
+                    out: vec![0; 1500],
+                };
+                worker.run(poll, recv_batch, command_rx, closer_rx);
+            });
+
+            workers.push(WorkerHandle {
+                mailbox: mailboxes[index].clone(),
+                closer: closer_tx,
+                handle: Mutex::new(Some(handle)),
+            });
+        }
+
+        Ok(Self { workers })
+    }
+
+    pub fn command(&self, command: Command) -> Result<(), Atom> {
+        let conn_id = command.conn_id().ok_or_else(atoms::bad_state)?;
+        let worker = &self.workers[(conn_id % self.workers.len() as u64) as usize];
+        worker.mailbox.send(command)
+    }
+
+    // blocks until the worker threads have stopped, a no-op once they have
+    pub fn stop(&self) {
+        for worker in self.workers.iter() {
+            let _ = worker.closer.send(());
+            let _ = worker.mailbox.waker.wake();
+        }
+        for worker in self.workers.iter() {
+            if let Some(handle) = worker.handle.lock().unwrap().take() {
+                let _ = handle.join();
+            }
+        }
+    }
+}
+
+impl Drop for Datapath {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+pub fn on_load(env: Env) -> bool {
+    rustler::resource!(Datapath, env);
+    true
+}
+
+fn bind_address(address: SocketAddr, reuse_port: bool) -> Result<std::net::UdpSocket, Atom> {
+    let domain = if address.is_ipv4() {
+        Domain::IPV4
+    } else {
+        Domain::IPV6
+    };
+
+    let sock =
+        Socket::new(domain, Type::DGRAM, Some(Protocol::UDP)).map_err(|_| atoms::socket_error())?;
+
+    if reuse_port {
+        sock.set_reuse_address(true)
+            .map_err(|_| atoms::socket_error())?;
+        sock.set_reuse_port(true)
+            .map_err(|_| atoms::socket_error())?;
+    }
+
+    sock.set_nonblocking(true)
+        .map_err(|_| atoms::socket_error())?;
+    sock.bind(&address.into()).map_err(|_| atoms::cant_bind())?;
+
+    Ok(sock.into())
+}
+
+// duplicates the descriptor, the original one stays owned by elixir
+fn adopt_fd(fd: RawFd) -> Result<std::net::UdpSocket, Atom> {
+    let borrowed = ManuallyDrop::new(unsafe { Socket::from_raw_fd(fd) });
+    match borrowed.r#type() {
+        Ok(ty) if ty == Type::DGRAM => {}
+        _ => return Err(atoms::bad_format()),
+    }
+    let sock = borrowed.try_clone().map_err(|_| atoms::socket_error())?;
+    sock.set_nonblocking(true)
+        .map_err(|_| atoms::socket_error())?;
+    Ok(sock.into())
+}
+
+fn execute(datapath: &Datapath, command: Command) -> NifResult<Atom> {
+    let _timer = diagnostics::time(Probe::DatapathCommand);
+    match datapath.command(command) {
+        Ok(()) => Ok(atoms::ok()),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
+
+// takes ownership of the configs, one for each worker, don't destroy them after this call.
+// the address is bound unless the descriptor of a bound socket is given, -1 for none.
+#[rustler::nif]
+pub fn datapath_start(
+    env: Env,
+    module: Atom,
+    conf_ptrs: Vec<i64>,
+    address: Binary,
+    fd: i64,
+    listener: LocalPid,
+    secrets: (Binary, Binary),
+    limits: (u64, u64, u64, u64, u64, u64, u64),
+    io: (u64, u64, bool, bool, u64),
+) -> NifResult<(Atom, ResourceArc<Datapath>)> {
+    let configs: Vec<Box<quiche::Config>> = conf_ptrs
+        .into_iter()
+        .map(|conf_ptr| unsafe { Box::from_raw(conf_ptr as *mut quiche::Config) })
+        .collect();
+
+    let bind = if fd >= 0 {
+        Bind::Fd(fd as RawFd)
+    } else {
+        let address: SocketAddr = str::from_utf8(address.as_slice())
+            .ok()
+            .and_then(|addr| addr.parse().ok())
+            .ok_or_else(|| common::error_term(atoms::bad_format()))?;
+        Bind::Address(address)
+    };
+
+    let (conn_id_secret, token_secret) = secrets;
+    let (
+        stream_buf_size,
+        stream_buf_min_size,
+        stream_buf_max_size,
+        max_field_section_size,
+        max_requests,
+        max_header_count,
+        max_connections,
+    ) = limits;
+    let (recv_batch_size, send_batch_size, gso, gro, idle_reap_timeout) = io;
+
+    let settings = DatapathSettings {
+        conn_id_secret: conn_id_secret.as_slice().to_vec(),
+        token_secret: token_secret.as_slice().to_vec(),
+        recv_batch_size: recv_batch_size as usize,
+        send_batch_size: send_batch_size as usize,
+        gso,
+        gro,
+        idle_reap_timeout: Duration::from_millis(idle_reap_timeout),
+        connection: ConnectionSettings {
+            stream_buf_size: stream_buf_size as usize,
+            stream_buf_min_size: stream_buf_min_size as usize,
+            stream_buf_max_size: stream_buf_max_size as usize,
+            max_field_section_size: max_field_section_size as usize,
+            max_requests: max_requests as usize,
+            max_header_count: max_header_count as usize,
+            max_connections: max_connections as usize,
+            counters: telemetry::counters(env, module)?,
+            registry: registry::registry(env, module)?,
+        },
+    };
+
+    match Datapath::start(bind, configs, listener, settings) {
+        Ok(datapath) => Ok((atoms::ok(), ResourceArc::new(datapath))),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
+
+// blocks until the worker threads have stopped, the resource is freed with its last
+// reference, which the processes of the connections may still hold
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn datapath_stop(datapath: ResourceArc<Datapath>) -> NifResult<Atom> {
+    datapath.stop();
+    Ok(atoms::ok())
+}
+
+#[rustler::nif]
+pub fn datapath_set_owner(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    pid: LocalPid,
+) -> NifResult<Atom> {
+    execute(&datapath, Command::SetOwner(conn_id, pid))
+}
+
+// the packets go to the socket of the datapath, they can't be subscribed to
+#[rustler::nif]
+pub fn datapath_set_subscriber(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    class: Atom,
+    pid: Option<LocalPid>,
+) -> NifResult<Atom> {
+    match EventClass::from_atom(class) {
+        Ok(EventClass::Packets) => Err(common::error_term(atoms::not_supported())),
+        Ok(class) => execute(&datapath, Command::SetSubscriber(conn_id, class, pid)),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
+
+#[rustler::nif]
+pub fn datapath_stream_qlog(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    pid: LocalPid,
+) -> NifResult<Atom> {
+    execute(&datapath, Command::StreamQlog(conn_id, pid))
+}
+
+#[rustler::nif]
+pub fn datapath_qlog_to_file(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    dir: String,
+    max_file_size: u64,
+    max_total_size: u64,
+    gzip: bool,
+) -> NifResult<Atom> {
+    let options = QlogFileOptions {
+        dir: dir.into(),
+        max_file_size,
+        max_total_size,
+        gzip,
+    };
+    execute(&datapath, Command::QlogToFile(conn_id, options))
+}
+
+#[rustler::nif]
+pub fn datapath_qlog_disable(datapath: ResourceArc<Datapath>, conn_id: u64) -> NifResult<Atom> {
+    execute(&datapath, Command::QlogDisable(conn_id))
+}
+
+#[rustler::nif]
+pub fn datapath_enable_congestion_events(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+) -> NifResult<Atom> {
+    execute(&datapath, Command::EnableCongestionEvents(conn_id))
+}
+
+#[rustler::nif]
+pub fn datapath_enable_pmtu_events(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+) -> NifResult<Atom> {
+    execute(&datapath, Command::EnablePmtuEvents(conn_id))
+}
+
+#[rustler::nif]
+pub fn datapath_enable_event_timestamps(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+) -> NifResult<Atom> {
+    execute(&datapath, Command::EnableEventTimestamps(conn_id))
+}
+
+#[rustler::nif]
+pub fn datapath_enable_stream_close_events(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+) -> NifResult<Atom> {
+    execute(&datapath, Command::EnableStreamCloseEvents(conn_id))
+}
+
+#[rustler::nif]
+pub fn datapath_enable_sampling(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    interval_ms: u64,
+    capacity: usize,
+) -> NifResult<Atom> {
+    execute(
+        &datapath,
+        Command::EnableSampling(conn_id, Duration::from_millis(interval_ms), capacity),
+    )
+}
+
+#[rustler::nif]
+pub fn datapath_set_stream_recv_window(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    window: usize,
+    reset_code: Option<u64>,
+) -> NifResult<Atom> {
+    execute(
+        &datapath,
+        Command::SetStreamRecvWindow(conn_id, window, reset_code),
+    )
+}
+
+#[rustler::nif]
+pub fn datapath_set_stream_framing(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    framing: Term,
+) -> NifResult<Atom> {
+    let framing = Framing::from_term(framing).map_err(common::error_term)?;
+    execute(&datapath, Command::SetStreamFraming(conn_id, framing))
+}
+
+#[rustler::nif]
+pub fn datapath_set_impairment(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    spec: Option<ImpairmentTuple>,
+) -> NifResult<Atom> {
+    let spec = spec.map(ImpairmentSpec::from);
+    execute(&datapath, Command::SetImpairment(conn_id, spec))
+}
+
+#[rustler::nif]
+pub fn datapath_set_stream_pull_mode(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    enabled: bool,
+) -> NifResult<Atom> {
+    execute(&datapath, Command::SetStreamPullMode(conn_id, enabled))
+}
+
+#[rustler::nif]
+pub fn datapath_set_stream_priority(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    stream_id: u64,
+    urgency: u8,
+    incremental: bool,
+) -> NifResult<Atom> {
+    execute(
+        &datapath,
+        Command::SetStreamPriority(conn_id, stream_id, urgency, incremental),
+    )
+}
+
+#[rustler::nif]
+pub fn datapath_set_send_order(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    order: Term,
+) -> NifResult<Atom> {
+    let order = SendOrder::from_term(order).map_err(common::error_term)?;
+    execute(&datapath, Command::SetSendOrder(conn_id, order))
+}
+
+#[rustler::nif]
+pub fn datapath_set_send_deadline(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    stream_id: Option<u64>,
+    deadline: Option<(u64, u64)>,
+) -> NifResult<Atom> {
+    let deadline = deadline.map(SendDeadline::from_tuple);
+    execute(
+        &datapath,
+        Command::SetSendDeadline(conn_id, stream_id, deadline),
+    )
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn datapath_stream_read<'a>(
+    env: Env<'a>,
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    stream_id: u64,
+    max_bytes: usize,
+) -> NifResult<(Atom, Binary<'a>)> {
+    let (reply_tx, reply_rx) = bounded::<Result<OwnedBinary, Atom>>(1);
+    execute(
+        &datapath,
+        Command::StreamRead(conn_id, stream_id, max_bytes, reply_tx),
+    )?;
+    match reply_rx.recv_timeout(COMMAND_REPLY_TIMEOUT) {
+        Ok(Ok(data)) => Ok((atoms::ok(), data.release(env))),
+        Ok(Err(reason)) => Err(common::error_term(reason)),
+        Err(_) => Err(common::error_term(atoms::system_error())),
+    }
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn datapath_h3_recv_body<'a>(
+    env: Env<'a>,
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    stream_id: u64,
+    max_bytes: usize,
+) -> NifResult<(Atom, Binary<'a>)> {
+    let (reply_tx, reply_rx) = bounded::<Result<OwnedBinary, Atom>>(1);
+    execute(
+        &datapath,
+        Command::H3RecvBody(conn_id, stream_id, max_bytes, reply_tx),
+    )?;
+    match reply_rx.recv_timeout(COMMAND_REPLY_TIMEOUT) {
+        Ok(Ok(data)) => Ok((atoms::ok(), data.release(env))),
+        Ok(Err(reason)) => Err(common::error_term(reason)),
+        Err(_) => Err(common::error_term(atoms::system_error())),
+    }
+}
+
+#[rustler::nif]
+pub fn datapath_set_dgram_pull_mode(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    enabled: bool,
+) -> NifResult<Atom> {
+    execute(&datapath, Command::SetDgramPullMode(conn_id, enabled))
+}
+
+#[rustler::nif]
+pub fn datapath_set_dgram_budget(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    budget: Option<usize>,
+) -> NifResult<Atom> {
+    execute(&datapath, Command::SetDgramBudget(conn_id, budget))
+}
+
+#[rustler::nif]
+pub fn datapath_set_keepalive(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    interval_ms: Option<u64>,
+) -> NifResult<Atom> {
+    // as connection_set_keepalive
+    if interval_ms == Some(0) {
+        return Err(common::error_term(atoms::bad_format()));
+    }
+    let interval = interval_ms.map(Duration::from_millis);
+    execute(&datapath, Command::SetKeepalive(conn_id, interval))
+}
+
+#[rustler::nif]
+pub fn datapath_set_lifetime_policy(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    max_lifetime: Option<(u64, u64)>,
+    max_inactivity: Option<(u64, u64)>,
+) -> NifResult<Atom> {
+    let max_lifetime = max_lifetime.map(|(ms, code)| (Duration::from_millis(ms), code));
+    let max_inactivity = max_inactivity.map(|(ms, code)| (Duration::from_millis(ms), code));
+    execute(
+        &datapath,
+        Command::SetLifetimePolicy(conn_id, max_lifetime, max_inactivity),
+    )
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn datapath_dgram_peek<'a>(
+    env: Env<'a>,
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    max_len: usize,
+) -> NifResult<(Atom, Binary<'a>, usize)> {
+    let (reply_tx, reply_rx) = bounded::<Result<(OwnedBinary, usize), Atom>>(1);
+    execute(&datapath, Command::DgramPeek(conn_id, max_len, reply_tx))?;
+    match reply_rx.recv_timeout(COMMAND_REPLY_TIMEOUT) {
+        Ok(Ok((data, total_len))) => Ok((atoms::ok(), data.release(env), total_len)),
+        Ok(Err(reason)) => Err(common::error_term(reason)),
+        Err(_) => Err(common::error_term(atoms::system_error())),
+    }
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn datapath_dgram_recv(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    deliver: bool,
+) -> NifResult<Atom> {
+    let (reply_tx, reply_rx) = bounded::<Result<(), Atom>>(1);
+    execute(&datapath, Command::DgramRecv(conn_id, deliver, reply_tx))?;
+    match reply_rx.recv_timeout(COMMAND_REPLY_TIMEOUT) {
+        Ok(Ok(())) => Ok(atoms::ok()),
+        Ok(Err(reason)) => Err(common::error_term(reason)),
+        Err(_) => Err(common::error_term(atoms::system_error())),
+    }
+}
+
+#[rustler::nif]
+pub fn datapath_set_trace_context(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    trace_id: Binary,
+    span_id: Binary,
+    flags: u8,
+) -> NifResult<Atom> {
+    let context = TraceContext::new(trace_id.as_slice(), span_id.as_slice(), flags)
+        .map_err(common::error_term)?;
+    execute(&datapath, Command::SetTraceContext(conn_id, context))
+}
+
+#[rustler::nif]
+pub fn datapath_stream_ack(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    stream_id: u64,
+    bytes: usize,
+) -> NifResult<Atom> {
+    execute(&datapath, Command::StreamAck(conn_id, stream_id, bytes))
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn datapath_samples(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+) -> NifResult<(Atom, Vec<SampleTuple>)> {
+    let (reply_tx, reply_rx) = bounded::<Result<Vec<Sample>, Atom>>(1);
+    execute(&datapath, Command::Samples(conn_id, reply_tx))?;
+    match reply_rx.recv_timeout(COMMAND_REPLY_TIMEOUT) {
+        Ok(Ok(samples)) => Ok((
+            atoms::ok(),
+            samples.into_iter().map(Sample::to_tuple).collect(),
+        )),
+        Ok(Err(reason)) => Err(common::error_term(reason)),
+        Err(_) => Err(common::error_term(atoms::system_error())),
+    }
+}
+
+#[rustler::nif]
+pub fn datapath_tap_pid(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    pid: LocalPid,
+) -> NifResult<Atom> {
+    execute(&datapath, Command::Tap(conn_id, Some(TapSpec::Pid(pid))))
+}
+
+#[rustler::nif]
+pub fn datapath_tap_pcap(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    dir: String,
+) -> NifResult<Atom> {
+    execute(
+        &datapath,
+        Command::Tap(conn_id, Some(TapSpec::Pcap(dir.into()))),
+    )
+}
+
+#[rustler::nif]
+pub fn datapath_untap(datapath: ResourceArc<Datapath>, conn_id: u64) -> NifResult<Atom> {
+    execute(&datapath, Command::Tap(conn_id, None))
+}
+
+#[rustler::nif]
+pub fn datapath_accept_connect_request(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+) -> NifResult<Atom> {
+    execute(&datapath, Command::AcceptConnectRequest(conn_id))
+}
+
+#[rustler::nif]
+pub fn datapath_reject_connect_request(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    code: u32,
+) -> NifResult<Atom> {
+    execute(&datapath, Command::RejectConnectRequest(conn_id, code))
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn datapath_open_stream(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    is_bidi: bool,
+) -> NifResult<(Atom, u64)> {
+    let (reply_tx, reply_rx) = bounded::<Result<u64, Atom>>(1);
+    execute(&datapath, Command::OpenStream(conn_id, is_bidi, reply_tx))?;
+    let _timer = diagnostics::time(Probe::DatapathOpenStreamWait);
+    match reply_rx.recv_timeout(COMMAND_REPLY_TIMEOUT) {
+        Ok(Ok(stream_id)) => Ok((atoms::ok(), stream_id)),
+        Ok(Err(reason)) => Err(common::error_term(reason)),
+        Err(_) => Err(common::error_term(atoms::system_error())),
+    }
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn datapath_stream_stats(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    stream_id: u64,
+) -> NifResult<(Atom, StreamStatsTuple)> {
+    let (reply_tx, reply_rx) = bounded::<Result<StreamStats, Atom>>(1);
+    execute(
+        &datapath,
+        Command::StreamStats(conn_id, stream_id, reply_tx),
+    )?;
+    match reply_rx.recv_timeout(COMMAND_REPLY_TIMEOUT) {
+        Ok(Ok(stats)) => Ok((atoms::ok(), stats.to_tuple())),
+        Ok(Err(reason)) => Err(common::error_term(reason)),
+        Err(_) => Err(common::error_term(atoms::system_error())),
+    }
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn datapath_stream_counts(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+) -> NifResult<(Atom, StreamCountsTuple)> {
+    let (reply_tx, reply_rx) = bounded::<Result<StreamCounts, Atom>>(1);
+    execute(&datapath, Command::StreamCounts(conn_id, reply_tx))?;
+    match reply_rx.recv_timeout(COMMAND_REPLY_TIMEOUT) {
+        Ok(Ok(counts)) => Ok((atoms::ok(), counts.to_tuple())),
+        Ok(Err(reason)) => Err(common::error_term(reason)),
+        Err(_) => Err(common::error_term(atoms::system_error())),
+    }
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn datapath_pmtu(datapath: ResourceArc<Datapath>, conn_id: u64) -> NifResult<(Atom, usize)> {
+    let (reply_tx, reply_rx) = bounded::<Result<usize, Atom>>(1);
+    execute(&datapath, Command::Pmtu(conn_id, reply_tx))?;
+    match reply_rx.recv_timeout(COMMAND_REPLY_TIMEOUT) {
+        Ok(Ok(pmtu)) => Ok((atoms::ok(), pmtu)),
+        Ok(Err(reason)) => Err(common::error_term(reason)),
+        Err(_) => Err(common::error_term(atoms::system_error())),
+    }
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn datapath_readable(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+) -> NifResult<(Atom, Vec<u64>)> {
+    poll_streams(datapath, conn_id, true)
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn datapath_writable(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+) -> NifResult<(Atom, Vec<u64>)> {
+    poll_streams(datapath, conn_id, false)
+}
+
+fn poll_streams(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    readable: bool,
+) -> NifResult<(Atom, Vec<u64>)> {
+    let (reply_tx, reply_rx) = bounded::<Result<Vec<u64>, Atom>>(1);
+    execute(&datapath, Command::PollStreams(conn_id, readable, reply_tx))?;
+    match reply_rx.recv_timeout(COMMAND_REPLY_TIMEOUT) {
+        Ok(Ok(stream_ids)) => Ok((atoms::ok(), stream_ids)),
+        Ok(Err(reason)) => Err(common::error_term(reason)),
+        Err(_) => Err(common::error_term(atoms::system_error())),
+    }
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn datapath_handoff_state(
+    env: Env,
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+) -> NifResult<(Atom, Term)> {
+    let (reply_tx, reply_rx) = bounded::<Result<HandoffState, Atom>>(1);
+    execute(&datapath, Command::HandoffState(conn_id, reply_tx))?;
+    match reply_rx.recv_timeout(COMMAND_REPLY_TIMEOUT) {
+        Ok(Ok(state)) => Ok((atoms::ok(), state.to_term(env)?)),
+        Ok(Err(reason)) => Err(common::error_term(reason)),
+        Err(_) => Err(common::error_term(atoms::system_error())),
+    }
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn datapath_peer_cert<'a>(
+    env: Env<'a>,
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+) -> NifResult<(Atom, Option<Binary<'a>>)> {
+    let (reply_tx, reply_rx) = bounded::<Result<Option<OwnedBinary>, Atom>>(1);
+    execute(&datapath, Command::PeerCert(conn_id, reply_tx))?;
+    match reply_rx.recv_timeout(COMMAND_REPLY_TIMEOUT) {
+        Ok(Ok(cert)) => Ok((atoms::ok(), cert.map(|cert| cert.release(env)))),
+        Ok(Err(reason)) => Err(common::error_term(reason)),
+        Err(_) => Err(common::error_term(atoms::system_error())),
+    }
+}
+
+#[rustler::nif]
+pub fn datapath_stream_send(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    stream_id: u64,
+    data: Binary,
+    fin: bool,
+) -> NifResult<(Atom, u64)> {
+    let data = data.as_slice();
+    if data.len() > STREAM_SEND_SLICE {
+        // {:continue, sent}, the caller sends the rest with another call
+        let chunk = data[..STREAM_SEND_SLICE].to_vec();
+        execute(
+            &datapath,
+            Command::StreamSend(conn_id, stream_id, vec![chunk], false),
+        )?;
+        return Ok((atoms::continue_(), STREAM_SEND_SLICE as u64));
+    }
+    execute(
+        &datapath,
+        Command::StreamSend(conn_id, stream_id, vec![data.to_vec()], fin),
+    )?;
+    Ok((atoms::ok(), data.len() as u64))
+}
+
+#[rustler::nif]
+pub fn datapath_stream_send_vectored(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    stream_id: u64,
+    data: ListIterator,
+    fin: bool,
+) -> NifResult<Atom> {
+    let chunks: Vec<Vec<u8>> = data
+        .map(|x| x.decode::<Binary>().map(|chunk| chunk.as_slice().to_vec()))
+        .collect::<NifResult<Vec<Vec<u8>>>>()
+        .map_err(|_| common::error_term(atoms::bad_format()))?;
+    execute(
+        &datapath,
+        Command::StreamSendMessage(conn_id, stream_id, chunks, fin),
+    )
+}
+
+// the chunks go as one command, which the worker writes whole or drops
+#[rustler::nif]
+pub fn datapath_stream_send_full(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    stream_id: u64,
+    data: ListIterator,
+    fin: bool,
+) -> NifResult<Atom> {
+    let chunks: Vec<Vec<u8>> = data
+        .map(|x| x.decode::<Binary>().map(|chunk| chunk.as_slice().to_vec()))
+        .collect::<NifResult<Vec<Vec<u8>>>>()
+        .map_err(|_| common::error_term(atoms::bad_format()))?;
+    execute(
+        &datapath,
+        Command::StreamSendFull(conn_id, stream_id, chunks, fin),
+    )
+}
+
+#[rustler::nif]
+pub fn datapath_dgram_send(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    data: Binary,
+) -> NifResult<Atom> {
+    execute(
+        &datapath,
+        Command::DgramSend(conn_id, data.as_slice().to_vec()),
+    )
+}
+
+// the datagrams are queued together, the count is that of the datagrams queued
+#[rustler::nif]
+pub fn datapath_dgram_send_batch(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    data: ListIterator,
+) -> NifResult<(Atom, usize)> {
+    let dgrams: Vec<Vec<u8>> = data
+        .map(|x| x.decode::<Binary>().map(|dgram| dgram.as_slice().to_vec()))
+        .collect::<NifResult<Vec<Vec<u8>>>>()
+        .map_err(|_| common::error_term(atoms::bad_format()))?;
+    let count = dgrams.len();
+    execute(&datapath, Command::DgramSendBatch(conn_id, dgrams))?;
+    Ok((atoms::ok(), count))
+}
+
+#[rustler::nif]
+pub fn datapath_h3_dgram_send(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    flow_id: u64,
+    data: Binary,
+) -> NifResult<Atom> {
+    execute(
+        &datapath,
+        Command::H3DgramSend(conn_id, flow_id, data.as_slice().to_vec()),
+    )
+}
+
+#[rustler::nif]
+pub fn datapath_close(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    app: bool,
+    err: u64,
+    reason: Binary,
+) -> NifResult<Atom> {
+    execute(
+        &datapath,
+        Command::Close(conn_id, app, err, reason.as_slice().to_vec()),
+    )
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn datapath_probe_path(
+    datapath: ResourceArc<Datapath>,
+    conn_id: u64,
+    timeout_ms: u64,
+) -> NifResult<Atom> {
+    let (reply_tx, reply_rx) = bounded::<Result<(), Atom>>(1);
+    let timeout = Duration::from_millis(timeout_ms);
+    execute(&datapath, Command::ProbePath(conn_id, timeout, reply_tx))?;
+    match reply_rx.recv_timeout(COMMAND_REPLY_TIMEOUT) {
+        Ok(Ok(())) => Ok(atoms::ok()),
+        Ok(Err(reason)) => Err(common::error_term(reason)),
+        Err(_) => Err(common::error_term(atoms::system_error())),
+    }
+}
+
+#[rustler::nif]
+pub fn datapath_forget(datapath: ResourceArc<Datapath>, conn_id: u64, err: u64) -> NifResult<Atom> {
+    execute(&datapath, Command::Forget(conn_id, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ODCID: &[u8] = b"original dcid";
+    const RETRY_SCID: &[u8] = b"retry scid";
+
+    fn key() -> hmac::Key {
+        hmac::Key::new(hmac::HMAC_SHA256, b"token secret")
+    }
+
+    fn peer() -> SocketAddr {
+        "192.0.2.1:4433".parse().unwrap()
+    }
+
+    fn token() -> Vec<u8> {
+        mint_token(&key(), &peer(), ODCID, RETRY_SCID, unix_time())
+    }
+
+    #[test]
+    fn token_round_trip() {
+        let token = token();
+        assert_eq!(
+            validate_token(&key(), &peer(), RETRY_SCID, &token),
+            Some(ODCID.to_vec())
+        );
+        let token = mint_token(&key(), &peer(), b"", RETRY_SCID, unix_time());
+        assert_eq!(
+            validate_token(&key(), &peer(), RETRY_SCID, &token),
+            Some(vec![])
+        );
+    }
+
+    #[test]
+    fn token_hmac() {
+        let token = token();
+        let other_key = hmac::Key::new(hmac::HMAC_SHA256, b"other secret");
+        assert_eq!(
+            validate_token(&other_key, &peer(), RETRY_SCID, &token),
+            None
+        );
+        // bound to the address and the port of the peer, and to the retry scid
+        let other_ip = "192.0.2.2:4433".parse().unwrap();
+        assert_eq!(validate_token(&key(), &other_ip, RETRY_SCID, &token), None);
+        let other_port = "192.0.2.1:4434".parse().unwrap();
+        assert_eq!(
+            validate_token(&key(), &other_port, RETRY_SCID, &token),
+            None
+        );
+        assert_eq!(validate_token(&key(), &peer(), b"other scid", &token), None);
+        // any altered byte, of the odcid, the issued at or the tag
+        for i in 1..token.len() {
+            let mut altered = token.clone();
+            altered[i] ^= 0x01;
+            assert_eq!(validate_token(&key(), &peer(), RETRY_SCID, &altered), None);
+        }
+    }
+
+    #[test]
+    fn token_expiry() {
+        let now = unix_time();
+        let valid = |issued_at| {
+            let token = mint_token(&key(), &peer(), ODCID, RETRY_SCID, issued_at);
+            validate_token(&key(), &peer(), RETRY_SCID, &token).is_some()
+        };
+        assert!(valid(now - TOKEN_LIFETIME_SECS + 1));
+        assert!(!valid(now - TOKEN_LIFETIME_SECS - 1));
+        assert!(!valid(0));
+        // the leeway for the clock stepping back, and no more
+        assert!(valid(now + 1));
+        assert!(!valid(now + 2));
+    }
+
+    #[test]
+    fn token_truncated() {
+        let token = token();
+        for len in 0..token.len() {
+            assert_eq!(
+                validate_token(&key(), &peer(), RETRY_SCID, &token[..len]),
+                None
+            );
+        }
+        // an odcid length beyond the token
+        let mut token = token;
+        token[0] = 20;
+        token.truncate(1 + 8);
+        assert_eq!(validate_token(&key(), &peer(), RETRY_SCID, &token), None);
+    }
+
+    #[test]
+    fn token_odcid_too_long() {
+        let odcid = [0x5a; quiche::MAX_CONN_ID_LEN + 1];
+        let token = mint_token(&key(), &peer(), &odcid, RETRY_SCID, unix_time());
+        assert_eq!(validate_token(&key(), &peer(), RETRY_SCID, &token), None);
+        let odcid = [0x5a; quiche::MAX_CONN_ID_LEN];
+        let token = mint_token(&key(), &peer(), &odcid, RETRY_SCID, unix_time());
+        assert_eq!(
+            validate_token(&key(), &peer(), RETRY_SCID, &token),
+            Some(odcid.to_vec())
+        );
+    }
+
+    #[test]
+    fn worker_for_issued_cid() {
+        let mut cid = vec![0u8; quiche::MAX_CONN_ID_LEN];
+        cid[0] = 6;
+        assert_eq!(worker_for_cid(&cid, 0, 4), 2);
+        assert_eq!(worker_for_cid(&cid, 3, 8), 6);
+    }
+
+    #[test]
+    fn worker_for_client_cid() {
+        // shorter than the connection IDs the workers issue, the receiving worker keeps it
+        assert_eq!(worker_for_cid(&[6; 8], 1, 4), 1);
+        assert_eq!(worker_for_cid(&[], 3, 4), 3);
+        assert_eq!(worker_for_cid(&[6; quiche::MAX_CONN_ID_LEN + 1], 1, 4), 1);
+    }
+}
@@ -0,0 +1,255 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crossbeam_channel::Sender;
+use rustler::types::tuple::make_tuple;
+use rustler::types::{Encoder, LocalPid};
+use rustler::{Atom, Env, NifResult, Term};
+
+use crate::common::{self, atoms};
+
+// bucket i counts the samples shorter than 2^i microseconds, the last one the rest
+const BUCKETS: usize = 24;
+
+// instrumentation is off unless enabled, then each probe costs two clock reads
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// warnings waiting for the watcher thread, dropped beyond that
+const SLOW_CALL_QUEUE_SIZE: usize = 256;
+
+// the calls longer than this are reported to the watcher, 0 when there's none
+static SLOW_CALL_THRESHOLD_US: AtomicU64 = AtomicU64::new(0);
+
+struct SlowCall {
+    probe: Probe,
+    trace_id: Option<String>,
+    elapsed_us: u64,
+}
+
+// set while an elixir process watches the slow calls
+static WATCHER: Mutex<Option<Sender<SlowCall>>> = Mutex::new(None);
+
+// only the connection NIFs which run quiche over packets, timers or sent data are timed,
+// those which read or set the state of a connection take no time worth a probe
+#[derive(Clone, Copy)]
+pub enum Probe {
+    ConnectionAccept,
+    // connection_on_packet and connection_on_packet_sync
+    ConnectionOnPacket,
+    ConnectionOnTimeout,
+    // connection_stream_send and connection_stream_send_sync
+    ConnectionStreamSend,
+    // connection_stream_send_vectored and connection_stream_send_full
+    ConnectionStreamSendVectored,
+    // connection_dgram_send and connection_dgram_send_batch
+    ConnectionDgramSend,
+    // NIF calls enqueueing a command to the native datapath, all of them but
+    // datapath_start and datapath_stop, which don't go through a worker
+    DatapathCommand,
+    // time a command waits in the queue until its worker executes it
+    DatapathCommandWait,
+    // time datapath_open_stream waits for the reply of the worker
+    DatapathOpenStreamWait,
+}
+
+const PROBES: [(Probe, &str); 9] = [
+    (Probe::ConnectionAccept, "connection_accept"),
+    (Probe::ConnectionOnPacket, "connection_on_packet"),
+    (Probe::ConnectionOnTimeout, "connection_on_timeout"),
+    (Probe::ConnectionStreamSend, "connection_stream_send"),
+    (
+        Probe::ConnectionStreamSendVectored,
+        "connection_stream_send_vectored",
+    ),
+    (Probe::ConnectionDgramSend, "connection_dgram_send"),
+    (Probe::DatapathCommand, "datapath_command"),
+    (Probe::DatapathCommandWait, "datapath_command_wait"),
+    (Probe::DatapathOpenStreamWait, "datapath_open_stream_wait"),
+];
+
+struct Histogram {
+    count: AtomicU64,
+    total_us: AtomicU64,
+    buckets: [AtomicU64; BUCKETS],
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY: Histogram = Histogram {
+    count: ZERO,
+    total_us: ZERO,
+    buckets: [ZERO; BUCKETS],
+};
+
+static HISTOGRAMS: [Histogram; PROBES.len()] = [EMPTY; PROBES.len()];
+
+impl Histogram {
+    fn record(&self, us: u64) {
+        let bucket = ((64 - us.leading_zeros()) as usize).min(BUCKETS - 1);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_us.fetch_add(us, Ordering::Relaxed);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn reset(&self) {
+        self.count.store(0, Ordering::Relaxed);
+        self.total_us.store(0, Ordering::Relaxed);
+        for bucket in self.buckets.iter() {
+            bucket.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+fn is_watched() -> bool {
+    SLOW_CALL_THRESHOLD_US.load(Ordering::Relaxed) > 0
+}
+
+// the current time when enabled or watched, to be passed to record() later
+pub fn now() -> Option<Instant> {
+    if is_enabled() || is_watched() {
+        Some(Instant::now())
+    } else {
+        None
+    }
+}
+
+// trace_id is the one of the connection the call was for, when it's known
+pub fn record(probe: Probe, since: Option<Instant>, trace_id: Option<&str>) {
+    if let Some(since) = since {
+        let us = since.elapsed().as_micros() as u64;
+        if is_enabled() {
+            HISTOGRAMS[probe as usize].record(us);
+        }
+        let threshold = SLOW_CALL_THRESHOLD_US.load(Ordering::Relaxed);
+        if threshold > 0 && us >= threshold {
+            warn(probe, trace_id, us);
+        }
+    }
+}
+
+fn warn(probe: Probe, trace_id: Option<&str>, elapsed_us: u64) {
+    if let Some(watcher) = WATCHER.lock().unwrap().as_ref() {
+        let call = SlowCall {
+            probe,
+            trace_id: trace_id.map(str::to_string),
+            elapsed_us,
+        };
+        // never blocks the caller, the warning is dropped when the queue is full
+        let _ = watcher.try_send(call);
+    }
+}
+
+// records the time until it's dropped
+pub struct Timer {
+    probe: Probe,
+    since: Option<Instant>,
+    trace_id: Option<String>,
+}
+
+impl Timer {
+    // the connection the call is for, only kept when the slow calls are watched
+    pub fn set_trace_id(&mut self, trace_id: &str) {
+        if self.since.is_some() && is_watched() {
+            self.trace_id = Some(trace_id.to_string());
+        }
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        record(self.probe, self.since, self.trace_id.as_deref());
+    }
+}
+
+pub fn time(probe: Probe) -> Timer {
+    Timer {
+        probe,
+        since: now(),
+        trace_id: None,
+    }
+}
+
+pub fn time_conn(probe: Probe, trace_id: &str) -> Timer {
+    let mut timer = time(probe);
+    timer.set_trace_id(trace_id);
+    timer
+}
+
+#[rustler::nif]
+pub fn diagnostics_enable(enabled: bool) -> NifResult<Atom> {
+    if enabled && !is_enabled() {
+        for histogram in HISTOGRAMS.iter() {
+            histogram.reset();
+        }
+    }
+    ENABLED.store(enabled, Ordering::Relaxed);
+    Ok(atoms::ok())
+}
+
+// {:slow_call, name, trace_id, elapsed_us}, sent from the thread of common::spawn_sender
+fn slow_call_term(env: Env, call: SlowCall) -> Term {
+    let name = PROBES[call.probe as usize].1;
+    make_tuple(
+        env,
+        &[
+            atoms::slow_call().to_term(env),
+            Atom::from_str(env, name).unwrap().to_term(env),
+            call.trace_id.encode(env),
+            call.elapsed_us.encode(env),
+        ],
+    )
+}
+
+// reports the probed calls which take threshold_us or longer to the process,
+// which replaces the previous one
+#[rustler::nif]
+pub fn diagnostics_watch_slow_calls(pid: LocalPid, threshold_us: u64) -> NifResult<Atom> {
+    if threshold_us == 0 {
+        return Err(common::error_term(atoms::bad_format()));
+    }
+    let tx = common::spawn_sender(
+        "requiem-slow-calls",
+        pid,
+        SLOW_CALL_QUEUE_SIZE,
+        slow_call_term,
+    )?;
+    // the previous watcher thread ends with its channel
+    *WATCHER.lock().unwrap() = Some(tx);
+    SLOW_CALL_THRESHOLD_US.store(threshold_us, Ordering::Relaxed);
+    Ok(atoms::ok())
+}
+
+#[rustler::nif]
+pub fn diagnostics_unwatch_slow_calls() -> NifResult<Atom> {
+    SLOW_CALL_THRESHOLD_US.store(0, Ordering::Relaxed);
+    *WATCHER.lock().unwrap() = None;
+    Ok(atoms::ok())
+}
+
+// [{name, count, total_us, buckets}]
+#[rustler::nif]
+pub fn diagnostics(env: Env) -> NifResult<Vec<(Atom, u64, u64, Vec<u64>)>> {
+    PROBES
+        .iter()
+        .map(|(probe, name)| {
+            let histogram = &HISTOGRAMS[*probe as usize];
+            Ok((
+                Atom::from_str(env, name)?,
+                histogram.count.load(Ordering::Relaxed),
+                histogram.total_us.load(Ordering::Relaxed),
+                histogram
+                    .buckets
+                    .iter()
+                    .map(|bucket| bucket.load(Ordering::Relaxed))
+                    .collect(),
+            ))
+        })
+        .collect()
+}
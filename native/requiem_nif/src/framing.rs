@@ -0,0 +1,326 @@
+use std::collections::HashMap;
+
+use rustler::{Atom, Term};
+
+use crate::common::atoms;
+
+// a varint prefix may announce up to 2^62 bytes, messages are limited to this
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+// the incomplete messages of all the streams of a connection are limited to this,
+// so that many streams each holding back a large message can't add up
+const MAX_BUFFERED_BYTES: usize = 2 * MAX_MESSAGE_SIZE;
+
+// how the data of a stream is cut into the messages passed to the owner,
+// and how the messages sent by the owner are written to the stream
+#[derive(Clone, Copy, PartialEq)]
+pub enum Framing {
+    // the chunks as they're read and written
+    Raw,
+    // a big-endian length of 1, 2, 4 or 8 bytes before each message,
+    // 2 being DNS over QUIC (RFC 9250)
+    LengthPrefix(usize),
+    // a QUIC variable-length integer before each message
+    VarintPrefix,
+    // each message followed by LF, which can't be in it
+    Newline,
+}
+
+impl Framing {
+    // :raw, :length_prefix (2 bytes), {:length_prefix, width}, :varint_prefix or :newline
+    pub fn from_term(term: Term) -> Result<Self, Atom> {
+        if let Ok(atom) = term.decode::<Atom>() {
+            if atom == atoms::raw() {
+                Ok(Framing::Raw)
+            } else if atom == atoms::length_prefix() {
+                Ok(Framing::LengthPrefix(2))
+            } else if atom == atoms::varint_prefix() {
+                Ok(Framing::VarintPrefix)
+            } else if atom == atoms::newline() {
+                Ok(Framing::Newline)
+            } else {
+                Err(atoms::bad_format())
+            }
+        } else {
+            match term.decode::<(Atom, usize)>() {
+                Ok((atom, width)) if atom == atoms::length_prefix() => match width {
+                    1 | 2 | 4 | 8 => Ok(Framing::LengthPrefix(width)),
+                    _ => Err(atoms::bad_format()),
+                },
+                _ => Err(atoms::bad_format()),
+            }
+        }
+    }
+
+    // (message start, message end, next message start) in the buffer,
+    // None until a message is complete. the first `scanned` bytes are known
+    // not to end a newline message
+    fn decode(self, buf: &[u8], scanned: usize) -> Result<Option<(usize, usize, usize)>, TooLarge> {
+        let (prefix, len) = match self {
+            Framing::Raw => return Ok(None),
+            Framing::LengthPrefix(width) => {
+                if buf.len() < width {
+                    return Ok(None);
+                }
+                let len = buf[..width]
+                    .iter()
+                    .fold(0u64, |acc, b| (acc << 8) | *b as u64);
+                (width, len)
+            }
+            Framing::VarintPrefix => {
+                let first = match buf.first() {
+                    Some(first) => *first,
+                    None => return Ok(None),
+                };
+                let width = 1 << (first >> 6);
+                if buf.len() < width {
+                    return Ok(None);
+                }
+                let len = buf[1..width]
+                    .iter()
+                    .fold((first & 0x3f) as u64, |acc, b| (acc << 8) | *b as u64);
+                (width, len)
+            }
+            Framing::Newline => {
+                let from = scanned.min(buf.len());
+                return match buf[from..]
+                    .iter()
+                    .position(|b| *b == b'\n')
+                    .map(|pos| from + pos)
+                {
+                    Some(pos) if pos > MAX_MESSAGE_SIZE => Err(TooLarge),
+                    Some(pos) => Ok(Some((0, pos, pos + 1))),
+                    None if buf.len() > MAX_MESSAGE_SIZE => Err(TooLarge),
+                    None => Ok(None),
+                };
+            }
+        };
+        if len > MAX_MESSAGE_SIZE as u64 {
+            return Err(TooLarge);
+        }
+        let end = prefix + len as usize;
+        if buf.len() < end {
+            return Ok(None);
+        }
+        Ok(Some((prefix, end, end)))
+    }
+
+    // what goes before and after the chunks of a message, None when it can't be framed
+    pub fn frame(self, chunks: &[&[u8]]) -> Option<(Vec<u8>, &'static [u8])> {
+        let len: usize = chunks.iter().map(|chunk| chunk.len()).sum();
+        match self {
+            Framing::Raw => Some((Vec::new(), &b""[..])),
+            Framing::LengthPrefix(width) => {
+                if width < 8 && len >> (width * 8) != 0 {
+                    return None;
+                }
+                Some(((len as u64).to_be_bytes()[8 - width..].to_vec(), &b""[..]))
+            }
+            Framing::VarintPrefix => varint(len as u64).map(|prefix| (prefix, &b""[..])),
+            Framing::Newline => {
+                if chunks.iter().any(|chunk| chunk.contains(&b'\n')) {
+                    return None;
+                }
+                Some((Vec::new(), &b"\n"[..]))
+            }
+        }
+    }
+}
+
+// RFC 9000 Section 16
+fn varint(value: u64) -> Option<Vec<u8>> {
+    let (width, tag) = match value {
+        0..=0x3f => (1, 0x00),
+        0x40..=0x3fff => (2, 0x40),
+        0x4000..=0x3fff_ffff => (4, 0x80),
+        0x4000_0000..=0x3fff_ffff_ffff_ffff => (8, 0xc0),
+        _ => return None,
+    };
+    let mut bytes = value.to_be_bytes()[8 - width..].to_vec();
+    bytes[0] |= tag;
+    Some(bytes)
+}
+
+// the message which doesn't fit in MAX_MESSAGE_SIZE, or in what the other streams
+// leave of MAX_BUFFERED_BYTES
+#[derive(Debug, PartialEq)]
+pub struct TooLarge;
+
+#[derive(Default)]
+struct Partial {
+    buf: Vec<u8>,
+    // how much of buf has been searched for a newline already
+    scanned: usize,
+}
+
+// the incomplete messages of the streams of a connection
+pub struct StreamFramer {
+    framing: Framing,
+    partial: HashMap<u64, Partial>,
+    // the bytes in partial
+    buffered: usize,
+}
+
+impl StreamFramer {
+    pub fn new(framing: Framing) -> Self {
+        Self {
+            framing,
+            partial: HashMap::new(),
+            buffered: 0,
+        }
+    }
+
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    // the messages completed by the data, without their framing
+    pub fn feed(&mut self, stream_id: u64, data: &[u8]) -> Result<Vec<Vec<u8>>, TooLarge> {
+        let partial = self.partial.entry(stream_id).or_default();
+        let buf = &mut partial.buf;
+        self.buffered -= buf.len();
+        buf.extend_from_slice(data);
+        let mut messages = Vec::new();
+        let mut pos = 0;
+        let mut scanned = partial.scanned;
+        loop {
+            match self.framing.decode(&buf[pos..], scanned) {
+                Ok(Some((start, end, next))) => {
+                    messages.push(buf[pos + start..pos + end].to_vec());
+                    pos += next;
+                    scanned = 0;
+                }
+                Ok(None) => break,
+                Err(TooLarge) => {
+                    self.partial.remove(&stream_id);
+                    return Err(TooLarge);
+                }
+            }
+        }
+        buf.drain(..pos);
+        // what's left has no newline, the next data is searched from its end
+        partial.scanned = buf.len();
+        if buf.is_empty() {
+            self.partial.remove(&stream_id);
+        } else if self.buffered + buf.len() > MAX_BUFFERED_BYTES {
+            self.partial.remove(&stream_id);
+            return Err(TooLarge);
+        } else {
+            self.buffered += buf.len();
+        }
+        Ok(messages)
+    }
+
+    // the bytes of an incomplete message are dropped with the stream
+    pub fn forget(&mut self, stream_id: u64) -> usize {
+        let dropped = self
+            .partial
+            .remove(&stream_id)
+            .map_or(0, |partial| partial.buf.len());
+        self.buffered -= dropped;
+        dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn length_prefix_messages() {
+        let mut framer = StreamFramer::new(Framing::LengthPrefix(2));
+        let messages = framer.feed(0, b"\x00\x03abc\x00\x00\x00\x02de").unwrap();
+        assert_eq!(
+            messages,
+            vec![b"abc".to_vec(), b"".to_vec(), b"de".to_vec()]
+        );
+        assert_eq!(framer.forget(0), 0);
+    }
+
+    #[test]
+    fn varint_prefix_messages() {
+        let mut framer = StreamFramer::new(Framing::VarintPrefix);
+        let long = vec![0x61; 300];
+        let mut data = varint(300).unwrap();
+        assert_eq!(data, vec![0x41, 0x2c]);
+        data.extend_from_slice(&long);
+        data.extend_from_slice(b"\x01x");
+        assert_eq!(framer.feed(0, &data).unwrap(), vec![long, b"x".to_vec()]);
+    }
+
+    #[test]
+    fn partial_messages() {
+        let mut framer = StreamFramer::new(Framing::VarintPrefix);
+        // the prefix itself split, then the payload
+        assert!(framer.feed(0, b"\x40").unwrap().is_empty());
+        assert!(framer.feed(0, b"\x05ab").unwrap().is_empty());
+        assert!(framer.feed(4, b"\x01").unwrap().is_empty());
+        assert_eq!(
+            framer.feed(0, b"cde\x02f").unwrap(),
+            vec![b"abcde".to_vec()]
+        );
+        assert_eq!(framer.feed(4, b"y").unwrap(), vec![b"y".to_vec()]);
+        assert_eq!(framer.forget(0), 2);
+        assert_eq!(framer.buffered, 0);
+    }
+
+    #[test]
+    fn newline_messages() {
+        let mut framer = StreamFramer::new(Framing::Newline);
+        assert_eq!(framer.feed(0, b"ab\ncd").unwrap(), vec![b"ab".to_vec()]);
+        assert_eq!(
+            framer.feed(0, b"e\n\n").unwrap(),
+            vec![b"cde".to_vec(), b"".to_vec()]
+        );
+        assert_eq!(framer.forget(0), 0);
+    }
+
+    #[test]
+    fn newline_scan_resumes() {
+        let mut framer = StreamFramer::new(Framing::Newline);
+        assert!(framer.feed(0, b"abc").unwrap().is_empty());
+        assert_eq!(framer.partial[&0].scanned, 3);
+        assert!(framer.feed(0, b"de").unwrap().is_empty());
+        assert_eq!(framer.partial[&0].scanned, 5);
+        assert_eq!(
+            framer.feed(0, b"\nf\ng").unwrap(),
+            vec![b"abcde".to_vec(), b"f".to_vec()]
+        );
+        assert_eq!(framer.partial[&0].scanned, 1);
+        assert_eq!(Framing::Newline.decode(b"a\nb", 2).unwrap(), None);
+    }
+
+    #[test]
+    fn too_large_message() {
+        let mut framer = StreamFramer::new(Framing::LengthPrefix(4));
+        let len = (MAX_MESSAGE_SIZE as u32 + 1).to_be_bytes();
+        assert_eq!(framer.feed(0, &len), Err(TooLarge));
+        assert_eq!(framer.forget(0), 0);
+    }
+
+    #[test]
+    fn buffered_bytes_are_capped_per_connection() {
+        let mut framer = StreamFramer::new(Framing::LengthPrefix(4));
+        let mut data = (MAX_MESSAGE_SIZE as u32).to_be_bytes().to_vec();
+        data.resize(MAX_MESSAGE_SIZE, 0);
+        assert!(framer.feed(0, &data).unwrap().is_empty());
+        assert!(framer.feed(4, &data).unwrap().is_empty());
+        assert_eq!(framer.feed(8, &data), Err(TooLarge));
+        assert_eq!(framer.buffered, 2 * MAX_MESSAGE_SIZE);
+        framer.forget(0);
+        assert!(framer.feed(8, &data).unwrap().is_empty());
+    }
+
+    #[test]
+    fn frame_prefixes() {
+        let chunks: [&[u8]; 2] = [b"ab", b"c"];
+        assert_eq!(
+            Framing::LengthPrefix(1).frame(&chunks),
+            Some((vec![3], &b""[..]))
+        );
+        assert_eq!(Framing::LengthPrefix(1).frame(&[&[0; 256][..]]), None);
+        assert_eq!(Framing::Newline.frame(&[b"a\n"]), None);
+        assert_eq!(varint(0x3fff_ffff_ffff_ffff).map(|v| v.len()), Some(8));
+        assert_eq!(varint(1 << 62), None);
+    }
+}
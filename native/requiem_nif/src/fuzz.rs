@@ -0,0 +1,190 @@
+// without the fuzzing feature, only the stub of fuzz_input is built into the NIF
+#![cfg_attr(not(feature = "fuzzing"), allow(dead_code))]
+
+use std::net::SocketAddr;
+#[cfg(feature = "fuzzing")]
+use std::sync::Arc;
+
+use quiche::h3::webtransport::{ServerEvent, ServerSession};
+use ring::hmac;
+use rustler::types::binary::Binary;
+#[cfg(feature = "fuzzing")]
+use rustler::types::LocalPid;
+use rustler::{Atom, NifResult};
+#[cfg(feature = "fuzzing")]
+use rustler::{Env, ResourceArc, Term};
+
+#[cfg(feature = "fuzzing")]
+use crate::common::Emitter;
+use crate::common::{self, atoms};
+#[cfg(feature = "fuzzing")]
+use crate::connection::{Connection, Output};
+use crate::datapath;
+#[cfg(feature = "fuzzing")]
+use crate::socket::Peer;
+#[cfg(feature = "fuzzing")]
+use crate::telemetry::Counters;
+
+// the entrypoints of the bytes which come from the network, for the cargo-fuzz targets
+// in fuzz/ and for fuzz_input, so that both go through the code the sockets use.
+// the cargo-fuzz targets run without the BEAM, so recv() stops at the WebTransport
+// session of the connections, and fuzz_input goes through the whole Connection.
+
+const PEER: &str = "127.0.0.1:4433";
+const MAX_DATAGRAM_SIZE: usize = 1500;
+
+fn peer() -> SocketAddr {
+    PEER.parse().unwrap()
+}
+
+// the bytes which the code under test turned down
+pub struct Rejected;
+
+// what the socket and the datapath read of a packet before routing it
+pub fn parse_header(data: &[u8]) -> Result<(), Rejected> {
+    let mut buf = data.to_vec();
+    let hdr =
+        quiche::Header::from_slice(&mut buf, quiche::MAX_CONN_ID_LEN).map_err(|_| Rejected)?;
+    if hdr.ty == quiche::Type::Initial && !quiche::version_is_supported(hdr.version) {
+        let mut out = [0; MAX_DATAGRAM_SIZE];
+        quiche::negotiate_version(&hdr.scid, &hdr.dcid, &mut out).map_err(|_| Rejected)?;
+    }
+    Ok(())
+}
+
+// a retry token of an Initial packet, checked against a fixed key: the data is
+// [retry scid length][retry scid][token]
+pub fn validate_token(data: &[u8]) -> Result<(), Rejected> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, &[0; 32]);
+    let (scid_len, rest) = data.split_first().ok_or(Rejected)?;
+    let scid_len = (*scid_len as usize).min(rest.len());
+    let (retry_scid, token) = rest.split_at(scid_len);
+    datapath::validate_token(&key, &peer(), retry_scid, token)
+        .map(|_| ())
+        .ok_or(Rejected)
+}
+
+// the data of recv(): a sequence of [2-byte big-endian length][datagram]
+fn datagrams(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut rest = data;
+    std::iter::from_fn(move || {
+        if rest.len() < 2 {
+            return None;
+        }
+        let len = (u16::from_be_bytes([rest[0], rest[1]]) as usize).min(rest.len() - 2);
+        let datagram = &rest[2..2 + len];
+        rest = &rest[2 + len..];
+        Some(datagram)
+    })
+}
+
+// datagrams received by a new server connection, then read by the WebTransport
+// session as Connection does once it's established, the CONNECT requests accepted
+pub fn recv(config: &mut quiche::Config, data: &[u8]) -> Result<(), Rejected> {
+    let scid = quiche::ConnectionId::from_ref(&[0; quiche::MAX_CONN_ID_LEN]);
+    let mut conn = quiche::accept(&scid, None, peer(), config).map_err(|_| Rejected)?;
+    let mut session = None;
+    let mut out = [0; MAX_DATAGRAM_SIZE];
+    for datagram in datagrams(data) {
+        let mut datagram = datagram.to_vec();
+        // errors are what the peer deserves, the next datagram is received all the same
+        let _ = conn.recv(&mut datagram, quiche::RecvInfo { from: peer() });
+        if conn.is_established() && session.is_none() {
+            session = Some(ServerSession::with_transport(&mut conn).map_err(|_| Rejected)?);
+        }
+        if let Some(t) = session.as_mut() {
+            while let Ok(event) = t.poll(&mut conn) {
+                match event {
+                    ServerEvent::ConnectRequest(_) => {
+                        let _ = t.accept_connect_request(&mut conn, None);
+                    }
+                    ServerEvent::StreamData(stream_id) => {
+                        while let Ok(read) = t.recv_stream_data(&mut conn, stream_id, &mut out) {
+                            if read == 0 {
+                                break;
+                            }
+                        }
+                    }
+                    ServerEvent::Datagram => while t.recv_dgram(&mut conn, &mut out).is_ok() {},
+                    _ => {}
+                }
+            }
+        }
+        while conn.send(&mut out).is_ok() {}
+        if conn.is_closed() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// the events of the connection, which have no process to go to
+#[cfg(feature = "fuzzing")]
+struct Discard;
+
+#[cfg(feature = "fuzzing")]
+impl Emitter for Discard {
+    fn emit<F>(&mut self, _pid: &LocalPid, _f: F)
+    where
+        F: for<'a> FnOnce(Env<'a>) -> Term<'a>,
+    {
+    }
+}
+
+// the datagrams of recv() received by a Connection as those of the sockets are,
+// its events and packets discarded, and its CONNECT requests accepted
+#[cfg(feature = "fuzzing")]
+fn recv_connection(env: Env, config: &mut quiche::Config, data: &[u8]) -> Result<(), Rejected> {
+    let scid = quiche::ConnectionId::from_ref(&[0; quiche::MAX_CONN_ID_LEN]);
+    let raw = quiche::accept(&scid, None, peer(), config).map_err(|_| Rejected)?;
+    let pid = env.pid();
+    let mut conn = Connection::new(
+        raw,
+        ResourceArc::new(Peer::new(peer())),
+        Output::Sender(pid.clone()),
+        MAX_DATAGRAM_SIZE,
+        Arc::new(Counters::default()),
+    );
+    let mut accepted = false;
+    for datagram in datagrams(data) {
+        // errors are what the peer deserves, the next datagram is received all the same
+        let _ = conn.process_packet(&mut Discard, &pid, datagram, peer());
+        if !accepted && conn.session_id().is_some() {
+            accepted = true;
+            let _ = conn.accept_connect_request(&mut Discard);
+        }
+        if conn.is_closed() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// feeds the bytes to the target, :header, :token or :recv, the latter with the
+// config of the module, which has its certificate. {:error, reason} is a rejection
+// of the bytes, a crash is a bug. only built with the fuzzing feature.
+#[cfg(feature = "fuzzing")]
+#[rustler::nif(schedule = "DirtyCpu")]
+pub fn fuzz_input(env: Env, conf_ptr: i64, target: Atom, data: Binary) -> NifResult<Atom> {
+    let result = if target == atoms::header() {
+        parse_header(data.as_slice())
+    } else if target == atoms::token() {
+        validate_token(data.as_slice())
+    } else if target == atoms::recv() {
+        let conf_ptr = conf_ptr as *mut quiche::Config;
+        let conf = unsafe { &mut *conf_ptr };
+        recv_connection(env, conf, data.as_slice())
+    } else {
+        return Err(common::error_term(atoms::bad_format()));
+    };
+    match result {
+        Ok(()) => Ok(atoms::ok()),
+        Err(Rejected) => Err(common::error_term(atoms::bad_format())),
+    }
+}
+
+#[cfg(not(feature = "fuzzing"))]
+#[rustler::nif]
+pub fn fuzz_input(_conf_ptr: i64, _target: Atom, _data: Binary) -> NifResult<Atom> {
+    Err(common::error_term(atoms::not_supported()))
+}
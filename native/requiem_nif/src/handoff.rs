@@ -0,0 +1,64 @@
+use std::net::SocketAddr;
+
+use rustler::types::atom;
+use rustler::types::binary::OwnedBinary;
+use rustler::types::Encoder;
+use rustler::{Env, NifResult, Term};
+
+use crate::common::atoms;
+
+// what another node needs to know of a connection to take its client over.
+// the TLS and QUIC state stays with quiche, the client comes back with a full
+// handshake: quiche 0.12 can't share the ticket keys, so resumption across nodes
+// isn't possible.
+pub struct HandoffState {
+    pub trace_id: String,
+    pub source_id: Vec<u8>,
+    pub destination_id: Vec<u8>,
+    pub peer: SocketAddr,
+    pub alpn: Vec<u8>,
+    pub server_name: Option<String>,
+    pub session_id: Option<u64>,
+    pub traceparent: Option<String>,
+    pub established: bool,
+}
+
+impl HandoffState {
+    // a map of plain terms, so that it survives term_to_binary and another node
+    pub fn to_term<'a>(&self, env: Env<'a>) -> NifResult<Term<'a>> {
+        let nil = atom::nil().to_term(env);
+        Term::map_new(env)
+            .map_put(atoms::trace_id().encode(env), self.trace_id.encode(env))?
+            .map_put(atoms::source_id().encode(env), binary(env, &self.source_id))?
+            .map_put(
+                atoms::destination_id().encode(env),
+                binary(env, &self.destination_id),
+            )?
+            .map_put(atoms::peer().encode(env), self.peer.to_string().encode(env))?
+            .map_put(atoms::alpn().encode(env), binary(env, &self.alpn))?
+            .map_put(
+                atoms::server_name().encode(env),
+                self.server_name
+                    .as_ref()
+                    .map_or(nil, |name| name.encode(env)),
+            )?
+            .map_put(
+                atoms::session_id().encode(env),
+                self.session_id.map_or(nil, |id| id.encode(env)),
+            )?
+            .map_put(
+                atoms::traceparent().encode(env),
+                self.traceparent.as_ref().map_or(nil, |tp| tp.encode(env)),
+            )?
+            .map_put(
+                atoms::established().encode(env),
+                self.established.encode(env),
+            )
+    }
+}
+
+fn binary<'a>(env: Env<'a>, data: &[u8]) -> Term<'a> {
+    let mut bin = OwnedBinary::new(data.len()).unwrap();
+    bin.as_mut_slice().copy_from_slice(data);
+    bin.release(env).to_term(env)
+}
@@ -0,0 +1,146 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// probabilities are in parts per million
+const MILLION: u64 = 1_000_000;
+
+// a reordered packet is held this much longer than the others, so that the next ones overtake it
+const REORDER_DELAY: Duration = Duration::from_millis(10);
+
+#[derive(Clone, Copy)]
+pub struct ImpairmentSpec {
+    pub loss: u32,
+    pub duplicate: u32,
+    pub reorder: u32,
+    pub delay: Duration,
+    // the same seed gives the same decisions for the same packets
+    pub seed: u64,
+}
+
+// {loss, duplicate, reorder, delay_ms, seed}, the probabilities in parts per million
+pub type ImpairmentTuple = (u32, u32, u32, u64, u64);
+
+impl From<ImpairmentTuple> for ImpairmentSpec {
+    fn from((loss, duplicate, reorder, delay_ms, seed): ImpairmentTuple) -> Self {
+        Self {
+            loss,
+            duplicate,
+            reorder,
+            delay: Duration::from_millis(delay_ms),
+            seed,
+        }
+    }
+}
+
+struct Held {
+    due: Instant,
+    data: Vec<u8>,
+    addr: SocketAddr,
+}
+
+// a simulated network between quiche and the socket, for tests: the packets in both
+// directions are dropped, duplicated, reordered and delayed as specified
+pub struct Impairment {
+    spec: ImpairmentSpec,
+    state: u64,
+    inbound: Vec<Held>,
+    outbound: Vec<Held>,
+}
+
+impl Impairment {
+    pub fn new(spec: ImpairmentSpec) -> Self {
+        Self {
+            spec,
+            // xorshift can't start from 0
+            state: spec.seed.max(1),
+            inbound: Vec::new(),
+            outbound: Vec::new(),
+        }
+    }
+
+    // xorshift64*, deterministic and good enough to roll dice
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn roll(&mut self, ppm: u32) -> bool {
+        ppm > 0 && self.next() % MILLION < ppm as u64
+    }
+
+    // the delays of the copies of a packet, none when it's lost
+    fn delays(&mut self) -> Vec<Duration> {
+        if self.roll(self.spec.loss) {
+            return Vec::new();
+        }
+        let copies = if self.roll(self.spec.duplicate) { 2 } else { 1 };
+        (0..copies)
+            .map(|_| {
+                if self.roll(self.spec.reorder) {
+                    self.spec.delay + REORDER_DELAY
+                } else {
+                    self.spec.delay
+                }
+            })
+            .collect()
+    }
+
+    fn hold(held: &mut Vec<Held>, delays: Vec<Duration>, data: &[u8], addr: SocketAddr) {
+        let now = Instant::now();
+        for delay in delays {
+            held.push(Held {
+                due: now + delay,
+                data: data.to_vec(),
+                addr,
+            });
+        }
+    }
+
+    // in the order they're due, those due at the same time in the order they came
+    fn take_due(held: &mut Vec<Held>) -> Vec<(Vec<u8>, SocketAddr)> {
+        let now = Instant::now();
+        let mut due = Vec::new();
+        let mut i = 0;
+        while i < held.len() {
+            if held[i].due <= now {
+                due.push(held.remove(i));
+            } else {
+                i += 1;
+            }
+        }
+        due.sort_by_key(|packet| packet.due);
+        due.into_iter()
+            .map(|packet| (packet.data, packet.addr))
+            .collect()
+    }
+
+    pub fn hold_inbound(&mut self, data: &[u8], addr: SocketAddr) {
+        let delays = self.delays();
+        Self::hold(&mut self.inbound, delays, data, addr);
+    }
+
+    pub fn hold_outbound(&mut self, data: &[u8], addr: SocketAddr) {
+        let delays = self.delays();
+        Self::hold(&mut self.outbound, delays, data, addr);
+    }
+
+    pub fn take_inbound(&mut self) -> Vec<(Vec<u8>, SocketAddr)> {
+        Self::take_due(&mut self.inbound)
+    }
+
+    pub fn take_outbound(&mut self) -> Vec<(Vec<u8>, SocketAddr)> {
+        Self::take_due(&mut self.outbound)
+    }
+
+    // when the next held packet is due
+    pub fn timeout(&self) -> Option<Duration> {
+        let now = Instant::now();
+        self.inbound
+            .iter()
+            .chain(self.outbound.iter())
+            .map(|packet| packet.due.saturating_duration_since(now))
+            .min()
+    }
+}
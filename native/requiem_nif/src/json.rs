@@ -0,0 +1,208 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+
+use rustler::types::binary::{Binary, OwnedBinary};
+use rustler::{Atom, Env, NifResult, Term};
+
+use crate::telemetry::Counters;
+use crate::{registry, stats, telemetry, version};
+
+// renders the modules, with their configs, live connections and counters, the totals
+// of all of them and the native memory as a single JSON document, for the monitoring
+// agents and the support bundles. the configs are passed by elixir, which keeps them,
+// and the values which JSON can't hold are rendered as null.
+#[rustler::nif]
+pub fn json_render<'a>(
+    env: Env<'a>,
+    configs: Vec<(Atom, Vec<(Atom, Term<'a>)>)>,
+) -> NifResult<Binary<'a>> {
+    let counters: HashMap<String, _> = telemetry::modules().into_iter().collect();
+    let configs = configs
+        .into_iter()
+        .map(|(module, config)| Ok((atom_name(env, module)?, config)))
+        .collect::<NifResult<HashMap<_, _>>>()?;
+
+    // the modules which have a config, have had connections or have a registry
+    let mut names: BTreeSet<String> = counters.keys().cloned().collect();
+    names.extend(configs.keys().cloned());
+    names.extend(registry::modules().into_iter().map(|(name, _)| name));
+
+    let empty = Counters::default();
+    let mut total_connections = 0;
+    let mut totals = empty.snapshot();
+    let mut total_drops = empty.drops();
+    let mut modules = Vec::new();
+    for name in &names {
+        let module_counters = counters.get(name).map_or(&empty, |counters| &**counters);
+        let snapshot = module_counters.snapshot();
+        let drops = module_counters.drops();
+        let connections = registry::connections(name);
+        sum(&mut totals, &snapshot);
+        sum(&mut total_drops, &drops);
+        total_connections += connections;
+
+        let mut module = String::new();
+        module.push('{');
+        key(&mut module, "module");
+        string(&mut module, name.strip_prefix("Elixir.").unwrap_or(name));
+        module.push(',');
+        key(&mut module, "connections");
+        let _ = write!(module, "{},", connections);
+        key(&mut module, "counters");
+        counters_object(env, &mut module, &snapshot)?;
+        module.push(',');
+        key(&mut module, "packet_drops");
+        counters_object(env, &mut module, &drops)?;
+        module.push(',');
+        key(&mut module, "config");
+        module.push('{');
+        for (i, (config_key, config_value)) in configs.get(name).into_iter().flatten().enumerate() {
+            if i > 0 {
+                module.push(',');
+            }
+            key(&mut module, &atom_name(env, *config_key)?);
+            value(&mut module, *config_value);
+        }
+        module.push_str("}}");
+        modules.push(module);
+    }
+
+    let mut out = String::new();
+    out.push('{');
+    key(&mut out, "nif");
+    string(&mut out, env!("CARGO_PKG_VERSION"));
+    out.push(',');
+    key(&mut out, "quiche");
+    string(&mut out, version::QUICHE_VERSION);
+    out.push(',');
+    key(&mut out, "memory");
+    counters_object(env, &mut out, &stats::memory())?;
+    out.push(',');
+    key(&mut out, "totals");
+    let _ = write!(
+        out,
+        "{{\"modules\":{},\"connections\":{},",
+        names.len(),
+        total_connections
+    );
+    key(&mut out, "counters");
+    counters_object(env, &mut out, &totals)?;
+    out.push(',');
+    key(&mut out, "packet_drops");
+    counters_object(env, &mut out, &total_drops)?;
+    out.push_str("},");
+    key(&mut out, "modules");
+    let _ = write!(out, "[{}]}}", modules.join(","));
+
+    let mut bin = OwnedBinary::new(out.len()).unwrap();
+    bin.as_mut_slice().copy_from_slice(out.as_bytes());
+    Ok(bin.release(env))
+}
+
+// the counters of snapshot() and drops() are in the same order for every module
+fn sum(totals: &mut [(Atom, u64)], values: &[(Atom, u64)]) {
+    for (total, (_, value)) in totals.iter_mut().zip(values) {
+        total.1 += value;
+    }
+}
+
+fn counters_object(env: Env, out: &mut String, values: &[(Atom, u64)]) -> NifResult<()> {
+    out.push('{');
+    for (i, (name, count)) in values.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        key(out, &atom_name(env, *name)?);
+        let _ = write!(out, "{}", count);
+    }
+    out.push('}');
+    Ok(())
+}
+
+// booleans, numbers, strings and lists of them, null for anything else
+fn value(out: &mut String, term: Term) {
+    if let Ok(b) = term.decode::<bool>() {
+        out.push_str(if b { "true" } else { "false" });
+    } else if let Ok(i) = term.decode::<i64>() {
+        let _ = write!(out, "{}", i);
+    } else if let Ok(u) = term.decode::<u64>() {
+        let _ = write!(out, "{}", u);
+    } else if let Ok(f) = term.decode::<f64>() {
+        if f.is_finite() {
+            let _ = write!(out, "{}", f);
+        } else {
+            out.push_str("null");
+        }
+    } else if let Ok(s) = term.decode::<String>() {
+        string(out, &s);
+    } else if let Ok(list) = term.decode::<Vec<Term>>() {
+        out.push('[');
+        for (i, item) in list.into_iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            value(out, item);
+        }
+        out.push(']');
+    } else {
+        out.push_str("null");
+    }
+}
+
+fn key(out: &mut String, name: &str) {
+    string(out, name);
+    out.push(':');
+}
+
+fn string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn atom_name(env: Env, atom: Atom) -> NifResult<String> {
+    atom.to_term(env).atom_to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(s: &str) -> String {
+        let mut out = String::new();
+        string(&mut out, s);
+        out
+    }
+
+    #[test]
+    fn string_as_is() {
+        assert_eq!(render(""), r#""""#);
+        assert_eq!(render("requiem/0.1 é"), r#""requiem/0.1 é""#);
+    }
+
+    #[test]
+    fn string_escapes() {
+        assert_eq!(render(r#"a"b\c"#), r#""a\"b\\c""#);
+        assert_eq!(render("a\nb\rc\td"), r#""a\nb\rc\td""#);
+        assert_eq!(render("\u{0}\u{1f}\u{7f}"), "\"\\u0000\\u001f\u{7f}\"");
+    }
+
+    #[test]
+    fn key() {
+        let mut out = String::new();
+        super::key(&mut out, "name");
+        assert_eq!(out, r#""name":"#);
+    }
+}
@@ -3,13 +3,40 @@ use rustler::{Env, Term};
 #[macro_use]
 extern crate log;
 
-use simplelog::{Config, SimpleLogger};
-
+mod alloc;
+mod batch;
 mod common;
 mod config;
+mod congestion;
 mod connection;
+mod control;
+mod datapath;
+mod diagnostics;
+mod framing;
+#[cfg(not(feature = "fuzzing"))]
+mod fuzz;
+#[cfg(feature = "fuzzing")]
+pub mod fuzz;
+mod handoff;
+mod impairment;
+mod json;
+mod loadgen;
+mod logger;
 mod packet;
+mod prometheus;
+mod qlog;
+mod registry;
+mod sampling;
 mod socket;
+mod stats;
+mod statsd;
+mod subscribers;
+mod tap;
+mod telemetry;
+mod timer;
+mod timestamp;
+mod trace_context;
+mod version;
 
 rustler::init!(
     "Elixir.Requiem.NIF.Bridge",
@@ -43,16 +70,118 @@ rustler::init!(
         packet::packet_builder_build_negotiate_version,
         packet::packet_builder_build_retry,
         connection::connection_accept,
+        connection::connection_count_refused,
         connection::connection_destroy,
         connection::connection_open_stream,
         connection::connection_accept_connect_request,
+        connection::connection_set_owner,
+        connection::connection_set_subscriber,
+        connection::connection_stream_qlog,
+        connection::connection_qlog_to_file,
+        connection::connection_qlog_disable,
         connection::connection_reject_connect_request,
         connection::connection_close,
         connection::connection_is_closed,
+        connection::connection_close_info,
+        connection::connection_handoff_state,
+        connection::connection_peer_cert,
+        connection::connection_set_trace_context,
+        connection::connection_stream_stats,
+        connection::connection_enable_congestion_events,
+        connection::connection_enable_pmtu_events,
+        connection::connection_enable_event_timestamps,
+        connection::connection_enable_stream_close_events,
+        connection::connection_enable_sampling,
+        connection::connection_samples,
+        connection::connection_set_stream_recv_window,
+        connection::connection_set_stream_framing,
+        connection::connection_set_impairment,
+        connection::connection_set_stream_pull_mode,
+        connection::connection_stream_read,
+        connection::connection_h3_recv_body,
+        connection::connection_set_dgram_pull_mode,
+        connection::connection_set_dgram_budget,
+        connection::connection_set_keepalive,
+        connection::connection_set_lifetime_policy,
+        connection::connection_dgram_peek,
+        connection::connection_dgram_recv,
+        connection::connection_probe_path,
+        connection::connection_set_send_deadline,
+        connection::connection_set_stream_priority,
+        connection::connection_set_send_order,
+        connection::connection_stream_counts,
+        connection::connection_pmtu,
+        connection::connection_readable,
+        connection::connection_writable,
+        connection::connection_stream_ack,
+        connection::connection_tap_pid,
+        connection::connection_tap_pcap,
+        connection::connection_untap,
+        connection::connection_set_request_limits,
+        connection::connection_stream_buffer_resize,
+        connection::connection_set_stream_buffer_range,
         connection::connection_on_packet,
+        connection::connection_on_packet_sync,
         connection::connection_on_timeout,
         connection::connection_stream_send,
+        connection::connection_stream_send_sync,
+        connection::connection_stream_send_vectored,
+        connection::connection_stream_send_full,
         connection::connection_dgram_send,
+        connection::connection_dgram_send_batch,
+        connection::connection_h3_dgram_send,
+        datapath::datapath_start,
+        datapath::datapath_stop,
+        datapath::datapath_set_owner,
+        datapath::datapath_set_subscriber,
+        datapath::datapath_stream_qlog,
+        datapath::datapath_qlog_to_file,
+        datapath::datapath_qlog_disable,
+        datapath::datapath_enable_congestion_events,
+        datapath::datapath_enable_pmtu_events,
+        datapath::datapath_enable_event_timestamps,
+        datapath::datapath_enable_stream_close_events,
+        datapath::datapath_enable_sampling,
+        datapath::datapath_samples,
+        datapath::datapath_set_stream_recv_window,
+        datapath::datapath_set_stream_framing,
+        datapath::datapath_set_impairment,
+        datapath::datapath_set_stream_pull_mode,
+        datapath::datapath_stream_read,
+        datapath::datapath_h3_recv_body,
+        datapath::datapath_set_dgram_pull_mode,
+        datapath::datapath_set_dgram_budget,
+        datapath::datapath_set_keepalive,
+        datapath::datapath_set_lifetime_policy,
+        datapath::datapath_dgram_peek,
+        datapath::datapath_dgram_recv,
+        datapath::datapath_probe_path,
+        datapath::datapath_set_send_deadline,
+        datapath::datapath_set_stream_priority,
+        datapath::datapath_set_send_order,
+        datapath::datapath_stream_counts,
+        datapath::datapath_pmtu,
+        datapath::datapath_readable,
+        datapath::datapath_writable,
+        datapath::datapath_stream_ack,
+        datapath::datapath_set_trace_context,
+        datapath::datapath_tap_pid,
+        datapath::datapath_tap_pcap,
+        datapath::datapath_untap,
+        datapath::datapath_accept_connect_request,
+        datapath::datapath_reject_connect_request,
+        datapath::datapath_open_stream,
+        datapath::datapath_stream_stats,
+        datapath::datapath_handoff_state,
+        datapath::datapath_peer_cert,
+        datapath::datapath_stream_send,
+        datapath::datapath_stream_send_vectored,
+        datapath::datapath_stream_send_full,
+        datapath::datapath_dgram_send,
+        datapath::datapath_dgram_send_batch,
+        datapath::datapath_h3_dgram_send,
+        datapath::datapath_close,
+        datapath::datapath_forget,
         socket::cpu_num,
         socket::socket_sender_get,
         socket::socket_sender_send,
@@ -62,6 +191,28 @@ rustler::init!(
         socket::socket_destroy,
         socket::socket_address_parts,
         socket::socket_address_from_string,
+        stats::memory_stats,
+        alloc::allocator_stats,
+        diagnostics::diagnostics_enable,
+        diagnostics::diagnostics,
+        diagnostics::diagnostics_watch_slow_calls,
+        diagnostics::diagnostics_unwatch_slow_calls,
+        logger::logger_forward,
+        logger::logger_stop_forwarding,
+        telemetry::telemetry_snapshot,
+        telemetry::telemetry_drops,
+        registry::module_shutdown,
+        registry::module_connections,
+        version::native_versions,
+        timestamp::native_monotonic_time,
+        prometheus::prometheus_render,
+        json::json_render,
+        statsd::statsd_start,
+        statsd::statsd_stop,
+        control::control_socket_open,
+        control::control_socket_close,
+        loadgen::loadgen_run,
+        fuzz::fuzz_input,
     ],
     load = load
 );
@@ -77,7 +228,8 @@ fn load(env: Env, trace: Term) -> bool {
         }
         Err(_) => log::LevelFilter::Error,
     };
-    SimpleLogger::init(log_level, Config::default()).unwrap();
+    logger::init(log_level);
     socket::on_load(env);
+    datapath::on_load(env);
     true
 }
@@ -0,0 +1,463 @@
+use rustler::{Atom, NifResult};
+
+use crate::common::{self, atoms};
+
+// what a load generation run is asked to do
+pub struct LoadSpec {
+    pub address: String,
+    pub server_name: String,
+    // the path of the WebTransport session each connection opens
+    pub path: String,
+    pub connections: usize,
+    // bidi streams opened in each session, each sending `message_size` bytes
+    pub streams: usize,
+    pub message_size: usize,
+    // datagrams sent per second in each session, at most MAX_DGRAM_RATE
+    pub dgram_rate: u64,
+    pub duration_ms: u64,
+}
+
+// above it, the interval between the datagrams of a session would round down to 0
+pub const MAX_DGRAM_RATE: u64 = 1_000_000;
+
+// {connected, failed, handshake latencies {p50, p90, p99} in microseconds,
+//  sessions established,
+//  {streams replied to, stream round trips {p50, p90, p99} in microseconds},
+//  {datagrams sent, datagrams echoed, datagram round trips {p50, p90, p99}},
+//  {bytes sent, elapsed milliseconds}}
+pub type ReportTuple = (
+    usize,
+    usize,
+    (u64, u64, u64),
+    usize,
+    (usize, (u64, u64, u64)),
+    (u64, u64, (u64, u64, u64)),
+    (u64, u64),
+);
+
+#[cfg(feature = "loadgen")]
+mod client {
+    use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+    use std::pin::Pin;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use quiche::h3::{self, NameValue};
+    use ring::rand::{SecureRandom, SystemRandom};
+    use rustler::Atom;
+
+    use super::{LoadSpec, ReportTuple};
+    use crate::common::{self, atoms};
+
+    const MAX_DATAGRAM_SIZE: usize = 1350;
+    const MAX_DGRAM_PAYLOAD: usize = 1024;
+    // the signal which starts a bidi stream of a WebTransport session
+    const WEBTRANSPORT_STREAM: u64 = 0x41;
+
+    enum Session {
+        // the connection isn't established yet
+        Connecting,
+        // the extended CONNECT is sent on this stream, the response hasn't come
+        Requested(u64),
+        Established(u64),
+        Refused,
+    }
+
+    struct Stream {
+        id: u64,
+        // the stream header and the message
+        data: Vec<u8>,
+        written: usize,
+        opened: Instant,
+        replied: bool,
+    }
+
+    struct Client {
+        conn: Pin<Box<quiche::Connection>>,
+        socket: UdpSocket,
+        h3: Option<h3::Connection>,
+        started: Instant,
+        handshake: Option<Duration>,
+        session: Session,
+        streams: Vec<Stream>,
+        next_dgram: Instant,
+    }
+
+    #[derive(Default)]
+    struct Report {
+        connected: usize,
+        failed: usize,
+        handshakes: Vec<u64>,
+        sessions: usize,
+        streams: Vec<u64>,
+        bytes_sent: u64,
+        dgrams_sent: u64,
+        dgrams: Vec<u64>,
+    }
+
+    fn percentiles(mut samples: Vec<u64>) -> (u64, u64, u64) {
+        if samples.is_empty() {
+            return (0, 0, 0);
+        }
+        samples.sort_unstable();
+        let at = |p: usize| samples[(samples.len() - 1) * p / 100];
+        (at(50), at(90), at(99))
+    }
+
+    fn config() -> Result<quiche::Config, quiche::Error> {
+        let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+        config.set_application_protos(h3::APPLICATION_PROTOCOL)?;
+        // the module under test usually has a self-signed certificate
+        config.verify_peer(false);
+        config.set_max_idle_timeout(30_000);
+        config.set_max_recv_udp_payload_size(MAX_DATAGRAM_SIZE);
+        config.set_max_send_udp_payload_size(MAX_DATAGRAM_SIZE);
+        config.set_initial_max_data(100_000_000);
+        config.set_initial_max_stream_data_bidi_local(10_000_000);
+        config.set_initial_max_stream_data_bidi_remote(10_000_000);
+        config.set_initial_max_stream_data_uni(10_000_000);
+        config.set_initial_max_streams_bidi(1_000);
+        config.set_initial_max_streams_uni(1_000);
+        config.enable_dgram(true, 1_000, 1_000);
+        Ok(config)
+    }
+
+    fn connect(
+        spec: &LoadSpec,
+        peer: SocketAddr,
+        config: &mut quiche::Config,
+    ) -> Result<Client, Atom> {
+        let local: SocketAddr = if peer.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        let socket = UdpSocket::bind(local).map_err(|_| atoms::cant_bind())?;
+        socket
+            .set_nonblocking(true)
+            .map_err(|_| atoms::socket_error())?;
+        let mut scid = [0; quiche::MAX_CONN_ID_LEN];
+        SystemRandom::new()
+            .fill(&mut scid)
+            .map_err(|_| atoms::system_error())?;
+        let scid = quiche::ConnectionId::from_ref(&scid);
+        let conn = quiche::connect(Some(&spec.server_name), &scid, peer, config)
+            .map_err(|e| common::quiche_error_atom(&e))?;
+        let now = Instant::now();
+        Ok(Client {
+            conn,
+            socket,
+            h3: None,
+            started: now,
+            handshake: None,
+            session: Session::Connecting,
+            streams: Vec::new(),
+            next_dgram: now,
+        })
+    }
+
+    impl Client {
+        fn recv(&mut self, buf: &mut [u8]) {
+            while let Ok((len, from)) = self.socket.recv_from(buf) {
+                let _ = self.conn.recv(&mut buf[..len], quiche::RecvInfo { from });
+            }
+            if self.conn.timeout() == Some(Duration::ZERO) {
+                self.conn.on_timeout();
+            }
+        }
+
+        fn flush(&mut self, out: &mut [u8]) {
+            while let Ok((len, info)) = self.conn.send(out) {
+                let _ = self.socket.send_to(&out[..len], info.to);
+            }
+        }
+
+        // the bidi streams other than the session's are WebTransport streams, ours or
+        // the module's, and are read here before h3 sees them, as it doesn't know them.
+        // the first bytes back on one of ours end its round trip.
+        fn read_streams(&mut self, buf: &mut [u8], report: &mut Report) {
+            let session_id = match self.session {
+                Session::Established(session_id) => session_id,
+                _ => return,
+            };
+            let readable: Vec<u64> = self
+                .conn
+                .readable()
+                .filter(|id| id & 0x2 == 0 && *id != session_id)
+                .collect();
+            for stream_id in readable {
+                let mut received = 0;
+                while let Ok((len, _fin)) = self.conn.stream_recv(stream_id, buf) {
+                    received += len;
+                }
+                if received == 0 {
+                    continue;
+                }
+                if let Some(stream) = self
+                    .streams
+                    .iter_mut()
+                    .find(|stream| stream.id == stream_id && !stream.replied)
+                {
+                    stream.replied = true;
+                    report
+                        .streams
+                        .push(stream.opened.elapsed().as_micros() as u64);
+                }
+            }
+        }
+
+        // the datagrams carry the time they were sent at, which the module echoes
+        fn read_dgrams(&mut self, buf: &mut [u8], report: &mut Report) {
+            while let Ok(len) = self.conn.dgram_recv(buf) {
+                let payload = match common::decode_varint(&buf[..len]) {
+                    Some((_, prefix)) => &buf[prefix..len],
+                    None => continue,
+                };
+                if payload.len() < 8 {
+                    continue;
+                }
+                let mut sent = [0; 8];
+                sent.copy_from_slice(&payload[..8]);
+                let now = self.started.elapsed().as_micros() as u64;
+                report
+                    .dgrams
+                    .push(now.saturating_sub(u64::from_be_bytes(sent)));
+            }
+        }
+
+        fn drive(&mut self, spec: &LoadSpec, body: &[u8], buf: &mut [u8], report: &mut Report) {
+            if !self.conn.is_established() {
+                return;
+            }
+            if self.handshake.is_none() {
+                let latency = self.started.elapsed();
+                self.handshake = Some(latency);
+                report.connected += 1;
+                report.handshakes.push(latency.as_micros() as u64);
+            }
+            if self.h3.is_none() {
+                let h3_config = match h3::Config::new() {
+                    Ok(h3_config) => h3_config,
+                    Err(_) => return,
+                };
+                match h3::Connection::with_transport(&mut self.conn, &h3_config) {
+                    Ok(h3) => self.h3 = Some(h3),
+                    Err(_) => return,
+                }
+            }
+            self.read_streams(buf, report);
+            self.read_dgrams(buf, report);
+
+            let h3 = self.h3.as_mut().unwrap();
+            let mut opened = false;
+            if let Session::Connecting = self.session {
+                let headers = [
+                    h3::Header::new(b":method", b"CONNECT"),
+                    h3::Header::new(b":protocol", b"webtransport"),
+                    h3::Header::new(b":scheme", b"https"),
+                    h3::Header::new(b":authority", spec.server_name.as_bytes()),
+                    h3::Header::new(b":path", spec.path.as_bytes()),
+                    h3::Header::new(b"origin", spec.server_name.as_bytes()),
+                ];
+                if let Ok(stream_id) = h3.send_request(&mut self.conn, &headers, false) {
+                    self.session = Session::Requested(stream_id);
+                }
+            }
+            loop {
+                match h3.poll(&mut self.conn) {
+                    Ok((stream_id, h3::Event::Headers { list, .. })) => {
+                        if !matches!(self.session, Session::Requested(id) if id == stream_id) {
+                            continue;
+                        }
+                        let ok = list.iter().any(|header| {
+                            header.name() == b":status" && header.value().starts_with(b"2")
+                        });
+                        if ok {
+                            report.sessions += 1;
+                            self.session = Session::Established(stream_id);
+                            opened = true;
+                        } else {
+                            self.session = Session::Refused;
+                            let _ = self.conn.close(true, 0x100, b"session refused");
+                        }
+                    }
+                    Ok((stream_id, h3::Event::Data)) => {
+                        while let Ok(read) = h3.recv_body(&mut self.conn, stream_id, buf) {
+                            if read == 0 {
+                                break;
+                            }
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(_) => break,
+                }
+            }
+            if opened {
+                self.open_streams(spec, body);
+            }
+
+            let session_id = match self.session {
+                Session::Established(session_id) => session_id,
+                _ => return,
+            };
+            for stream in self.streams.iter_mut() {
+                if stream.written == stream.data.len() {
+                    continue;
+                }
+                if let Ok(written) =
+                    self.conn
+                        .stream_send(stream.id, &stream.data[stream.written..], true)
+                {
+                    stream.written += written;
+                    report.bytes_sent += written as u64;
+                }
+            }
+
+            if spec.dgram_rate > 0 {
+                let interval = Duration::from_nanos(1_000_000_000 / spec.dgram_rate);
+                let mut dgram = Vec::with_capacity(MAX_DGRAM_PAYLOAD + 16);
+                while self.next_dgram <= Instant::now() {
+                    // the quarter stream id of the session, then the time it's sent at
+                    dgram.clear();
+                    let _ = common::encode_varint(session_id / 4, &mut dgram);
+                    let now = self.started.elapsed().as_micros() as u64;
+                    dgram.extend_from_slice(&now.to_be_bytes());
+                    dgram.extend_from_slice(&body[..body.len().min(MAX_DGRAM_PAYLOAD) - 8]);
+                    if self.conn.dgram_send(&dgram).is_ok() {
+                        report.dgrams_sent += 1;
+                    }
+                    self.next_dgram += interval;
+                }
+            }
+        }
+
+        // the streams start with the signal and the session id, then the message
+        fn open_streams(&mut self, spec: &LoadSpec, body: &[u8]) {
+            let session_id = match self.session {
+                Session::Established(session_id) => session_id,
+                _ => return,
+            };
+            let now = Instant::now();
+            for i in 0..spec.streams {
+                let mut data = Vec::with_capacity(spec.message_size + 16);
+                let _ = common::encode_varint(WEBTRANSPORT_STREAM, &mut data);
+                let _ = common::encode_varint(session_id, &mut data);
+                data.extend_from_slice(&body[..spec.message_size]);
+                self.streams.push(Stream {
+                    id: session_id + 4 * (i as u64 + 1),
+                    data,
+                    written: 0,
+                    opened: now,
+                    replied: false,
+                });
+            }
+        }
+    }
+
+    pub fn run(spec: &LoadSpec) -> Result<ReportTuple, Atom> {
+        let peer = spec
+            .address
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .ok_or_else(atoms::bad_format)?;
+        let mut config = config().map_err(|e| common::quiche_error_atom(&e))?;
+        let body = vec![0x61; spec.message_size.max(MAX_DGRAM_PAYLOAD)];
+
+        let started = Instant::now();
+        let deadline = started + Duration::from_millis(spec.duration_ms);
+        let mut report = Report::default();
+        let mut clients = Vec::with_capacity(spec.connections);
+        for _ in 0..spec.connections {
+            match connect(spec, peer, &mut config) {
+                Ok(client) => clients.push(client),
+                Err(_) => report.failed += 1,
+            }
+        }
+
+        let mut buf = vec![0; 65535];
+        let mut out = vec![0; MAX_DATAGRAM_SIZE];
+        while Instant::now() < deadline && !clients.is_empty() {
+            for client in clients.iter_mut() {
+                client.recv(&mut buf);
+                client.drive(spec, &body, &mut buf, &mut report);
+                client.flush(&mut out);
+            }
+            clients.retain(|client| {
+                if client.conn.is_closed() {
+                    if client.handshake.is_none() {
+                        report.failed += 1;
+                    }
+                    false
+                } else {
+                    true
+                }
+            });
+            thread::sleep(Duration::from_millis(1));
+        }
+        for client in clients.iter_mut() {
+            if client.handshake.is_none() {
+                report.failed += 1;
+            }
+            let _ = client.conn.close(true, 0x100, b"loadgen done");
+            client.flush(&mut out);
+        }
+
+        Ok((
+            report.connected,
+            report.failed,
+            percentiles(report.handshakes),
+            report.sessions,
+            (report.streams.len(), percentiles(report.streams)),
+            (
+                report.dgrams_sent,
+                report.dgrams.len() as u64,
+                percentiles(report.dgrams),
+            ),
+            (report.bytes_sent, started.elapsed().as_millis() as u64),
+        ))
+    }
+}
+
+#[cfg(feature = "loadgen")]
+fn run(spec: &LoadSpec) -> Result<ReportTuple, Atom> {
+    client::run(spec)
+}
+
+#[cfg(not(feature = "loadgen"))]
+fn run(_spec: &LoadSpec) -> Result<ReportTuple, Atom> {
+    Err(atoms::not_supported())
+}
+
+// drives in-process client connections, each with a WebTransport session, against a
+// listening module for `duration_ms`, on a dirty scheduler which it holds until then.
+// built only with the loadgen feature.
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn loadgen_run(
+    address: String,
+    server_name: String,
+    path: String,
+    connections: usize,
+    streams: usize,
+    message_size: usize,
+    dgram_rate: u64,
+    duration_ms: u64,
+) -> NifResult<(Atom, ReportTuple)> {
+    if dgram_rate > MAX_DGRAM_RATE {
+        return Err(common::error_term(atoms::bad_format()));
+    }
+    let spec = LoadSpec {
+        address,
+        server_name,
+        path,
+        connections,
+        streams,
+        message_size,
+        dgram_rate,
+        duration_ms,
+    };
+    match run(&spec) {
+        Ok(report) => Ok((atoms::ok(), report)),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
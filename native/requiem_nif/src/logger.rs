@@ -0,0 +1,117 @@
+use std::sync::Mutex;
+
+use crossbeam_channel::Sender;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use rustler::types::tuple::make_tuple;
+use rustler::types::{Encoder, LocalPid};
+use rustler::{Atom, Env, NifResult, Term};
+use simplelog::{Config, SimpleLogger};
+
+use crate::common::{self, atoms};
+
+// records waiting for the forwarder thread, dropped beyond that
+const QUEUE_SIZE: usize = 1024;
+
+struct LogRecord {
+    level: Level,
+    target: String,
+    message: String,
+}
+
+// set while an elixir process receives the records
+static FORWARDER: Mutex<Option<Sender<LogRecord>>> = Mutex::new(None);
+
+// passes the records to the elixir process when one is registered, to stderr otherwise.
+// they're sent from the thread of common::spawn_sender.
+struct BridgeLogger {
+    fallback: Box<SimpleLogger>,
+}
+
+impl Log for BridgeLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if let Some(forwarder) = FORWARDER.lock().unwrap().as_ref() {
+            let record = LogRecord {
+                level: record.level(),
+                target: record.target().to_string(),
+                message: record.args().to_string(),
+            };
+            // never blocks the caller, the record is dropped when the queue is full
+            let _ = forwarder.try_send(record);
+            return;
+        }
+        self.fallback.log(record);
+    }
+
+    fn flush(&self) {}
+}
+
+pub fn init(level: LevelFilter) {
+    let logger = BridgeLogger {
+        fallback: SimpleLogger::new(level, Config::default()),
+    };
+    if log::set_boxed_logger(Box::new(logger)).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+fn level_atom(level: Level) -> Atom {
+    match level {
+        Level::Error => atoms::error(),
+        Level::Warn => atoms::warn(),
+        Level::Info => atoms::info(),
+        Level::Debug => atoms::debug(),
+        Level::Trace => atoms::trace(),
+    }
+}
+
+fn level_filter(level: Atom) -> Option<LevelFilter> {
+    if level == atoms::error() {
+        Some(LevelFilter::Error)
+    } else if level == atoms::warn() {
+        Some(LevelFilter::Warn)
+    } else if level == atoms::info() {
+        Some(LevelFilter::Info)
+    } else if level == atoms::debug() {
+        Some(LevelFilter::Debug)
+    } else if level == atoms::trace() {
+        Some(LevelFilter::Trace)
+    } else {
+        None
+    }
+}
+
+// {:__log__, level, target, message}
+fn log_term(env: Env, record: LogRecord) -> Term {
+    make_tuple(
+        env,
+        &[
+            atoms::__log__().to_term(env),
+            level_atom(record.level).to_term(env),
+            record.target.encode(env),
+            record.message.encode(env),
+        ],
+    )
+}
+
+#[rustler::nif]
+pub fn logger_forward(pid: LocalPid, level: Atom) -> NifResult<Atom> {
+    let level = level_filter(level).ok_or_else(|| common::error_term(atoms::bad_format()))?;
+    let tx = common::spawn_sender("requiem-logger", pid, QUEUE_SIZE, log_term)?;
+    // the previous forwarder thread ends with its channel
+    *FORWARDER.lock().unwrap() = Some(tx);
+    log::set_max_level(level);
+    Ok(atoms::ok())
+}
+
+#[rustler::nif]
+pub fn logger_stop_forwarding() -> NifResult<Atom> {
+    *FORWARDER.lock().unwrap() = None;
+    Ok(atoms::ok())
+}
@@ -0,0 +1,77 @@
+use std::fmt::Write;
+
+use rustler::types::binary::{Binary, OwnedBinary};
+use rustler::{Atom, Env, NifResult};
+
+use crate::{registry, stats, telemetry};
+
+// renders the counters of all the modules and the native memory gauges
+// in the Prometheus text format (version 0.0.4)
+#[rustler::nif]
+pub fn prometheus_render(env: Env) -> NifResult<Binary> {
+    let modules = telemetry::modules();
+    let mut out = String::new();
+
+    // the counters are in the same order for every module
+    let snapshots: Vec<_> = modules
+        .iter()
+        .map(|(name, counters)| (label(name), counters.snapshot(), counters.drops()))
+        .collect();
+    if let Some((_, first, _)) = snapshots.first() {
+        for (i, (counter, _)) in first.iter().enumerate() {
+            let metric = format!("requiem_{}_total", atom_name(env, *counter)?);
+            header(&mut out, &metric, "counter");
+            for (module, snapshot, _) in &snapshots {
+                let _ = writeln!(out, "{}{{module=\"{}\"}} {}", metric, module, snapshot[i].1);
+            }
+        }
+        header(&mut out, "requiem_packet_drops_total", "counter");
+        for (module, _, drops) in &snapshots {
+            for (reason, value) in drops {
+                let _ = writeln!(
+                    out,
+                    "requiem_packet_drops_total{{module=\"{}\",reason=\"{}\"}} {}",
+                    module,
+                    atom_name(env, *reason)?,
+                    value
+                );
+            }
+        }
+        header(&mut out, "requiem_live_connections", "gauge");
+        for (name, _) in &modules {
+            let _ = writeln!(
+                out,
+                "requiem_live_connections{{module=\"{}\"}} {}",
+                label(name),
+                registry::connections(name)
+            );
+        }
+    }
+
+    for (gauge, value) in stats::memory() {
+        let metric = format!("requiem_memory_{}", atom_name(env, gauge)?);
+        header(&mut out, &metric, "gauge");
+        let _ = writeln!(out, "{} {}", metric, value);
+    }
+
+    let mut bin = OwnedBinary::new(out.len()).unwrap();
+    bin.as_mut_slice().copy_from_slice(out.as_bytes());
+    Ok(bin.release(env))
+}
+
+fn header(out: &mut String, metric: &str, ty: &str) {
+    let _ = writeln!(out, "# TYPE {} {}", metric, ty);
+}
+
+fn atom_name(env: Env, atom: Atom) -> NifResult<String> {
+    atom.to_term(env).atom_to_string()
+}
+
+// "Elixir.MyApp.Handler" as "MyApp.Handler", escaped for a label value
+fn label(module: &str) -> String {
+    module
+        .strip_prefix("Elixir.")
+        .unwrap_or(module)
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+}
@@ -0,0 +1,352 @@
+#[cfg(feature = "qlog")]
+use std::collections::{HashMap, VecDeque};
+#[cfg(feature = "qlog")]
+use std::fs::{self, File};
+#[cfg(feature = "qlog")]
+use std::io::BufWriter;
+use std::io::{self, Write};
+#[cfg(feature = "qlog")]
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "qlog")]
+use flate2::write::GzEncoder;
+#[cfg(feature = "qlog")]
+use flate2::Compression;
+
+use rustler::types::binary::OwnedBinary;
+use rustler::types::tuple::make_tuple;
+use rustler::types::{Encoder, LocalPid};
+
+use crate::common::{atoms, Emitter};
+
+// JSON-seq (RFC 7464), each record starts with RS and ends with LF
+const RECORD_SEPARATOR: u8 = 0x1e;
+
+// qlog output of quiche, collected until the connection passes it to the subscriber
+#[derive(Clone, Default)]
+pub struct QlogBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for QlogBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl QlogBuffer {
+    // the complete records written so far, without RS and LF
+    fn take_records(&self) -> Vec<Vec<u8>> {
+        let mut buf = self.0.lock().unwrap();
+        let end = match buf.iter().rposition(|b| *b == b'\n') {
+            Some(pos) => pos + 1,
+            None => return Vec::new(),
+        };
+        let complete: Vec<u8> = buf.drain(..end).collect();
+        complete
+            .split(|b| *b == RECORD_SEPARATOR)
+            .map(|record| record.strip_suffix(b"\n").unwrap_or(record))
+            .filter(|record| !record.is_empty())
+            .map(|record| record.to_vec())
+            .collect()
+    }
+}
+
+pub struct QlogStream {
+    pid: LocalPid,
+    buffer: QlogBuffer,
+}
+
+impl QlogStream {
+    #[cfg(feature = "qlog")]
+    pub fn new(pid: LocalPid) -> Self {
+        Self {
+            pid,
+            buffer: QlogBuffer::default(),
+        }
+    }
+
+    #[cfg(feature = "qlog")]
+    pub fn writer(&self) -> Box<dyn Write + Send + Sync> {
+        Box::new(self.buffer.clone())
+    }
+
+    // passes each record to the subscriber as `{:qlog, trace_id, record}`
+    pub fn emit<E: Emitter>(&self, emitter: &mut E, trace_id: &str) {
+        for record in self.buffer.take_records() {
+            let mut bin = OwnedBinary::new(record.len()).unwrap();
+            bin.as_mut_slice().copy_from_slice(&record);
+            let trace_id = trace_id.to_string();
+            emitter.emit(&self.pid, move |env| {
+                make_tuple(
+                    env,
+                    &[
+                        atoms::qlog().to_term(env),
+                        trace_id.encode(env),
+                        bin.release(env).to_term(env),
+                    ],
+                )
+            });
+        }
+    }
+}
+
+#[cfg(feature = "qlog")]
+#[derive(Default)]
+struct Sinks {
+    sinks: Vec<Box<dyn Write + Send + Sync>>,
+    // the first record, written by quiche once when qlog is set
+    header: Vec<u8>,
+    header_done: bool,
+}
+
+// quiche takes a single writer, which passes the output to all the sinks of the connection.
+// the sinks come and go while quiche keeps writing, each added one gets the header first.
+#[cfg(feature = "qlog")]
+#[derive(Clone, Default)]
+pub struct QlogSinks(Arc<Mutex<Sinks>>);
+
+#[cfg(feature = "qlog")]
+impl QlogSinks {
+    pub fn add(&self, mut sink: Box<dyn Write + Send + Sync>) {
+        let mut sinks = self.0.lock().unwrap();
+        if !sinks.header.is_empty() {
+            let _ = sink.write_all(&sinks.header);
+        }
+        sinks.sinks.push(sink);
+    }
+
+    // drops the sinks, which closes their files
+    pub fn clear(&self) {
+        self.0.lock().unwrap().sinks.clear();
+    }
+}
+
+#[cfg(feature = "qlog")]
+impl Write for QlogSinks {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut sinks = self.0.lock().unwrap();
+        if !sinks.header_done {
+            sinks.header.extend_from_slice(buf);
+            sinks.header_done = buf.contains(&b'\n');
+        }
+        for sink in sinks.sinks.iter_mut() {
+            // a failing sink must not stop the others
+            let _ = sink.write_all(buf);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for sink in self.0.lock().unwrap().sinks.iter_mut() {
+            let _ = sink.flush();
+        }
+        Ok(())
+    }
+}
+
+#[cfg_attr(not(feature = "qlog"), allow(dead_code))]
+pub struct QlogFileOptions {
+    pub dir: PathBuf,
+    // a file is rotated once this many bytes have been written to it
+    pub max_file_size: u64,
+    // bytes kept in the directory, the oldest rotated files are removed beyond that
+    pub max_total_size: u64,
+    pub gzip: bool,
+}
+
+// disk usage of a qlog directory, shared by all the connections writing into it
+#[cfg(feature = "qlog")]
+struct QlogDir {
+    max_total_size: u64,
+    used: u64,
+    // closed files, oldest first
+    files: VecDeque<(PathBuf, u64)>,
+}
+
+#[cfg(feature = "qlog")]
+impl QlogDir {
+    // frees room for len more bytes, false when the open files alone use the budget.
+    // the rest of a record already started is always accepted, to keep the file valid.
+    fn reserve(&mut self, len: u64, in_record: bool) -> bool {
+        while self.used + len > self.max_total_size {
+            match self.files.pop_front() {
+                Some((path, size)) => {
+                    if let Err(e) = fs::remove_file(&path) {
+                        debug!("failed to remove qlog file {}: {}", path.display(), e);
+                    }
+                    self.used -= size;
+                }
+                None if in_record => break,
+                None => return false,
+            }
+        }
+        self.used += len;
+        true
+    }
+}
+
+// by path, each module being configured with its own directory
+#[cfg(feature = "qlog")]
+static DIRS: Mutex<Option<HashMap<PathBuf, Arc<Mutex<QlogDir>>>>> = Mutex::new(None);
+
+#[cfg(feature = "qlog")]
+fn qlog_dir(options: &QlogFileOptions) -> Arc<Mutex<QlogDir>> {
+    let mut dirs = DIRS.lock().unwrap();
+    let dirs = dirs.get_or_insert_with(HashMap::new);
+    let dir = dirs.entry(options.dir.clone()).or_insert_with(|| {
+        Arc::new(Mutex::new(QlogDir {
+            max_total_size: options.max_total_size,
+            used: 0,
+            files: VecDeque::new(),
+        }))
+    });
+    dir.lock().unwrap().max_total_size = options.max_total_size;
+    dir.clone()
+}
+
+#[cfg(feature = "qlog")]
+enum QlogFileSink {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+}
+
+#[cfg(feature = "qlog")]
+impl QlogFileSink {
+    fn create(path: &Path, gzip: bool) -> io::Result<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        if gzip {
+            Ok(QlogFileSink::Gzip(GzEncoder::new(
+                file,
+                Compression::default(),
+            )))
+        } else {
+            Ok(QlogFileSink::Plain(file))
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            QlogFileSink::Plain(w) => w.write_all(buf),
+            QlogFileSink::Gzip(w) => w.write_all(buf),
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self {
+            QlogFileSink::Plain(mut w) => w.flush(),
+            QlogFileSink::Gzip(w) => w.finish()?.flush(),
+        }
+    }
+}
+
+// `<trace_id>.<index>.sqlog[.gz]` files of a connection, rotated by size.
+// the budget is counted in uncompressed bytes, so gzip files use less than that.
+#[cfg(feature = "qlog")]
+pub struct QlogFile {
+    dir: Arc<Mutex<QlogDir>>,
+    path: PathBuf,
+    trace_id: String,
+    max_file_size: u64,
+    gzip: bool,
+    index: usize,
+    current: Option<(QlogFileSink, PathBuf, u64)>,
+    dropping: bool,
+}
+
+#[cfg(feature = "qlog")]
+impl QlogFile {
+    pub fn new(options: &QlogFileOptions, trace_id: &str) -> io::Result<Self> {
+        fs::create_dir_all(&options.dir)?;
+        Ok(Self {
+            dir: qlog_dir(options),
+            path: options.dir.clone(),
+            trace_id: trace_id.to_string(),
+            max_file_size: options.max_file_size,
+            gzip: options.gzip,
+            index: 0,
+            current: None,
+            dropping: false,
+        })
+    }
+
+    fn open(&mut self) -> io::Result<()> {
+        let ext = if self.gzip { "sqlog.gz" } else { "sqlog" };
+        let path = self
+            .path
+            .join(format!("{}.{}.{}", self.trace_id, self.index, ext));
+        self.index += 1;
+        self.current = Some((QlogFileSink::create(&path, self.gzip)?, path, 0));
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        if let Some((sink, path, written)) = self.current.take() {
+            if let Err(e) = sink.finish() {
+                debug!("failed to close qlog file {}: {}", path.display(), e);
+            }
+            self.dir.lock().unwrap().files.push_back((path, written));
+        }
+    }
+}
+
+#[cfg(feature = "qlog")]
+impl Write for QlogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // quiche writes RS on its own at the start of each record, which is
+        // where the file is rotated or the record dropped, so that each file is valid JSON-seq
+        let record_start = buf.first() == Some(&RECORD_SEPARATOR);
+        if record_start {
+            if let Some((_, _, written)) = &self.current {
+                if *written >= self.max_file_size {
+                    self.close();
+                }
+            }
+        } else if self.dropping {
+            return Ok(buf.len());
+        }
+        if !self
+            .dir
+            .lock()
+            .unwrap()
+            .reserve(buf.len() as u64, !record_start)
+        {
+            if !self.dropping {
+                debug!(
+                    "qlog directory is full, dropping the events of {}",
+                    self.trace_id
+                );
+            }
+            self.dropping = true;
+            return Ok(buf.len());
+        }
+        self.dropping = false;
+        if self.current.is_none() {
+            self.open()?;
+        }
+        let (sink, _, written) = self.current.as_mut().unwrap();
+        sink.write_all(buf)?;
+        *written += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.current {
+            Some((QlogFileSink::Plain(w), _, _)) => w.flush(),
+            Some((QlogFileSink::Gzip(w), _, _)) => w.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(feature = "qlog")]
+impl Drop for QlogFile {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
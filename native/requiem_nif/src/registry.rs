@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use rustler::types::binary::{Binary, OwnedBinary};
+use rustler::types::tuple::make_tuple;
+use rustler::types::{Encoder, LocalPid};
+use rustler::{Atom, Env, NifResult};
+
+use crate::common::{atoms, Emitter};
+use crate::qlog::QlogFileOptions;
+
+// what can be asked of a live connection from outside of its owner
+pub enum Control {
+    Close(u64, Vec<u8>),
+    QlogToFile(QlogFileOptions),
+    QlogDisable,
+}
+
+// how a live connection is reached by module_shutdown() and the control socket
+pub enum Target {
+    // the owner process of a connection on the BEAM path, which closes it itself
+    Owner(LocalPid),
+    // a connection of the native datapath, controlled by commands to its worker
+    Worker(Box<dyn Fn(Control) + Send>),
+}
+
+impl Target {
+    // asks the connection to close with the application error
+    fn shutdown<E: Emitter>(&self, emitter: &mut E, err_code: u64, reason: &[u8]) {
+        match self {
+            Target::Owner(pid) => {
+                let reason = reason.to_vec();
+                emitter.emit(pid, move |env| {
+                    let mut bin = OwnedBinary::new(reason.len()).unwrap();
+                    bin.as_mut_slice().copy_from_slice(&reason);
+                    make_tuple(
+                        env,
+                        &[
+                            atoms::__shutdown__().to_term(env),
+                            err_code.encode(env),
+                            bin.release(env).to_term(env),
+                        ],
+                    )
+                });
+            }
+            Target::Worker(control) => control(Control::Close(err_code, reason.to_vec())),
+        }
+    }
+}
+
+struct Entry {
+    trace_id: String,
+    target: Target,
+}
+
+// the live connections of a module
+#[derive(Default)]
+pub struct Registry {
+    entries: Mutex<HashMap<u64, Entry>>,
+    next_key: AtomicU64,
+}
+
+impl Registry {
+    pub fn register(self: &Arc<Self>, trace_id: String, target: Target) -> Registration {
+        let key = self.next_key.fetch_add(1, Ordering::Relaxed);
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key, Entry { trace_id, target });
+        Registration {
+            registry: Arc::clone(self),
+            key,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    // the trace ids of the live connections, and whether each is on the native datapath
+    pub fn list(&self) -> Vec<(String, bool)> {
+        let entries = self.entries.lock().unwrap();
+        let mut list: Vec<(String, bool)> = entries
+            .values()
+            .map(|entry| {
+                let native = matches!(entry.target, Target::Worker(_));
+                (entry.trace_id.clone(), native)
+            })
+            .collect();
+        list.sort();
+        list
+    }
+
+    // false when there's no live connection with the trace id
+    pub(crate) fn shutdown<E: Emitter>(
+        &self,
+        emitter: &mut E,
+        trace_id: &str,
+        err_code: u64,
+        reason: &[u8],
+    ) -> bool {
+        let entries = self.entries.lock().unwrap();
+        match entries.values().find(|entry| entry.trace_id == trace_id) {
+            Some(entry) => {
+                entry.target.shutdown(emitter, err_code, reason);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn qlog_to_file(&self, trace_id: &str, options: QlogFileOptions) -> Result<(), Atom> {
+        self.control(trace_id, Control::QlogToFile(options))
+    }
+
+    pub fn qlog_disable(&self, trace_id: &str) -> Result<(), Atom> {
+        self.control(trace_id, Control::QlogDisable)
+    }
+
+    // only the connections of the native datapath take commands from outside
+    fn control(&self, trace_id: &str, command: Control) -> Result<(), Atom> {
+        let entries = self.entries.lock().unwrap();
+        match entries.values().find(|entry| entry.trace_id == trace_id) {
+            Some(Entry {
+                target: Target::Worker(control),
+                ..
+            }) => {
+                control(command);
+                Ok(())
+            }
+            Some(_) => Err(atoms::not_supported()),
+            None => Err(atoms::not_found()),
+        }
+    }
+}
+
+// held by a connection, unregisters it when the connection is dropped
+pub struct Registration {
+    registry: Arc<Registry>,
+    key: u64,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.entries.lock().unwrap().remove(&self.key);
+    }
+}
+
+// by module name, kept for the lifetime of the NIF
+static MODULES: Mutex<Option<HashMap<String, Arc<Registry>>>> = Mutex::new(None);
+
+pub fn registry(env: Env, module: Atom) -> NifResult<Arc<Registry>> {
+    let name = module.to_term(env).atom_to_string()?;
+    let mut modules = MODULES.lock().unwrap();
+    let registry = modules
+        .get_or_insert_with(HashMap::new)
+        .entry(name)
+        .or_default();
+    Ok(Arc::clone(registry))
+}
+
+// asks every live connection of the module to close with the application error,
+// returns how many have been asked. they're gone once module_connections() reaches 0.
+#[rustler::nif]
+pub fn module_shutdown(env: Env, module: Atom, err_code: u64, reason: Binary) -> NifResult<usize> {
+    let registry = registry(env, module)?;
+    let entries = registry.entries.lock().unwrap();
+    let mut env = env;
+    for entry in entries.values() {
+        entry.target.shutdown(&mut env, err_code, reason.as_slice());
+    }
+    Ok(entries.len())
+}
+
+// the registries of all the modules, sorted by module name
+pub fn modules() -> Vec<(String, Arc<Registry>)> {
+    let modules = MODULES.lock().unwrap();
+    let mut list: Vec<(String, Arc<Registry>)> = modules
+        .iter()
+        .flatten()
+        .map(|(name, registry)| (name.clone(), Arc::clone(registry)))
+        .collect();
+    list.sort_by(|a, b| a.0.cmp(&b.0));
+    list
+}
+
+// the live connections of a module by name, 0 for an unknown module
+pub fn connections(name: &str) -> usize {
+    let modules = MODULES.lock().unwrap();
+    modules
+        .as_ref()
+        .and_then(|modules| modules.get(name))
+        .map_or(0, |registry| registry.len())
+}
+
+#[rustler::nif]
+pub fn module_connections(env: Env, module: Atom) -> NifResult<usize> {
+    Ok(registry(env, module)?.len())
+}
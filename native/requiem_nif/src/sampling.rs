@@ -0,0 +1,70 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// {timestamp_ms, rtt_us, cwnd, delivery_rate}
+pub type SampleTuple = (u64, u64, usize, u64);
+
+#[derive(Clone, Copy)]
+pub struct Sample {
+    timestamp_ms: u64,
+    rtt: Duration,
+    cwnd: usize,
+    delivery_rate: u64,
+}
+
+impl Sample {
+    pub fn to_tuple(self) -> SampleTuple {
+        (
+            self.timestamp_ms,
+            self.rtt.as_micros() as u64,
+            self.cwnd,
+            self.delivery_rate,
+        )
+    }
+}
+
+// the last `capacity` samples of the path, taken at most once per `interval`
+// when the connection is drained
+pub struct Sampler {
+    interval: Duration,
+    capacity: usize,
+    last: Option<Instant>,
+    samples: VecDeque<Sample>,
+}
+
+impl Sampler {
+    pub fn new(interval: Duration, capacity: usize) -> Self {
+        Self {
+            interval,
+            capacity,
+            last: None,
+            samples: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        self.last
+            .map_or(true, |last| now.duration_since(last) >= self.interval)
+    }
+
+    pub fn record(&mut self, now: Instant, rtt: Duration, cwnd: usize, delivery_rate: u64) {
+        self.last = Some(now);
+        if self.samples.len() >= self.capacity {
+            self.samples.pop_front();
+        }
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        self.samples.push_back(Sample {
+            timestamp_ms,
+            rtt,
+            cwnd,
+            delivery_rate,
+        });
+    }
+
+    // oldest first
+    pub fn samples(&self) -> Vec<Sample> {
+        self.samples.iter().copied().collect()
+    }
+}
@@ -0,0 +1,59 @@
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use rustler::{Atom, NifResult};
+
+use crate::common::atoms;
+
+// native memory which the BEAM can't see, updated where it's allocated and freed
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+static CONNECTION_BUFFER_BYTES: AtomicUsize = AtomicUsize::new(0);
+static DATAPATH_BUFFER_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+pub fn connection_created(buffer_bytes: usize) {
+    CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+    CONNECTION_BUFFER_BYTES.fetch_add(buffer_bytes, Ordering::Relaxed);
+}
+
+pub fn connection_destroyed(buffer_bytes: usize) {
+    CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    CONNECTION_BUFFER_BYTES.fetch_sub(buffer_bytes, Ordering::Relaxed);
+}
+
+pub fn connection_buffer_grown(bytes: usize) {
+    CONNECTION_BUFFER_BYTES.fetch_add(bytes, Ordering::Relaxed);
+}
+
+pub fn datapath_buffer_allocated(bytes: usize) {
+    DATAPATH_BUFFER_BYTES.fetch_add(bytes, Ordering::Relaxed);
+}
+
+pub fn datapath_buffer_freed(bytes: usize) {
+    DATAPATH_BUFFER_BYTES.fetch_sub(bytes, Ordering::Relaxed);
+}
+
+// quiche allocates its own state behind the connection, so only
+// the fixed part is counted here. take it as a lower bound.
+pub fn memory() -> Vec<(Atom, u64)> {
+    let connections = CONNECTIONS.load(Ordering::Relaxed);
+    vec![
+        (atoms::connections(), connections as u64),
+        (
+            atoms::connection_buffer_bytes(),
+            CONNECTION_BUFFER_BYTES.load(Ordering::Relaxed) as u64,
+        ),
+        (
+            atoms::datapath_buffer_bytes(),
+            DATAPATH_BUFFER_BYTES.load(Ordering::Relaxed) as u64,
+        ),
+        (
+            atoms::quiche_connection_bytes(),
+            (connections * mem::size_of::<quiche::Connection>()) as u64,
+        ),
+    ]
+}
+
+#[rustler::nif]
+pub fn memory_stats() -> NifResult<Vec<(Atom, u64)>> {
+    Ok(memory())
+}
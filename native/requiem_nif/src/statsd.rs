@@ -0,0 +1,192 @@
+use std::fmt::Write;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::str;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use rustler::types::binary::Binary;
+use rustler::{Atom, Env, NifResult};
+
+use crossbeam_channel::{bounded, RecvTimeoutError, Sender};
+
+use crate::common::{self, atoms};
+use crate::registry::{self, Registry};
+use crate::telemetry::{self, Counters};
+
+// metric lines are packed into datagrams of at most this size
+const MAX_DATAGRAM_SIZE: usize = 1432;
+
+// what the pusher thread reports of a module
+struct Metrics {
+    module: String,
+    counters: Arc<Counters>,
+    registry: Arc<Registry>,
+    // the names of the counters and of the drop reasons, in the order of their values
+    counter_names: Vec<String>,
+    drop_names: Vec<String>,
+    prefix: String,
+    // DogStatsD tags the lines with the module, plain StatsD puts it in the name
+    tags: bool,
+}
+
+impl Metrics {
+    // the counters are pushed as the increments since the previous push
+    fn render(&self, last: &mut Vec<u64>, lines: &mut Vec<String>) {
+        let mut values: Vec<u64> = self.counters.snapshot().iter().map(|c| c.1).collect();
+        values.extend(self.counters.drops().iter().map(|d| d.1));
+        last.resize(values.len(), 0);
+        let names = self
+            .counter_names
+            .iter()
+            .map(|name| (name.as_str(), None))
+            .chain(
+                self.drop_names
+                    .iter()
+                    .map(|reason| ("packet_drops", Some(reason.as_str()))),
+            );
+        for (i, (name, reason)) in names.enumerate() {
+            let delta = values[i].saturating_sub(last[i]);
+            if delta > 0 {
+                lines.push(self.line(name, reason, delta, "c"));
+            }
+        }
+        *last = values;
+        lines.push(self.line("live_connections", None, self.registry.len() as u64, "g"));
+    }
+
+    fn line(&self, name: &str, reason: Option<&str>, value: u64, ty: &str) -> String {
+        let mut line = String::new();
+        if self.tags {
+            let _ = write!(
+                line,
+                "{}.{}:{}|{}|#module:{}",
+                self.prefix, name, value, ty, self.module
+            );
+            if let Some(reason) = reason {
+                let _ = write!(line, ",reason:{}", reason);
+            }
+        } else {
+            let _ = write!(line, "{}.{}.{}", self.prefix, self.module, name);
+            if let Some(reason) = reason {
+                let _ = write!(line, ".{}", reason);
+            }
+            let _ = write!(line, ":{}|{}", value, ty);
+        }
+        line
+    }
+}
+
+// pushes the counters of a module to a StatsD server from a thread of its own,
+// so that the metrics neither wait for nor go through the BEAM
+pub struct StatsdPusher {
+    closer: Sender<()>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl StatsdPusher {
+    fn start(target: SocketAddr, interval: Duration, metrics: Metrics) -> Result<Self, Atom> {
+        let local: SocketAddr = if target.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        let sock = UdpSocket::bind(local).map_err(|_| atoms::cant_bind())?;
+        sock.connect(target).map_err(|_| atoms::socket_error())?;
+
+        let (closer_tx, closer_rx) = bounded::<()>(1);
+
+        let handle = thread::spawn(move || {
+            let mut last = Vec::new();
+            let mut lines = Vec::new();
+            loop {
+                match closer_rx.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    _ => break,
+                }
+                metrics.render(&mut last, &mut lines);
+                for datagram in pack(&lines) {
+                    if let Err(e) = sock.send(datagram.as_bytes()) {
+                        debug!("statsd send error: {:?}", e);
+                    }
+                }
+                lines.clear();
+            }
+        });
+
+        Ok(Self {
+            closer: closer_tx,
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for StatsdPusher {
+    fn drop(&mut self) {
+        let _ = self.closer.send(());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+// newline separated lines, as many as fit in a datagram
+fn pack(lines: &[String]) -> Vec<String> {
+    let mut datagrams = Vec::new();
+    let mut datagram = String::new();
+    for line in lines {
+        if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM_SIZE {
+            datagrams.push(std::mem::take(&mut datagram));
+        }
+        if !datagram.is_empty() {
+            datagram.push('\n');
+        }
+        datagram.push_str(line);
+    }
+    if !datagram.is_empty() {
+        datagrams.push(datagram);
+    }
+    datagrams
+}
+
+// `address` is "host:port", `prefix` starts the metric names and `tags` makes
+// the lines DogStatsD ones. resolving the host may block, hence the dirty scheduler.
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn statsd_start(
+    env: Env,
+    module: Atom,
+    address: Binary,
+    interval_ms: u64,
+    prefix: String,
+    tags: bool,
+) -> NifResult<(Atom, i64)> {
+    let target: SocketAddr = str::from_utf8(address.as_slice())
+        .ok()
+        .and_then(|addr| addr.to_socket_addrs().ok())
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| common::error_term(atoms::bad_format()))?;
+
+    let counters = telemetry::counters(env, module)?;
+    let name = module.to_term(env).atom_to_string()?;
+    let metrics = Metrics {
+        module: name.strip_prefix("Elixir.").unwrap_or(&name).to_string(),
+        counter_names: telemetry::names(env, counters.snapshot())?,
+        drop_names: telemetry::names(env, counters.drops())?,
+        counters,
+        registry: registry::registry(env, module)?,
+        prefix,
+        tags,
+    };
+
+    match StatsdPusher::start(target, Duration::from_millis(interval_ms), metrics) {
+        Ok(pusher) => Ok((atoms::ok(), Box::into_raw(Box::new(pusher)) as i64)),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
+
+#[rustler::nif]
+pub fn statsd_stop(pusher_ptr: i64) -> NifResult<Atom> {
+    let pusher_ptr = pusher_ptr as *mut StatsdPusher;
+    unsafe { drop(Box::from_raw(pusher_ptr)) };
+    Ok(atoms::ok())
+}
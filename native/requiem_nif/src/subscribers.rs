@@ -0,0 +1,68 @@
+use rustler::types::LocalPid;
+use rustler::Atom;
+
+use crate::common::atoms;
+
+// the classes of the events of a connection, each of which can go to a process of its own
+#[derive(Clone, Copy, PartialEq)]
+pub enum EventClass {
+    // {:__drain__, peer, packet}, only on the BEAM path where the packets are passed to a process
+    Packets,
+    // the data, the ends, the resets and the closes of the streams
+    Streams,
+    // the received datagrams, and {:__dgram_readable__} in pull mode
+    Dgrams,
+    // the handshake and close transitions
+    Lifecycle,
+}
+
+impl EventClass {
+    // :packets, :streams, :dgrams or :lifecycle
+    pub fn from_atom(atom: Atom) -> Result<Self, Atom> {
+        if atom == atoms::packets() {
+            Ok(EventClass::Packets)
+        } else if atom == atoms::streams() {
+            Ok(EventClass::Streams)
+        } else if atom == atoms::dgrams() {
+            Ok(EventClass::Dgrams)
+        } else if atom == atoms::lifecycle() {
+            Ok(EventClass::Lifecycle)
+        } else {
+            Err(atoms::bad_format())
+        }
+    }
+}
+
+// the processes subscribed to the event classes, the others go where they went before
+#[derive(Default)]
+pub struct Subscribers {
+    packets: Option<LocalPid>,
+    streams: Option<LocalPid>,
+    dgrams: Option<LocalPid>,
+    lifecycle: Option<LocalPid>,
+}
+
+impl Subscribers {
+    fn slot(&mut self, class: EventClass) -> &mut Option<LocalPid> {
+        match class {
+            EventClass::Packets => &mut self.packets,
+            EventClass::Streams => &mut self.streams,
+            EventClass::Dgrams => &mut self.dgrams,
+            EventClass::Lifecycle => &mut self.lifecycle,
+        }
+    }
+
+    // None gives the class back to its default process
+    pub fn set(&mut self, class: EventClass, pid: Option<LocalPid>) {
+        *self.slot(class) = pid;
+    }
+
+    pub fn get(&self, class: EventClass) -> Option<&LocalPid> {
+        match class {
+            EventClass::Packets => self.packets.as_ref(),
+            EventClass::Streams => self.streams.as_ref(),
+            EventClass::Dgrams => self.dgrams.as_ref(),
+            EventClass::Lifecycle => self.lifecycle.as_ref(),
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rustler::types::binary::OwnedBinary;
+use rustler::types::tuple::make_tuple;
+use rustler::types::{Encoder, LocalPid};
+use rustler::Atom;
+
+use crate::common::{atoms, Emitter};
+
+// pcap with microsecond timestamps, each record being an IP packet (LINKTYPE_RAW)
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const LINKTYPE_RAW: u32 = 101;
+const SNAPLEN: u32 = 65535;
+
+#[derive(Clone, Copy)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+impl Direction {
+    fn to_atom(self) -> Atom {
+        match self {
+            Direction::In => atoms::inbound(),
+            Direction::Out => atoms::outbound(),
+        }
+    }
+}
+
+pub enum TapSpec {
+    Pid(LocalPid),
+    // the file is `<dir>/<trace_id>.pcap`
+    Pcap(PathBuf),
+}
+
+enum TapTarget {
+    Pid(LocalPid, Vec<(Direction, u64, SocketAddr, Vec<u8>)>),
+    Pcap(BufWriter<File>),
+}
+
+// copies the datagrams of a connection as they are on the wire, still encrypted
+pub struct Tap {
+    target: TapTarget,
+}
+
+impl Tap {
+    pub fn open(spec: TapSpec, trace_id: &str) -> io::Result<Self> {
+        let target = match spec {
+            TapSpec::Pid(pid) => TapTarget::Pid(pid, Vec::new()),
+            TapSpec::Pcap(dir) => {
+                std::fs::create_dir_all(&dir)?;
+                let mut file =
+                    BufWriter::new(File::create(dir.join(format!("{}.pcap", trace_id)))?);
+                file.write_all(&PCAP_MAGIC.to_le_bytes())?;
+                file.write_all(&2u16.to_le_bytes())?; // version 2.4
+                file.write_all(&4u16.to_le_bytes())?;
+                file.write_all(&0i32.to_le_bytes())?; // GMT offset
+                file.write_all(&0u32.to_le_bytes())?; // timestamp accuracy
+                file.write_all(&SNAPLEN.to_le_bytes())?;
+                file.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+                TapTarget::Pcap(file)
+            }
+        };
+        Ok(Self { target })
+    }
+
+    pub fn capture(&mut self, direction: Direction, peer: SocketAddr, data: &[u8]) {
+        let timestamp_us = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_micros() as u64);
+        match &mut self.target {
+            TapTarget::Pid(_, captured) => {
+                captured.push((direction, timestamp_us, peer, data.to_vec()));
+            }
+            TapTarget::Pcap(file) => {
+                if let Err(e) = write_record(file, direction, timestamp_us, peer, data) {
+                    debug!("failed to write pcap record: {}", e);
+                }
+            }
+        }
+    }
+
+    // passes the captured datagrams as `{:tap, direction, timestamp_us, peer, datagram}`,
+    // direction being `:inbound` or `:outbound` and peer an "address:port" string
+    pub fn emit<E: Emitter>(&mut self, emitter: &mut E) {
+        if let TapTarget::Pid(pid, captured) = &mut self.target {
+            for (direction, timestamp_us, peer, data) in captured.drain(..) {
+                let mut bin = OwnedBinary::new(data.len()).unwrap();
+                bin.as_mut_slice().copy_from_slice(&data);
+                let peer = peer.to_string();
+                emitter.emit(pid, move |env| {
+                    make_tuple(
+                        env,
+                        &[
+                            atoms::tap().to_term(env),
+                            direction.to_atom().to_term(env),
+                            timestamp_us.encode(env),
+                            peer.encode(env),
+                            bin.release(env).to_term(env),
+                        ],
+                    )
+                });
+            }
+        }
+    }
+}
+
+impl Drop for Tap {
+    fn drop(&mut self) {
+        if let TapTarget::Pcap(file) = &mut self.target {
+            let _ = file.flush();
+        }
+    }
+}
+
+// the local address isn't known here, the unspecified address and port 0 stand for it
+fn write_record(
+    file: &mut BufWriter<File>,
+    direction: Direction,
+    timestamp_us: u64,
+    peer: SocketAddr,
+    data: &[u8],
+) -> io::Result<()> {
+    let local = match peer {
+        SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    };
+    let (src, dst) = match direction {
+        Direction::In => (peer, local),
+        Direction::Out => (local, peer),
+    };
+    let packet = ip_udp_packet(src, dst, data);
+    let len = packet.len().min(SNAPLEN as usize);
+    file.write_all(&((timestamp_us / 1_000_000) as u32).to_le_bytes())?;
+    file.write_all(&((timestamp_us % 1_000_000) as u32).to_le_bytes())?;
+    file.write_all(&(len as u32).to_le_bytes())?;
+    file.write_all(&(packet.len() as u32).to_le_bytes())?;
+    file.write_all(&packet[..len])
+}
+
+fn ip_udp_packet(src: SocketAddr, dst: SocketAddr, data: &[u8]) -> Vec<u8> {
+    let udp_len = 8 + data.len();
+    let mut packet = Vec::with_capacity(40 + udp_len);
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            let mut header = [0u8; 20];
+            header[0] = 0x45;
+            header[2..4].copy_from_slice(&((20 + udp_len) as u16).to_be_bytes());
+            header[6] = 0x40; // don't fragment
+            header[8] = 64; // ttl
+            header[9] = 17; // udp
+            header[12..16].copy_from_slice(&src_ip.octets());
+            header[16..20].copy_from_slice(&dst_ip.octets());
+            let checksum = ipv4_checksum(&header);
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+            packet.extend_from_slice(&header);
+        }
+        (src_ip, dst_ip) => {
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            packet.push(0x60);
+            packet.extend_from_slice(&[0, 0, 0]);
+            packet.extend_from_slice(&(udp_len as u16).to_be_bytes());
+            packet.push(17); // next header: udp
+            packet.push(64); // hop limit
+            packet.extend_from_slice(&to_v6(src_ip).octets());
+            packet.extend_from_slice(&to_v6(dst_ip).octets());
+        }
+    }
+    // the UDP checksum is left out, tools accept it for captures
+    packet.extend_from_slice(&src.port().to_be_bytes());
+    packet.extend_from_slice(&dst.port().to_be_bytes());
+    packet.extend_from_slice(&(udp_len as u16).to_be_bytes());
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(data);
+    packet
+}
+
+fn ipv4_checksum(header: &[u8; 20]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use rustler::{Atom, Env, NifResult};
+
+use crate::common::atoms;
+
+// why a received packet was dropped
+#[derive(Clone, Copy)]
+pub enum DropReason {
+    DecryptFailed,
+    UnknownCid,
+    InvalidHeader,
+    BufferTooShort,
+    Other,
+}
+
+impl DropReason {
+    const ALL: [DropReason; 5] = [
+        DropReason::DecryptFailed,
+        DropReason::UnknownCid,
+        DropReason::InvalidHeader,
+        DropReason::BufferTooShort,
+        DropReason::Other,
+    ];
+
+    // the reason of an error returned by quiche::Connection::recv
+    pub fn from_recv_error(e: &quiche::Error) -> Self {
+        match e {
+            quiche::Error::CryptoFail => DropReason::DecryptFailed,
+            quiche::Error::InvalidPacket | quiche::Error::UnknownVersion => {
+                DropReason::InvalidHeader
+            }
+            quiche::Error::BufferTooShort => DropReason::BufferTooShort,
+            _ => DropReason::Other,
+        }
+    }
+
+    fn to_atom(self) -> Atom {
+        match self {
+            DropReason::DecryptFailed => atoms::decrypt_failed(),
+            DropReason::UnknownCid => atoms::unknown_cid(),
+            DropReason::InvalidHeader => atoms::invalid_header(),
+            DropReason::BufferTooShort => atoms::buffer_too_short(),
+            DropReason::Other => atoms::other(),
+        }
+    }
+}
+
+// counters of the connections of a module, shared by all of them
+#[derive(Default)]
+pub struct Counters {
+    accepted: AtomicU64,
+    refused: AtomicU64,
+    packets_in: AtomicU64,
+    packets_out: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    handshake_failures: AtomicU64,
+    dgram_drops: AtomicU64,
+    // by DropReason
+    packet_drops: [AtomicU64; 5],
+}
+
+impl Counters {
+    pub fn accepted(&self) {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn refused(&self) {
+        self.refused.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn packet_in(&self, len: usize) {
+        self.packets_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn packet_out(&self, len: usize) {
+        self.packets_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn handshake_failed(&self) {
+        self.handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dgram_dropped(&self) {
+        self.dgram_drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn packet_dropped(&self, reason: DropReason) {
+        self.packet_drops[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn drops(&self) -> Vec<(Atom, u64)> {
+        DropReason::ALL
+            .iter()
+            .map(|reason| {
+                (
+                    reason.to_atom(),
+                    self.packet_drops[*reason as usize].load(Ordering::Relaxed),
+                )
+            })
+            .collect()
+    }
+
+    pub fn snapshot(&self) -> Vec<(Atom, u64)> {
+        vec![
+            (atoms::accepted(), self.accepted.load(Ordering::Relaxed)),
+            (atoms::refused(), self.refused.load(Ordering::Relaxed)),
+            (atoms::packets_in(), self.packets_in.load(Ordering::Relaxed)),
+            (
+                atoms::packets_out(),
+                self.packets_out.load(Ordering::Relaxed),
+            ),
+            (atoms::bytes_in(), self.bytes_in.load(Ordering::Relaxed)),
+            (atoms::bytes_out(), self.bytes_out.load(Ordering::Relaxed)),
+            (
+                atoms::handshake_failures(),
+                self.handshake_failures.load(Ordering::Relaxed),
+            ),
+            (
+                atoms::dgram_drops(),
+                self.dgram_drops.load(Ordering::Relaxed),
+            ),
+        ]
+    }
+}
+
+// the names of the counters of snapshot() or drops(), for the threads which can't resolve atoms
+pub fn names(env: Env, values: Vec<(Atom, u64)>) -> NifResult<Vec<String>> {
+    values
+        .into_iter()
+        .map(|(atom, _)| atom.to_term(env).atom_to_string())
+        .collect()
+}
+
+// by module name, kept for the lifetime of the NIF
+static MODULES: Mutex<Option<HashMap<String, Arc<Counters>>>> = Mutex::new(None);
+
+pub fn counters(env: Env, module: Atom) -> NifResult<Arc<Counters>> {
+    let name = module.to_term(env).atom_to_string()?;
+    let mut modules = MODULES.lock().unwrap();
+    let counters = modules
+        .get_or_insert_with(HashMap::new)
+        .entry(name)
+        .or_default();
+    Ok(Arc::clone(counters))
+}
+
+// the counters of all the modules which have had connections, by module name
+pub fn modules() -> Vec<(String, Arc<Counters>)> {
+    let modules = MODULES.lock().unwrap();
+    let mut modules: Vec<_> = modules
+        .iter()
+        .flatten()
+        .map(|(name, counters)| (name.clone(), Arc::clone(counters)))
+        .collect();
+    modules.sort_by(|a, b| a.0.cmp(&b.0));
+    modules
+}
+
+// all 0 for a module without connections yet
+#[rustler::nif]
+pub fn telemetry_snapshot(env: Env, module: Atom) -> NifResult<Vec<(Atom, u64)>> {
+    Ok(counters(env, module)?.snapshot())
+}
+
+#[rustler::nif]
+pub fn telemetry_drops(env: Env, module: Atom) -> NifResult<Vec<(Atom, u64)>> {
+    Ok(counters(env, module)?.drops())
+}
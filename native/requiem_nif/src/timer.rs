@@ -0,0 +1,226 @@
+use std::time::{Duration, Instant};
+
+use rustler::types::Encoder;
+use rustler::{Env, Term};
+
+use crate::common::atoms;
+
+// the time until the next timeout of a connection, passed to elixir as milliseconds,
+// or :infinity when quiche has no timer running (the idle timeout is disabled)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Timeout {
+    After(u64),
+    Infinity,
+}
+
+impl Timeout {
+    pub fn from_duration(duration: Option<Duration>) -> Self {
+        match duration {
+            // saturated, the millis of a Duration don't fit in u64
+            Some(duration) => {
+                Timeout::After(u64::try_from(duration.as_millis()).unwrap_or(u64::MAX))
+            }
+            None => Timeout::Infinity,
+        }
+    }
+}
+
+impl Encoder for Timeout {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        match self {
+            Timeout::After(millis) => millis.encode(env),
+            Timeout::Infinity => atoms::infinity().encode(env),
+        }
+    }
+}
+
+// hashed timer wheel for the connection timeouts of a datapath worker.
+// scheduling is O(1), and the caller cancels the timer it replaces
+// or whose connection is gone, so that the slots don't keep them.
+pub struct TimerWheel {
+    start: Instant,
+    tick: Duration,
+    // the next tick to be processed
+    current: u64,
+    // (tick, conn_id)
+    slots: Vec<Vec<(u64, u64)>>,
+    len: usize,
+}
+
+impl TimerWheel {
+    pub fn new(tick: Duration, num_slots: usize) -> Self {
+        Self {
+            start: Instant::now(),
+            tick,
+            current: 0,
+            slots: vec![Vec::new(); num_slots.max(1)],
+            len: 0,
+        }
+    }
+
+    fn tick_of(&self, at: Instant) -> u64 {
+        (at.saturating_duration_since(self.start).as_nanos() / self.tick.as_nanos()) as u64
+    }
+
+    // the tick on which a timer for `deadline` fires, rounded up so that
+    // a timer never fires before its deadline
+    pub fn tick_for(&self, deadline: Instant) -> u64 {
+        self.tick_of(deadline + self.tick).max(self.current)
+    }
+
+    pub fn schedule(&mut self, conn_id: u64, tick: u64) {
+        let slot = (tick % self.slots.len() as u64) as usize;
+        self.slots[slot].push((tick, conn_id));
+        self.len += 1;
+    }
+
+    // removes a timer scheduled for the tick, a no-op when it has expired already
+    pub fn cancel(&mut self, conn_id: u64, tick: u64) {
+        let slot = (tick % self.slots.len() as u64) as usize;
+        let slot = &mut self.slots[slot];
+        if let Some(pos) = slot.iter().position(|entry| *entry == (tick, conn_id)) {
+            slot.swap_remove(pos);
+            self.len -= 1;
+        }
+    }
+
+    // collects (conn_id, tick) of the timers expired until `now`
+    pub fn expire(&mut self, now: Instant, expired: &mut Vec<(u64, u64)>) {
+        let now_tick = self.tick_of(now);
+        if now_tick < self.current {
+            return;
+        }
+        let num_slots = self.slots.len() as u64;
+        let ticks = (now_tick - self.current + 1).min(num_slots);
+        for i in 0..ticks {
+            let slot = ((self.current + i) % num_slots) as usize;
+            let before = self.slots[slot].len();
+            self.slots[slot].retain(|(tick, conn_id)| {
+                if *tick <= now_tick {
+                    expired.push((*conn_id, *tick));
+                    false
+                } else {
+                    true
+                }
+            });
+            self.len -= before - self.slots[slot].len();
+        }
+        self.current = now_tick + 1;
+    }
+
+    // the start of the next non-empty slot, entries in it may be for later rounds
+    pub fn next_expiration(&self) -> Option<Instant> {
+        if self.len == 0 {
+            return None;
+        }
+        let num_slots = self.slots.len() as u64;
+        let ahead = (0..num_slots)
+            .find(|i| !self.slots[((self.current + i) % num_slots) as usize].is_empty())
+            .unwrap_or(0);
+        let nanos = self.tick.as_nanos() as u64 * (self.current + ahead);
+        Some(self.start + Duration::from_nanos(nanos))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK: Duration = Duration::from_millis(10);
+
+    fn at(wheel: &TimerWheel, ticks: u32) -> Instant {
+        wheel.start + TICK * ticks
+    }
+
+    #[test]
+    fn tick_rounds_up() {
+        let wheel = TimerWheel::new(TICK, 8);
+        assert_eq!(wheel.tick_for(wheel.start), 1);
+        assert_eq!(wheel.tick_for(at(&wheel, 3)), 4);
+        assert_eq!(wheel.tick_for(at(&wheel, 3) + TICK / 2), 4);
+    }
+
+    #[test]
+    fn tick_is_never_in_the_past() {
+        let mut wheel = TimerWheel::new(TICK, 8);
+        let mut expired = Vec::new();
+        wheel.expire(at(&wheel, 5), &mut expired);
+        assert_eq!(wheel.tick_for(wheel.start), 6);
+    }
+
+    #[test]
+    fn expires_at_its_tick() {
+        let mut wheel = TimerWheel::new(TICK, 8);
+        let tick = wheel.tick_for(at(&wheel, 2));
+        wheel.schedule(1, tick);
+        let mut expired = Vec::new();
+        wheel.expire(at(&wheel, 2), &mut expired);
+        assert!(expired.is_empty());
+        wheel.expire(at(&wheel, 3), &mut expired);
+        assert_eq!(expired, vec![(1, 3)]);
+        assert_eq!(wheel.next_expiration(), None);
+    }
+
+    #[test]
+    fn wraps_around() {
+        let mut wheel = TimerWheel::new(TICK, 4);
+        // the same slot as tick 2, a round later
+        wheel.schedule(1, 6);
+        wheel.schedule(2, 2);
+        let mut expired = Vec::new();
+        wheel.expire(at(&wheel, 2), &mut expired);
+        assert_eq!(expired, vec![(2, 2)]);
+        expired.clear();
+        wheel.expire(at(&wheel, 5), &mut expired);
+        assert!(expired.is_empty());
+        wheel.expire(at(&wheel, 6), &mut expired);
+        assert_eq!(expired, vec![(1, 6)]);
+    }
+
+    #[test]
+    fn expires_after_more_than_a_round() {
+        let mut wheel = TimerWheel::new(TICK, 4);
+        wheel.schedule(1, 1);
+        wheel.schedule(2, 3);
+        let mut expired = Vec::new();
+        wheel.expire(at(&wheel, 10), &mut expired);
+        expired.sort_unstable();
+        assert_eq!(expired, vec![(1, 1), (2, 3)]);
+    }
+
+    #[test]
+    fn cancels() {
+        let mut wheel = TimerWheel::new(TICK, 4);
+        wheel.schedule(1, 5);
+        wheel.cancel(1, 5);
+        wheel.schedule(1, 3);
+        // expired already, or never scheduled
+        wheel.cancel(1, 2);
+        wheel.cancel(2, 3);
+        assert_eq!(wheel.len, 1);
+        let mut expired = Vec::new();
+        wheel.expire(at(&wheel, 10), &mut expired);
+        assert_eq!(expired, vec![(1, 3)]);
+        assert_eq!(wheel.len, 0);
+    }
+
+    #[test]
+    fn next_expiration() {
+        let mut wheel = TimerWheel::new(TICK, 8);
+        assert_eq!(wheel.next_expiration(), None);
+        wheel.schedule(1, 5);
+        wheel.schedule(2, 3);
+        assert_eq!(wheel.next_expiration(), Some(at(&wheel, 3)));
+        let mut expired = Vec::new();
+        wheel.expire(at(&wheel, 3), &mut expired);
+        assert_eq!(wheel.next_expiration(), Some(at(&wheel, 5)));
+    }
+
+    #[test]
+    fn next_expiration_of_a_later_round() {
+        let mut wheel = TimerWheel::new(TICK, 4);
+        // in the slot of tick 1, which is reached before the timer fires
+        wheel.schedule(1, 5);
+        assert_eq!(wheel.next_expiration(), Some(at(&wheel, 1)));
+    }
+}
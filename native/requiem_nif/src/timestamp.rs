@@ -0,0 +1,56 @@
+use rustler::types::tuple::make_tuple;
+use rustler::types::{Encoder, LocalPid};
+use rustler::{Env, NifResult, Term};
+
+use crate::common::{atoms, Emitter};
+
+// CLOCK_MONOTONIC in microseconds, which the BEAM doesn't share: Elixir reads it
+// with native_monotonic_time() to compare with the timestamps of the events
+pub fn monotonic_us() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000 + ts.tv_nsec as u64 / 1_000
+}
+
+// passes the messages on as {:__at__, emitted_at, received_at, message}, received_at
+// being when the packet which caused them was received, nil for the timers
+pub struct Stamped<'e, E: Emitter> {
+    inner: &'e mut E,
+    received_at: Option<u64>,
+}
+
+impl<'e, E: Emitter> Stamped<'e, E> {
+    pub fn new(inner: &'e mut E, received_at: Option<u64>) -> Self {
+        Self { inner, received_at }
+    }
+}
+
+impl<'e, E: Emitter> Emitter for Stamped<'e, E> {
+    fn emit<F>(&mut self, pid: &LocalPid, f: F)
+    where
+        F: for<'a> FnOnce(Env<'a>) -> Term<'a>,
+    {
+        let emitted_at = monotonic_us();
+        let received_at = self.received_at;
+        self.inner.emit(pid, move |env| {
+            let message = f(env);
+            make_tuple(
+                env,
+                &[
+                    atoms::__at__().to_term(env),
+                    emitted_at.encode(env),
+                    received_at.encode(env),
+                    message,
+                ],
+            )
+        });
+    }
+}
+
+#[rustler::nif]
+pub fn native_monotonic_time() -> NifResult<u64> {
+    Ok(monotonic_us())
+}
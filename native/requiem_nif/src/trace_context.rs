@@ -0,0 +1,38 @@
+use std::fmt::Write;
+
+use rustler::Atom;
+
+use crate::common::atoms;
+
+// W3C trace context of the span the connection belongs to, set by the owner at accept
+#[derive(Clone, Copy)]
+pub struct TraceContext {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    flags: u8,
+}
+
+impl TraceContext {
+    pub fn new(trace_id: &[u8], span_id: &[u8], flags: u8) -> Result<Self, Atom> {
+        Ok(Self {
+            trace_id: trace_id.try_into().map_err(|_| atoms::bad_format())?,
+            span_id: span_id.try_into().map_err(|_| atoms::bad_format())?,
+            flags,
+        })
+    }
+
+    // "00-<trace_id>-<span_id>-<flags>", as in the traceparent header
+    pub fn traceparent(&self) -> String {
+        let mut out = String::with_capacity(55);
+        out.push_str("00-");
+        for b in self.trace_id {
+            let _ = write!(out, "{:02x}", b);
+        }
+        out.push('-');
+        for b in self.span_id {
+            let _ = write!(out, "{:02x}", b);
+        }
+        let _ = write!(out, "-{:02x}", self.flags);
+        out
+    }
+}
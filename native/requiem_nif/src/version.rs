@@ -0,0 +1,52 @@
+use std::str::FromStr;
+
+use rustler::{Atom, NifResult};
+
+use crate::common::atoms;
+
+// the quiche which the NIF is built with, as locked in Cargo.lock
+pub const QUICHE_VERSION: &str = "0.12.0 (lyokato/quiche 0.12.0-path-validation)";
+
+// QUIC v1 and the drafts which quiche may still speak
+const QUIC_VERSIONS: [u32; 4] = [0x0000_0001, 0xff00_001d, 0xff00_001c, 0xff00_001b];
+
+// the names config_set_cc_algorithm_name may accept
+const CC_ALGORITHMS: [&str; 4] = ["reno", "cubic", "bbr", "bbr2"];
+
+fn features() -> Vec<Atom> {
+    // datagrams, HTTP/3 and WebTransport are always built in
+    let mut features = vec![atoms::dgram(), atoms::h3(), atoms::webtransport()];
+    if cfg!(feature = "qlog") {
+        features.push(atoms::qlog());
+    }
+    if cfg!(feature = "jemalloc") {
+        features.push(atoms::jemalloc());
+    }
+    if cfg!(feature = "mimalloc") {
+        features.push(atoms::mimalloc());
+    }
+    features
+}
+
+// (nif version, quiche version, supported QUIC versions, congestion control algorithms,
+// features), the versions and algorithms being asked to quiche itself
+#[rustler::nif]
+pub fn native_versions() -> NifResult<(String, String, Vec<u32>, Vec<String>, Vec<Atom>)> {
+    let quic_versions = QUIC_VERSIONS
+        .iter()
+        .copied()
+        .filter(|version| quiche::version_is_supported(*version))
+        .collect();
+    let cc_algorithms = CC_ALGORITHMS
+        .iter()
+        .filter(|name| quiche::CongestionControlAlgorithm::from_str(name).is_ok())
+        .map(|name| name.to_string())
+        .collect();
+    Ok((
+        env!("CARGO_PKG_VERSION").to_string(),
+        QUICHE_VERSION.to_string(),
+        quic_versions,
+        cc_algorithms,
+        features(),
+    ))
+}
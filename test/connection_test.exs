@@ -30,14 +30,14 @@ defmodule RequiemTest.ConnectionTest do
     {:ok, c} = Config.new()
 
     try do
-      {:ok, conn} = Connection.accept(c, scid, odcid, peer, sender_pid, 1024 * 10)
+      {:ok, conn} = Connection.accept(__MODULE__, c, scid, odcid, peer, sender_pid, 1024 * 10)
 
       try do
         assert Connection.is_closed?(conn) == false
         assert Connection.close(conn, false, 0x1, "") == {:error, :already_closed}
         assert Connection.is_closed?(conn) == true
       after
-        Connection.destroy(conn)
+        Connection.destroy(conn, 0)
       end
     after
       Config.destroy(c)
@@ -58,8 +58,8 @@ defmodule RequiemTest.ConnectionTest do
     {:ok, c} = Config.new()
 
     try do
-      {:ok, conn1} = Connection.accept(c, scid1, odcid1, peer, sender_pid, 1024 * 10)
-      {:ok, conn2} = Connection.accept(c, scid2, odcid2, peer, sender_pid, 1024 * 10)
+      {:ok, conn1} = Connection.accept(__MODULE__, c, scid1, odcid1, peer, sender_pid, 1024 * 10)
+      {:ok, conn2} = Connection.accept(__MODULE__, c, scid2, odcid2, peer, sender_pid, 1024 * 10)
 
       try do
         assert Connection.is_closed?(conn1) == false
@@ -77,8 +77,8 @@ defmodule RequiemTest.ConnectionTest do
         assert Connection.close(conn1, false, 0x1, "") == {:error, :already_closed}
         assert Connection.close(conn2, false, 0x1, "") == {:error, :already_closed}
       after
-        Connection.destroy(conn1)
-        Connection.destroy(conn2)
+        Connection.destroy(conn1, 0)
+        Connection.destroy(conn2, 0)
       end
     after
       Config.destroy(c)